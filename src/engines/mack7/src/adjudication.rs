@@ -0,0 +1,183 @@
+//! Ends self-play games early once their outcome is clear, based on the value
+//! at the root of the search after every move. A fraction of the games never
+//! resigns, so that the rate of wrong resignations can be measured.
+
+use rand::{rngs::StdRng, Rng};
+
+use crate::chunk::Termination;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AdjudicationOptions {
+    /// A player resigns once its value stayed below this threshold for
+    /// `resign_plies` plies in a row. Disabled if not given.
+    pub resign_threshold: Option<f32>,
+    pub resign_plies: u32,
+    /// The fraction of games that are played until the end instead of
+    /// resigning.
+    pub resign_playthrough: f32,
+    /// A game is adjudicated as a draw once the absolute value stayed below
+    /// this threshold for `draw_plies` plies in a row. Disabled if not given.
+    pub draw_threshold: Option<f32>,
+    pub draw_plies: u32,
+    /// Games that reach this many plies are adjudicated as draws.
+    pub max_plies: Option<u32>,
+}
+
+/// Follows the values of one game and decides when it ends.
+pub struct Adjudicator {
+    options: AdjudicationOptions,
+    playthrough: bool,
+    plies: u32,
+    /// The number of plies in a row that white and black were below the
+    /// resignation threshold.
+    losing_plies: [u32; 2],
+    drawn_plies: u32,
+    would_resign: Option<i8>,
+}
+
+impl Adjudicator {
+    pub fn new(options: AdjudicationOptions, rng: &mut StdRng) -> Adjudicator {
+        Adjudicator {
+            options,
+            playthrough: options.resign_threshold.is_some()
+                && rng.gen::<f32>() < options.resign_playthrough,
+            plies: 0,
+            losing_plies: [0, 0],
+            drawn_plies: 0,
+            would_resign: None,
+        }
+    }
+
+    /// Takes the value at the root after searching a position, from the
+    /// perspective of white, and returns the result of the game from the
+    /// perspective of white if it ends here.
+    pub fn update(&mut self, value: f32) -> Option<(i8, Termination)> {
+        self.plies += 1;
+
+        if let Some(threshold) = self.options.resign_threshold {
+            for (side, side_value) in [value, -value].into_iter().enumerate() {
+                if side_value < threshold {
+                    self.losing_plies[side] += 1;
+                } else {
+                    self.losing_plies[side] = 0;
+                }
+                if self.losing_plies[side] >= self.options.resign_plies {
+                    let result = if side == 0 { -1 } else { 1 };
+                    if !self.playthrough {
+                        return Some((result, Termination::Resignation));
+                    }
+                    self.would_resign.get_or_insert(result);
+                }
+            }
+        }
+
+        if let Some(threshold) = self.options.draw_threshold {
+            if value.abs() < threshold {
+                self.drawn_plies += 1;
+            } else {
+                self.drawn_plies = 0;
+            }
+            if self.drawn_plies >= self.options.draw_plies {
+                return Some((0, Termination::DrawAdjudication));
+            }
+        }
+
+        match self.options.max_plies {
+            Some(max_plies) if self.plies >= max_plies => Some((0, Termination::MaxPlies)),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the game would have been resigned with a different
+    /// result than it ended with, for games that are played through.
+    pub fn is_false_positive(&self, result: i8) -> bool {
+        self.would_resign.is_some_and(|resigned| resigned != result)
+    }
+
+    /// Returns whether resignation was suppressed at some point of the game.
+    pub fn would_have_resigned(&self) -> bool {
+        self.would_resign.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn options() -> AdjudicationOptions {
+        AdjudicationOptions {
+            resign_threshold: Some(-0.9),
+            resign_plies: 3,
+            resign_playthrough: 0.,
+            draw_threshold: Some(0.05),
+            draw_plies: 4,
+            max_plies: Some(10),
+        }
+    }
+
+    fn play(adjudicator: &mut Adjudicator, values: &[f32]) -> Option<(usize, i8, Termination)> {
+        values.iter().enumerate().find_map(|(ply, &value)| {
+            adjudicator
+                .update(value)
+                .map(|(result, termination)| (ply, result, termination))
+        })
+    }
+
+    #[test]
+    fn resigns_lost_games() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // The streak of white is interrupted once
+        let mut adjudicator = Adjudicator::new(options(), &mut rng);
+        let values = [-0.95, -0.95, -0.5, -0.95, -0.99, -0.92];
+        assert_eq!(
+            play(&mut adjudicator, &values),
+            Some((5, -1, Termination::Resignation))
+        );
+
+        let mut adjudicator = Adjudicator::new(options(), &mut rng);
+        assert_eq!(
+            play(&mut adjudicator, &[0.3, 0.95, 0.95, 0.95]),
+            Some((3, 1, Termination::Resignation))
+        );
+    }
+
+    #[test]
+    fn plays_through_to_measure_false_positives() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut adjudicator = Adjudicator::new(
+            AdjudicationOptions {
+                resign_playthrough: 1.,
+                ..options()
+            },
+            &mut rng,
+        );
+        assert_eq!(play(&mut adjudicator, &[-0.95, -0.95, -0.95, 0.5]), None);
+        assert!(adjudicator.would_have_resigned());
+        assert!(adjudicator.is_false_positive(1));
+        assert!(adjudicator.is_false_positive(0));
+        assert!(!adjudicator.is_false_positive(-1));
+    }
+
+    #[test]
+    fn adjudicates_draws() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut adjudicator = Adjudicator::new(options(), &mut rng);
+        let values = [0.01, -0.02, 0.3, 0., 0.01, -0.04, 0.02];
+        assert_eq!(
+            play(&mut adjudicator, &values),
+            Some((6, 0, Termination::DrawAdjudication))
+        );
+
+        let mut adjudicator = Adjudicator::new(options(), &mut rng);
+        let values = [0.5, -0.5].repeat(10);
+        assert_eq!(
+            play(&mut adjudicator, &values),
+            Some((9, 0, Termination::MaxPlies))
+        );
+
+        let mut adjudicator = Adjudicator::new(AdjudicationOptions::default(), &mut rng);
+        assert_eq!(play(&mut adjudicator, &[-1.; 100]), None);
+    }
+}
@@ -0,0 +1,246 @@
+//! A depth-limited alpha-beta search that evaluates positions with the NNUE.
+//! The accumulator is updated along every move of the search instead of being
+//! computed from scratch for each leaf. With tablebases or bitbases,
+//! positions they know end the search with their result.
+
+use crate::{
+    bitbase::Bitbases,
+    chess_move::{Move, MoveIndex},
+    game::Game,
+    nnue::{Accumulator, Nnue},
+    syzygy::{Tablebases, Wdl},
+};
+
+/// The score of being checkmated, far beyond the values of the NNUE so that
+/// every mate is preferred over any evaluation.
+const MATE: f32 = 100.;
+
+/// The score of a win that the tablebases or bitbases know, below any mate
+/// but beyond the values of the NNUE.
+const TABLEBASE_WIN: f32 = MATE / 4.;
+
+pub struct SearchResult {
+    pub best_move: Option<Move>,
+    /// The score from the perspective of the player to move.
+    pub score: f32,
+    pub nodes: u64,
+}
+
+/// Returns whether the score is a mate for either side.
+pub fn is_mate(score: f32) -> bool {
+    score.abs() > MATE / 2.
+}
+
+struct Searcher<'a> {
+    nnue: &'a Nnue,
+    nodes: u64,
+    /// The best move at the root from the previous iteration.
+    root_move: Option<MoveIndex>,
+    tablebases: Option<&'a Tablebases>,
+    /// The moves at the root that the tablebases rank best, if they know
+    /// the position.
+    root_moves: Option<Vec<MoveIndex>>,
+    bitbases: Option<&'a Bitbases>,
+}
+
+impl Searcher<'_> {
+    /// Returns the result for the player to move if the tablebases or the
+    /// bitbases know the position.
+    fn endgame_result(&self, game: &Game) -> Option<i8> {
+        self.tablebases
+            .and_then(|tablebases| tablebases.probe_wdl(game))
+            .map(Wdl::score)
+            .or_else(|| self.bitbases.and_then(|bitbases| bitbases.probe(game)))
+    }
+
+    fn evaluate(&self, game: &Game, accumulator: &Accumulator) -> f32 {
+        let value = self.nnue.evaluate(accumulator, game.player);
+        if game.player {
+            value
+        } else {
+            -value
+        }
+    }
+
+    /// Searches only captures until the position is quiet, so that leaves are
+    /// not evaluated in the middle of an exchange.
+    fn quiescence(&mut self, game: &Game, accumulator: &Accumulator, alpha: f32, beta: f32) -> f32 {
+        self.nodes += 1;
+        let mut best = self.evaluate(game, accumulator);
+        if best >= beta {
+            return best;
+        }
+        let mut alpha = alpha.max(best);
+
+        for m in game.legal_moves(game.player) {
+            if !game.is_capture(&m) {
+                continue;
+            }
+            let next = game.make_move(&m, false);
+            let next_accumulator = self.nnue.update(accumulator, game, &next);
+            let score = -self.quiescence(&next, &next_accumulator, -beta, -alpha);
+            if score > best {
+                best = score;
+                alpha = alpha.max(score);
+                if score >= beta {
+                    break;
+                }
+            }
+        }
+        best
+    }
+
+    /// Returns the best score and move with the given number of plies left.
+    /// Captures are searched first, after the best move of the previous
+    /// iteration at the root.
+    fn negamax(
+        &mut self,
+        game: &Game,
+        accumulator: &Accumulator,
+        depth: u32,
+        ply: u32,
+        alpha: f32,
+        beta: f32,
+    ) -> (f32, Option<Move>) {
+        let mut moves = game.legal_moves(game.player);
+        if moves.is_empty() {
+            self.nodes += 1;
+            let score = if game.position.is_check(game.player) {
+                -(MATE - ply as f32)
+            } else {
+                0.
+            };
+            return (score, None);
+        }
+        if ply > 0 {
+            if let Some(result) = self.endgame_result(game) {
+                self.nodes += 1;
+                return (result as f32 * (TABLEBASE_WIN - ply as f32), None);
+            }
+        }
+        if depth == 0 {
+            return (self.quiescence(game, accumulator, alpha, beta), None);
+        }
+        self.nodes += 1;
+
+        if let (0, Some(root_moves)) = (ply, &self.root_moves) {
+            moves.retain(|m| root_moves.contains(&m.index()));
+        }
+
+        moves.sort_by_key(|m| {
+            if ply == 0 && self.root_move == Some(m.index()) {
+                0
+            } else if game.is_capture(m) {
+                1
+            } else {
+                2
+            }
+        });
+
+        let mut alpha = alpha;
+        let mut best = (f32::NEG_INFINITY, None);
+        for m in moves {
+            let next = game.make_move(&m, false);
+            let next_accumulator = self.nnue.update(accumulator, game, &next);
+            let (score, _) =
+                self.negamax(&next, &next_accumulator, depth - 1, ply + 1, -beta, -alpha);
+            let score = -score;
+            if score > best.0 {
+                best = (score, Some(m));
+                alpha = alpha.max(score);
+                if score >= beta {
+                    break;
+                }
+            }
+        }
+        best
+    }
+}
+
+/// Searches the position with iterative deepening up to the given depth.
+/// With tablebases that know the position, only the moves they rank best are
+/// searched at the root.
+pub fn search(
+    game: &Game,
+    nnue: &Nnue,
+    depth: u32,
+    tablebases: Option<&Tablebases>,
+    bitbases: Option<&Bitbases>,
+) -> SearchResult {
+    let mut searcher = Searcher {
+        nnue,
+        nodes: 0,
+        root_move: None,
+        tablebases,
+        root_moves: tablebases
+            .and_then(|tablebases| tablebases.root_moves(game))
+            .map(|moves| moves.iter().map(Move::index).collect()),
+        bitbases,
+    };
+    let accumulator = nnue.refresh(game);
+
+    let mut result = SearchResult {
+        best_move: None,
+        score: 0.,
+        nodes: 0,
+    };
+    for depth in 1..=depth.max(1) {
+        let (score, best_move) = searcher.negamax(
+            game,
+            &accumulator,
+            depth,
+            0,
+            f32::NEG_INFINITY,
+            f32::INFINITY,
+        );
+        searcher.root_move = best_move.as_ref().map(Move::index);
+        result.score = score;
+        result.best_move = best_move;
+        if is_mate(score) {
+            break;
+        }
+    }
+    result.nodes = searcher.nodes;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_mate_in_one() {
+        let nnue = Nnue::new(8);
+        let game = Game::from_fen("6k1/5ppp/8/8/8/8/5PPP/R5K1 w - - 0 1");
+        let result = search(&game, &nnue, 2, None, None);
+        assert_eq!(result.best_move.unwrap().to_uci(), "a1a8");
+        assert!(is_mate(result.score) && result.score > 0.);
+    }
+
+    #[test]
+    fn scores_final_positions() {
+        let nnue = Nnue::new(8);
+
+        let mated = Game::from_fen("R5k1/5ppp/8/8/8/8/5PPP/6K1 b - - 1 1");
+        let result = search(&mated, &nnue, 3, None, None);
+        assert!(result.best_move.is_none());
+        assert_eq!(result.score, -MATE);
+
+        let stalemate = Game::from_fen("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+        let result = search(&stalemate, &nnue, 3, None, None);
+        assert!(result.best_move.is_none());
+        assert_eq!(result.score, 0.);
+    }
+
+    #[test]
+    fn avoids_getting_mated() {
+        let nnue = Nnue::new(8);
+        // Every move but h7h6 or g7g6 runs into Ra8 mate
+        let game = Game::from_fen("6k1/5ppp/8/8/8/8/5PPP/R5K1 b - - 0 1");
+        let result = search(&game, &nnue, 3, None, None);
+        assert!(!is_mate(result.score));
+        let best_move = result.best_move.unwrap().to_uci();
+        let escapes = ["h7h6", "h7h5", "g7g6", "g7g5", "f7f6", "f7f5", "g8f8"];
+        assert!(escapes.contains(&best_move.as_str()), "{}", best_move);
+    }
+}
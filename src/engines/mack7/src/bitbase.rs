@@ -0,0 +1,341 @@
+//! Bitbases for the endgames of a king with a pawn, a rook or a queen against
+//! a lone king. They are generated by retrograde analysis, starting from the
+//! positions where the lone king is mated, and store one bit per position,
+//! whether the stronger side wins. Everything that is not a win is a draw.
+
+use crate::{
+    bitboard::Bitboard,
+    game::Game,
+    position::{Pieces, Position},
+};
+
+/// The number of positions of an endgame, indexed by the player to move, the
+/// king of the stronger side, the lone king and the extra piece, with the
+/// stronger side as white.
+const SIZE: usize = 2 * 64 * 64 * 64;
+
+// Successors that leave the endgame, by capturing the extra piece or by
+// promoting the pawn
+const WIN: u32 = u32::MAX;
+const DRAW: u32 = u32::MAX - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endgame {
+    Kpk,
+    Krk,
+    Kqk,
+}
+
+impl std::fmt::Display for Endgame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Endgame::Kpk => "KPK",
+            Endgame::Krk => "KRK",
+            Endgame::Kqk => "KQK",
+        })
+    }
+}
+
+fn index(white_to_move: bool, strong_king: usize, weak_king: usize, piece: usize) -> usize {
+    (((!white_to_move as usize * 64 + strong_king) * 64 + weak_king) * 64) + piece
+}
+
+/// Returns the endgame and index of a position with white as the stronger
+/// side.
+fn key(game: &Game) -> Option<(Endgame, usize)> {
+    let (white, black) = (&game.position.white, &game.position.black);
+    if black.all != black.king || white.all.count_ones() != 2 {
+        return None;
+    }
+    let endgame = if !white.pawn.is_empty() {
+        Endgame::Kpk
+    } else if !white.rook.is_empty() {
+        Endgame::Krk
+    } else if !white.queen.is_empty() {
+        Endgame::Kqk
+    } else {
+        return None;
+    };
+    let piece = white.all ^ white.king;
+    Some((
+        endgame,
+        index(
+            game.player,
+            white.king.square(),
+            black.king.square(),
+            piece.square(),
+        ),
+    ))
+}
+
+fn pieces(king: usize) -> Pieces {
+    let king = Bitboard::new(1 << king);
+    Pieces {
+        all: king,
+        king,
+        queen: Bitboard::EMPTY,
+        rook: Bitboard::EMPTY,
+        bishop: Bitboard::EMPTY,
+        knight: Bitboard::EMPTY,
+        pawn: Bitboard::EMPTY,
+    }
+}
+
+/// Returns the position at the index, unless it is illegal.
+fn position(endgame: Endgame, idx: usize, template: &Game) -> Option<Game> {
+    let white_to_move = idx < SIZE / 2;
+    let (strong_king, weak_king, square) = ((idx >> 12) & 63, (idx >> 6) & 63, idx & 63);
+    let distance = |a: usize, b: usize| (a & 7).abs_diff(b & 7).max((a >> 3).abs_diff(b >> 3));
+    if square == strong_king || square == weak_king || distance(strong_king, weak_king) <= 1 {
+        return None;
+    }
+    if endgame == Endgame::Kpk && !(8..56).contains(&square) {
+        return None;
+    }
+
+    let mut white = pieces(strong_king);
+    let piece = Bitboard::new(1 << square);
+    white.all |= piece;
+    match endgame {
+        Endgame::Kpk => white.pawn = piece,
+        Endgame::Krk => white.rook = piece,
+        Endgame::Kqk => white.queen = piece,
+    }
+    let black = pieces(weak_king);
+
+    let mut game = template.clone();
+    game.position = Position {
+        all: white.all | black.all,
+        white,
+        black,
+    };
+    game.player = white_to_move;
+    // The player who just moved can't be in check
+    if game.position.is_check(!white_to_move) {
+        return None;
+    }
+    Some(game)
+}
+
+pub struct Bitbase {
+    endgame: Endgame,
+    /// One bit per position, set if white wins.
+    wins: Vec<u64>,
+    /// The most plies of a win with white to move, up to the mate or, in
+    /// KPK, up to the promotion.
+    longest_win: u32,
+}
+
+impl Bitbase {
+    /// Generates the bitbase of the endgame. The positions after a promotion
+    /// are looked up in the other bitbases.
+    fn generate(endgame: Endgame, others: &[Bitbase]) -> Bitbase {
+        let template = Game::from_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1");
+
+        // The successors of every legal position, by index or as a result
+        let mut legal = vec![false; SIZE];
+        let mut wins = vec![false; SIZE];
+        let mut offsets = vec![0; SIZE + 1];
+        let mut successors: Vec<u32> = vec![];
+        for idx in 0..SIZE {
+            if let Some(game) = position(endgame, idx, &template) {
+                legal[idx] = true;
+                let moves = game.legal_moves(game.player);
+                if moves.is_empty() && game.position.is_check(game.player) {
+                    // Only the lone king can be mated
+                    wins[idx] = true;
+                }
+                for m in moves {
+                    let next = game.make_move(&m, false);
+                    successors.push(match key(&next) {
+                        Some((next_endgame, next_idx)) if next_endgame == endgame => {
+                            next_idx as u32
+                        }
+                        Some((next_endgame, next_idx)) => {
+                            let other = others.iter().find(|other| other.endgame == next_endgame);
+                            if other.is_some_and(|other| other.get(next_idx)) {
+                                WIN
+                            } else {
+                                DRAW
+                            }
+                        }
+                        None => DRAW,
+                    });
+                }
+            }
+            offsets[idx + 1] = successors.len();
+        }
+
+        // Every pass finds the wins that are one ply longer
+        let mut longest_win = 0;
+        for plies in 1.. {
+            let mut next_wins = wins.clone();
+            let mut changed = false;
+            for idx in 0..SIZE {
+                if !legal[idx] || wins[idx] {
+                    continue;
+                }
+                let children = &successors[offsets[idx]..offsets[idx + 1]];
+                let is_won = |&child: &u32| child == WIN || (child != DRAW && wins[child as usize]);
+                let white_to_move = idx < SIZE / 2;
+                let win = if white_to_move {
+                    children.iter().any(is_won)
+                } else {
+                    !children.is_empty() && children.iter().all(is_won)
+                };
+                if win {
+                    next_wins[idx] = true;
+                    changed = true;
+                    if white_to_move {
+                        longest_win = plies;
+                    }
+                }
+            }
+            wins = next_wins;
+            if !changed {
+                break;
+            }
+        }
+
+        let mut bits = vec![0; SIZE / 64];
+        for (idx, _) in wins.iter().enumerate().filter(|(_, &win)| win) {
+            bits[idx / 64] |= 1 << (idx % 64);
+        }
+        Bitbase {
+            endgame,
+            wins: bits,
+            longest_win,
+        }
+    }
+
+    fn get(&self, idx: usize) -> bool {
+        self.wins[idx / 64] & (1 << (idx % 64)) != 0
+    }
+}
+
+impl std::fmt::Display for Bitbases {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let descriptions: Vec<String> = self
+            .bitbases
+            .iter()
+            .map(|bitbase| {
+                format!(
+                    "{} longest win {} plies",
+                    bitbase.endgame, bitbase.longest_win
+                )
+            })
+            .collect();
+        f.write_str(&descriptions.join(", "))
+    }
+}
+
+/// The bitbases of KPK, KRK and KQK.
+pub struct Bitbases {
+    bitbases: Vec<Bitbase>,
+}
+
+impl Bitbases {
+    /// Generates all bitbases. KQK and KRK come first, since promotions in
+    /// KPK lead to them.
+    pub fn generate() -> Bitbases {
+        let mut bitbases = vec![];
+        for endgame in [Endgame::Kqk, Endgame::Krk, Endgame::Kpk] {
+            let bitbase = Bitbase::generate(endgame, &bitbases);
+            bitbases.push(bitbase);
+        }
+        Bitbases { bitbases }
+    }
+
+    /// Returns the result for the player to move, 1 for a win, 0 for a draw
+    /// and -1 for a loss, if the position is one of the endgames.
+    pub fn probe(&self, game: &Game) -> Option<i8> {
+        if game.can_castle() {
+            return None;
+        }
+        let (endgame, idx) = key(game).or_else(|| key(&game.recolor()))?;
+        let bitbase = self
+            .bitbases
+            .iter()
+            .find(|bitbase| bitbase.endgame == endgame)?;
+        if !bitbase.get(idx) {
+            Some(0)
+        } else if idx < SIZE / 2 {
+            Some(1)
+        } else {
+            Some(-1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    fn bitbases() -> &'static Bitbases {
+        static BITBASES: OnceLock<Bitbases> = OnceLock::new();
+        BITBASES.get_or_init(Bitbases::generate)
+    }
+
+    /// Counts the wins and draws among the legal positions with the player
+    /// to move.
+    fn count(bitbase: &Bitbase, white_to_move: bool) -> (u32, u32) {
+        let template = Game::from_fen("4k3/8/8/8/8/8/8/4K3 w - - 0 1");
+        let range = if white_to_move {
+            0..SIZE / 2
+        } else {
+            SIZE / 2..SIZE
+        };
+        let (mut wins, mut draws) = (0, 0);
+        for idx in range {
+            if position(bitbase.endgame, idx, &template).is_some() {
+                if bitbase.get(idx) {
+                    wins += 1;
+                } else {
+                    draws += 1;
+                }
+            }
+        }
+        (wins, draws)
+    }
+
+    #[test]
+    fn generates_known_results() {
+        let [kqk, krk, kpk] = &bitbases().bitbases[..] else {
+            panic!("Missing bitbases");
+        };
+        // White always wins with a queen or a rook, black draws by taking it
+        // or by stalemate
+        assert_eq!(count(kqk, true), (144508, 0));
+        assert_eq!(count(kqk, false), (200896, 23048));
+        assert_eq!(count(krk, true), (175168, 0));
+        assert_eq!(count(krk, false), (201700, 22244));
+        assert_eq!(count(kpk, true), (124960, 38368));
+        assert_eq!(count(kpk, false), (97604, 70420));
+
+        // Mate in 10 and 16 moves
+        assert_eq!(kqk.longest_win, 19);
+        assert_eq!(krk.longest_win, 31);
+    }
+
+    #[test]
+    fn probes_positions() {
+        let probe = |fen: &str| bitbases().probe(&Game::from_fen(fen));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4KQ2 w - - 0 1"), Some(1));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4KQ2 b - - 0 1"), Some(-1));
+        // Black takes the queen
+        assert_eq!(probe("8/8/8/8/8/3k4/3Q4/7K b - - 0 1"), Some(0));
+        assert_eq!(probe("4kr2/8/8/8/8/8/8/4K3 b - - 0 1"), Some(1));
+        // The rook pawn is a draw with the defending king in the corner
+        assert_eq!(probe("k7/8/8/8/8/8/P7/K7 w - - 0 1"), Some(0));
+        assert_eq!(probe("8/1P6/8/8/8/8/8/K6k w - - 0 1"), Some(1));
+        // With black to move the king is stalemated
+        assert_eq!(probe("4k3/4P3/4K3/8/8/8/8/8 w - - 0 1"), Some(1));
+        assert_eq!(probe("4k3/4P3/4K3/8/8/8/8/8 b - - 0 1"), Some(0));
+        // Black pawns are looked up with the colors swapped
+        assert_eq!(probe("8/8/8/8/4p3/4k3/8/4K3 b - - 0 1"), Some(1));
+        assert_eq!(probe("8/8/8/8/4p3/4k3/8/4K3 w - - 0 1"), Some(-1));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4KB2 w - - 0 1"), None);
+        assert_eq!(probe("4k3/8/8/8/8/8/8/R3K3 w Q - 0 1"), None);
+    }
+}
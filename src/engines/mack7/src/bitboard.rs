@@ -5,7 +5,7 @@ use std::ops::Shl;
 
 use crate::direction::Direction;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct Bitboard(u64);
 
 impl fmt::Display for Bitboard {
@@ -223,6 +223,18 @@ impl Bitboard {
         self.0 == 0
     }
 
+    /// Mirrors the board from left to right, so that a1 becomes h1.
+    #[inline]
+    pub fn mirror(self) -> Bitboard {
+        Bitboard(self.0.reverse_bits().swap_bytes())
+    }
+
+    /// Flips the board from top to bottom, so that a1 becomes a8.
+    #[inline]
+    pub fn flip(self) -> Bitboard {
+        Bitboard(self.0.swap_bytes())
+    }
+
     #[inline]
     pub fn into_iter(self) -> BitboardIter {
         BitboardIter { n: self.0, c: 0 }
@@ -286,6 +298,12 @@ impl Bitboard {
 
     pub const ALL: Bitboard = Bitboard(0xFFFF_FFFF_FFFF_FFFF);
 
+    /// Returns the index of the lowest square, where a1 is 0 and h8 is 63.
+    #[inline]
+    pub fn square(self) -> usize {
+        self.0.trailing_zeros() as usize
+    }
+
     #[inline]
     pub fn count_ones(self) -> u32 {
         self.0.count_ones()
@@ -0,0 +1,427 @@
+//! Networks are saved as numbered generations, each in its own directory
+//! inside the checkpoint directory:
+//!
+//! ```text
+//! checkpoints/
+//!     000000/
+//!         metadata.json
+//!         network.bin
+//!     000001/
+//!         ...
+//!     best
+//! ```
+//!
+//! `best` holds the number of the generation that passed the last gate, which
+//! is the one that plays unless another generation is asked for.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    network::{Network, NetworkConfig},
+    weights::{self, Precision},
+};
+
+/// The version of the encoding of positions as network inputs. It has to be
+/// increased whenever `Game::get_input` changes, so that old networks are not
+/// used with inputs they were not trained on.
+pub const INPUT_VERSION: u32 = 1;
+
+pub const DEFAULT_DIRECTORY: &str = "checkpoints";
+const METADATA: &str = "metadata.json";
+const NETWORK: &str = "network.bin";
+const BEST: &str = "best";
+/// Networks used to be saved as JSON before the binary format existed.
+const LEGACY_NETWORK: &str = "network.json";
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Metadata {
+    pub generation: u32,
+    pub architecture: NetworkConfig,
+    pub input_version: u32,
+    /// The number of training steps since the first generation.
+    pub training_steps: u64,
+    /// Seconds since the Unix epoch when the generation was saved.
+    pub created: u64,
+    /// The generation this one was trained from.
+    pub parent: Option<u32>,
+}
+
+pub struct Checkpoints {
+    directory: PathBuf,
+}
+
+impl Checkpoints {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the directory of the given generation.
+    pub fn directory(&self, generation: u32) -> PathBuf {
+        self.directory.join(format!("{:06}", generation))
+    }
+
+    /// Returns all saved generations in ascending order.
+    pub fn generations(&self) -> std::io::Result<Vec<u32>> {
+        let mut generations = vec![];
+        match fs::read_dir(&self.directory) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry?;
+                    if !entry.file_type()?.is_dir() {
+                        continue;
+                    }
+                    if let Some(generation) = entry
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| name.parse().ok())
+                    {
+                        generations.push(generation);
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        generations.sort_unstable();
+        Ok(generations)
+    }
+
+    pub fn latest(&self) -> std::io::Result<Option<u32>> {
+        Ok(self.generations()?.last().copied())
+    }
+
+    /// Resolves the generation to use, which is the latest if none is given.
+    pub fn resolve(&self, generation: Option<u32>) -> std::io::Result<u32> {
+        match generation {
+            Some(generation) => Ok(generation),
+            None => self.latest()?.ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "No generation found in {}, create one with `mack7 init`",
+                        self.directory.display()
+                    ),
+                )
+            }),
+        }
+    }
+
+    /// Returns the generation that passed the last gate, if any did.
+    pub fn best(&self) -> std::io::Result<Option<u32>> {
+        let path = self.directory.join(BEST);
+        match fs::read_to_string(&path) {
+            Ok(content) => content.trim().parse().map(Some).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} does not contain a generation", path.display()),
+                )
+            }),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Resolves the generation to play with, which is the best one if none
+    /// is given, or the latest one before any generation passed a gate.
+    pub fn resolve_best(&self, generation: Option<u32>) -> std::io::Result<u32> {
+        match generation {
+            Some(generation) => Ok(generation),
+            None => match self.best()? {
+                Some(best) => Ok(best),
+                None => self.resolve(None),
+            },
+        }
+    }
+
+    /// Makes the generation the best one.
+    pub fn promote(&self, generation: u32) -> std::io::Result<()> {
+        let path = self.directory.join(BEST);
+        let temporary = self.directory.join(format!(".{}.tmp", BEST));
+        fs::write(&temporary, format!("{}\n", generation))?;
+        fs::rename(&temporary, path)
+    }
+
+    pub fn metadata(&self, generation: u32) -> std::io::Result<Metadata> {
+        let path = self.directory(generation).join(METADATA);
+        let metadata: Metadata = serde_json::from_reader(BufReader::new(open(&path)?))
+            .map_err(|err| invalid(&path, err))?;
+
+        if metadata.generation != generation {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} belongs to generation {} instead of {}",
+                    path.display(),
+                    metadata.generation,
+                    generation
+                ),
+            ));
+        }
+        if metadata.input_version != INPUT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Generation {} uses input encoding {}, but this version of mack7 uses {}",
+                    generation, metadata.input_version, INPUT_VERSION
+                ),
+            ));
+        }
+        Ok(metadata)
+    }
+
+    /// Loads the network of the given generation, or the latest one if none
+    /// is given.
+    pub fn load(&self, generation: Option<u32>) -> std::io::Result<(Network, Metadata)> {
+        let generation = self.resolve(generation)?;
+        let metadata = self.metadata(generation)?;
+
+        let path = self.directory(generation).join(NETWORK);
+        if !path.exists() && self.directory(generation).join(LEGACY_NETWORK).exists() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Generation {} is saved as JSON, migrate it with `mack7 convert-weights`",
+                    generation
+                ),
+            ));
+        }
+        let network = weights::load(&path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound => Error::new(
+                ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ),
+            _ => Error::new(err.kind(), format!("{}: {}", path.display(), err)),
+        })?;
+        if network.config() != metadata.architecture {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "The network of generation {} is {:?}, but its metadata says {:?}",
+                    generation,
+                    network.config(),
+                    metadata.architecture
+                ),
+            ));
+        }
+
+        eprintln!("Loaded generation {}", generation);
+        Ok((network, metadata))
+    }
+
+    /// Saves the network as the next generation and returns its metadata.
+    /// The files are written to a temporary directory first, so that a
+    /// generation is either complete or missing.
+    pub fn save(
+        &self,
+        network: &Network,
+        parent: Option<&Metadata>,
+        training_steps: u64,
+    ) -> std::io::Result<Metadata> {
+        let generation = self.latest()?.map_or(0, |latest| latest + 1);
+        let metadata = Metadata {
+            generation,
+            architecture: network.config(),
+            input_version: INPUT_VERSION,
+            training_steps: parent.map_or(0, |parent| parent.training_steps) + training_steps,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+            parent: parent.map(|parent| parent.generation),
+        };
+
+        let temporary = self.directory.join(format!(".{:06}.tmp", generation));
+        if temporary.exists() {
+            fs::remove_dir_all(&temporary)?;
+        }
+        fs::create_dir_all(&temporary)?;
+        weights::save(temporary.join(NETWORK), network, Precision::F32)?;
+        write_json(&temporary.join(METADATA), &metadata)?;
+        fs::rename(&temporary, self.directory(generation))?;
+
+        println!("Saved generation {}", generation);
+        Ok(metadata)
+    }
+
+    /// Rewrites the JSON network of a generation in the binary format and
+    /// removes the JSON file once the binary one reads back the same weights,
+    /// up to the given precision. Returns false if there is no JSON network
+    /// to convert.
+    pub fn convert(&self, generation: u32, precision: Precision) -> std::io::Result<bool> {
+        let json = self.directory(generation).join(LEGACY_NETWORK);
+        if !json.exists() {
+            return Ok(false);
+        }
+        let network: Network = serde_json::from_reader(BufReader::new(open(&json)?))
+            .map_err(|err| invalid(&json, err))?;
+
+        let path = self.directory(generation).join(NETWORK);
+        weights::save(&path, &network, precision)?;
+        let converted = weights::load(&path)?;
+        if weights::to_bytes(&converted, precision) != weights::to_bytes(&network, precision) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} does not read back correctly", path.display()),
+            ));
+        }
+        fs::remove_file(json)?;
+        Ok(true)
+    }
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    File::open(path).map_err(|err| match err.kind() {
+        ErrorKind::NotFound => Error::new(
+            ErrorKind::NotFound,
+            format!("{} does not exist", path.display()),
+        ),
+        _ => err,
+    })
+}
+
+fn invalid(path: &Path, err: serde_json::Error) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{} is invalid: {}", path.display(), err),
+    )
+}
+
+fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ValueHead;
+
+    fn checkpoints(name: &str) -> Checkpoints {
+        let directory =
+            std::env::temp_dir().join(format!("mack7-checkpoints-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&directory);
+        Checkpoints::new(directory)
+    }
+
+    fn config() -> NetworkConfig {
+        NetworkConfig {
+            blocks: 1,
+            filters: 4,
+            value_head: ValueHead::Wdl,
+        }
+    }
+
+    #[test]
+    fn saves_generations() {
+        let checkpoints = checkpoints("generations");
+        assert!(checkpoints.load(None).is_err());
+
+        let first = checkpoints.save(&Network::new(config()), None, 0).unwrap();
+        assert_eq!(first.generation, 0);
+        let second = checkpoints
+            .save(&Network::new(config()), Some(&first), 100)
+            .unwrap();
+        assert_eq!(second.generation, 1);
+        assert_eq!(second.parent, Some(0));
+        assert_eq!(checkpoints.generations().unwrap(), vec![0, 1]);
+
+        let (network, metadata) = checkpoints.load(None).unwrap();
+        assert_eq!(metadata, second);
+        assert_eq!(network.config(), config());
+        assert_eq!(checkpoints.load(Some(0)).unwrap().1, first);
+        assert_eq!(
+            checkpoints.load(Some(2)).err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn promotes_generations() {
+        let checkpoints = checkpoints("promote");
+        let first = checkpoints.save(&Network::new(config()), None, 0).unwrap();
+        checkpoints
+            .save(&Network::new(config()), Some(&first), 10)
+            .unwrap();
+
+        assert_eq!(checkpoints.best().unwrap(), None);
+        assert_eq!(checkpoints.resolve_best(None).unwrap(), 1);
+        checkpoints.promote(0).unwrap();
+        assert_eq!(checkpoints.best().unwrap(), Some(0));
+        assert_eq!(checkpoints.resolve_best(None).unwrap(), 0);
+        assert_eq!(checkpoints.resolve_best(Some(1)).unwrap(), 1);
+        assert_eq!(checkpoints.resolve(None).unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_mismatched_files() {
+        let checkpoints = checkpoints("mismatched");
+        let metadata = checkpoints.save(&Network::new(config()), None, 0).unwrap();
+        let path = checkpoints.directory(0).join(METADATA);
+
+        write_json(
+            &path,
+            &Metadata {
+                input_version: INPUT_VERSION + 1,
+                ..metadata.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            checkpoints.load(None).err().unwrap().kind(),
+            ErrorKind::InvalidData
+        );
+
+        write_json(
+            &path,
+            &Metadata {
+                architecture: NetworkConfig {
+                    blocks: 2,
+                    ..config()
+                },
+                ..metadata
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            checkpoints.load(None).err().unwrap().kind(),
+            ErrorKind::InvalidData
+        );
+
+        fs::remove_file(checkpoints.directory(0).join(NETWORK)).unwrap();
+        assert_eq!(
+            checkpoints.load(None).err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn converts_json_networks() {
+        let checkpoints = checkpoints("convert");
+        let network = Network::new(config());
+        checkpoints.save(&network, None, 0).unwrap();
+        let directory = checkpoints.directory(0);
+        fs::remove_file(directory.join(NETWORK)).unwrap();
+        write_json(&directory.join(LEGACY_NETWORK), &network).unwrap();
+        assert!(checkpoints.load(None).is_err());
+
+        assert!(checkpoints.convert(0, Precision::F32).unwrap());
+        assert!(!directory.join(LEGACY_NETWORK).exists());
+        let (loaded, _) = checkpoints.load(None).unwrap();
+        assert_eq!(
+            weights::to_bytes(&loaded, Precision::F32),
+            weights::to_bytes(&network, Precision::F32)
+        );
+        assert!(!checkpoints.convert(0, Precision::F32).unwrap());
+
+        // Half precision reads back the same weights once they are rounded
+        fs::remove_file(directory.join(NETWORK)).unwrap();
+        write_json(&directory.join(LEGACY_NETWORK), &network).unwrap();
+        assert!(checkpoints.convert(0, Precision::F16).unwrap());
+        assert!(!directory.join(LEGACY_NETWORK).exists());
+    }
+}
@@ -2,9 +2,11 @@ use crate::{
     bitboard::Bitboard,
     piece::{Piece, PromotionPiece},
 };
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::OnceLock;
 
-const MOVE_INDEX_TO_HUMAN: [&'static str; 1972] = [
+static MOVE_INDEX_TO_HUMAN: [&str; 1972] = [
     // Castles
     "0-0", "0-0-0", "0-0", "0-0-0", //
     // Promotions by white
@@ -302,7 +304,7 @@ const MOVE_INDEX_TO_HUMAN: [&'static str; 1972] = [
     "g8h7", "h7g8",
 ];
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct MoveIndex(pub usize);
 
 impl fmt::Display for MoveIndex {
@@ -311,6 +313,48 @@ impl fmt::Display for MoveIndex {
     }
 }
 
+impl MoveIndex {
+    fn from_human(human: &str) -> MoveIndex {
+        static INDICES: OnceLock<HashMap<&str, usize>> = OnceLock::new();
+        let indices = INDICES.get_or_init(|| {
+            MOVE_INDEX_TO_HUMAN
+                .iter()
+                .enumerate()
+                // Castles are the same for both players
+                .skip(4)
+                .map(|(i, &human)| (human, i))
+                .collect()
+        });
+        MoveIndex(indices[human])
+    }
+
+    /// Replaces the characters at the given positions of the human readable
+    /// move, which keeps the promotion piece.
+    fn transform(self, positions: [usize; 2], f: fn(u8) -> u8) -> MoveIndex {
+        let mut human = MOVE_INDEX_TO_HUMAN[self.0].as_bytes().to_vec();
+        for i in positions {
+            human[i] = f(human[i]);
+        }
+        MoveIndex::from_human(std::str::from_utf8(&human).unwrap())
+    }
+
+    /// Returns the index of the same move on a board that is mirrored from left
+    /// to right. Castles have no mirrored move.
+    pub fn mirror(self) -> MoveIndex {
+        assert!(self.0 >= 4, "Castles can not be mirrored");
+        self.transform([0, 2], |file| b'a' + b'h' - file)
+    }
+
+    /// Returns the index of the same move on a board where the colors are
+    /// swapped and the ranks flipped.
+    pub fn recolor(self) -> MoveIndex {
+        match self.0 {
+            0..=3 => MoveIndex((self.0 + 2) % 4),
+            _ => self.transform([1, 3], |rank| b'1' + b'8' - rank),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Castle {
     Kingside,
@@ -2450,4 +2494,22 @@ impl Move {
             ),
         }
     }
+
+    /// Formats the move in long algebraic notation as used by UCI, e.g. `e2e4`
+    /// or `e7e8q`. Castling is written as the king's move.
+    pub fn to_uci(&self) -> String {
+        let promotion = match self.is_promoting_to {
+            Some(PromotionPiece::Queen) => "q",
+            Some(PromotionPiece::Rook) => "r",
+            Some(PromotionPiece::Bishop) => "b",
+            Some(PromotionPiece::Knight) => "n",
+            None => "",
+        };
+        format!(
+            "{}{}{}",
+            self.from_square.to_human(),
+            self.to_square.to_human(),
+            promotion
+        )
+    }
 }
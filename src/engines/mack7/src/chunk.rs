@@ -0,0 +1,478 @@
+//! A compact binary format for training data. A chunk file starts with a
+//! header, followed by one record per position:
+//!
+//! | Field        | Type                          |
+//! |--------------|-------------------------------|
+//! | FEN length   | u8                            |
+//! | FEN          | ASCII                         |
+//! | start length | u8                            |
+//! | start FEN    | ASCII, where self-play of the game started |
+//! | result       | i8, 1 if white won, -1 if black won, 0 for a draw |
+//! | termination  | u8, how the game ended, see `Termination` |
+//! | flags        | u8, bit 0 if the policy and bit 1 if the value is a training target |
+//! | game         | u32, unique within the chunk  |
+//! | ply          | u16, counted from the start of the game |
+//! | policy count | u16                           |
+//! | policy       | (u16 `MoveIndex`, f32 visits) for every searched move |
+//!
+//! All numbers are little endian. Version 1 had no start FEN, and all of its
+//! games started from the standard starting position. Versions 1 and 2 had no
+//! termination, and all of their games ended by the rules. Versions 1 to 3 had
+//! no flags, and all of their positions were policy and value targets.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use crate::{chess_move::MoveIndex, game::Game};
+
+const MAGIC: &[u8; 4] = b"MK7D";
+const VERSION: u32 = 4;
+
+pub fn filename(run_index: &str) -> String {
+    format!("games.{}.bin", run_index)
+}
+
+/// How a self-play game ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Termination {
+    /// Checkmate or a draw by the rules of chess.
+    Rules,
+    /// The losing side resigned.
+    Resignation,
+    /// Adjudicated as a draw because the value stayed close to 0.
+    DrawAdjudication,
+    /// Adjudicated as a draw because the game got too long.
+    MaxPlies,
+    /// Adjudicated with the result that the tablebases know.
+    Tablebase,
+}
+
+impl Termination {
+    fn from_u8(byte: u8) -> Option<Termination> {
+        match byte {
+            0 => Some(Termination::Rules),
+            1 => Some(Termination::Resignation),
+            2 => Some(Termination::DrawAdjudication),
+            3 => Some(Termination::MaxPlies),
+            4 => Some(Termination::Tablebase),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Termination::Rules => 0,
+            Termination::Resignation => 1,
+            Termination::DrawAdjudication => 2,
+            Termination::MaxPlies => 3,
+            Termination::Tablebase => 4,
+        }
+    }
+}
+
+impl std::fmt::Display for Termination {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Termination::Rules => "rules",
+            Termination::Resignation => "resignation",
+            Termination::DrawAdjudication => "draw adjudication",
+            Termination::MaxPlies => "max plies",
+            Termination::Tablebase => "tablebase",
+        })
+    }
+}
+
+/// A position from self-play together with its training targets.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub fen: String,
+    /// The position the game started from, after its opening.
+    pub start_fen: String,
+    /// The result of the game from the perspective of white.
+    pub result: i8,
+    pub termination: Termination,
+    pub game: u32,
+    pub ply: u16,
+    /// The visits of every move that was searched.
+    pub policy: Vec<(MoveIndex, f32)>,
+    /// Whether the visits are a policy target, which they are not for fast
+    /// searches with few playouts.
+    pub has_policy_target: bool,
+    /// Whether the result is a value target, which it is not for games that
+    /// were cut off.
+    pub has_value_target: bool,
+}
+
+impl Sample {
+    pub fn game(&self) -> Game {
+        Game::from_fen(&self.fen)
+    }
+}
+
+// ##################################################################
+// Writing
+
+pub struct ChunkWriter<W: Write> {
+    writer: W,
+}
+
+impl ChunkWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        ChunkWriter::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(mut writer: W) -> std::io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, sample: &Sample) -> std::io::Result<()> {
+        let fen = sample.fen.as_bytes();
+        let start_fen = sample.start_fen.as_bytes();
+        if fen.len() > u8::MAX as usize
+            || start_fen.len() > u8::MAX as usize
+            || sample.policy.len() > u16::MAX as usize
+        {
+            return Err(Error::new(ErrorKind::InvalidInput, "Sample is too large"));
+        }
+
+        self.writer.write_all(&[fen.len() as u8])?;
+        self.writer.write_all(fen)?;
+        self.writer.write_all(&[start_fen.len() as u8])?;
+        self.writer.write_all(start_fen)?;
+        self.writer.write_all(&sample.result.to_le_bytes())?;
+        self.writer.write_all(&[sample.termination.to_u8()])?;
+        self.writer
+            .write_all(&[sample.has_policy_target as u8 | (sample.has_value_target as u8) << 1])?;
+        self.writer.write_all(&sample.game.to_le_bytes())?;
+        self.writer.write_all(&sample.ply.to_le_bytes())?;
+        self.writer
+            .write_all(&(sample.policy.len() as u16).to_le_bytes())?;
+        for &(m, visits) in sample.policy.iter() {
+            self.writer.write_all(&(m.0 as u16).to_le_bytes())?;
+            self.writer.write_all(&visits.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// ##################################################################
+// Reading
+
+/// Reads the samples of a chunk one after another, without loading the whole
+/// file.
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    version: u32,
+}
+
+impl ChunkReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        ChunkReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(mut reader: R) -> std::io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Not a training data chunk",
+            ));
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version == 0 || version > VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported chunk version {}", version),
+            ));
+        }
+        Ok(Self { reader, version })
+    }
+
+    fn read_bytes<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_fen(&mut self, length: u8) -> std::io::Result<String> {
+        let mut fen = vec![0; length as usize];
+        self.reader.read_exact(&mut fen)?;
+        String::from_utf8(fen)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "FEN is not valid text"))
+    }
+
+    fn read_sample(&mut self) -> std::io::Result<Option<Sample>> {
+        let mut fen_length = [0; 1];
+        if self.reader.read(&mut fen_length)? == 0 {
+            return Ok(None);
+        }
+        let fen = self.read_fen(fen_length[0])?;
+        let start_fen = if self.version >= 2 {
+            let [length] = self.read_bytes()?;
+            self.read_fen(length)?
+        } else {
+            String::from(START_POSITION)
+        };
+
+        let result = i8::from_le_bytes(self.read_bytes()?);
+        let termination = if self.version >= 3 {
+            let [byte] = self.read_bytes()?;
+            Termination::from_u8(byte)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid termination"))?
+        } else {
+            Termination::Rules
+        };
+        let [flags] = if self.version >= 4 {
+            self.read_bytes()?
+        } else {
+            [0b11]
+        };
+        let game = u32::from_le_bytes(self.read_bytes()?);
+        let ply = u16::from_le_bytes(self.read_bytes()?);
+        let count = u16::from_le_bytes(self.read_bytes()?);
+        let mut policy = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let index = u16::from_le_bytes(self.read_bytes()?) as usize;
+            if index >= 1972 {
+                return Err(Error::new(ErrorKind::InvalidData, "Invalid move index"));
+            }
+            let visits = f32::from_le_bytes(self.read_bytes()?);
+            policy.push((MoveIndex(index), visits));
+        }
+
+        Ok(Some(Sample {
+            fen,
+            start_fen,
+            result,
+            termination,
+            game,
+            ply,
+            policy,
+            has_policy_target: flags & 1 != 0,
+            has_value_target: flags & 2 != 0,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = std::io::Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_sample().transpose()
+    }
+}
+
+// ##################################################################
+// Converting the old CSV files
+
+const START_POSITION: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+fn parse_row(line: &str) -> std::io::Result<Vec<f32>> {
+    line.split(',')
+        .map(|value| {
+            value
+                .parse::<f32>()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid number in CSV"))
+        })
+        .collect()
+}
+
+/// Converts the CSV files `value.{idx}.csv` and `policy.{idx}.csv` into a
+/// chunk, line by line. The CSV files do not store where games start, so every
+/// occurrence of the starting position is taken as a new game. Returns the
+/// number of converted positions.
+pub fn convert_csv<V: BufRead, P: BufRead, W: Write>(
+    value_csv: V,
+    policy_csv: P,
+    writer: &mut ChunkWriter<W>,
+) -> std::io::Result<usize> {
+    let start = Game::from_fen(START_POSITION).get_input();
+    let mut game: Option<u32> = None;
+    let mut ply = 0;
+    let mut count = 0;
+
+    let mut policy_lines = policy_csv.lines();
+    for value_line in value_csv.lines() {
+        let value_line = value_line?;
+        if value_line.is_empty() {
+            continue;
+        }
+        let policy_line = policy_lines.next().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "The value and policy training data have a different number of positions",
+            )
+        })??;
+
+        let value_row = parse_row(&value_line)?;
+        let policy_row = parse_row(&policy_line)?;
+        if value_row.len() != 838 || policy_row.len() != 2809 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Unexpected number of columns",
+            ));
+        }
+
+        let input = ndarray::ArrayView1::from(&value_row[..837]);
+        if game.is_none() || input == start {
+            game = Some(game.map_or(0, |game| game + 1));
+            ply = 0;
+        }
+
+        let policy = policy_row[837..]
+            .iter()
+            .enumerate()
+            .filter(|(_, &visits)| visits > 0.)
+            .map(|(i, &visits)| (MoveIndex(i), visits))
+            .collect();
+
+        writer.write(&Sample {
+            fen: Game::from_input(input).to_fen(),
+            start_fen: String::from(START_POSITION),
+            result: (2. * value_row[837] - 1.).round() as i8,
+            termination: Termination::Rules,
+            game: game.unwrap(),
+            ply,
+            policy,
+            has_policy_target: true,
+            has_value_target: true,
+        })?;
+        ply += 1;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(game: u32, ply: u16) -> Sample {
+        Sample {
+            fen: String::from("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1"),
+            start_fen: String::from("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1"),
+            result: 1,
+            termination: Termination::Resignation,
+            game,
+            ply,
+            policy: vec![(MoveIndex(3), 10.), (MoveIndex(1971), 0.5)],
+            has_policy_target: ply == 0,
+            has_value_target: true,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = ChunkWriter::new(vec![]).unwrap();
+        writer.write(&sample(0, 0)).unwrap();
+        writer.write(&sample(0, 1)).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let samples: Vec<Sample> = ChunkReader::new(&bytes[..])
+            .unwrap()
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(samples, vec![sample(0, 0), sample(0, 1)]);
+
+        // A cut off record is an error rather than the end of the chunk
+        let mut reader = ChunkReader::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let fen = "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1";
+        let mut bytes = b"MK7D\x01\x00\x00\x00".to_vec();
+        bytes.push(fen.len() as u8);
+        bytes.extend_from_slice(fen.as_bytes());
+        bytes.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let samples: Vec<Sample> = ChunkReader::new(&bytes[..])
+            .unwrap()
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].fen, fen);
+        assert_eq!(samples[0].start_fen, START_POSITION);
+        assert_eq!(samples[0].result, 1);
+        assert_eq!(samples[0].termination, Termination::Rules);
+        assert!(samples[0].has_policy_target && samples[0].has_value_target);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(ChunkReader::new(&b"MK7D\x05\x00\x00\x00"[..]).is_err());
+        assert!(ChunkReader::new(&b"1,0,0"[..]).is_err());
+    }
+
+    #[test]
+    fn converts_csv() {
+        let row = |fen: &str| -> String {
+            Game::from_fen(fen)
+                .get_input()
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        let policy = |index: usize| -> String {
+            (0..1972)
+                .map(|i| if i == index { "1" } else { "0" })
+                .collect::<Vec<&str>>()
+                .join(",")
+        };
+        let other = "6k1/5ppp/8/8/8/8/5PPP/3R2K1 b - - 0 1";
+
+        let value_csv = format!(
+            "{},1\n{},1\n{},0.5\n",
+            row(START_POSITION),
+            row(other),
+            row(START_POSITION)
+        );
+        let policy_csv = format!(
+            "{},{}\n{},{}\n{},{}\n",
+            row(START_POSITION),
+            policy(7),
+            row(other),
+            policy(8),
+            row(START_POSITION),
+            policy(9)
+        );
+
+        let mut writer = ChunkWriter::new(vec![]).unwrap();
+        let count = convert_csv(value_csv.as_bytes(), policy_csv.as_bytes(), &mut writer).unwrap();
+        assert_eq!(count, 3);
+
+        let bytes = writer.finish().unwrap();
+        let samples: Vec<Sample> = ChunkReader::new(&bytes[..])
+            .unwrap()
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+        assert_eq!(samples[1].fen, other);
+        assert_eq!(samples[1].policy, vec![(MoveIndex(8), 1.)]);
+        assert_eq!(
+            samples
+                .iter()
+                .map(|s| (s.game, s.ply, s.result))
+                .collect::<Vec<_>>(),
+            vec![(0, 0, 1), (0, 1, 1), (1, 0, 0)]
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use ndarray::{Array1, Array2, Axis};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{
+    game::Game,
+    network::{Inference, Network},
+    quantized::{Bits, QuantizedNetwork},
+};
+
+/// The output of the network for a single position.
+pub struct Evaluation {
+    /// The prior for every `MoveIndex`, including illegal moves.
+    pub priors: Array1<f32>,
+    /// The expected result from the perspective of white, between -1 and 1.
+    pub value: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EvaluatorParams {
+    /// The number of queued positions that are evaluated right away.
+    pub batch_size: usize,
+    /// How long a position waits for others to fill up the batch.
+    pub timeout: Duration,
+    /// The number of evaluations that are kept, 0 disables the cache.
+    pub cache_size: usize,
+    /// Runs a quantized copy of the network instead of the network itself.
+    pub quantization: Option<Bits>,
+}
+
+impl Default for EvaluatorParams {
+    fn default() -> Self {
+        Self {
+            batch_size: 1,
+            timeout: Duration::from_millis(1),
+            cache_size: 4096,
+            quantization: None,
+        }
+    }
+}
+
+// ##################################################################
+// Cache
+
+/// Keeps the most recently used evaluations up to a fixed number.
+struct LruCache {
+    capacity: usize,
+    entries: HashMap<u64, (Arc<Evaluation>, u64)>,
+    /// Maps the time of last use to the key, the first entry is evicted next.
+    usage: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl LruCache {
+    fn new(capacity: usize) -> LruCache {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, key: u64) -> Option<Arc<Evaluation>> {
+        let (evaluation, last_used) = self.entries.get_mut(&key)?;
+        self.usage.remove(last_used);
+        self.clock += 1;
+        *last_used = self.clock;
+        self.usage.insert(self.clock, key);
+        Some(evaluation.clone())
+    }
+
+    fn insert(&mut self, key: u64, evaluation: Arc<Evaluation>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.clock += 1;
+        if let Some((_, last_used)) = self.entries.insert(key, (evaluation, self.clock)) {
+            self.usage.remove(&last_used);
+        }
+        self.usage.insert(self.clock, key);
+
+        if self.entries.len() > self.capacity {
+            if let Some((_, oldest)) = self.usage.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+// ##################################################################
+// Evaluation queue
+
+struct Request {
+    ticket: u64,
+    hash: u64,
+    input: Array1<f32>,
+}
+
+#[derive(Default)]
+struct Queue {
+    next_ticket: u64,
+    pending: Vec<Request>,
+    finished: HashMap<u64, Arc<Evaluation>>,
+}
+
+/// Evaluates positions for many search threads at once. Positions are queued
+/// until enough of them are waiting or the timeout passes, and then the thread
+/// that notices runs the network on all of them together.
+pub struct Evaluator {
+    network: Box<dyn Inference>,
+    params: EvaluatorParams,
+    queue: Mutex<Queue>,
+    evaluated: Condvar,
+    cache: Mutex<LruCache>,
+}
+
+impl Evaluator {
+    pub fn new(network: Network, params: EvaluatorParams) -> Self {
+        let network: Box<dyn Inference> = match params.quantization {
+            Some(bits) => Box::new(QuantizedNetwork::new(&network, bits)),
+            None => Box::new(network),
+        };
+        Self::with_inference(network, params)
+    }
+
+    /// Evaluates positions with any model, such as the NNUE. The quantization
+    /// of the parameters only applies to networks passed to `new`.
+    pub fn with_inference(network: Box<dyn Inference>, params: EvaluatorParams) -> Self {
+        Self {
+            network,
+            params,
+            queue: Mutex::new(Queue::default()),
+            evaluated: Condvar::new(),
+            cache: Mutex::new(LruCache::new(params.cache_size)),
+        }
+    }
+
+    /// Returns the evaluations of all given positions in the same order,
+    /// blocking until they are available.
+    pub fn evaluate(&self, games: &[&Game]) -> Vec<Arc<Evaluation>> {
+        let hashes: Vec<u64> = games.iter().map(|game| game.hash()).collect();
+        let mut evaluations: Vec<Option<Arc<Evaluation>>> = {
+            let mut cache = self.cache.lock().unwrap();
+            hashes.iter().map(|&hash| cache.get(hash)).collect()
+        };
+
+        let mut queue = self.queue.lock().unwrap();
+        let mut waiting: Vec<(usize, u64)> = vec![];
+        for (i, game) in games.iter().enumerate() {
+            if evaluations[i].is_none() {
+                let ticket = queue.next_ticket;
+                queue.next_ticket += 1;
+                queue.pending.push(Request {
+                    ticket,
+                    hash: hashes[i],
+                    input: game.get_input(),
+                });
+                waiting.push((i, ticket));
+            }
+        }
+
+        let deadline = Instant::now() + self.params.timeout;
+        loop {
+            waiting.retain(|&(i, ticket)| match queue.finished.remove(&ticket) {
+                Some(evaluation) => {
+                    evaluations[i] = Some(evaluation);
+                    false
+                }
+                None => true,
+            });
+            if waiting.is_empty() {
+                break;
+            }
+
+            let now = Instant::now();
+            if queue.pending.len() >= self.params.batch_size
+                || (!queue.pending.is_empty() && now >= deadline)
+            {
+                let batch = std::mem::take(&mut queue.pending);
+                drop(queue);
+                let results = self.run_batch(batch);
+                queue = self.queue.lock().unwrap();
+                queue.finished.extend(results);
+                self.evaluated.notify_all();
+            } else if now < deadline {
+                queue = self
+                    .evaluated
+                    .wait_timeout(queue, deadline - now)
+                    .unwrap()
+                    .0;
+            } else {
+                // Another thread is evaluating the remaining positions
+                queue = self.evaluated.wait(queue).unwrap();
+            }
+        }
+
+        evaluations.into_iter().map(Option::unwrap).collect()
+    }
+
+    fn run_batch(&self, batch: Vec<Request>) -> Vec<(u64, Arc<Evaluation>)> {
+        let mut inputs = Array2::<f32>::zeros((0, 837));
+        for request in batch.iter() {
+            inputs.push(Axis(0), request.input.view()).unwrap();
+        }
+
+        let (all_priors, values) = self.network.forward(&inputs);
+
+        let mut cache = self.cache.lock().unwrap();
+        batch
+            .into_iter()
+            .enumerate()
+            .map(|(i, request)| {
+                let evaluation = Arc::new(Evaluation {
+                    priors: all_priors.row(i).to_owned(),
+                    value: values[i],
+                });
+                cache.insert(request.hash, evaluation.clone());
+                (request.ticket, evaluation)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{NetworkConfig, ValueHead};
+
+    fn evaluation(value: f32) -> Arc<Evaluation> {
+        Arc::new(Evaluation {
+            priors: Array1::zeros(1972),
+            value,
+        })
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, evaluation(0.1));
+        cache.insert(2, evaluation(0.2));
+        assert_eq!(cache.get(1).unwrap().value, 0.1);
+
+        cache.insert(3, evaluation(0.3));
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(1).unwrap().value, 0.1);
+        assert_eq!(cache.get(3).unwrap().value, 0.3);
+
+        let mut disabled = LruCache::new(0);
+        disabled.insert(1, evaluation(0.1));
+        assert!(disabled.get(1).is_none());
+    }
+
+    #[test]
+    fn evaluates_batches_from_several_threads() {
+        let evaluator = Evaluator::new(
+            Network::new(NetworkConfig {
+                blocks: 1,
+                filters: 4,
+                value_head: ValueHead::Tanh,
+            }),
+            EvaluatorParams {
+                batch_size: 4,
+                timeout: Duration::from_millis(50),
+                cache_size: 16,
+                quantization: None,
+            },
+        );
+        let start = Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let games: Vec<Game> = start
+            .legal_moves(start.player)
+            .iter()
+            .take(4)
+            .map(|m| start.make_move(m, true))
+            .collect();
+
+        let results: Vec<Vec<Arc<Evaluation>>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = games
+                .chunks(2)
+                .map(|chunk| {
+                    let evaluator = &evaluator;
+                    scope.spawn(move || evaluator.evaluate(&chunk.iter().collect::<Vec<_>>()))
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        let mut inputs = Array2::<f32>::zeros((0, 837));
+        for game in games.iter() {
+            inputs.push(Axis(0), game.get_input().view()).unwrap();
+        }
+        let (_, values) = evaluator.network.forward(&inputs);
+        for (i, evaluation) in results.concat().iter().enumerate() {
+            assert!((evaluation.value - values[i]).abs() < 1e-5);
+            assert_eq!(evaluation.priors.len(), 1972);
+        }
+
+        // Evaluating a position again comes from the cache
+        let cached = evaluator.evaluate(&[&games[0]]);
+        assert!(Arc::ptr_eq(&cached[0], &results[0][0]));
+    }
+}
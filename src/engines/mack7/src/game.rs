@@ -2,6 +2,8 @@
 
 use rayon::prelude::*;
 use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 use crate::{
     bitboard::Bitboard,
@@ -62,7 +64,7 @@ fn get_diagonal_moves(all_pieces: Bitboard, enemy_pieces: Bitboard, square: Bitb
         | get_moves_in_direction(all_pieces, enemy_pieces, square, Direction::BottomRight)
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Hash)]
 pub struct PossibleCastles {
     pub white_kingside: bool,
     pub white_queenside: bool,
@@ -207,13 +209,13 @@ impl PartialEq for PositionWithMeta {
             && self.white_rook == other.white_rook
             && self.white_bishop == other.white_bishop
             && self.white_knight == other.white_knight
-            && self.white_pawn == other.white_king
+            && self.white_pawn == other.white_pawn
             && self.black_king == other.black_king
             && self.black_queen == other.black_queen
             && self.black_rook == other.black_rook
             && self.black_bishop == other.black_bishop
             && self.black_knight == other.black_knight
-            && self.black_pawn == other.black_king
+            && self.black_pawn == other.black_pawn
             && self.player == other.player
             && self.castle_white_kingside == other.castle_white_kingside
             && self.castle_white_queenside == other.castle_white_queenside
@@ -226,31 +228,31 @@ impl PartialEq for PositionWithMeta {
 impl Eq for PositionWithMeta {}
 
 impl PositionWithMeta {
-    fn empty() -> PositionWithMeta {
+    fn new(game: &Game) -> PositionWithMeta {
         PositionWithMeta {
-            white_king: Bitboard::EMPTY,
-            white_queen: Bitboard::EMPTY,
-            white_rook: Bitboard::EMPTY,
-            white_bishop: Bitboard::EMPTY,
-            white_knight: Bitboard::EMPTY,
-            white_pawn: Bitboard::EMPTY,
-            black_king: Bitboard::EMPTY,
-            black_queen: Bitboard::EMPTY,
-            black_rook: Bitboard::EMPTY,
-            black_bishop: Bitboard::EMPTY,
-            black_knight: Bitboard::EMPTY,
-            black_pawn: Bitboard::EMPTY,
-            player: true,
-            castle_white_kingside: true,
-            castle_white_queenside: true,
-            castle_black_kingside: true,
-            castle_black_queenside: true,
-            en_passant_square: Bitboard::EMPTY,
+            white_king: game.position.white.king,
+            white_queen: game.position.white.queen,
+            white_rook: game.position.white.rook,
+            white_bishop: game.position.white.bishop,
+            white_knight: game.position.white.knight,
+            white_pawn: game.position.white.pawn,
+            black_king: game.position.black.king,
+            black_queen: game.position.black.queen,
+            black_rook: game.position.black.rook,
+            black_bishop: game.position.black.bishop,
+            black_knight: game.position.black.knight,
+            black_pawn: game.position.black.pawn,
+            player: game.player,
+            castle_white_kingside: game.possible_castles.white_kingside,
+            castle_white_queenside: game.possible_castles.white_queenside,
+            castle_black_kingside: game.possible_castles.black_kingside,
+            castle_black_queenside: game.possible_castles.black_queenside,
+            en_passant_square: game.en_passant_square,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Game {
     pub position: Position,
     pub player: bool,
@@ -287,7 +289,7 @@ impl Game {
             },
         };
 
-        for (rank_index, rank) in fen_parts[0].split("/").into_iter().enumerate() {
+        for (rank_index, rank) in fen_parts[0].split('/').enumerate() {
             let mut file_index = 0;
             for piece in rank.chars() {
                 match piece.to_digit(10) {
@@ -390,7 +392,7 @@ impl Game {
             _ => Bitboard::EMPTY,
         };
 
-        return Game {
+        Game {
             position,
             player: fen_parts[1] == "w",
             last_move: None,
@@ -402,23 +404,139 @@ impl Game {
             },
             en_passant_square,
             previous_positions: vec![],
-            move_counter: fen_parts[5]
-                .chars()
-                .next()
-                .unwrap_or('0')
-                .to_digit(10)
-                .unwrap_or(0) as i32,
-            fifty_move_counter: fen_parts[4]
-                .chars()
-                .next()
-                .unwrap_or('0')
-                .to_digit(10)
-                .unwrap_or(0) as i32,
-        };
+            move_counter: fen_parts.get(5).and_then(|n| n.parse().ok()).unwrap_or(1),
+            fifty_move_counter: fen_parts.get(4).and_then(|n| n.parse().ok()).unwrap_or(0),
+        }
+    }
+
+    pub fn to_fen(&self) -> String {
+        let pieces = [
+            (self.position.white.king, 'K'),
+            (self.position.white.queen, 'Q'),
+            (self.position.white.rook, 'R'),
+            (self.position.white.bishop, 'B'),
+            (self.position.white.knight, 'N'),
+            (self.position.white.pawn, 'P'),
+            (self.position.black.king, 'k'),
+            (self.position.black.queen, 'q'),
+            (self.position.black.rook, 'r'),
+            (self.position.black.bishop, 'b'),
+            (self.position.black.knight, 'n'),
+            (self.position.black.pawn, 'p'),
+        ];
+
+        let mut ranks: Vec<String> = vec![];
+        for rank in (0..8).rev() {
+            let mut fen_rank = String::new();
+            let mut empty = 0;
+            for file in 0..8 {
+                let square = Bitboard::new(1 << (rank * 8 + file));
+                match pieces
+                    .iter()
+                    .find(|(pieces, _)| !(*pieces & square).is_empty())
+                {
+                    Some((_, piece)) => {
+                        if empty > 0 {
+                            fen_rank.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        fen_rank.push(*piece);
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                fen_rank.push_str(&empty.to_string());
+            }
+            ranks.push(fen_rank);
+        }
+
+        let castles = &self.possible_castles;
+        let castles: String = [
+            (castles.white_kingside, 'K'),
+            (castles.white_queenside, 'Q'),
+            (castles.black_kingside, 'k'),
+            (castles.black_queenside, 'q'),
+        ]
+        .iter()
+        .filter(|(possible, _)| *possible)
+        .map(|(_, c)| *c)
+        .collect();
+
+        format!(
+            "{} {} {} {} {} {}",
+            ranks.join("/"),
+            if self.player { "w" } else { "b" },
+            if castles.is_empty() { "-" } else { &castles },
+            if self.en_passant_square.is_empty() {
+                String::from("-")
+            } else {
+                self.en_passant_square.to_human()
+            },
+            self.fifty_move_counter,
+            self.move_counter
+        )
+    }
+
+    /// Returns the position mirrored from left to right. This is only the same
+    /// position from a different angle if no side can castle anymore.
+    pub fn mirror(&self) -> Game {
+        Game {
+            position: Position {
+                all: self.position.all.mirror(),
+                white: self.position.white.map(Bitboard::mirror),
+                black: self.position.black.map(Bitboard::mirror),
+            },
+            player: self.player,
+            last_move: None,
+            possible_castles: self.possible_castles,
+            en_passant_square: self.en_passant_square.mirror(),
+            previous_positions: vec![],
+            move_counter: self.move_counter,
+            fifty_move_counter: self.fifty_move_counter,
+        }
+    }
+
+    /// Returns the position with the colors swapped and the board flipped from
+    /// top to bottom, so that the other player is to move.
+    pub fn recolor(&self) -> Game {
+        Game {
+            position: Position {
+                all: self.position.all.flip(),
+                white: self.position.black.map(Bitboard::flip),
+                black: self.position.white.map(Bitboard::flip),
+            },
+            player: !self.player,
+            last_move: None,
+            possible_castles: PossibleCastles {
+                white_kingside: self.possible_castles.black_kingside,
+                white_queenside: self.possible_castles.black_queenside,
+                black_kingside: self.possible_castles.white_kingside,
+                black_queenside: self.possible_castles.white_queenside,
+            },
+            en_passant_square: self.en_passant_square.flip(),
+            previous_positions: vec![],
+            move_counter: self.move_counter,
+            fifty_move_counter: self.fifty_move_counter,
+        }
+    }
+
+    /// Returns whether any side can still castle.
+    pub fn can_castle(&self) -> bool {
+        let castles = &self.possible_castles;
+        castles.white_kingside
+            || castles.white_queenside
+            || castles.black_kingside
+            || castles.black_queenside
+    }
+
+    /// Returns whether the move captures a piece.
+    pub fn is_capture(&self, m: &Move) -> bool {
+        m.is_capturing_en_passant || !(self.position.all & m.to_square).is_empty()
     }
 
     pub fn make_move(&self, m: &Move, store: bool) -> Game {
-        let (new_position, is_capturing) = self.position.make_move(&m);
+        let (new_position, is_capturing) = self.position.make_move(m);
 
         let possible_castles = PossibleCastles {
             white_kingside: self.possible_castles.white_kingside
@@ -438,7 +556,7 @@ impl Game {
                     && is_capturing == CapturedPiece::Rook
                     && m.to_square == Bitboard::new(0x0000_0000_0000_0001)),
             black_kingside: self.possible_castles.black_kingside
-                && !(!self.player && m.piece == Piece::King)
+                && (self.player || m.piece != Piece::King)
                 && !(!self.player
                     && m.piece == Piece::Rook
                     && m.from_square == Bitboard::new(0x8000_0000_0000_0000))
@@ -446,7 +564,7 @@ impl Game {
                     && is_capturing == CapturedPiece::Rook
                     && m.to_square == Bitboard::new(0x8000_0000_0000_0000)),
             black_queenside: self.possible_castles.black_queenside
-                && !(!self.player && m.piece == Piece::King)
+                && (self.player || m.piece != Piece::King)
                 && !(!self.player
                     && m.piece == Piece::Rook
                     && m.from_square == Bitboard::new(0x0100_0000_0000_0000))
@@ -467,34 +585,14 @@ impl Game {
             self.fifty_move_counter + 1
         };
 
+        // Positions before a pawn move, a capture or castling can never occur
+        // again
         let mut previous_positions: Vec<PositionWithMeta>;
-        if !(is_capturing != CapturedPiece::None
-            || m.is_promoting_to.is_some()
-            || m.is_castling.is_some())
-        {
+        if fifty_move_counter == 0 || m.is_castling.is_some() {
             previous_positions = vec![];
         } else {
             previous_positions = self.previous_positions.clone();
-            previous_positions.push(PositionWithMeta {
-                white_king: self.position.white.king,
-                white_queen: self.position.white.queen,
-                white_rook: self.position.white.rook,
-                white_bishop: self.position.white.bishop,
-                white_knight: self.position.white.knight,
-                white_pawn: self.position.white.pawn,
-                black_king: self.position.black.king,
-                black_queen: self.position.black.queen,
-                black_rook: self.position.black.rook,
-                black_bishop: self.position.black.bishop,
-                black_knight: self.position.black.knight,
-                black_pawn: self.position.black.pawn,
-                player: self.player,
-                castle_white_kingside: self.possible_castles.white_kingside,
-                castle_white_queenside: self.possible_castles.white_queenside,
-                castle_black_kingside: self.possible_castles.black_kingside,
-                castle_black_queenside: self.possible_castles.black_queenside,
-                en_passant_square: self.en_passant_square,
-            });
+            previous_positions.push(PositionWithMeta::new(self));
         }
 
         Game {
@@ -1075,6 +1173,17 @@ impl Game {
             .sum()
     }
 
+    /// Returns a hash of everything the networks see about the position, which
+    /// leaves out the move history.
+    pub fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.position.hash(&mut hasher);
+        self.player.hash(&mut hasher);
+        self.possible_castles.hash(&mut hasher);
+        self.en_passant_square.hash(&mut hasher);
+        hasher.finish()
+    }
+
     pub fn result(&mut self) -> Option<GameResult> {
         let legal_moves = self.legal_moves(self.player).len();
         if legal_moves == 0 {
@@ -1092,26 +1201,22 @@ impl Game {
             return Some(GameResult::FiftyMoveRule);
         }
 
-        self.previous_positions.sort();
-        let mut count = 1;
-        let mut prev = PositionWithMeta::empty();
-        for id in self.previous_positions.iter() {
-            if prev == *id {
-                count += 1;
-            } else {
-                count = 1;
-                prev = *id;
-            }
-            if count >= 3 {
-                return Some(GameResult::Repitition);
-            }
+        // The current position occurs for the third time
+        let current = PositionWithMeta::new(self);
+        let repetitions = self
+            .previous_positions
+            .iter()
+            .filter(|&&position| position == current)
+            .count();
+        if repetitions >= 2 {
+            return Some(GameResult::Repitition);
         }
 
         if self.position.is_dead() {
             return Some(GameResult::DeadPosition);
         }
 
-        return None;
+        None
     }
 }
 
@@ -1130,9 +1235,9 @@ enum PieceMove {
     QueensideCastle,
 }
 
-impl Into<Piece> for PieceMove {
-    fn into(self) -> Piece {
-        match self {
+impl From<PieceMove> for Piece {
+    fn from(piece_move: PieceMove) -> Piece {
+        match piece_move {
             PieceMove::King | PieceMove::KingsideCastle | PieceMove::QueensideCastle => Piece::King,
             PieceMove::Queen => Piece::Queen,
             PieceMove::Rook => Piece::Rook,
@@ -1146,9 +1251,9 @@ impl Into<Piece> for PieceMove {
     }
 }
 
-impl Into<Option<Castle>> for PieceMove {
-    fn into(self) -> Option<Castle> {
-        match self {
+impl From<PieceMove> for Option<Castle> {
+    fn from(piece_move: PieceMove) -> Option<Castle> {
+        match piece_move {
             PieceMove::KingsideCastle => Some(Castle::Kingside),
             PieceMove::QueensideCastle => Some(Castle::Queenside),
             _ => None,
@@ -1662,7 +1767,7 @@ impl Iterator for LegalMoves {
             }
             self.current_piece.0 >>= Bitboard::new(1);
             self.current_piece.1 += 1;
-            self.current_from = Bitboard::new(1 << self.current_piece.1 - 1);
+            self.current_from = Bitboard::new(1 << (self.current_piece.1 - 1));
 
             self.current_moves = (
                 match self.current_piece.2 {
@@ -1690,7 +1795,7 @@ impl Iterator for LegalMoves {
         self.current_moves.0 >>= Bitboard::new(1);
         self.current_moves.1 += 1;
 
-        let to_square = Bitboard::new(1 << self.current_moves.1 - 1);
+        let to_square = Bitboard::new(1 << (self.current_moves.1 - 1));
 
         // Note that double moves can't promote, so we can ignore that here
         let is_pawn = self.current_piece.2 == PieceMove::PawnSingleMove
@@ -1748,6 +1853,73 @@ impl Iterator for LegalMoves {
 mod tests {
     use super::*;
 
+    #[test]
+    fn fen_round_trip() {
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w Kq f6 0 3",
+            "6k1/5ppp/8/8/8/8/5PPP/3R2K1 b - - 42 117",
+        ] {
+            assert_eq!(Game::from_fen(fen).to_fen(), fen);
+        }
+
+        let game = Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let m = game
+            .legal_moves(game.player)
+            .into_iter()
+            .find(|m| m.to_uci() == "e2e4");
+        assert_eq!(
+            game.make_move(&m.unwrap(), true).to_fen(),
+            "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"
+        );
+    }
+
+    #[test]
+    fn detects_repetitions() {
+        let play = |game: Game, moves: &[&str]| {
+            moves.iter().fold(game, |game, uci| {
+                let m = game
+                    .legal_moves(game.player)
+                    .into_iter()
+                    .find(|m| m.from_square.to_human() + &m.to_square.to_human() == *uci)
+                    .unwrap();
+                game.make_move(&m, true)
+            })
+        };
+        let shuffle = ["g1f3", "g8f6", "f3g1", "f6g8"];
+        let start = || Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+
+        let mut game = play(start(), &shuffle);
+        assert!(game.result().is_none());
+        let mut game = play(game, &shuffle);
+        assert!(matches!(game.result(), Some(GameResult::Repitition)));
+
+        // A pawn move in between starts over
+        let game = play(play(start(), &shuffle), &["d2d3", "d7d6"]);
+        let mut game = play(play(game, &shuffle), &shuffle[..2]);
+        assert!(game.result().is_none());
+        let mut game = play(game, &shuffle[2..]);
+        assert!(matches!(game.result(), Some(GameResult::Repitition)));
+    }
+
+    #[test]
+    fn symmetries() {
+        let game = Game::from_fen("rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w Kq f6 0 3");
+        assert_eq!(
+            game.recolor().to_fen(),
+            "rnbqkbnr/pppp1ppp/8/8/3PpP2/8/PPP1P1PP/RNBQKBNR b Qk f3 0 3"
+        );
+
+        let game = Game::from_fen("6k1/5ppp/8/8/3pP3/8/5PPP/3R2K1 b - e3 0 1");
+        assert_eq!(
+            game.mirror().to_fen(),
+            "1k6/ppp5/8/8/3Pp3/8/PPP5/1K2R3 b - d3 0 1"
+        );
+        assert_eq!(game.mirror().mirror().to_fen(), game.to_fen());
+        assert_eq!(game.recolor().recolor().to_fen(), game.to_fen());
+        assert!(!game.can_castle());
+    }
+
     #[test]
     fn test_position_1() {
         let cases = [(1, 20), (2, 400), (3, 8902), (4, 197281), (5, 4865609)];
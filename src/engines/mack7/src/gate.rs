@@ -0,0 +1,333 @@
+//! Plays a newly trained generation against the current best one, and only
+//! promotes it for self-play if it wins the match. The report and the PGNs
+//! are saved in the directory of the candidate as `gate-NNNNNN.json` and
+//! `gate-NNNNNN.pgn`, named after the baseline generation.
+
+use rand::{rngs::StdRng, SeedableRng};
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::sync::Arc;
+
+use crate::{
+    bitbase::Bitbases,
+    checkpoint::Checkpoints,
+    evaluator::{Evaluator, EvaluatorParams},
+    game::{Game, GameResult},
+    mcts::{self, SearchParams, Tree},
+    pgn::{self, PgnGame},
+    stats::{Score, Sprt},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GateRule {
+    /// Passes once all games are played if the candidate scored at least
+    /// this fraction of the points.
+    Threshold(f64),
+    /// A sequential probability ratio test of the Elo difference `elo1`
+    /// against `elo0`, which stops as soon as one of them is accepted. Passes
+    /// if `elo1` is accepted, and fails if the games run out before.
+    Sprt {
+        elo0: f64,
+        elo1: f64,
+        alpha: f64,
+        beta: f64,
+    },
+}
+
+pub struct GateOptions {
+    /// The maximum number of games, half of them with the candidate as white.
+    pub games: u32,
+    /// The number of playouts for every move.
+    pub playouts: u32,
+    /// The number of plies at the start of each game whose moves are chosen
+    /// proportionally to their visits, so that the games differ.
+    pub temperature_plies: u32,
+    /// Games that are not over after this many plies count as draws.
+    pub max_plies: u32,
+    pub seed: Option<u64>,
+    pub search: SearchParams,
+    pub evaluator: EvaluatorParams,
+    pub rule: GateRule,
+    /// Bitbases that end playouts in the positions they know.
+    pub bitbases: Option<Arc<Bitbases>>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct GateReport {
+    pub candidate: u32,
+    pub baseline: u32,
+    /// Wins, draws and losses of the candidate.
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+    pub rule: GateRule,
+    /// The log-likelihood ratio of the SPRT.
+    pub llr: Option<f64>,
+    pub passed: bool,
+}
+
+impl GateReport {
+    pub fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    /// The fraction of the points the candidate scored.
+    pub fn score(&self) -> f64 {
+        (self.wins as f64 + self.draws as f64 / 2.) / self.games().max(1) as f64
+    }
+}
+
+/// Plays one game between the two evaluators, the first one as white, and
+/// returns the result from the perspective of white between -1 and 1.
+fn play_game(
+    players: [&Evaluator; 2],
+    pgn: &mut PgnGame,
+    options: &GateOptions,
+    rng: &mut StdRng,
+) -> i8 {
+    let mut game = Game::from_fen(pgn::START_POSITION);
+    let mut trees = [
+        Tree::new(0, game.clone(), options.search).with_bitbases(options.bitbases.clone()),
+        Tree::new(1, game.clone(), options.search).with_bitbases(options.bitbases.clone()),
+    ];
+
+    // The result is checked before the length, so that a mate on the last
+    // ply counts
+    let mut ply = 0;
+    loop {
+        if let Some(result) = game.result() {
+            pgn.finish(Some(&result));
+            return match result {
+                GameResult::White => 1,
+                GameResult::Black => -1,
+                _ => 0,
+            };
+        }
+        if ply >= options.max_plies {
+            break;
+        }
+
+        let side = if game.player { 0 } else { 1 };
+        mcts::search(&mut trees[side], options.playouts, None, players[side]);
+        let temperature = if ply < options.temperature_plies {
+            1.
+        } else {
+            0.
+        };
+        let index = match mcts::choose_move(&trees[side], temperature, rng) {
+            Some(index) => index,
+            None => break,
+        };
+        let m = game
+            .legal_moves(game.player)
+            .into_iter()
+            .find(|m| m.index() == index)
+            .unwrap();
+
+        pgn.push(&game, &m);
+        game = game.make_move(&m, true);
+        for tree in trees.iter_mut() {
+            tree.advance(index);
+        }
+        ply += 1;
+    }
+
+    pgn.tags
+        .push((String::from("Termination"), String::from("adjudication")));
+    0
+}
+
+/// Plays the candidate, the latest generation by default, against the
+/// baseline, the best generation or else the parent of the candidate, and
+/// promotes the candidate if it passes.
+pub fn run(
+    checkpoints: &Checkpoints,
+    candidate: Option<u32>,
+    baseline: Option<u32>,
+    options: GateOptions,
+) -> std::io::Result<GateReport> {
+    let candidate = checkpoints.resolve(candidate)?;
+    let baseline = match baseline {
+        Some(baseline) => baseline,
+        None => match checkpoints.best()? {
+            Some(best) => best,
+            None => checkpoints.metadata(candidate)?.parent.ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "Generation {} has no parent, choose the baseline with --baseline",
+                        candidate
+                    ),
+                )
+            })?,
+        },
+    };
+    if candidate == baseline {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Generation {} is already the best one", candidate),
+        ));
+    }
+
+    let (candidate_network, _) = checkpoints.load(Some(candidate))?;
+    let (baseline_network, _) = checkpoints.load(Some(baseline))?;
+    let evaluators = [
+        Evaluator::new(candidate_network, options.evaluator),
+        Evaluator::new(baseline_network, options.evaluator),
+    ];
+    let names = [
+        format!("mack7 generation {}", candidate),
+        format!("mack7 generation {}", baseline),
+    ];
+
+    let directory = checkpoints.directory(candidate);
+    let mut pgns = BufWriter::new(File::create(
+        directory.join(format!("gate-{:06}.pgn", baseline)),
+    )?);
+    let mut rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let mut report = GateReport {
+        candidate,
+        baseline,
+        wins: 0,
+        draws: 0,
+        losses: 0,
+        rule: options.rule,
+        llr: None,
+        passed: false,
+    };
+    for round in 0..options.games {
+        // The candidate plays white in even rounds
+        let (white, black) = if round % 2 == 0 { (0, 1) } else { (1, 0) };
+        let mut pgn = PgnGame::new(
+            "mack7 gate",
+            round + 1,
+            &names[white],
+            &names[black],
+            pgn::START_POSITION,
+        );
+        let result = play_game(
+            [&evaluators[white], &evaluators[black]],
+            &mut pgn,
+            &options,
+            &mut rng,
+        );
+        writeln!(pgns, "{}", pgn)?;
+        pgns.flush()?;
+
+        match if white == 0 { result } else { -result } {
+            1 => report.wins += 1,
+            -1 => report.losses += 1,
+            _ => report.draws += 1,
+        }
+        println!(
+            "Game {}: {}, candidate +{} ={} -{}",
+            round + 1,
+            pgn.result(),
+            report.wins,
+            report.draws,
+            report.losses
+        );
+
+        if let GateRule::Sprt {
+            elo0,
+            elo1,
+            alpha,
+            beta,
+        } = options.rule
+        {
+            let sprt = Sprt {
+                elo0,
+                elo1,
+                alpha,
+                beta,
+            };
+            let llr = sprt.llr_trinomial(&Score {
+                wins: report.wins,
+                draws: report.draws,
+                losses: report.losses,
+            });
+            report.llr = Some(llr);
+            if let Some(passed) = sprt.decision(llr) {
+                report.passed = passed;
+                break;
+            }
+        }
+    }
+    if let GateRule::Threshold(threshold) = options.rule {
+        report.passed = report.score() >= threshold;
+    }
+
+    fs::write(
+        directory.join(format!("gate-{:06}.json", baseline)),
+        serde_json::to_string_pretty(&report)?,
+    )?;
+    if report.passed {
+        checkpoints.promote(candidate)?;
+        println!(
+            "Generation {} passed with a score of {:.3} and is the best one now",
+            candidate,
+            report.score()
+        );
+    } else {
+        println!(
+            "Generation {} failed with a score of {:.3}, generation {} stays the best one",
+            candidate,
+            report.score(),
+            baseline
+        );
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{Network, NetworkConfig, ValueHead};
+
+    #[test]
+    fn plays_and_promotes() {
+        let directory = std::env::temp_dir().join(format!("mack7-gate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let checkpoints = Checkpoints::new(&directory);
+        let config = NetworkConfig {
+            blocks: 1,
+            filters: 4,
+            value_head: ValueHead::Tanh,
+        };
+        let parent = checkpoints.save(&Network::new(config), None, 0).unwrap();
+        checkpoints
+            .save(&Network::new(config), Some(&parent), 1)
+            .unwrap();
+
+        let options = || GateOptions {
+            games: 2,
+            playouts: 2,
+            temperature_plies: 2,
+            max_plies: 6,
+            seed: Some(1),
+            search: SearchParams::default(),
+            evaluator: EvaluatorParams::default(),
+            rule: GateRule::Threshold(0.5),
+            bitbases: None,
+        };
+        let report = run(&checkpoints, None, None, options()).unwrap();
+        assert_eq!((report.candidate, report.baseline), (1, 0));
+        // Nobody gets mated within 6 plies, so both games are adjudicated
+        assert_eq!((report.wins, report.draws, report.losses), (0, 2, 0));
+        assert!(report.passed);
+        assert_eq!(checkpoints.best().unwrap(), Some(1));
+
+        let pgns = fs::read_to_string(checkpoints.directory(1).join("gate-000000.pgn")).unwrap();
+        assert_eq!(pgns.matches("[Event \"mack7 gate\"]").count(), 2);
+        assert!(pgns.contains("[White \"mack7 generation 0\"]"));
+        assert!(checkpoints.directory(1).join("gate-000000.json").exists());
+
+        // Generation 1 is the best one now, so there is nothing to gate
+        assert!(run(&checkpoints, None, None, options()).is_err());
+    }
+}
@@ -1,18 +1,243 @@
+mod adjudication;
+mod alphabeta;
+mod bitbase;
 mod bitboard;
+mod book;
+mod checkpoint;
 mod chess_move;
+mod chunk;
 mod direction;
+mod evaluator;
 mod game;
-// mod mcts;
+mod gate;
+mod mcts;
+mod metrics;
+mod mlp;
+mod network;
+mod nn;
+mod nnue;
+mod openings;
+mod pgn;
 mod piece;
-// mod policy_network;
 mod position;
-// mod train;
-// mod value_network;
+mod quantized;
+mod replay;
+mod run_directory;
+mod stats;
+mod syzygy;
+mod tournament;
+mod train;
+mod uci;
+mod weights;
 
-use clap::{App, Arg};
-use std::time::Instant;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::game::Game;
+use crate::{
+    checkpoint::Checkpoints,
+    evaluator::{Evaluator, EvaluatorParams},
+    game::Game,
+    network::{Network, NetworkConfig, ValueHead},
+    nnue::Nnue,
+};
+
+fn parallel_search_args<'help>() -> [Arg<'help>; 3] {
+    [
+        Arg::new("THREADS")
+            .long("threads")
+            .help("The number of threads searching at the same time")
+            .takes_value(true)
+            .default_value("1")
+            .validator(|value| match value.parse::<usize>() {
+                Ok(threads) if threads > 0 => Ok(()),
+                _ => Err("Must be a positive integer"),
+            }),
+        Arg::new("BATCH_SIZE")
+            .long("batch-size")
+            .help("The number of leaves each thread evaluates together")
+            .takes_value(true)
+            .default_value("1")
+            .validator(|value| match value.parse::<usize>() {
+                Ok(batch_size) if batch_size > 0 => Ok(()),
+                _ => Err("Must be a positive integer"),
+            }),
+        Arg::new("VIRTUAL_LOSS")
+            .long("virtual-loss")
+            .help("The number of lost visits each unfinished playout counts as")
+            .takes_value(true)
+            .default_value("1")
+            .validator(|value| match value.parse::<f32>() {
+                Ok(virtual_loss) if virtual_loss >= 0. => Ok(()),
+                _ => Err("Must be a non-negative number"),
+            }),
+    ]
+}
+
+fn network_args<'help>() -> [Arg<'help>; 3] {
+    [
+        Arg::new("BLOCKS")
+            .long("blocks")
+            .help("The number of residual blocks of a new network")
+            .takes_value(true)
+            .default_value("4")
+            .validator(|value| match value.parse::<usize>() {
+                Err(_) => Err("Must be an integer"),
+                Ok(_) => Ok(()),
+            }),
+        Arg::new("FILTERS")
+            .long("filters")
+            .help("The number of filters in each convolution of a new network")
+            .takes_value(true)
+            .default_value("32")
+            .validator(|value| match value.parse::<usize>() {
+                Ok(filters) if filters > 0 => Ok(()),
+                _ => Err("Must be a positive integer"),
+            }),
+        Arg::new("VALUE_HEAD")
+            .long("value-head")
+            .help(
+                "Whether a new network predicts a single value or win, draw and loss probabilities",
+            )
+            .takes_value(true)
+            .possible_values(["tanh", "wdl"])
+            .default_value("tanh"),
+    ]
+}
+
+fn network_config(matches: &ArgMatches) -> NetworkConfig {
+    NetworkConfig {
+        blocks: matches.value_of_t_or_exit("BLOCKS"),
+        filters: matches.value_of_t_or_exit("FILTERS"),
+        value_head: matches.value_of_t_or_exit::<ValueHead>("VALUE_HEAD"),
+    }
+}
+
+fn checkpoint_args<'help>() -> [Arg<'help>; 2] {
+    [
+        Arg::new("CHECKPOINTS")
+            .long("checkpoints")
+            .help("The directory with the generations of the network")
+            .takes_value(true)
+            .default_value(checkpoint::DEFAULT_DIRECTORY),
+        Arg::new("GENERATION")
+            .long("generation")
+            .help("The generation of the network to load, by default the best one for playing and the latest one otherwise")
+            .takes_value(true)
+            .validator(|value| match value.parse::<u32>() {
+                Err(_) => Err("Must be an integer"),
+                Ok(_) => Ok(()),
+            }),
+    ]
+}
+
+fn checkpoints(matches: &ArgMatches) -> Checkpoints {
+    Checkpoints::new(matches.value_of("CHECKPOINTS").unwrap())
+}
+
+/// Loads the network to play with, which is the best generation unless
+/// another one is given.
+fn load_network(matches: &ArgMatches) -> Network {
+    let checkpoints = checkpoints(matches);
+    match checkpoints
+        .resolve_best(matches.value_of_t("GENERATION").ok())
+        .and_then(|generation| checkpoints.load(Some(generation)))
+    {
+        Ok((network, _)) => network,
+        Err(err) => panic!("Loading the network failed: {}", err),
+    }
+}
+
+fn load_nnue(path: &str) -> Nnue {
+    match Nnue::load(path) {
+        Ok(nnue) => nnue,
+        Err(err) => panic!("Loading the NNUE failed: {}", err),
+    }
+}
+
+fn load_book(path: &str) -> book::Book {
+    match book::Book::load(path) {
+        Ok(book) => book,
+        Err(err) => panic!("Loading the book failed: {}", err),
+    }
+}
+
+fn load_tablebases(path: &str) -> Arc<syzygy::Tablebases> {
+    match syzygy::Tablebases::open(path) {
+        Ok(tablebases) => Arc::new(tablebases),
+        Err(err) => panic!("Opening the tablebases failed: {}", err),
+    }
+}
+
+fn evaluator_args<'help>() -> [Arg<'help>; 4] {
+    [
+        Arg::new("EVAL_BATCH_SIZE")
+            .long("eval-batch-size")
+            .help("The number of positions from all threads that are evaluated together")
+            .takes_value(true)
+            .default_value("1")
+            .validator(|value| match value.parse::<usize>() {
+                Ok(batch_size) if batch_size > 0 => Ok(()),
+                _ => Err("Must be a positive integer"),
+            }),
+        Arg::new("EVAL_TIMEOUT")
+            .long("eval-timeout")
+            .help("How many milliseconds positions wait for a batch to fill up")
+            .takes_value(true)
+            .default_value("1")
+            .validator(|value| match value.parse::<u64>() {
+                Err(_) => Err("Must be an integer"),
+                Ok(_) => Ok(()),
+            }),
+        Arg::new("CACHE_SIZE")
+            .long("cache-size")
+            .help("The number of evaluated positions to keep, 0 disables the cache")
+            .takes_value(true)
+            .default_value("4096")
+            .validator(|value| match value.parse::<usize>() {
+                Err(_) => Err("Must be an integer"),
+                Ok(_) => Ok(()),
+            }),
+        Arg::new("QUANTIZE")
+            .long("quantize")
+            .help("Evaluate positions with weights quantized to 8 or 16 bit integers")
+            .takes_value(true)
+            .possible_values(["int8", "int16"]),
+    ]
+}
+
+fn evaluator_params(matches: &ArgMatches) -> EvaluatorParams {
+    EvaluatorParams {
+        batch_size: matches.value_of_t_or_exit("EVAL_BATCH_SIZE"),
+        timeout: Duration::from_millis(matches.value_of_t_or_exit("EVAL_TIMEOUT")),
+        cache_size: matches.value_of_t_or_exit("CACHE_SIZE"),
+        quantization: matches.value_of_t("QUANTIZE").ok(),
+    }
+}
+
+fn parallel_search_params(matches: &ArgMatches, params: mcts::SearchParams) -> mcts::SearchParams {
+    mcts::SearchParams {
+        threads: matches.value_of_t_or_exit("THREADS"),
+        batch_size: matches.value_of_t_or_exit("BATCH_SIZE"),
+        virtual_loss: matches.value_of_t_or_exit("VIRTUAL_LOSS"),
+        ..params
+    }
+}
+
+/// Converts `value.{idx}.csv` and `policy.{idx}.csv` into a training data
+/// chunk.
+fn convert(run_index: &str) -> std::io::Result<usize> {
+    let value_csv = BufReader::new(File::open(format!("value.{}.csv", run_index))?);
+    let policy_csv = BufReader::new(File::open(format!("policy.{}.csv", run_index))?);
+    let mut writer = chunk::ChunkWriter::create(chunk::filename(run_index))?;
+    let count = chunk::convert_csv(value_csv, policy_csv, &mut writer)?;
+    writer.finish()?;
+    Ok(count)
+}
 
 fn main() {
     let matches = App::new("cheers")
@@ -41,10 +266,296 @@ fn main() {
                         })
                         .required(true),
                 )
+                .arg(
+                    Arg::new("TOTAL_GAMES")
+                        .long("total-games")
+                        .help("The number of games to play in the whole run, defaults to the number of parallel games for a new run and to the total of the run when resuming")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(games) if games > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("RESUME")
+                        .long("resume")
+                        .help("Continue an interrupted run after its finished games"),
+                )
+                .arg(
+                    Arg::new("STATS_INTERVAL")
+                        .long("stats-interval")
+                        .help("The number of seconds between two reports of the statistics of the run")
+                        .takes_value(true)
+                        .default_value("30")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(seconds) if seconds > 0. => Ok(()),
+                            _ => Err("Must be a positive number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("DIRICHLET_ALPHA")
+                        .long("dirichlet-alpha")
+                        .help("The concentration of the Dirichlet noise added to the root priors")
+                        .takes_value(true)
+                        .default_value("0.3")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(alpha) if alpha > 0. => Ok(()),
+                            _ => Err("Must be a positive number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("DIRICHLET_EPSILON")
+                        .long("dirichlet-epsilon")
+                        .help("The weight of the Dirichlet noise, 0 disables the noise")
+                        .takes_value(true)
+                        .default_value("0.25")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(epsilon) if (0. ..=1.).contains(&epsilon) => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("TEMPERATURE")
+                        .long("temperature")
+                        .help("The temperature for choosing moves proportional to visit counts")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(temperature) if temperature >= 0. => Ok(()),
+                            _ => Err("Must be a non-negative number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("TEMPERATURE_PLIES")
+                        .long("temperature-plies")
+                        .help("The number of plies using the temperature, later moves are chosen greedily")
+                        .takes_value(true)
+                        .default_value("30")
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("PLAYOUTS")
+                        .long("playouts")
+                        .help("The number of playouts of a full search")
+                        .takes_value(true)
+                        .default_value("1600")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(playouts) if playouts > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("FAST_PLAYOUTS")
+                        .long("fast-playouts")
+                        .help("Randomize the playout cap: search most moves with this many playouts and do not train the policy on them")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(playouts) if playouts > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("FULL_PROBABILITY")
+                        .long("full-probability")
+                        .help("The probability of a move to get a full search when randomizing the playout cap")
+                        .takes_value(true)
+                        .default_value("0.25")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(probability) if (0. ..=1.).contains(&probability) => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("MIN_KLD_GAIN")
+                        .long("min-kld-gain")
+                        .help("Stop searching a move once the KL divergence of the visits per playout drops below this")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(gain) if gain > 0. => Ok(()),
+                            _ => Err("Must be a positive number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("KLD_INTERVAL")
+                        .long("kld-interval")
+                        .help("The number of playouts between two checks of the KL divergence")
+                        .takes_value(true)
+                        .default_value("100")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(interval) if interval > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("OPENINGS")
+                        .long("openings")
+                        .help("An opening suite in EPD or PGN to draw the starting position of every game from")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("OPENINGS_ORDER")
+                        .long("openings-order")
+                        .help("Whether to draw the openings randomly or one after the other")
+                        .takes_value(true)
+                        .possible_values(["random", "round-robin"])
+                        .default_value("random"),
+                )
+                .arg(
+                    Arg::new("RANDOM_PLIES")
+                        .long("random-plies")
+                        .help("The number of uniformly random moves played at the start of every game before searching")
+                        .takes_value(true)
+                        .default_value("0")
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("RESIGN_THRESHOLD")
+                        .long("resign-threshold")
+                        .help("Resign once the value of a player stays below this threshold, never resign if not given")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(threshold) if (-1. ..=1.).contains(&threshold) => Ok(()),
+                            _ => Err("Must be a number between -1 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("RESIGN_PLIES")
+                        .long("resign-plies")
+                        .help("The number of plies in a row the value has to stay below the resignation threshold")
+                        .takes_value(true)
+                        .default_value("4")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(plies) if plies > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("RESIGN_PLAYTHROUGH")
+                        .long("resign-playthrough")
+                        .help("The fraction of games that never resign, to measure how often resigning would be wrong")
+                        .takes_value(true)
+                        .default_value("0.1")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(fraction) if (0. ..=1.).contains(&fraction) => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("DRAW_THRESHOLD")
+                        .long("draw-threshold")
+                        .help("Adjudicate a draw once the absolute value stays below this threshold, never if not given")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(threshold) if (0. ..=1.).contains(&threshold) => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("DRAW_PLIES")
+                        .long("draw-plies")
+                        .help("The number of plies in a row the value has to stay below the draw threshold")
+                        .takes_value(true)
+                        .default_value("40")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(plies) if plies > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("MAX_PLIES")
+                        .long("max-plies")
+                        .help("The number of plies after which a game is adjudicated as a draw")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(plies) if plies > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("CPUCT")
+                        .long("cpuct")
+                        .help("The weight of the exploration term when selecting children")
+                        .takes_value(true)
+                        .default_value("2.5")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(c_puct) if c_puct >= 0. => Ok(()),
+                            _ => Err("Must be a non-negative number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("CPUCT_BASE")
+                        .long("cpuct-base")
+                        .help("Grow the exploration weight with the number of visits as in AlphaZero, using this base")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(base) if base > 0. => Ok(()),
+                            _ => Err("Must be a positive number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("FPU_REDUCTION")
+                        .long("fpu-reduction")
+                        .help("How much lower than their parent unvisited children are valued")
+                        .takes_value(true)
+                        .default_value("0.25")
+                        .allow_hyphen_values(true)
+                        .validator(|value| match value.parse::<f32>() {
+                            Err(_) => Err("Must be a number"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("NORMALIZE_VALUES")
+                        .long("normalize-values")
+                        .help("Rescale values to the range seen in the search tree when selecting children"),
+                )
+                .arg(
+                    Arg::new("SEED")
+                        .long("seed")
+                        .help("The seed for all random choices, making self-play reproducible")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u64>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("SYZYGY")
+                        .long("syzygy")
+                        .help("A directory with Syzygy tablebases that end searches and adjudicate games in the positions they know")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("BITBASES")
+                        .long("bitbases")
+                        .help("Generate bitbases for KPK, KRK and KQK at startup and probe them in the search"),
+                )
+                .args(parallel_search_args())
+                .args(evaluator_args())
+                .args(checkpoint_args()),
+        )
+        .subcommand(
+            App::new("init")
+                .about("Create the first generation of the network with random weights")
+                .arg(
+                    Arg::new("CHECKPOINTS")
+                        .long("checkpoints")
+                        .help("The directory with the generations of the network")
+                        .takes_value(true)
+                        .default_value(checkpoint::DEFAULT_DIRECTORY),
+                )
+                .args(network_args()),
         )
         .subcommand(
             App::new("train")
-                .about("Train value and policy networks using training data generated during self-play")
+                .about("Train the network using training data generated during self-play")
                 .arg(
                     Arg::new("IDX")
                         .short('i')
@@ -54,6 +565,656 @@ fn main() {
                         .multiple_values(true)
                         .required(true),
                 )
+                .arg(
+                    Arg::new("EPOCHS")
+                        .long("epochs")
+                        .help("The number of epochs to train for")
+                        .takes_value(true)
+                        .default_value("100")
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("BATCHES")
+                        .long("batches")
+                        .help("The maximum number of batches in each epoch, by default an epoch goes through the whole window")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<usize>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("BATCH_SIZE")
+                        .long("batch-size")
+                        .help("The number of positions in each batch")
+                        .takes_value(true)
+                        .default_value("100")
+                        .validator(|value| match value.parse::<usize>() {
+                            Ok(batch_size) if batch_size > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("LEARNING_RATE")
+                        .long("learning-rate")
+                        .help("The learning rate at the start of training")
+                        .takes_value(true)
+                        .default_value("0.01")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(learning_rate) if learning_rate > 0. => Ok(()),
+                            _ => Err("Must be a positive number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("LR_SCHEDULE")
+                        .long("lr-schedule")
+                        .help("How the learning rate changes over the epochs")
+                        .takes_value(true)
+                        .possible_values(["constant", "step", "cosine"])
+                        .default_value("constant"),
+                )
+                .arg(
+                    Arg::new("LR_STEP_EPOCHS")
+                        .long("lr-step-epochs")
+                        .help("The number of epochs between learning rate drops of the step schedule")
+                        .takes_value(true)
+                        .default_value("30")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(epochs) if epochs > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("LR_GAMMA")
+                        .long("lr-gamma")
+                        .help("The factor of each learning rate drop of the step schedule")
+                        .takes_value(true)
+                        .default_value("0.1")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(gamma) if gamma > 0. => Ok(()),
+                            _ => Err("Must be a positive number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("OPTIMIZER")
+                        .long("optimizer")
+                        .help("The algorithm that updates the weights")
+                        .takes_value(true)
+                        .possible_values(["sgd", "momentum", "adam"])
+                        .default_value("momentum"),
+                )
+                .arg(
+                    Arg::new("MOMENTUM")
+                        .long("momentum")
+                        .help("The momentum, or the decay of the first moment for Adam")
+                        .takes_value(true)
+                        .default_value("0.9")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(momentum) if (0. ..1.).contains(&momentum) => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("WEIGHT_DECAY")
+                        .long("weight-decay")
+                        .help("The factor of the L2 penalty on the weights")
+                        .takes_value(true)
+                        .default_value("0.0001")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(weight_decay) if weight_decay >= 0. => Ok(()),
+                            _ => Err("Must be a non-negative number"),
+                        }),
+                )
+                .arg(
+                    Arg::new("WINDOW")
+                        .long("window")
+                        .help("The number of most recent games to train on")
+                        .takes_value(true)
+                        .default_value("10000")
+                        .validator(|value| match value.parse::<usize>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("SHUFFLE_BUFFER")
+                        .long("shuffle-buffer")
+                        .help("The number of positions that are shuffled at once")
+                        .takes_value(true)
+                        .default_value("50000")
+                        .validator(|value| match value.parse::<usize>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("OLDEST_WEIGHT")
+                        .long("oldest-weight")
+                        .help("The probability to use a position of the oldest game in the window, which grows linearly to 1 for the newest game")
+                        .takes_value(true)
+                        .default_value("0.5")
+                        .validator(|value| match value.parse::<f32>() {
+                            Ok(weight) if (0. ..=1.).contains(&weight) => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("AUGMENT")
+                        .long("augment")
+                        .help("Randomly recolor positions, and mirror them if no side can castle"),
+                )
+                .arg(
+                    Arg::new("NNUE")
+                        .long("nnue")
+                        .help("Train the value of the NNUE in this file instead of the network, creating it if it does not exist")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("NNUE_SIZE")
+                        .long("nnue-size")
+                        .help("The size of the accumulator of a new NNUE")
+                        .takes_value(true)
+                        .default_value("64")
+                        .validator(|value| match value.parse::<usize>() {
+                            Ok(size) if size > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .args(checkpoint_args())
+        )
+        .subcommand(
+            App::new("gate")
+                .about("Play a new generation against the best one and promote it if it wins")
+                .arg(
+                    Arg::new("CHECKPOINTS")
+                        .long("checkpoints")
+                        .help("The directory with the generations of the network")
+                        .takes_value(true)
+                        .default_value(checkpoint::DEFAULT_DIRECTORY),
+                )
+                .arg(
+                    Arg::new("CANDIDATE")
+                        .long("candidate")
+                        .help("The generation to promote, the latest one by default")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("BASELINE")
+                        .long("baseline")
+                        .help("The generation to play against, by default the best one or else the parent of the candidate")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("GAMES")
+                        .long("games")
+                        .help("The maximum number of games, with alternating colors")
+                        .takes_value(true)
+                        .default_value("40")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(games) if games > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("PLAYOUTS")
+                        .long("playouts")
+                        .help("The number of playouts for every move")
+                        .takes_value(true)
+                        .default_value("200")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(playouts) if playouts > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("TEMPERATURE_PLIES")
+                        .long("temperature-plies")
+                        .help("The number of plies at the start of each game whose moves are chosen proportionally to their visits")
+                        .takes_value(true)
+                        .default_value("8")
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("MAX_PLIES")
+                        .long("max-plies")
+                        .help("The number of plies after which a game counts as a draw")
+                        .takes_value(true)
+                        .default_value("400")
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("THRESHOLD")
+                        .long("threshold")
+                        .help("The fraction of the points the candidate has to score")
+                        .takes_value(true)
+                        .default_value("0.55")
+                        .validator(|value| match value.parse::<f64>() {
+                            Ok(threshold) if (0. ..=1.).contains(&threshold) => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("SPRT")
+                        .long("sprt")
+                        .help("Stop as soon as a sequential probability ratio test decides, instead of using the threshold"),
+                )
+                .arg(
+                    Arg::new("ELO0")
+                        .long("elo0")
+                        .help("The Elo difference of the null hypothesis of the SPRT")
+                        .takes_value(true)
+                        .default_value("0")
+                        .validator(|value| match value.parse::<f64>() {
+                            Err(_) => Err("Must be a number"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("ELO1")
+                        .long("elo1")
+                        .help("The Elo difference of the alternative hypothesis of the SPRT")
+                        .takes_value(true)
+                        .default_value("10")
+                        .validator(|value| match value.parse::<f64>() {
+                            Err(_) => Err("Must be a number"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("ALPHA")
+                        .long("alpha")
+                        .help("The probability of the SPRT to promote a candidate that is not stronger")
+                        .takes_value(true)
+                        .default_value("0.05")
+                        .validator(|value| match value.parse::<f64>() {
+                            Ok(alpha) if alpha > 0. && alpha < 1. => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("BETA")
+                        .long("beta")
+                        .help("The probability of the SPRT to reject a candidate that is stronger")
+                        .takes_value(true)
+                        .default_value("0.05")
+                        .validator(|value| match value.parse::<f64>() {
+                            Ok(beta) if beta > 0. && beta < 1. => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("SEED")
+                        .long("seed")
+                        .help("The seed for choosing the first moves of each game")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u64>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("BITBASES")
+                        .long("bitbases")
+                        .help("Generate bitbases for KPK, KRK and KQK at startup and probe them in the search"),
+                )
+                .args(parallel_search_args())
+                .args(evaluator_args()),
+        )
+        .subcommand(
+            App::new("match")
+                .about("Play a tournament between UCI engines and report their Elo differences")
+                .arg(
+                    Arg::new("ENGINE")
+                        .long("engine")
+                        .help("An engine as NAME=COMMAND, given once for every engine")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .required(true)
+                        .validator(|value| value.parse::<tournament::EngineConfig>().map(|_| ())),
+                )
+                .arg(
+                    Arg::new("FORMAT")
+                        .long("format")
+                        .help("Whether the first engine plays all others or everyone plays everyone")
+                        .takes_value(true)
+                        .possible_values(["gauntlet", "round-robin"])
+                        .default_value("round-robin"),
+                )
+                .arg(
+                    Arg::new("GAMES")
+                        .long("games")
+                        .help("The number of games of every pairing, with alternating colors")
+                        .takes_value(true)
+                        .default_value("2")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(games) if games > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("TIME_CONTROL")
+                        .long("tc")
+                        .help("The time control as [MOVES/]BASE[+INCREMENT] in seconds")
+                        .takes_value(true)
+                        .default_value("10+0.1")
+                        .validator(|value| value.parse::<tournament::TimeControl>().map(|_| ())),
+                )
+                .arg(
+                    Arg::new("TIME_MARGIN")
+                        .long("time-margin")
+                        .help("The number of milliseconds an engine may exceed its clock before it loses on time")
+                        .takes_value(true)
+                        .default_value("100")
+                        .validator(|value| match value.parse::<u64>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("OPENINGS")
+                        .long("openings")
+                        .help("An opening suite in EPD or PGN, every opening is played with both colors")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("OPENINGS_ORDER")
+                        .long("openings-order")
+                        .help("Whether to draw the openings randomly or one after the other")
+                        .takes_value(true)
+                        .possible_values(["random", "round-robin"])
+                        .default_value("round-robin"),
+                )
+                .arg(
+                    Arg::new("BOOK")
+                        .long("book")
+                        .help("A Polyglot book to play random moves from after the opening")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("BOOK_PLIES")
+                        .long("book-plies")
+                        .help("The number of plies to play from the book at most")
+                        .takes_value(true)
+                        .default_value("16")
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("MAX_PLIES")
+                        .long("max-plies")
+                        .help("The number of plies after which a game counts as a draw")
+                        .takes_value(true)
+                        .default_value("600")
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("PGN")
+                        .long("pgn")
+                        .help("The file to write the games to")
+                        .takes_value(true)
+                        .default_value("match.pgn"),
+                )
+                .arg(
+                    Arg::new("SEED")
+                        .long("seed")
+                        .help("Seed for drawing random openings")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u64>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                ),
+        )
+        .subcommand(
+            App::new("stats")
+                .about("Compute the Elo difference and the state of an SPRT from match results")
+                .arg(
+                    Arg::new("WINS")
+                        .long("wins")
+                        .help("The number of games won")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("DRAWS")
+                        .long("draws")
+                        .help("The number of games drawn")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("LOSSES")
+                        .long("losses")
+                        .help("The number of games lost")
+                        .takes_value(true)
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("PAIRS")
+                        .long("pairs")
+                        .help("The number of game pairs that scored 0, 0.5, 1, 1.5 and 2 points, separated by commas")
+                        .takes_value(true)
+                        .validator(|value| value.parse::<stats::Pentanomial>().map(|_| ())),
+                )
+                .arg(
+                    Arg::new("SPRT")
+                        .long("sprt")
+                        .help("Also report the state of a sequential probability ratio test"),
+                )
+                .arg(
+                    Arg::new("ELO0")
+                        .long("elo0")
+                        .help("The Elo difference of the null hypothesis of the SPRT")
+                        .takes_value(true)
+                        .default_value("0")
+                        .validator(|value| match value.parse::<f64>() {
+                            Err(_) => Err("Must be a number"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("ELO1")
+                        .long("elo1")
+                        .help("The Elo difference of the alternative hypothesis of the SPRT")
+                        .takes_value(true)
+                        .default_value("10")
+                        .validator(|value| match value.parse::<f64>() {
+                            Err(_) => Err("Must be a number"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .arg(
+                    Arg::new("ALPHA")
+                        .long("alpha")
+                        .help("The probability of accepting elo1 although elo0 is true")
+                        .takes_value(true)
+                        .default_value("0.05")
+                        .validator(|value| match value.parse::<f64>() {
+                            Ok(alpha) if alpha > 0. && alpha < 1. => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                )
+                .arg(
+                    Arg::new("BETA")
+                        .long("beta")
+                        .help("The probability of accepting elo0 although elo1 is true")
+                        .takes_value(true)
+                        .default_value("0.05")
+                        .validator(|value| match value.parse::<f64>() {
+                            Ok(beta) if beta > 0. && beta < 1. => Ok(()),
+                            _ => Err("Must be a number between 0 and 1"),
+                        }),
+                ),
+        )
+        .subcommand(
+            App::new("book")
+                .about("Work with opening books in the Polyglot format")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("build")
+                        .about("Build a book from the games of PGN files")
+                        .arg(
+                            Arg::new("PGN")
+                                .long("pgn")
+                                .help("A PGN file to read games from, given once for every file")
+                                .takes_value(true)
+                                .multiple_occurrences(true)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("OUTPUT")
+                                .long("output")
+                                .help("The file to write the book to")
+                                .takes_value(true)
+                                .default_value("book.bin"),
+                        )
+                        .arg(
+                            Arg::new("MIN_GAMES")
+                                .long("min-games")
+                                .help("The number of games a move must have been played in")
+                                .takes_value(true)
+                                .default_value("1")
+                                .validator(|value| match value.parse::<u32>() {
+                                    Err(_) => Err("Must be an integer"),
+                                    Ok(_) => Ok(()),
+                                }),
+                        )
+                        .arg(
+                            Arg::new("RESULTS")
+                                .long("results")
+                                .help("Whether to use all games, only decisive ones, or only the moves of the winners")
+                                .takes_value(true)
+                                .possible_values(["all", "decisive", "winner"])
+                                .default_value("all"),
+                        )
+                        .arg(
+                            Arg::new("MAX_PLIES")
+                                .long("max-plies")
+                                .help("The number of plies of every game that go into the book")
+                                .takes_value(true)
+                                .default_value("30")
+                                .validator(|value| match value.parse::<u32>() {
+                                    Err(_) => Err("Must be an integer"),
+                                    Ok(_) => Ok(()),
+                                }),
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("convert")
+                .about("Convert training data from the old CSV files into the binary format")
+                .arg(
+                    Arg::new("IDX")
+                        .short('i')
+                        .long("index")
+                        .help("The indices under which the training data is stored")
+                        .takes_value(true)
+                        .multiple_values(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            App::new("convert-weights")
+                .about("Convert networks saved as JSON into the binary weight format")
+                .args(checkpoint_args())
+                .arg(
+                    Arg::new("PRECISION")
+                        .long("precision")
+                        .help("Whether to store weights as 32 or 16 bit floats")
+                        .takes_value(true)
+                        .possible_values(["f32", "f16"])
+                        .default_value("f32"),
+                ),
+        )
+        .subcommand(
+            App::new("uci")
+                .about("Play using the Universal Chess Interface")
+                .arg(
+                    Arg::new("NNUE")
+                        .long("nnue")
+                        .help("Evaluate positions with the NNUE in this file instead of the network")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("DEPTH")
+                        .long("depth")
+                        .help("Search to this depth with alpha-beta instead of MCTS, using the NNUE")
+                        .takes_value(true)
+                        .requires("NNUE")
+                        .validator(|value| match value.parse::<u32>() {
+                            Ok(depth) if depth > 0 => Ok(()),
+                            _ => Err("Must be a positive integer"),
+                        }),
+                )
+                .arg(
+                    Arg::new("BOOK")
+                        .long("book")
+                        .help("A Polyglot book to play moves from while the position is in it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("SYZYGY")
+                        .long("syzygy")
+                        .help("A directory with Syzygy tablebases to probe in the positions they know")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("BITBASES")
+                        .long("bitbases")
+                        .help("Generate bitbases for KPK, KRK and KQK at startup and probe them in the search"),
+                )
+                .args(parallel_search_args())
+                .args(evaluator_args())
+                .args(checkpoint_args()),
+        )
+        .subcommand(
+            App::new("bench")
+                .about("Measure the number of playouts per second of the search")
+                .arg(
+                    Arg::new("PLAYOUTS")
+                        .long("playouts")
+                        .help("The number of playouts for each run")
+                        .takes_value(true)
+                        .default_value("1600")
+                        .validator(|value| match value.parse::<u32>() {
+                            Err(_) => Err("Must be an integer"),
+                            Ok(_) => Ok(()),
+                        }),
+                )
+                .args(parallel_search_args())
+                .args(evaluator_args())
+                .args(network_args()),
         )
         .get_matches();
 
@@ -75,7 +1236,7 @@ fn main() {
                 let seconds = time as f64 / 1_000_000_000_f64;
                 let nps = nodes as f64 / seconds;
                 total_nps += nps;
-                println!("");
+                println!();
                 println!("Seconds: {}", seconds);
                 println!("Nodes: {}", nodes);
                 println!("NPS: {}", nps);
@@ -100,7 +1261,7 @@ fn main() {
                 let seconds = time as f64 / 1_000_000_000_f64;
                 let nps = nodes as f64 / seconds;
                 total_nps += nps;
-                println!("");
+                println!();
                 println!("Seconds: {}", seconds);
                 println!("Nodes: {}", nodes);
                 println!("NPS: {}", nps);
@@ -110,26 +1271,363 @@ fn main() {
             println!("Average NPS: {}", (total_nps / runs as f64) as u64);
             // To beat: 148_463_968
         }
-        // Some(("mcts", sub_matches)) => {
-        //     let run_index = sub_matches.value_of("IDX").unwrap().to_owned();
-        //     let parallel_games = sub_matches
-        //         .value_of("GAMES")
-        //         .unwrap()
-        //         .parse::<usize>()
-        //         .unwrap();
-
-        //     match mcts::run(run_index, parallel_games) {
-        //         Err(err) => panic!("Running MCTS failed: {:?}", err),
-        //         Ok(_) => {}
-        //     }
-        // }
-        // Some(("train", sub_matches)) => {
-        //     let run_indices: Vec<&str> = sub_matches.values_of("IDX").unwrap().collect();
-        //     match train::run(run_indices) {
-        //         Err(err) => panic!("Training failed: {:?}", err),
-        //         Ok(_) => {}
-        //     }
-        // }
+        Some(("mcts", sub_matches)) => {
+            let run_index = sub_matches.value_of("IDX").unwrap().to_owned();
+            let parallel_games = sub_matches
+                .value_of("GAMES")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap();
+
+            let options = mcts::SelfPlayOptions {
+                search: parallel_search_params(
+                    sub_matches,
+                    mcts::SearchParams {
+                        c_puct: sub_matches.value_of_t_or_exit("CPUCT"),
+                        c_puct_base: sub_matches.value_of_t("CPUCT_BASE").ok(),
+                        fpu_reduction: sub_matches.value_of_t_or_exit("FPU_REDUCTION"),
+                        normalize_values: sub_matches.is_present("NORMALIZE_VALUES"),
+                        ..mcts::SearchParams::default()
+                    },
+                ),
+                playouts: sub_matches.value_of_t_or_exit("PLAYOUTS"),
+                playout_cap: sub_matches
+                    .value_of_t("FAST_PLAYOUTS")
+                    .ok()
+                    .map(|fast_playouts| mcts::PlayoutCap {
+                        fast_playouts,
+                        full_probability: sub_matches.value_of_t_or_exit("FULL_PROBABILITY"),
+                    }),
+                early_stop: sub_matches
+                    .value_of_t("MIN_KLD_GAIN")
+                    .ok()
+                    .map(|min_kld_gain| mcts::EarlyStop {
+                        min_kld_gain,
+                        interval: sub_matches.value_of_t_or_exit("KLD_INTERVAL"),
+                    }),
+                dirichlet_alpha: sub_matches.value_of_t_or_exit("DIRICHLET_ALPHA"),
+                dirichlet_epsilon: sub_matches.value_of_t_or_exit("DIRICHLET_EPSILON"),
+                temperature: sub_matches.value_of_t_or_exit("TEMPERATURE"),
+                temperature_plies: sub_matches.value_of_t_or_exit("TEMPERATURE_PLIES"),
+                openings: sub_matches.value_of("OPENINGS").map(|path| {
+                    let order = match sub_matches.value_of("OPENINGS_ORDER") {
+                        Some("round-robin") => openings::Order::RoundRobin,
+                        _ => openings::Order::Random,
+                    };
+                    match openings::Openings::load(path, order) {
+                        Ok(openings) => openings,
+                        Err(err) => panic!("Loading the openings failed: {}", err),
+                    }
+                }),
+                random_plies: sub_matches.value_of_t_or_exit("RANDOM_PLIES"),
+                adjudication: adjudication::AdjudicationOptions {
+                    resign_threshold: sub_matches.value_of_t("RESIGN_THRESHOLD").ok(),
+                    resign_plies: sub_matches.value_of_t_or_exit("RESIGN_PLIES"),
+                    resign_playthrough: sub_matches.value_of_t_or_exit("RESIGN_PLAYTHROUGH"),
+                    draw_threshold: sub_matches.value_of_t("DRAW_THRESHOLD").ok(),
+                    draw_plies: sub_matches.value_of_t_or_exit("DRAW_PLIES"),
+                    max_plies: sub_matches.value_of_t("MAX_PLIES").ok(),
+                },
+                seed: sub_matches.value_of_t("SEED").ok(),
+                evaluator: evaluator_params(sub_matches),
+                stats_interval: Duration::from_secs_f32(
+                    sub_matches.value_of_t_or_exit("STATS_INTERVAL"),
+                ),
+                tablebases: sub_matches.value_of("SYZYGY").map(load_tablebases),
+                bitbases: sub_matches
+                    .is_present("BITBASES")
+                    .then(|| Arc::new(bitbase::Bitbases::generate())),
+            };
+
+            let resume = sub_matches.is_present("RESUME");
+            let (directory, mut manifest) =
+                match run_directory::RunDirectory::open(run_directory::path(&run_index), resume) {
+                    Ok(opened) => opened,
+                    Err(err) => panic!("Opening the run failed: {}", err),
+                };
+            manifest.total_games = match sub_matches.value_of_t("TOTAL_GAMES") {
+                Ok(total_games) => total_games,
+                Err(_) if resume => manifest.total_games,
+                Err(_) => parallel_games as u32,
+            };
+
+            // The first interrupt finishes the current move and saves the
+            // run, the second one exits right away
+            let stop = Arc::new(AtomicBool::new(false));
+            let handler_stop = stop.clone();
+            let handler = ctrlc::set_handler(move || {
+                if handler_stop.swap(true, Ordering::Relaxed) {
+                    std::process::exit(130);
+                }
+                println!("Stopping after the current move, interrupt again to exit right away");
+            });
+            if let Err(err) = handler {
+                panic!("Installing the interrupt handler failed: {}", err)
+            }
+
+            let network = load_network(sub_matches);
+            if let Err(err) = mcts::run(
+                &directory,
+                manifest,
+                parallel_games,
+                network,
+                options,
+                &stop,
+            ) {
+                panic!("Running MCTS failed: {:?}", err)
+            }
+        }
+        Some(("init", sub_matches)) => {
+            let checkpoints = checkpoints(sub_matches);
+            match checkpoints.latest() {
+                Ok(None) => {
+                    let network = Network::new(network_config(sub_matches));
+                    if let Err(err) = checkpoints.save(&network, None, 0) {
+                        panic!("Saving the network failed: {}", err)
+                    }
+                }
+                Ok(Some(latest)) => panic!("Generation {} already exists", latest),
+                Err(err) => panic!("Reading the checkpoints failed: {}", err),
+            }
+        }
+        Some(("train", sub_matches)) => {
+            let run_indices: Vec<&str> = sub_matches.values_of("IDX").unwrap().collect();
+            let options = train::TrainOptions {
+                epochs: sub_matches.value_of_t_or_exit("EPOCHS"),
+                batches_per_epoch: sub_matches.value_of_t("BATCHES").ok(),
+                batch_size: sub_matches.value_of_t_or_exit("BATCH_SIZE"),
+                learning_rate: sub_matches.value_of_t_or_exit("LEARNING_RATE"),
+                schedule: match sub_matches.value_of("LR_SCHEDULE") {
+                    Some("step") => train::LearningRateSchedule::Step {
+                        epochs: sub_matches.value_of_t_or_exit("LR_STEP_EPOCHS"),
+                        gamma: sub_matches.value_of_t_or_exit("LR_GAMMA"),
+                    },
+                    Some("cosine") => train::LearningRateSchedule::Cosine,
+                    _ => train::LearningRateSchedule::Constant,
+                },
+                optimizer: sub_matches.value_of_t_or_exit("OPTIMIZER"),
+                momentum: sub_matches.value_of_t_or_exit("MOMENTUM"),
+                weight_decay: sub_matches.value_of_t_or_exit("WEIGHT_DECAY"),
+                replay: replay::ReplayOptions {
+                    window: sub_matches.value_of_t_or_exit("WINDOW"),
+                    shuffle_buffer: sub_matches.value_of_t_or_exit("SHUFFLE_BUFFER"),
+                    oldest_weight: sub_matches.value_of_t_or_exit("OLDEST_WEIGHT"),
+                    augment: sub_matches.is_present("AUGMENT"),
+                },
+            };
+            let result = match sub_matches.value_of("NNUE") {
+                Some(path) => train::run_nnue(
+                    run_indices,
+                    Path::new(path),
+                    sub_matches.value_of_t_or_exit("NNUE_SIZE"),
+                    options,
+                ),
+                None => train::run(
+                    run_indices,
+                    &checkpoints(sub_matches),
+                    sub_matches.value_of_t("GENERATION").ok(),
+                    options,
+                ),
+            };
+            if let Err(err) = result {
+                panic!("Training failed: {:?}", err)
+            }
+        }
+        Some(("gate", sub_matches)) => {
+            let options = gate::GateOptions {
+                games: sub_matches.value_of_t_or_exit("GAMES"),
+                playouts: sub_matches.value_of_t_or_exit("PLAYOUTS"),
+                temperature_plies: sub_matches.value_of_t_or_exit("TEMPERATURE_PLIES"),
+                max_plies: sub_matches.value_of_t_or_exit("MAX_PLIES"),
+                seed: sub_matches.value_of_t("SEED").ok(),
+                search: parallel_search_params(sub_matches, mcts::SearchParams::default()),
+                evaluator: evaluator_params(sub_matches),
+                rule: if sub_matches.is_present("SPRT") {
+                    gate::GateRule::Sprt {
+                        elo0: sub_matches.value_of_t_or_exit("ELO0"),
+                        elo1: sub_matches.value_of_t_or_exit("ELO1"),
+                        alpha: sub_matches.value_of_t_or_exit("ALPHA"),
+                        beta: sub_matches.value_of_t_or_exit("BETA"),
+                    }
+                } else {
+                    gate::GateRule::Threshold(sub_matches.value_of_t_or_exit("THRESHOLD"))
+                },
+                bitbases: sub_matches
+                    .is_present("BITBASES")
+                    .then(|| Arc::new(bitbase::Bitbases::generate())),
+            };
+            if let Err(err) = gate::run(
+                &checkpoints(sub_matches),
+                sub_matches.value_of_t("CANDIDATE").ok(),
+                sub_matches.value_of_t("BASELINE").ok(),
+                options,
+            ) {
+                panic!("Gating failed: {}", err)
+            }
+        }
+        Some(("match", sub_matches)) => {
+            let engines = sub_matches
+                .values_of("ENGINE")
+                .unwrap()
+                .map(|engine| engine.parse().unwrap())
+                .collect();
+            let options = tournament::MatchOptions {
+                format: match sub_matches.value_of("FORMAT") {
+                    Some("gauntlet") => tournament::Format::Gauntlet,
+                    _ => tournament::Format::RoundRobin,
+                },
+                games: sub_matches.value_of_t_or_exit("GAMES"),
+                time_control: sub_matches.value_of_t_or_exit("TIME_CONTROL"),
+                time_margin: Duration::from_millis(sub_matches.value_of_t_or_exit("TIME_MARGIN")),
+                openings: sub_matches.value_of("OPENINGS").map(|path| {
+                    let order = match sub_matches.value_of("OPENINGS_ORDER") {
+                        Some("random") => openings::Order::Random,
+                        _ => openings::Order::RoundRobin,
+                    };
+                    match openings::Openings::load(path, order) {
+                        Ok(openings) => openings,
+                        Err(err) => panic!("Loading the openings failed: {}", err),
+                    }
+                }),
+                book: sub_matches.value_of("BOOK").map(load_book),
+                book_plies: sub_matches.value_of_t_or_exit("BOOK_PLIES"),
+                max_plies: sub_matches.value_of_t_or_exit("MAX_PLIES"),
+                pgn: sub_matches.value_of("PGN").unwrap().into(),
+                seed: sub_matches.value_of_t("SEED").ok(),
+            };
+            if let Err(err) = tournament::run(engines, options) {
+                panic!("Match failed: {}", err)
+            }
+        }
+        Some(("stats", sub_matches)) => {
+            let has_games = ["WINS", "DRAWS", "LOSSES"]
+                .iter()
+                .any(|name| sub_matches.is_present(name));
+            let score = has_games.then(|| stats::Score {
+                wins: sub_matches.value_of_t("WINS").unwrap_or(0),
+                draws: sub_matches.value_of_t("DRAWS").unwrap_or(0),
+                losses: sub_matches.value_of_t("LOSSES").unwrap_or(0),
+            });
+            let pentanomial = sub_matches.value_of_t("PAIRS").ok();
+            if score.is_none() && pentanomial.is_none() {
+                panic!("Give the results with --wins, --draws and --losses or with --pairs")
+            }
+            let sprt = sub_matches.is_present("SPRT").then(|| stats::Sprt {
+                elo0: sub_matches.value_of_t_or_exit("ELO0"),
+                elo1: sub_matches.value_of_t_or_exit("ELO1"),
+                alpha: sub_matches.value_of_t_or_exit("ALPHA"),
+                beta: sub_matches.value_of_t_or_exit("BETA"),
+            });
+            stats::report(score, pentanomial, sprt);
+        }
+        Some(("book", sub_matches)) => {
+            if let Some(("build", sub_matches)) = sub_matches.subcommand() {
+                let mut records = vec![];
+                for path in sub_matches.values_of("PGN").unwrap() {
+                    let games =
+                        std::fs::read_to_string(path).and_then(|text| pgn::read_games(&text));
+                    match games {
+                        Ok(games) => records.extend(games),
+                        Err(err) => panic!("Reading {} failed: {}", path, err),
+                    }
+                }
+                let options = book::BuildOptions {
+                    max_plies: sub_matches.value_of_t_or_exit("MAX_PLIES"),
+                    min_games: sub_matches.value_of_t_or_exit("MIN_GAMES"),
+                    results: match sub_matches.value_of("RESULTS") {
+                        Some("decisive") => book::ResultFilter::Decisive,
+                        Some("winner") => book::ResultFilter::Winner,
+                        _ => book::ResultFilter::All,
+                    },
+                };
+                let book = book::build(&records, &options);
+                let output = sub_matches.value_of("OUTPUT").unwrap();
+                if let Err(err) = book.save(output) {
+                    panic!("Writing the book failed: {}", err)
+                }
+                println!(
+                    "Wrote {} entries from {} games to {}",
+                    book.len(),
+                    records.len(),
+                    output
+                );
+            }
+        }
+        Some(("convert", sub_matches)) => {
+            for run_index in sub_matches.values_of("IDX").unwrap() {
+                match convert(run_index) {
+                    Ok(count) => println!("Converted {} positions of {}", count, run_index),
+                    Err(err) => panic!("Converting {} failed: {:?}", run_index, err),
+                }
+            }
+        }
+        Some(("convert-weights", sub_matches)) => {
+            let precision = sub_matches.value_of_t_or_exit("PRECISION");
+            for (path, kind) in mlp::MlpKind::FILES {
+                if !Path::new(path).exists() {
+                    continue;
+                }
+                match mlp::convert(path, kind, precision) {
+                    Ok(converted) => println!("Converted {} to {}", path, converted.display()),
+                    Err(err) => panic!("Converting {} failed: {}", path, err),
+                }
+            }
+            let checkpoints = checkpoints(sub_matches);
+            let generations = match sub_matches.value_of_t("GENERATION") {
+                Ok(generation) => Ok(vec![generation]),
+                Err(_) => checkpoints.generations(),
+            };
+
+            for generation in generations.unwrap_or_else(|err| panic!("{}", err)) {
+                match checkpoints.convert(generation, precision) {
+                    Ok(true) => println!("Converted generation {}", generation),
+                    Ok(false) => println!("Generation {} is already converted", generation),
+                    Err(err) => panic!("Converting generation {} failed: {}", generation, err),
+                }
+            }
+        }
+        Some(("uci", sub_matches)) => {
+            let params = parallel_search_params(sub_matches, mcts::SearchParams::default());
+            let (evaluator, nnue);
+            let engine = match (
+                sub_matches.value_of("NNUE"),
+                sub_matches.value_of_t("DEPTH").ok(),
+            ) {
+                (Some(path), Some(depth)) => {
+                    nnue = load_nnue(path);
+                    uci::Engine::AlphaBeta { nnue: &nnue, depth }
+                }
+                (Some(path), None) => {
+                    evaluator = Evaluator::with_inference(
+                        Box::new(load_nnue(path)),
+                        evaluator_params(sub_matches),
+                    );
+                    uci::Engine::Mcts(&evaluator)
+                }
+                (None, _) => {
+                    evaluator =
+                        Evaluator::new(load_network(sub_matches), evaluator_params(sub_matches));
+                    uci::Engine::Mcts(&evaluator)
+                }
+            };
+            let book = sub_matches.value_of("BOOK").map(load_book);
+            let tablebases = sub_matches.value_of("SYZYGY").map(load_tablebases);
+            let bitbases = sub_matches
+                .is_present("BITBASES")
+                .then(|| Arc::new(bitbase::Bitbases::generate()));
+            if let Err(err) = uci::run(params, engine, book.as_ref(), tablebases, bitbases) {
+                panic!("Playing failed: {:?}", err)
+            }
+        }
+        Some(("bench", sub_matches)) => {
+            let params = parallel_search_params(sub_matches, mcts::SearchParams::default());
+            mcts::bench(
+                params,
+                evaluator_params(sub_matches),
+                network_config(sub_matches),
+                sub_matches.value_of_t_or_exit("PLAYOUTS"),
+            );
+        }
         _ => unreachable!(),
     };
 }
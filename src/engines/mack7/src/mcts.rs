@@ -1,62 +1,38 @@
-use ndarray::{Array1, Array2, Axis, ShapeError};
-use rand::Rng;
-use std::fs::File;
-use std::io::Write;
+use ndarray::{array, Array1, ArrayView1};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Gamma};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::{
+    adjudication::{AdjudicationOptions, Adjudicator},
+    bitbase::Bitbases,
+    bitboard::Bitboard,
+    chess_move::MoveIndex,
+    chunk::{Sample, Termination},
+    evaluator::{Evaluator, EvaluatorParams},
     game::{Game, GameResult},
-    policy_network::{self, PolicyNetwork},
-    value_network::{self, ValueNetwork},
+    metrics::SelfPlayStats,
+    network::{Network, NetworkConfig},
+    openings::{self, Openings},
+    pgn,
+    run_directory::{Manifest, RunDirectory},
+    syzygy::Tablebases,
 };
 
 impl Game {
-    pub fn from_game(game: &Game) -> Game {
-        let mut previous_positions = vec![];
-        for position in &game.previous_positions {
-            previous_positions.push(position.clone());
-        }
-        Game {
-            position: Position {
-                all: game.position.all,
-                white: Pieces {
-                    all: game.position.white.all,
-                    king: game.position.white.king,
-                    queen: game.position.white.queen,
-                    rook: game.position.white.rook,
-                    bishop: game.position.white.bishop,
-                    knight: game.position.white.knight,
-                    pawn: game.position.white.pawn,
-                },
-                black: Pieces {
-                    all: game.position.black.all,
-                    king: game.position.black.king,
-                    queen: game.position.black.queen,
-                    rook: game.position.black.rook,
-                    bishop: game.position.black.bishop,
-                    knight: game.position.black.knight,
-                    pawn: game.position.black.pawn,
-                },
-            },
-            player: game.player,
-            last_move: game.last_move,
-            possible_castles: game.possible_castles,
-            en_passant_square: game.en_passant_square,
-            previous_positions,
-            move_counter: game.move_counter,
-            fifty_move_counter: game.fifty_move_counter,
-        }
-    }
-
     pub fn get_input(&self) -> Array1<f32> {
         fn t(bitboard: Bitboard) -> f32 {
             if bitboard.is_empty() {
-                0. as f32
+                0.
             } else {
                 1.
             }
         }
         array![
-            if self.player { 1. as f32 } else { 0. as f32 },
+            if self.player { 1. } else { 0. },
             t(self.position.white.king & Bitboard::new(0x0000_0000_0000_0001)),
             t(self.position.white.king & Bitboard::new(0x0000_0000_0000_0002)),
             t(self.position.white.king & Bitboard::new(0x0000_0000_0000_0004)),
@@ -911,236 +887,715 @@ impl Game {
             t(self.en_passant_square & Bitboard::new(0x8000_0000_0000_0000)),
         ]
     }
+
+    /// Restores the position from the input of the networks. The move history
+    /// is not part of the input, so it is lost.
+    pub fn from_input(input: ArrayView1<f32>) -> Game {
+        const PIECES: [char; 12] = ['K', 'Q', 'R', 'B', 'N', 'P', 'k', 'q', 'r', 'b', 'n', 'p'];
+        const FILES: [char; 8] = ['a', 'b', 'c', 'd', 'e', 'f', 'g', 'h'];
+
+        let mut ranks: Vec<String> = vec![];
+        for rank in (0..8).rev() {
+            let mut fen_rank = String::new();
+            let mut empty = 0;
+            for file in 0..8 {
+                let square = rank * 8 + file;
+                match (0..12).find(|&piece| input[1 + piece * 64 + square] > 0.5) {
+                    Some(piece) => {
+                        if empty > 0 {
+                            fen_rank.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        fen_rank.push(PIECES[piece]);
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                fen_rank.push_str(&empty.to_string());
+            }
+            ranks.push(fen_rank);
+        }
+
+        let castles: String = ['K', 'Q', 'k', 'q']
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| input[769 + i] > 0.5)
+            .map(|(_, &c)| c)
+            .collect();
+        let en_passant = match (0..64).find(|&square| input[773 + square] > 0.5) {
+            Some(square) => format!("{}{}", FILES[square % 8], square / 8 + 1),
+            None => String::from("-"),
+        };
+
+        Game::from_fen(&format!(
+            "{} {} {} {} 0 1",
+            ranks.join("/"),
+            if input[0] > 0.5 { "w" } else { "b" },
+            if castles.is_empty() { "-" } else { &castles },
+            en_passant
+        ))
+    }
+
+    /// Returns 1 for the `MoveIndex` of every legal move and 0 otherwise.
+    pub fn legal_move_mask(&self) -> Array1<f32> {
+        let mut mask = Array1::zeros(1972);
+        for m in self.legal_moves(self.player) {
+            mask[m.index().0] = 1.;
+        }
+        mask
+    }
 }
 
+type NodeId = usize;
+
+/// The visits of every move at the root.
+type Policy = Vec<(MoveIndex, f32)>;
+
 #[derive(Debug)]
 struct Node {
-    tree_id: usize,
     state: Game,
-    input: Array1<f32>,
-    children: Vec<Node>,
+    children: Range<NodeId>,
     is_expanded: bool,
     has_checked_for_terminal: bool,
     is_terminal: bool,
     terminal_value: f32,
+    /// The value from the perspective of white that the tablebases or the
+    /// bitbases know for the position. Playouts end at such nodes like at
+    /// terminal ones, but the game goes on if it reaches them.
+    tablebase_value: Option<f32>,
     prior: f32,
     visits: f32,
     total_value: f32,
+    /// The number of playouts through this node that are not finished yet.
+    in_flight: f32,
 }
 
 impl Node {
-    fn new(tree_id: usize, state: Game, prior: f32) -> Node {
-        let input = state.get_input();
-        return Node {
-            tree_id,
+    fn new(state: Game, prior: f32) -> Node {
+        Node {
             state,
-            input,
-            children: vec![],
+            children: 0..0,
             is_expanded: false,
             has_checked_for_terminal: false,
             is_terminal: false,
             prior,
             terminal_value: 0.,
+            tablebase_value: None,
             visits: 0.,
             total_value: 0.,
-        };
+            in_flight: 0.,
+        }
+    }
+
+    /// The average value of the node from the perspective of the player who
+    /// made the move leading to it, from -1 for a loss to 1 for a win.
+    fn value(&self) -> f32 {
+        self.total_value / self.visits
+    }
+}
+
+/// Parameters for selecting children during the search.
+#[derive(Clone, Copy, Debug)]
+pub struct SearchParams {
+    /// The weight of the exploration term. When using the AlphaZero schedule,
+    /// this is the weight for a parent without visits.
+    pub c_puct: f32,
+    /// Enables the AlphaZero schedule, where the exploration weight grows by
+    /// `ln((1 + N + base) / base)` for a parent with `N` visits.
+    pub c_puct_base: Option<f32>,
+    /// Unvisited children are valued like their parent minus this reduction
+    /// (first-play urgency).
+    pub fpu_reduction: f32,
+    /// Rescales values to the range of values seen so far in the tree before
+    /// adding the exploration term.
+    pub normalize_values: bool,
+    /// The number of lost visits that each unfinished playout through a node
+    /// counts as, which keeps parallel playouts from choosing the same nodes.
+    pub virtual_loss: f32,
+    /// The number of threads searching the same trees.
+    pub threads: usize,
+    /// The number of leaves each thread collects from a tree before
+    /// evaluating them together.
+    pub batch_size: usize,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        SearchParams {
+            c_puct: 2.5,
+            c_puct_base: None,
+            fpu_reduction: 0.25,
+            normalize_values: false,
+            virtual_loss: 1.,
+            threads: 1,
+            batch_size: 1,
+        }
+    }
+}
+
+impl SearchParams {
+    fn exploration(&self, parent_visits: f32) -> f32 {
+        match self.c_puct_base {
+            Some(base) => self.c_puct + ((1. + parent_visits + base) / base).ln(),
+            None => self.c_puct,
+        }
+    }
+}
+
+fn puct_score(value: f32, prior: f32, visits: f32, parent_visits: f32, exploration: f32) -> f32 {
+    value + exploration * prior * parent_visits.sqrt() / (1. + visits)
+}
+
+/// A search tree that stores all nodes in one arena. Nodes reference their
+/// children by index, and the children of a node are always stored next to
+/// each other.
+pub struct Tree {
+    tree_id: usize,
+    nodes: Vec<Node>,
+    root: NodeId,
+    params: SearchParams,
+    min_value: f32,
+    max_value: f32,
+    tablebases: Option<Arc<Tablebases>>,
+    bitbases: Option<Arc<Bitbases>>,
+}
+
+impl Tree {
+    pub fn new(tree_id: usize, state: Game, params: SearchParams) -> Tree {
+        Tree {
+            tree_id,
+            nodes: vec![Node::new(state, 0.)],
+            root: 0,
+            params,
+            min_value: f32::MAX,
+            max_value: f32::MIN,
+            tablebases: None,
+            bitbases: None,
+        }
+    }
+
+    /// Ends playouts at positions that the tablebases know, and limits the
+    /// moves searched at the root to the ones they rank best.
+    pub fn with_tablebases(mut self, tablebases: Option<Arc<Tablebases>>) -> Tree {
+        self.tablebases = tablebases;
+        self
+    }
+
+    pub fn tablebases(&self) -> Option<&Arc<Tablebases>> {
+        self.tablebases.as_ref()
     }
 
-    fn from_node(node: &Node) -> Node {
-        let state = Game::from_game(&node.state);
-        let input = state.get_input();
-        let children = node
+    /// Ends playouts at positions that the bitbases know.
+    pub fn with_bitbases(mut self, bitbases: Option<Arc<Bitbases>>) -> Tree {
+        self.bitbases = bitbases;
+        self
+    }
+
+    pub fn bitbases(&self) -> Option<&Arc<Bitbases>> {
+        self.bitbases.as_ref()
+    }
+
+    pub fn state(&self) -> &Game {
+        &self.nodes[self.root].state
+    }
+
+    /// The visits of the children of the root.
+    fn child_visits(&self) -> Vec<f32> {
+        self.nodes[self.root]
             .children
-            .iter()
-            .map(|child| Node::from_node(child))
-            .collect();
-        return Node {
-            tree_id: node.tree_id,
-            state,
-            input,
-            children,
-            is_expanded: node.is_expanded,
-            has_checked_for_terminal: node.has_checked_for_terminal,
-            is_terminal: node.is_terminal,
-            terminal_value: node.terminal_value,
-            prior: node.prior,
-            visits: node.visits,
-            total_value: node.total_value,
-        };
+            .clone()
+            .map(|child| self.nodes[child].visits)
+            .collect()
     }
 
-    fn ucb_score(&self, parent_visits: f32) -> f32 {
-        let mut value = 0.;
-        if self.visits > 0. {
-            value = if self.state.player {
-                1. - self.total_value / self.visits
-            } else {
-                self.total_value / self.visits
-            };
+    /// The average value of the root from the perspective of white.
+    pub fn root_value(&self) -> f32 {
+        let root = &self.nodes[self.root];
+        if root.visits == 0. {
+            0.
+        } else if root.state.player {
+            -root.value()
+        } else {
+            root.value()
         }
-        value + 5. * self.prior * parent_visits.sqrt() / (self.visits + 1e-6)
     }
 
-    fn choose_child(&mut self) -> (&mut Node, usize) {
-        let mut result: Option<&mut Node> = None;
-        let mut index = 0;
+    pub fn visits(&self) -> f32 {
+        self.nodes[self.root].visits
+    }
+
+    /// The number of moves from the root to the deepest visited node.
+    pub fn depth(&self) -> u32 {
+        let mut depth = 0;
+        let mut stack = vec![(self.root, 0)];
+        while let Some((id, node_depth)) = stack.pop() {
+            depth = depth.max(node_depth);
+            for child in self.nodes[id].children.clone() {
+                if self.nodes[child].visits > 0. {
+                    stack.push((child, node_depth + 1));
+                }
+            }
+        }
+        depth
+    }
+
+    pub fn params(&self) -> SearchParams {
+        self.params
+    }
+
+    fn normalize(&self, value: f32) -> f32 {
+        if self.params.normalize_values && self.max_value > self.min_value {
+            (value - self.min_value) / (self.max_value - self.min_value)
+        } else {
+            value
+        }
+    }
+
+    fn choose_child(&self, id: NodeId) -> NodeId {
+        let node = &self.nodes[id];
+        let parent_visits = node.visits + node.in_flight * self.params.virtual_loss;
+        let exploration = self.params.exploration(parent_visits);
+        // The value of the parent is stored from the opponent's perspective
+        let parent_value = if node.visits > 0. { -node.value() } else { 0. };
+        let fpu_value = parent_value - self.params.fpu_reduction;
+
+        let mut result = node.children.start;
         let mut max = f32::MIN;
 
-        for (i, child) in self.children.iter_mut().enumerate() {
-            let score = child.ucb_score(self.visits);
+        for child_id in node.children.clone() {
+            let child = &self.nodes[child_id];
+            // Unfinished playouts count as lost visits
+            let virtual_loss = child.in_flight * self.params.virtual_loss;
+            let visits = child.visits + virtual_loss;
+            let value = if visits > 0. {
+                (child.total_value - virtual_loss) / visits
+            } else {
+                fpu_value
+            };
+            let score = puct_score(
+                self.normalize(value),
+                child.prior,
+                visits,
+                parent_visits,
+                exploration,
+            );
             if score > max {
                 max = score;
+                result = child_id;
+            }
+        }
+        result
+    }
+
+    /// Returns the most visited child of the root, unless the game is over
+    /// at the root.
+    fn most_visited_child(&self) -> Option<NodeId> {
+        let root = &self.nodes[self.root];
+        let mut result: Option<NodeId> = None;
+
+        for child in root.children.clone() {
+            if result.is_none_or(|best| self.nodes[child].visits > self.nodes[best].visits) {
                 result = Some(child);
-                index = i;
             }
         }
-        (result.unwrap(), index)
+        result
     }
-}
 
-fn expand(
-    nodes: &mut Vec<&mut Node>,
-    force: bool,
-    policy_nn: &PolicyNetwork,
-) -> Result<(), ShapeError> {
-    let mut inputs = Array2::<f32>::zeros((0, 837));
-    for node in nodes.iter() {
-        inputs.push(Axis(0), node.input.view())?;
+    /// Follows the best children from the root to a node that is not expanded
+    /// yet, and marks the playout as unfinished for all nodes on the way.
+    fn select_leaf(&mut self) -> Vec<NodeId> {
+        let mut search_path = vec![self.root];
+        let mut id = self.root;
+        self.nodes[id].in_flight += 1.;
+        while self.nodes[id].is_expanded {
+            id = self.choose_child(id);
+            self.nodes[id].in_flight += 1.;
+            search_path.push(id);
+        }
+        search_path
     }
 
-    let all_priors_diff = policy_nn.forward(neuronika::from_ndarray(inputs));
-    all_priors_diff.forward();
-    let all_priors = all_priors_diff.data();
+    fn cancel_playout(&mut self, search_path: &[NodeId]) {
+        for &id in search_path {
+            self.nodes[id].in_flight -= 1.;
+        }
+    }
 
-    for (i, node) in nodes.iter_mut().enumerate() {
-        // If already expanded, then do nothing
-        if node.is_expanded {
-            continue;
+    /// Adds the result of a playout to all nodes on the search path. The value
+    /// is from the perspective of white.
+    fn backpropagate(&mut self, search_path: &[NodeId], value: f32) {
+        for &id in search_path {
+            let node = &mut self.nodes[id];
+            node.total_value += if node.state.player { -value } else { value };
+            node.visits += 1.;
+            node.in_flight -= 1.;
+
+            let node_value = node.value();
+            self.min_value = self.min_value.min(node_value);
+            self.max_value = self.max_value.max(node_value);
         }
+    }
 
-        // If already not visited or terminal, then we don't expand further,
-        // except we force an expansion
-        if (node.visits == 0. || node.is_terminal) && !force {
-            continue;
+    /// Adds children for all legal moves, using the output of the policy
+    /// network for their priors. Does nothing if the node has already been
+    /// expanded or if there are no legal moves.
+    fn expand(&mut self, id: NodeId, all_priors: ArrayView1<f32>) {
+        let node = &self.nodes[id];
+        if node.is_expanded {
+            return;
         }
 
-        let moves = node.state.legal_moves();
+        let state = &node.state;
+        let moves = state.legal_moves(state.player);
+        if moves.is_empty() {
+            // The node will be evaluated as terminal instead
+            return;
+        }
         let num_moves = moves.len() as f32;
 
         let mut legal_priors: Vec<f32> = vec![];
         let mut legal_priors_sum = 0.;
         for m in moves.iter() {
-            let v = all_priors[[i, m.index().0]];
+            let v = all_priors[m.index().0];
             legal_priors.push(v);
             legal_priors_sum += v;
         }
 
-        for (j, m) in moves.iter().enumerate() {
-            let normalized_prior = if legal_priors_sum > 0. {
-                legal_priors[j] / legal_priors_sum
-            } else {
-                1. / num_moves
-            };
+        let children = moves
+            .iter()
+            .enumerate()
+            .map(|(j, m)| {
+                let normalized_prior = if legal_priors_sum > 0. {
+                    legal_priors[j] / legal_priors_sum
+                } else {
+                    1. / num_moves
+                };
+                Node::new(state.make_move(m, true), normalized_prior)
+            })
+            .collect();
 
-            node.children.push(Node::new(
-                node.tree_id,
-                node.state.make_move(m, true),
-                normalized_prior,
-            ))
+        self.add_children(id, children);
+    }
+
+    /// Mixes Dirichlet noise into the priors of the children of the root, so
+    /// that self-play also explores moves that the policy network dislikes.
+    fn add_dirichlet_noise(&mut self, alpha: f32, epsilon: f32, rng: &mut StdRng) {
+        let children = self.nodes[self.root].children.clone();
+        if children.len() < 2 || epsilon == 0. {
+            return;
         }
 
-        node.is_expanded = true
+        let gamma = Gamma::new(alpha, 1.).unwrap();
+        let noise: Vec<f32> = children.clone().map(|_| gamma.sample(rng)).collect();
+        let noise_sum: f32 = noise.iter().sum();
+        if noise_sum == 0. {
+            return;
+        }
+
+        for (child, n) in children.zip(noise) {
+            let node = &mut self.nodes[child];
+            node.prior = (1. - epsilon) * node.prior + epsilon * n / noise_sum;
+        }
     }
 
-    Ok(())
-}
+    fn add_children(&mut self, id: NodeId, children: Vec<Node>) {
+        let start = self.nodes.len();
+        self.nodes.extend(children);
+        self.nodes[id].children = start..self.nodes.len();
+        self.nodes[id].is_expanded = true;
+    }
 
-fn iteration(
-    nodes: &mut Vec<Node>,
-    value_nn: &ValueNetwork,
-    policy_nn: &PolicyNetwork,
-) -> Result<(), ShapeError> {
-    let mut search_paths: Vec<Vec<usize>> = vec![];
-    let mut leaf_nodes: Vec<&mut Node> = vec![];
-
-    // Find child to explore
-    for node in nodes.iter_mut() {
-        let mut n = node;
-        let mut search_path = vec![];
-        while n.is_expanded {
-            let (child, index) = n.choose_child();
-            n = child;
-            search_path.push(index);
+    /// Marks the node as terminal if the game is over, or looks it up in the
+    /// tablebases and bitbases, and returns its value in either case.
+    fn check_terminal(&mut self, id: NodeId) -> Option<f32> {
+        let node = &mut self.nodes[id];
+        if !node.has_checked_for_terminal {
+            node.has_checked_for_terminal = true;
+            if let Some(result) = node.state.result() {
+                node.is_terminal = true;
+                node.terminal_value = match result {
+                    GameResult::White => 1.,
+                    GameResult::Black => -1.,
+                    _ => 0.,
+                };
+            } else {
+                let result = tablebase_result(self.tablebases.as_deref(), &node.state)
+                    .or_else(|| bitbase_result(self.bitbases.as_deref(), &node.state));
+                node.tablebase_value = result.map(f32::from);
+            }
+        }
+
+        if node.is_terminal {
+            Some(node.terminal_value)
+        } else {
+            node.tablebase_value
         }
-        search_paths.push(search_path);
-        leaf_nodes.push(n);
     }
 
-    // Try to expand notes where possible and choose one of the children
-    expand(&mut leaf_nodes, false, policy_nn)?;
+    /// Keeps only the children of the root with the moves that the
+    /// tablebases rank best, so that won endgames make progress towards
+    /// mate and lost ones are defended for as long as possible.
+    fn filter_root_moves(&mut self) {
+        let best_moves = match self
+            .tablebases
+            .as_deref()
+            .and_then(|tablebases| tablebases.root_moves(self.state()))
+        {
+            Some(best_moves) => best_moves,
+            None => return,
+        };
+        let best_moves: Vec<MoveIndex> = best_moves.iter().map(|m| m.index()).collect();
+        let children = self.nodes[self.root].children.clone();
+        let kept: Vec<NodeId> = children
+            .clone()
+            .filter(|&child| best_moves.contains(&self.nodes[child].state.last_move.unwrap()))
+            .collect();
+        if kept.is_empty() || kept.len() == children.len() {
+            return;
+        }
 
-    let mut evaluation_nodes: Vec<&mut Node> = vec![];
-    for (i, node) in leaf_nodes.iter_mut().enumerate() {
-        if node.is_expanded {
-            let (child, index) = node.choose_child();
-            search_paths[i].push(index);
-            evaluation_nodes.push(child);
-        } else {
-            evaluation_nodes.push(node);
+        // The kept children move to the end of the arena, so that they stay
+        // next to each other, and their priors are normalized again
+        let prior_sum: f32 = kept.iter().map(|&child| self.nodes[child].prior).sum();
+        let start = self.nodes.len();
+        for &child in &kept {
+            let placeholder = Node::new(self.nodes[child].state.clone(), 0.);
+            let mut node = std::mem::replace(&mut self.nodes[child], placeholder);
+            node.prior = if prior_sum > 0. {
+                node.prior / prior_sum
+            } else {
+                1. / kept.len() as f32
+            };
+            self.nodes.push(node);
         }
+        let root = self.root;
+        self.nodes[root].children = start..self.nodes.len();
     }
 
-    let mut inputs = Array2::<f32>::zeros((0, 837));
-    for node in evaluation_nodes.iter() {
-        inputs.push(Axis(0), node.input.view())?;
+    /// Makes the position after the given move the new root. This works for
+    /// moves of both players, so the subtree that was searched for the
+    /// opponent's reply is kept as well, including its visit statistics.
+    pub fn advance(&mut self, m: MoveIndex) {
+        let root = &self.nodes[self.root];
+        let child = root
+            .children
+            .clone()
+            .find(|&child| self.nodes[child].state.last_move == Some(m));
+
+        match child {
+            Some(child) => self.retain_subtree(child),
+            None => {
+                let state = self.state();
+                let legal_move = state
+                    .legal_moves(state.player)
+                    .into_iter()
+                    .find(|legal_move| legal_move.index() == m)
+                    .expect("Move is not legal in the current position");
+                let next = state.make_move(&legal_move, true);
+                self.nodes = vec![Node::new(next, 0.)];
+                self.root = 0;
+                self.min_value = f32::MAX;
+                self.max_value = f32::MIN;
+            }
+        }
     }
 
-    let values_diff = value_nn.forward(neuronika::from_ndarray(inputs));
-    values_diff.forward();
-    let predicted_values = values_diff.data();
+    /// Moves all nodes reachable from the given node into a new arena and
+    /// drops the rest. Nodes are ordered breadth-first so siblings stay next
+    /// to each other.
+    fn retain_subtree(&mut self, root: NodeId) {
+        let mut new_ids: Vec<Option<NodeId>> = vec![None; self.nodes.len()];
+        let mut order = vec![root];
+        new_ids[root] = Some(0);
 
-    let mut values: Vec<f32> = vec![];
-    for (i, node) in evaluation_nodes.iter_mut().enumerate() {
-        if node.is_terminal {
-            values.push(node.terminal_value);
-        } else if !node.has_checked_for_terminal {
-            node.has_checked_for_terminal = true;
-            let game = &mut node.state;
-            match game.result() {
-                Some(result) => {
-                    node.is_terminal = true;
-                    node.terminal_value = match result {
-                        // TODO: Is it right to use 1&0, or should it be 1&-1 ???
-                        GameResult::White => 1.,
-                        GameResult::Black => 0.,
-                        _ => 0.5,
-                    };
-                    values.push(node.terminal_value);
+        let mut i = 0;
+        while i < order.len() {
+            for child in self.nodes[order[i]].children.clone() {
+                new_ids[child] = Some(order.len());
+                order.push(child);
+            }
+            i += 1;
+        }
+
+        let mut slots: Vec<Option<Node>> = (0..order.len()).map(|_| None).collect();
+        for (id, node) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            if let Some(new_id) = new_ids[id] {
+                slots[new_id] = Some(node);
+            }
+        }
+
+        self.nodes = slots
+            .into_iter()
+            .map(|slot| {
+                let mut node = slot.unwrap();
+                if !node.children.is_empty() {
+                    let start = new_ids[node.children.start].unwrap();
+                    node.children = start..start + node.children.len();
                 }
-                None => values.push(predicted_values[[i, 0]]),
+                node
+            })
+            .collect();
+        self.root = 0;
+    }
+}
+
+/// Expands the roots of all trees, whether they have been visited or not.
+fn expand_roots(trees: &mut [Tree], evaluator: &Evaluator) {
+    let expanding: Vec<usize> = (0..trees.len())
+        .filter(|&i| !trees[i].nodes[trees[i].root].is_expanded)
+        .collect();
+    let games: Vec<&Game> = expanding.iter().map(|&i| trees[i].state()).collect();
+    let evaluations = evaluator.evaluate(&games);
+
+    for (i, evaluation) in expanding.into_iter().zip(evaluations) {
+        let root = trees[i].root;
+        trees[i].expand(root, evaluation.priors.view());
+    }
+}
+
+/// Returns the result of the game from the perspective of white if the
+/// tablebases know the position, where wins and losses that the fifty move
+/// rule spoils count as draws.
+fn tablebase_result(tablebases: Option<&Tablebases>, game: &Game) -> Option<i8> {
+    let score = tablebases?.probe_wdl(game)?.score();
+    Some(if game.player { score } else { -score })
+}
+
+/// Returns the result of the game from the perspective of white if the
+/// bitbases know the position.
+fn bitbase_result(bitbases: Option<&Bitbases>, game: &Game) -> Option<i8> {
+    let score = bitbases?.probe(game)?;
+    Some(if game.player { score } else { -score })
+}
+
+/// Runs a batch of playouts of the given size on each tree. The trees are
+/// only locked while choosing and updating nodes, so several threads can
+/// search the same trees while waiting for their positions to be evaluated.
+/// Returns how many playouts each tree got, which is fewer than its batch
+/// size when two playouts reach the same leaf.
+fn iteration(
+    trees: &[Mutex<&mut Tree>],
+    batch_sizes: &[usize],
+    evaluator: &Evaluator,
+) -> Vec<usize> {
+    // Find leaves to explore, virtual loss makes the playouts of a batch
+    // choose different ones
+    let mut search_paths: Vec<Vec<Vec<NodeId>>> = vec![];
+    let mut values: Vec<Vec<Option<f32>>> = vec![];
+    let mut games: Vec<Game> = vec![];
+    for (tree, &batch_size) in trees.iter().zip(batch_sizes) {
+        let mut tree = tree.lock().unwrap();
+        let mut tree_search_paths: Vec<Vec<NodeId>> = vec![];
+        let mut tree_values: Vec<Option<f32>> = vec![];
+        for _ in 0..batch_size {
+            let search_path = tree.select_leaf();
+            let leaf = *search_path.last().unwrap();
+            let value = tree.check_terminal(leaf);
+
+            let is_collision = value.is_none()
+                && tree_search_paths
+                    .iter()
+                    .any(|other| *other.last().unwrap() == leaf);
+            if is_collision {
+                // There are no more new leaves worth exploring right now
+                tree.cancel_playout(&search_path);
+                break;
             }
-        } else {
-            values.push(predicted_values[[i, 0]])
+
+            if value.is_none() {
+                games.push(tree.nodes[leaf].state.clone());
+            }
+            tree_search_paths.push(search_path);
+            tree_values.push(value);
         }
+        search_paths.push(tree_search_paths);
+        values.push(tree_values);
     }
 
-    // Backpropagate
-    for (i, path) in search_paths.iter().enumerate() {
-        let mut node = nodes.get_mut(i).unwrap();
-        node.total_value += values[i];
-        node.visits += 1.;
-        for j in path {
-            node = node.children.get_mut(*j).unwrap();
-            node.total_value += values[i];
-            node.visits += 1.;
+    let playouts = search_paths.iter().map(Vec::len).collect();
+    let evaluations = evaluator.evaluate(&games.iter().collect::<Vec<_>>());
+    let mut evaluations = evaluations.into_iter();
+
+    // Expand the leaves and backpropagate
+    for ((tree, tree_search_paths), tree_values) in trees.iter().zip(search_paths).zip(values) {
+        let mut tree = tree.lock().unwrap();
+        for (search_path, value) in tree_search_paths.iter().zip(tree_values) {
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    let evaluation = evaluations.next().unwrap();
+                    tree.expand(*search_path.last().unwrap(), evaluation.priors.view());
+                    evaluation.value
+                }
+            };
+            tree.backpropagate(search_path, value);
         }
     }
+    playouts
+}
 
-    Ok(())
+/// Runs playouts on all trees in parallel until each tree got the given number
+/// of playouts or the time is up.
+fn run_playouts(
+    trees: &mut [&mut Tree],
+    playouts: u32,
+    movetime: Option<Duration>,
+    evaluator: &Evaluator,
+) {
+    let now = Instant::now();
+    let params = match trees.first() {
+        Some(tree) => tree.params,
+        None => return,
+    };
+    let trees: Vec<Mutex<&mut Tree>> = trees
+        .iter_mut()
+        .map(|tree| Mutex::new(&mut **tree))
+        .collect();
+    let started: Vec<AtomicU32> = trees.iter().map(|_| AtomicU32::new(0)).collect();
+
+    std::thread::scope(|scope| {
+        for _ in 0..params.threads {
+            scope.spawn(|| loop {
+                if movetime.is_some_and(|movetime| now.elapsed() >= movetime) {
+                    return;
+                }
+
+                // Claim a batch of the playouts that each tree has left
+                let claimed: Vec<u32> = started
+                    .iter()
+                    .map(|started| {
+                        let claim = |started: u32| {
+                            playouts
+                                .saturating_sub(started)
+                                .min(params.batch_size as u32)
+                        };
+                        let before = started
+                            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |started| {
+                                Some(started + claim(started))
+                            })
+                            .unwrap();
+                        claim(before)
+                    })
+                    .collect();
+                if claimed.iter().all(|&claimed| claimed == 0) {
+                    return;
+                }
+
+                let batch_sizes: Vec<usize> =
+                    claimed.iter().map(|&claimed| claimed as usize).collect();
+                let ran = iteration(&trees, &batch_sizes, evaluator);
+                // Give back the playouts that collisions cut short, so that
+                // this or another thread runs them later
+                for ((started, claimed), ran) in started.iter().zip(claimed).zip(ran) {
+                    started.fetch_sub(claimed - ran as u32, Ordering::Relaxed);
+                }
+            });
+        }
+    });
 }
 
-fn choose_random(cdf: Vec<f32>) -> usize {
-    let mut rng = rand::thread_rng();
+fn choose_random(cdf: Vec<f32>, rng: &mut StdRng) -> usize {
     let sum = cdf.iter().sum::<f32>();
     let random = rng.gen_range(0.0..sum);
 
@@ -1155,145 +1610,884 @@ fn choose_random(cdf: Vec<f32>) -> usize {
     unreachable!()
 }
 
-const RUNS: u32 = 1600;
-
-fn find_best_moves<'a>(
-    nodes: &'a mut Vec<Node>,
-    greedy: bool,
-    value_nn: &'a ValueNetwork,
-    policy_nn: &'a PolicyNetwork,
-) -> Result<(Vec<&'a Node>, Array2<f32>), ShapeError> {
-    for _ in 0..RUNS {
-        iteration(nodes, value_nn, policy_nn)?;
+/// Picks a child index with probability proportional to `visits^(1/τ)`. A
+/// temperature of zero, or children without visits, always pick the most
+/// visited child. There is none to pick without children.
+fn choose_with_temperature(visits: Vec<f32>, temperature: f32, rng: &mut StdRng) -> Option<usize> {
+    if visits.is_empty() {
+        return None;
     }
-
-    let mut new_nodes: Vec<&Node> = vec![];
-    let mut policies = Array2::<f32>::zeros((0, 1972));
-    for node in nodes.iter() {
-        let mut policy = Array1::<f32>::zeros(1972);
-
-        let mut best: Option<&Node> = None;
-        let mut cdf: Vec<f32> = vec![];
-
-        for child in &node.children {
-            // println!(
-            //     "{}\t{}\t{}\t{}",
-            //     child.state.last_move.as_ref().unwrap(),
-            //     child.prior,
-            //     child.visits,
-            //     child.total_value
-            // );
-            policy[child.state.last_move.as_ref().unwrap().0] = child.visits;
-            cdf.push(child.visits);
-            if best.is_none() || child.visits > best.unwrap().visits {
-                best = Some(child);
+    if temperature == 0. || visits.iter().all(|&v| v == 0.) {
+        let mut best = 0;
+        for (i, v) in visits.iter().enumerate() {
+            if *v > visits[best] {
+                best = i;
             }
         }
+        return Some(best);
+    }
 
-        if greedy {
-            new_nodes.push(best.unwrap());
-        } else {
-            new_nodes.push(&node.children[choose_random(cdf)]);
-        }
+    Some(choose_random(
+        visits.iter().map(|v| v.powf(1. / temperature)).collect(),
+        rng,
+    ))
+}
 
-        let policy_sum = policy.sum();
-        policies.push(Axis(0), (policy / policy_sum).view())?;
+/// Returns how much the visit distribution at the root changed from `old` to
+/// `new`, as their Kullback-Leibler divergence per added playout.
+fn kld_gain(old: &[f32], new: &[f32]) -> f32 {
+    let old_total: f32 = old.iter().sum();
+    let new_total: f32 = new.iter().sum();
+    if old_total == 0. || new_total <= old_total {
+        return f32::INFINITY;
     }
-    Ok((new_nodes, policies))
+
+    let mut kld = 0.;
+    for (&old, &new) in old.iter().zip(new) {
+        if new == 0. {
+            continue;
+        }
+        if old == 0. {
+            return f32::INFINITY;
+        }
+        let (p, q) = (new / new_total, old / old_total);
+        kld += p * (p / q).ln();
+    }
+    kld / (new_total - old_total)
 }
 
-fn save(
-    run_index: String,
-    input_strings: Vec<Vec<String>>,
-    terminal_values: Vec<f32>,
-    policy_strings: Vec<Vec<String>>,
-) -> std::io::Result<()> {
-    let mut value_network_data_file = File::create(format!("value.{}.csv", run_index))?;
-    let mut policy_network_data_file = File::create(format!("policy.{}.csv", run_index))?;
-    for (i, strings) in input_strings.iter().enumerate() {
-        for (j, string) in strings.iter().enumerate() {
-            value_network_data_file
-                .write(format!("{},{}\n", string, terminal_values[i]).as_bytes())?;
-            policy_network_data_file
-                .write(format!("{},{}\n", string, policy_strings[i][j]).as_bytes())?;
+/// Runs playouts on all trees in parallel until each tree got its own budget
+/// of playouts. With early stopping, a tree stops before that once its visit
+/// distribution at the root has converged.
+fn run_budgets(
+    trees: &mut [Tree],
+    budgets: &[u32],
+    early_stop: Option<EarlyStop>,
+    evaluator: &Evaluator,
+) {
+    let mut spent = vec![0; trees.len()];
+    let mut converged = vec![false; trees.len()];
+    loop {
+        let active: Vec<usize> = (0..trees.len())
+            .filter(|&i| !converged[i] && spent[i] < budgets[i])
+            .collect();
+        // The trees search together until the smallest remaining budget or
+        // the next check for convergence
+        let mut playouts = match active.iter().map(|&i| budgets[i] - spent[i]).min() {
+            Some(playouts) => playouts,
+            None => return,
+        };
+        if let Some(early_stop) = early_stop {
+            playouts = playouts.min(early_stop.interval);
+        }
+
+        let before: Vec<Vec<f32>> = active.iter().map(|&i| trees[i].child_visits()).collect();
+        let mut active_trees: Vec<&mut Tree> = trees
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| active.contains(i))
+            .map(|(_, tree)| tree)
+            .collect();
+        run_playouts(&mut active_trees, playouts, None, evaluator);
+
+        for (&i, before) in active.iter().zip(before) {
+            spent[i] += playouts;
+            if let Some(early_stop) = early_stop {
+                converged[i] =
+                    kld_gain(&before, &trees[i].child_visits()) < early_stop.min_kld_gain;
+            }
         }
     }
-    Ok(())
 }
 
-pub fn run(run_index: String, parallel_games: usize) -> Result<(), ShapeError> {
-    let value_nn = match value_network::load() {
-        Ok(nn) => nn,
-        Err(err) => panic!("Error loading the value network: {:?}", err),
+pub const RUNS: u32 = 1600;
+
+/// Playout cap randomization as in KataGo: most moves get a fast search that
+/// only serves to play the game, and only the moves with a full search are
+/// policy targets.
+#[derive(Clone, Copy, Debug)]
+pub struct PlayoutCap {
+    /// Number of playouts of a fast search.
+    pub fast_playouts: u32,
+    /// Probability of a move to get a full search.
+    pub full_probability: f32,
+}
+
+/// Stops searching a move once more playouts hardly change the visits.
+#[derive(Clone, Copy, Debug)]
+pub struct EarlyStop {
+    /// The search stops once the Kullback-Leibler divergence between the
+    /// visits before and after `interval` playouts, per playout, is below
+    /// this.
+    pub min_kld_gain: f32,
+    pub interval: u32,
+}
+
+/// Settings for generating training data through self-play.
+pub struct SelfPlayOptions {
+    /// Parameters for selecting children during the search.
+    pub search: SearchParams,
+    /// Number of playouts of a full search.
+    pub playouts: u32,
+    /// Searches most moves with fewer playouts, full searches for all moves
+    /// if not given.
+    pub playout_cap: Option<PlayoutCap>,
+    pub early_stop: Option<EarlyStop>,
+    /// Concentration of the Dirichlet noise added to the root priors.
+    pub dirichlet_alpha: f32,
+    /// Weight of the Dirichlet noise, zero disables it.
+    pub dirichlet_epsilon: f32,
+    /// Temperature for choosing moves during the first plies of a game.
+    pub temperature: f32,
+    /// Number of plies that use `temperature`, later moves are greedy.
+    pub temperature_plies: u32,
+    /// Starting positions for the games, the standard one if not given.
+    pub openings: Option<Openings>,
+    /// Number of uniformly random moves played before the search takes over.
+    pub random_plies: u32,
+    /// When to resign or adjudicate games before they end by the rules.
+    pub adjudication: AdjudicationOptions,
+    /// Seed for all random choices, uses entropy if not given.
+    pub seed: Option<u64>,
+    pub evaluator: EvaluatorParams,
+    /// How often to report the statistics of the run.
+    pub stats_interval: Duration,
+    /// Tablebases that end playouts in the positions they know, and games
+    /// that reach such a position with its result.
+    pub tablebases: Option<Arc<Tablebases>>,
+    /// Bitbases that end playouts in the positions they know.
+    pub bitbases: Option<Arc<Bitbases>>,
+}
+
+/// Draws the position a game starts from and plays the random plies.
+fn start_position(id: u32, options: &SelfPlayOptions, rng: &mut StdRng) -> Game {
+    let game = match options.openings.as_ref() {
+        Some(openings) => Game::from_fen(openings.pick(id, rng)),
+        None => Game::from_fen(pgn::START_POSITION),
     };
-    let policy_nn = match policy_network::load() {
-        Ok(nn) => nn,
-        Err(err) => panic!("Error loading the policy network: {:?}", err),
+    openings::random_plies(game, options.random_plies, rng)
+}
+
+/// A self-play game in progress.
+struct SelfPlayGame {
+    id: u32,
+    start_fen: String,
+    samples: Vec<Sample>,
+    adjudicator: Adjudicator,
+}
+
+/// Starts the next game of the run, searched by a tree with the given id.
+fn start_game(
+    tree_id: usize,
+    manifest: &mut Manifest,
+    options: &SelfPlayOptions,
+    rng: &mut StdRng,
+) -> (SelfPlayGame, Tree) {
+    let id = manifest.next_game;
+    manifest.next_game += 1;
+    let game = start_position(id, options, rng);
+    let self_play_game = SelfPlayGame {
+        id,
+        start_fen: game.to_fen(),
+        samples: vec![],
+        adjudicator: Adjudicator::new(options.adjudication, rng),
+    };
+    let tree = Tree::new(tree_id, game, options.search)
+        .with_tablebases(options.tablebases.clone())
+        .with_bitbases(options.bitbases.clone());
+    (self_play_game, tree)
+}
+
+/// Searches every tree with its budget of playouts, and returns the chosen
+/// move of every tree together with the visits of all moves at the root.
+/// Trees whose game is over at the root have no move.
+fn find_best_moves(
+    trees: &mut [Tree],
+    budgets: &[u32],
+    early_stop: Option<EarlyStop>,
+    temperatures: &[f32],
+    rng: &mut StdRng,
+    evaluator: &Evaluator,
+) -> (Vec<Option<MoveIndex>>, Vec<Policy>) {
+    run_budgets(trees, budgets, early_stop, evaluator);
+
+    let mut best_moves: Vec<Option<MoveIndex>> = vec![];
+    let mut policies: Vec<Policy> = vec![];
+    for (tree, &temperature) in trees.iter().zip(temperatures) {
+        let root = &tree.nodes[tree.root];
+        let policy: Policy = root
+            .children
+            .clone()
+            .map(|child| {
+                let child = &tree.nodes[child];
+                (child.state.last_move.unwrap(), child.visits)
+            })
+            .collect();
+
+        best_moves.push(choose_move(tree, temperature, rng));
+        policies.push(policy);
+    }
+    (best_moves, policies)
+}
+
+/// Chooses a move at the root of a searched tree with a probability
+/// proportional to its visits raised to `1 / temperature`, or the most
+/// visited one for a temperature of 0. There is no move if the game is over
+/// at the root.
+pub fn choose_move(tree: &Tree, temperature: f32, rng: &mut StdRng) -> Option<MoveIndex> {
+    let root = &tree.nodes[tree.root];
+    let visits = root
+        .children
+        .clone()
+        .map(|child| tree.nodes[child].visits)
+        .collect();
+    let best = root.children.start + choose_with_temperature(visits, temperature, rng)?;
+    tree.nodes[best].state.last_move
+}
+
+/// Searches the position at the root of the tree until either the number of
+/// playouts or the time limit is reached, and returns the most visited move.
+/// There is no move if the game is already over at the root.
+pub fn search(
+    tree: &mut Tree,
+    playouts: u32,
+    movetime: Option<Duration>,
+    evaluator: &Evaluator,
+) -> Option<MoveIndex> {
+    expand_roots(std::slice::from_mut(tree), evaluator);
+    tree.filter_root_moves();
+    run_playouts(&mut [&mut *tree], playouts, movetime, evaluator);
+
+    let best = tree.most_visited_child()?;
+    tree.nodes[best].state.last_move
+}
+
+/// Measures how many playouts per second the search manages from the starting
+/// position with the given parameters.
+pub fn bench(
+    params: SearchParams,
+    evaluator_params: EvaluatorParams,
+    network_config: NetworkConfig,
+    playouts: u32,
+) {
+    let game = Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+
+    println!(
+        "Threads: {}, batch size: {}, virtual loss: {}",
+        params.threads, params.batch_size, params.virtual_loss
+    );
+
+    let runs = 3;
+    let mut total_pps = 0.;
+    for _ in 0..runs {
+        // A fresh evaluator for every run, so that its cache does not help
+        let evaluator = Evaluator::new(Network::new(network_config), evaluator_params);
+        let mut tree = Tree::new(0, game.clone(), params);
+        let now = Instant::now();
+        search(&mut tree, playouts, None, &evaluator);
+        let seconds = now.elapsed().as_secs_f64();
+        let pps = tree.visits() as f64 / seconds;
+        total_pps += pps;
+        println!("Playouts: {}", tree.visits());
+        println!("Seconds: {}", seconds);
+        println!("Playouts per second: {}", pps);
+        println!();
+    }
+
+    println!(
+        "Average playouts per second: {}",
+        (total_pps / runs as f64) as u64
+    );
+}
+
+/// Plays the games of a self-play run, `parallel_games` at a time, and
+/// writes each game as soon as it ends. Once `stop` is set, the games in
+/// progress are dropped after the current move and the run can be resumed
+/// later. Returns the manifest of the run.
+pub fn run(
+    directory: &RunDirectory,
+    mut manifest: Manifest,
+    parallel_games: usize,
+    network: Network,
+    options: SelfPlayOptions,
+    stop: &AtomicBool,
+) -> std::io::Result<Manifest> {
+    let evaluator = Evaluator::new(network, options.evaluator);
+    let mut rng = match options.seed {
+        // A resumed run must not repeat the random choices of its first games
+        Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(manifest.next_game as u64)),
+        None => StdRng::from_entropy(),
     };
 
-    value_nn.eval();
-    policy_nn.eval();
-
-    let mut input_strings: Vec<Vec<String>> = vec![];
-    let mut policy_strings: Vec<Vec<String>> = vec![];
-    let mut terminal_values: Vec<f32> = vec![];
-    let mut logs: Vec<String> = vec![];
-    let mut roots: Vec<Node> = vec![];
-
-    for i in 0..parallel_games {
-        input_strings.push(vec![]);
-        policy_strings.push(vec![]);
-        terminal_values.push(-999.);
-        logs.push(String::from(""));
-        roots.push(Node::new(
-            i,
+    let mut remaining = manifest.total_games.saturating_sub(manifest.finished_games);
+    if remaining == 0 {
+        println!("All {} games of the run are finished", manifest.total_games);
+    }
+    manifest.interrupted = false;
+
+    let mut games: Vec<SelfPlayGame> = vec![];
+    let mut trees: Vec<Tree> = vec![];
+    for i in 0..parallel_games.min(remaining as usize) {
+        let (game, tree) = start_game(i, &mut manifest, &options, &mut rng);
+        games.push(game);
+        trees.push(tree);
+        remaining -= 1;
+    }
+
+    let mut stats = SelfPlayStats::new(options.stats_interval);
+    while !trees.is_empty() {
+        expand_roots(&mut trees, &evaluator);
+        // Only full searches get noise, fast ones are just meant to play well
+        let full: Vec<bool> = trees
+            .iter()
+            .map(|_| {
+                options
+                    .playout_cap
+                    .is_none_or(|cap| rng.gen::<f32>() < cap.full_probability)
+            })
+            .collect();
+        for (tree, &full) in trees.iter_mut().zip(full.iter()) {
+            if full {
+                tree.add_dirichlet_noise(
+                    options.dirichlet_alpha,
+                    options.dirichlet_epsilon,
+                    &mut rng,
+                );
+            }
+        }
+        let budgets: Vec<u32> = full
+            .iter()
+            .map(|&full| match options.playout_cap {
+                Some(cap) if !full => cap.fast_playouts,
+                _ => options.playouts,
+            })
+            .collect();
+        let temperatures: Vec<f32> = trees
+            .iter()
+            .map(|tree| {
+                if (games[tree.tree_id].samples.len() as u32) < options.temperature_plies {
+                    options.temperature
+                } else {
+                    0.
+                }
+            })
+            .collect();
+
+        let visits: Vec<f32> = trees.iter().map(Tree::visits).collect();
+        let (best_moves, policies) = find_best_moves(
+            &mut trees,
+            &budgets,
+            options.early_stop,
+            &temperatures,
+            &mut rng,
+            &evaluator,
+        );
+
+        for (tree, visits) in trees.iter().zip(visits) {
+            stats.record_search(tree.visits() - visits, tree.depth());
+        }
+        for ((tree, policy), full) in trees.iter().zip(policies).zip(full) {
+            let game = &mut games[tree.tree_id];
+            game.samples.push(Sample {
+                fen: tree.state().to_fen(),
+                start_fen: game.start_fen.clone(),
+                result: 0,
+                termination: Termination::Rules,
+                game: game.id,
+                ply: game.samples.len() as u16,
+                policy,
+                has_policy_target: full,
+                has_value_target: true,
+            });
+        }
+
+        let mut trees_to_continue: Vec<Tree> = vec![];
+        for (mut tree, best_move) in trees.into_iter().zip(best_moves) {
+            let id = tree.tree_id;
+            let ending = match games[id].adjudicator.update(tree.root_value()) {
+                Some(ending) => Some(ending),
+                None => {
+                    // Without a move, the game was already over at the root
+                    if let Some(best_move) = best_move {
+                        tree.advance(best_move);
+                    }
+                    let root = tree.root;
+                    tree.check_terminal(root);
+                    let root = &tree.nodes[root];
+                    if root.is_terminal {
+                        Some((root.terminal_value.round() as i8, Termination::Rules))
+                    } else {
+                        tablebase_result(options.tablebases.as_deref(), tree.state())
+                            .map(|result| (result, Termination::Tablebase))
+                    }
+                }
+            };
+
+            let (result, termination) = match ending {
+                Some(ending) => ending,
+                None => {
+                    trees_to_continue.push(tree);
+                    continue;
+                }
+            };
+
+            let game = &mut games[id];
+            for sample in game.samples.iter_mut() {
+                sample.result = result;
+                sample.termination = termination;
+                // A game cut off by its length has no real result
+                sample.has_value_target = termination != Termination::MaxPlies;
+            }
+            directory.write_game(game.id, &game.samples)?;
+            manifest.finished_games += 1;
+            manifest.positions += game.samples.len() as u64;
+            directory.write_manifest(&manifest)?;
+
+            let rules_result = match termination {
+                Termination::Rules => tree.state().clone().result(),
+                _ => None,
+            };
+            stats.record_game(game.samples.len(), termination, rules_result);
+            if game.adjudicator.would_have_resigned() {
+                stats.record_playthrough(game.adjudicator.is_false_positive(result));
+            }
+
+            if remaining > 0 && !stop.load(Ordering::Relaxed) {
+                let (game, tree) = start_game(id, &mut manifest, &options, &mut rng);
+                games[id] = game;
+                trees_to_continue.push(tree);
+                remaining -= 1;
+            }
+        }
+
+        trees = trees_to_continue;
+        if stats.is_due() || trees.is_empty() {
+            let report = stats.report(&manifest);
+            println!("{}", report);
+            directory.append_metrics(&report)?;
+        }
+
+        if stop.load(Ordering::Relaxed) {
+            manifest.interrupted = true;
+            directory.write_manifest(&manifest)?;
+            println!(
+                "Stopped after {} of {} games, continue the run with --resume",
+                manifest.finished_games, manifest.total_games
+            );
+            break;
+        }
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{NetworkConfig, ValueHead};
+
+    fn evaluator() -> Evaluator {
+        let network = Network::new(NetworkConfig {
+            blocks: 1,
+            filters: 8,
+            value_head: ValueHead::Tanh,
+        });
+        Evaluator::new(network, EvaluatorParams::default())
+    }
+
+    #[test]
+    fn advance_keeps_subtree() {
+        let evaluator = evaluator();
+        let mut tree = Tree::new(
+            0,
             Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"),
-            0.,
-        ))
+            SearchParams::default(),
+        );
+
+        let best_move = search(&mut tree, 40, None, &evaluator).unwrap();
+        let child = tree.most_visited_child().unwrap();
+        let visits = tree.nodes[child].visits;
+        let total_value = tree.nodes[child].total_value;
+        let grandchildren = tree.nodes[child].children.len();
+        let depth = tree.depth();
+        assert!(depth >= 2);
+
+        tree.advance(best_move);
+        assert!(tree.depth() < depth);
+
+        assert_eq!(tree.visits(), visits);
+        assert_eq!(tree.nodes[tree.root].total_value, total_value);
+        assert_eq!(tree.nodes[tree.root].children.len(), grandchildren);
+        assert_eq!(tree.state().last_move, Some(best_move));
+        for id in tree.nodes[tree.root].children.clone() {
+            assert_eq!(tree.nodes[id].state.player, !tree.state().player);
+        }
+
+        // Searching on continues from the existing statistics
+        search(&mut tree, 10, None, &evaluator);
+        assert_eq!(tree.visits(), visits + 10.);
     }
 
-    expand(&mut roots.iter_mut().collect(), true, &policy_nn)?;
+    #[test]
+    fn dirichlet_noise_is_reproducible() {
+        let evaluator = evaluator();
+        let game = Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let mut trees = vec![
+            Tree::new(0, game.clone(), SearchParams::default()),
+            Tree::new(1, game.clone(), SearchParams::default()),
+        ];
+        expand_roots(&mut trees, &evaluator);
+        let priors = |tree: &Tree| -> Vec<f32> {
+            tree.nodes[tree.root]
+                .children
+                .clone()
+                .map(|child| tree.nodes[child].prior)
+                .collect()
+        };
+        let original = priors(&trees[0]);
 
-    let mut counter = 0;
-    while roots.len() > 0 {
-        let (new_roots, policies) = find_best_moves(&mut roots, false, &value_nn, &policy_nn)?;
+        trees[0].add_dirichlet_noise(0.3, 0.25, &mut StdRng::seed_from_u64(42));
+        trees[1].add_dirichlet_noise(0.3, 0.25, &mut StdRng::seed_from_u64(42));
 
-        for (i, root) in new_roots.iter().enumerate() {
-            input_strings[root.tree_id].push(
-                root.input
-                    .iter()
-                    .map(|i| i.to_string())
-                    .collect::<Vec<String>>()
-                    .join(","),
+        assert_eq!(priors(&trees[0]), priors(&trees[1]));
+        assert_ne!(priors(&trees[0]), original);
+        assert!((priors(&trees[0]).iter().sum::<f32>() - 1.).abs() < 1e-4);
+
+        let mut tree = Tree::new(2, game, SearchParams::default());
+        expand_roots(std::slice::from_mut(&mut tree), &evaluator);
+        tree.add_dirichlet_noise(0.3, 0.25, &mut StdRng::seed_from_u64(43));
+        assert_ne!(priors(&tree), priors(&trees[0]));
+    }
+
+    #[test]
+    fn temperature() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            assert_eq!(
+                choose_with_temperature(vec![1., 5., 2.], 0., &mut rng),
+                Some(1)
             );
-            policy_strings[root.tree_id].push(
-                policies
-                    .row(i)
-                    .iter()
-                    .map(|i| i.to_string())
-                    .collect::<Vec<String>>()
-                    .join(","),
+        }
+
+        let mut counts = [0; 2];
+        for _ in 0..1000 {
+            counts[choose_with_temperature(vec![1., 3.], 1., &mut rng).unwrap()] += 1;
+        }
+        assert!(counts[0] > 150 && counts[0] < 350);
+
+        let mut counts = [0; 2];
+        for _ in 0..1000 {
+            counts[choose_with_temperature(vec![1., 3.], 0.25, &mut rng).unwrap()] += 1;
+        }
+        assert!(counts[0] < 50);
+
+        // A root without children has no move to choose
+        assert_eq!(choose_with_temperature(vec![], 0., &mut rng), None);
+        assert_eq!(choose_with_temperature(vec![], 1., &mut rng), None);
+        assert_eq!(choose_with_temperature(vec![0., 0.], 1., &mut rng), Some(0));
+    }
+
+    fn tree_with_children(
+        params: SearchParams,
+        parent: (f32, f32),
+        children: &[(f32, f32, f32)],
+    ) -> Tree {
+        let game = Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let mut tree = Tree::new(0, game.clone(), params);
+        tree.nodes[0].visits = parent.0;
+        tree.nodes[0].total_value = parent.1;
+        let children = children
+            .iter()
+            .map(|&(prior, visits, total_value)| {
+                let mut node = Node::new(game.clone(), prior);
+                node.visits = visits;
+                node.total_value = total_value;
+                node
+            })
+            .collect();
+        tree.add_children(0, children);
+        tree
+    }
+
+    #[test]
+    fn puct_score_formula() {
+        assert_eq!(puct_score(0.5, 0.2, 0., 16., 2.), 0.5 + 2. * 0.2 * 4.);
+        assert_eq!(puct_score(0.5, 0.2, 3., 16., 2.), 0.5 + 2. * 0.2 * 4. / 4.);
+        assert_eq!(puct_score(-0.5, 0.2, 0., 0., 2.), -0.5);
+    }
+
+    #[test]
+    fn c_puct_schedule() {
+        let params = SearchParams {
+            c_puct: 1.25,
+            c_puct_base: None,
+            ..SearchParams::default()
+        };
+        assert_eq!(params.exploration(0.), 1.25);
+        assert_eq!(params.exploration(100_000.), 1.25);
+
+        let params = SearchParams {
+            c_puct: 1.25,
+            c_puct_base: Some(19652.),
+            ..SearchParams::default()
+        };
+        assert!((params.exploration(0.) - 1.25).abs() < 1e-4);
+        assert!((params.exploration(19651.) - (1.25 + 2_f32.ln())).abs() < 1e-4);
+    }
+
+    #[test]
+    fn first_play_urgency() {
+        // The parent is valued -0.4 by the opponent, so 0.4 by the player choosing a child
+        let parent = (10., -4.);
+        let children = [(0.1, 5., 0.5), (0.1, 0., 0.)];
+
+        let params = SearchParams {
+            fpu_reduction: 0.25,
+            ..SearchParams::default()
+        };
+        let tree = tree_with_children(params, parent, &children);
+        assert_eq!(tree.choose_child(0), 2);
+
+        let params = SearchParams {
+            fpu_reduction: 1.,
+            ..SearchParams::default()
+        };
+        let tree = tree_with_children(params, parent, &children);
+        assert_eq!(tree.choose_child(0), 1);
+    }
+
+    #[test]
+    fn value_normalization() {
+        let params = SearchParams {
+            c_puct: 1.,
+            normalize_values: true,
+            ..SearchParams::default()
+        };
+        let mut tree = tree_with_children(params, (20., 0.), &[(0.9, 10., -1.), (0.1, 10., 1.)]);
+        tree.min_value = -0.1;
+        tree.max_value = 0.1;
+        assert_eq!(tree.normalize(-0.1), 0.);
+        assert_eq!(tree.normalize(0.1), 1.);
+        // The difference in value outweighs the difference in priors once normalized
+        assert_eq!(tree.choose_child(0), 2);
+
+        tree.params.normalize_values = false;
+        assert_eq!(tree.normalize(0.1), 0.1);
+        assert_eq!(tree.choose_child(0), 1);
+    }
+
+    #[test]
+    fn virtual_loss() {
+        let mut tree = tree_with_children(
+            SearchParams::default(),
+            (20., 0.),
+            &[(0.6, 10., 0.), (0.4, 10., 0.)],
+        );
+        assert_eq!(tree.choose_child(0), 1);
+
+        // Unfinished playouts make the other child more attractive
+        tree.nodes[0].in_flight = 3.;
+        tree.nodes[1].in_flight = 3.;
+        assert_eq!(tree.choose_child(0), 2);
+
+        tree.params.virtual_loss = 0.;
+        assert_eq!(tree.choose_child(0), 1);
+    }
+
+    #[test]
+    fn parallel_search() {
+        let evaluator = evaluator();
+        for (threads, batch_size) in [(4, 1), (2, 4)] {
+            let params = SearchParams {
+                threads,
+                batch_size,
+                ..SearchParams::default()
+            };
+            let mut tree = Tree::new(
+                0,
+                Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"),
+                params,
             );
+            search(&mut tree, 40, None, &evaluator);
+
+            // Playouts that collide within a batch are run later
+            assert_eq!(tree.visits(), 40.);
+            let root = &tree.nodes[tree.root];
+            let child_visits: f32 = root.children.clone().map(|id| tree.nodes[id].visits).sum();
+            assert_eq!(child_visits, tree.visits());
+            assert!(tree.nodes.iter().all(|node| node.in_flight == 0.));
         }
+    }
 
-        let mut roots_to_continue: Vec<Node> = vec![];
-        for root in new_roots {
-            if root.is_terminal {
-                terminal_values[root.tree_id] = root.terminal_value;
-                logs[root.tree_id] = root.terminal_value.to_string();
-            } else {
-                roots_to_continue.push(Node::from_node(root));
-                logs[root.tree_id] = format!("{}", root.state.last_move.as_ref().unwrap());
-            }
+    #[test]
+    fn kld_gain_per_playout() {
+        // Twice the visits in the same proportions do not change anything
+        assert_eq!(kld_gain(&[10., 30.], &[20., 60.]), 0.);
+        let gain = kld_gain(&[10., 10.], &[30., 10.]);
+        let expected = (0.75 * 1.5f32.ln() + 0.25 * 0.5f32.ln()) / 20.;
+        assert!((gain - expected).abs() < 1e-6);
+        // A move that gets its first visits, or no visits at all, is a change
+        assert_eq!(kld_gain(&[10., 0.], &[10., 5.]), f32::INFINITY);
+        assert_eq!(kld_gain(&[0., 0.], &[3., 2.]), f32::INFINITY);
+    }
+
+    #[test]
+    fn searches_with_budgets() {
+        let evaluator = evaluator();
+        let new_trees = || -> Vec<Tree> {
+            (0..2)
+                .map(|i| {
+                    Tree::new(
+                        i,
+                        Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"),
+                        SearchParams::default(),
+                    )
+                })
+                .collect()
+        };
+
+        let mut trees = new_trees();
+        expand_roots(&mut trees, &evaluator);
+        run_budgets(&mut trees, &[10, 60], None, &evaluator);
+        assert_eq!(trees[0].visits(), 10.);
+        assert_eq!(trees[1].visits(), 60.);
+
+        // Every check needs at least as many playouts as there are moves, so
+        // that all of them have visits, before the search can stop
+        let mut trees = new_trees();
+        expand_roots(&mut trees, &evaluator);
+        let early_stop = EarlyStop {
+            min_kld_gain: 1.,
+            interval: 30,
+        };
+        run_budgets(&mut trees, &[200, 200], Some(early_stop), &evaluator);
+        for tree in trees.iter() {
+            assert!(tree.visits() >= 60. && tree.visits() < 200.);
+        }
+    }
+
+    #[test]
+    fn finds_mate_in_one() {
+        let evaluator = evaluator();
+        for fen in [
+            "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1",
+            "3r2k1/5ppp/8/8/8/8/5PPP/6K1 b - - 0 1",
+        ] {
+            let mut tree = Tree::new(0, Game::from_fen(fen), SearchParams::default());
+            let best_move = search(&mut tree, 300, None, &evaluator).unwrap();
+            assert_eq!(
+                best_move.to_string(),
+                if fen.contains(" w ") { "d1d8" } else { "d8d1" }
+            );
         }
+    }
+
+    #[test]
+    fn finds_no_move_when_the_game_is_over() {
+        let evaluator = evaluator();
+        let mated = Game::from_fen("3R2k1/5ppp/8/8/8/8/5PPP/6K1 b - - 1 1");
+        let mut tree = Tree::new(0, mated, SearchParams::default());
+        assert!(search(&mut tree, 10, None, &evaluator).is_none());
+    }
+
+    #[test]
+    fn input_round_trip() {
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w Kq f6 0 3",
+            "6k1/5ppp/8/8/8/8/5PPP/3R2K1 b - - 0 1",
+        ] {
+            let game = Game::from_fen(fen);
+            let restored = Game::from_input(game.get_input().view());
+            assert_eq!(restored.get_input(), game.get_input());
+            assert_eq!(restored.legal_move_mask(), game.legal_move_mask());
+        }
+    }
+
+    #[test]
+    fn advance_to_unexplored_move() {
+        let mut tree = Tree::new(
+            0,
+            Game::from_fen("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"),
+            SearchParams::default(),
+        );
+        let state = tree.state();
+        let m = state.legal_moves(state.player)[0].index();
+
+        tree.advance(m);
 
-        roots = roots_to_continue;
-        counter += 1;
-        println!("{}\t{}", counter, logs.join("\t"));
+        assert_eq!(tree.nodes.len(), 1);
+        assert_eq!(tree.visits(), 0.);
+        assert_eq!(tree.state().last_move, Some(m));
+        assert!(!tree.state().player);
     }
 
-    match save(run_index, input_strings, terminal_values, policy_strings) {
-        Ok(_) => Ok(()),
-        Err(_) => panic!("Error saving training data"),
+    #[test]
+    fn resumes_self_play_runs() {
+        let options = || SelfPlayOptions {
+            search: SearchParams::default(),
+            playouts: 2,
+            playout_cap: None,
+            early_stop: None,
+            dirichlet_alpha: 0.3,
+            dirichlet_epsilon: 0.25,
+            temperature: 1.,
+            temperature_plies: 2,
+            openings: None,
+            random_plies: 0,
+            adjudication: AdjudicationOptions {
+                max_plies: Some(4),
+                ..AdjudicationOptions::default()
+            },
+            seed: Some(0),
+            evaluator: EvaluatorParams::default(),
+            stats_interval: Duration::from_secs(3600),
+            tablebases: None,
+            bitbases: None,
+        };
+        let network = || {
+            Network::new(NetworkConfig {
+                blocks: 1,
+                filters: 8,
+                value_head: ValueHead::Tanh,
+            })
+        };
+        let path = std::env::temp_dir().join(format!("mack7-self-play-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let stop = AtomicBool::new(false);
+
+        let (directory, mut manifest) = RunDirectory::open(&path, false).unwrap();
+        manifest.total_games = 3;
+        let manifest = run(&directory, manifest, 2, network(), options(), &stop).unwrap();
+        assert_eq!(manifest.finished_games, 3);
+        assert_eq!(manifest.positions, 12);
+        assert!(!manifest.interrupted);
+        assert_eq!(directory.games().unwrap().len(), 3);
+        assert!(RunDirectory::open(&path, false).is_err());
+
+        let (directory, mut manifest) = RunDirectory::open(&path, true).unwrap();
+        assert_eq!(manifest.finished_games, 3);
+        manifest.total_games = 5;
+        let manifest = run(&directory, manifest, 2, network(), options(), &stop).unwrap();
+        assert_eq!(manifest.finished_games, 5);
+        let ids: Vec<u32> = directory
+            .games()
+            .unwrap()
+            .iter()
+            .map(|&(id, _)| id)
+            .collect();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+
+        // Both sessions report once they are done
+        let metrics = std::fs::read_to_string(path.join("metrics.jsonl")).unwrap();
+        let reports: Vec<serde_json::Value> = metrics
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1]["finished_games"], 5);
+        assert_eq!(reports[1]["games"], 2);
+        assert_eq!(reports[1]["endings"]["max_plies"], 2);
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
 }
@@ -0,0 +1,258 @@
+//! Statistics of a self-play run, reported periodically on the console and as
+//! JSON lines in the metrics file of the run, so that runs can be plotted.
+
+use std::time::{Duration, Instant};
+
+use crate::{chunk::Termination, game::GameResult, run_directory::Manifest};
+
+/// How the finished games ended.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize)]
+pub struct Endings {
+    pub white_wins: u32,
+    pub black_wins: u32,
+    pub stalemates: u32,
+    pub dead_positions: u32,
+    pub repetitions: u32,
+    pub fifty_move_rule: u32,
+    pub resignations: u32,
+    pub draw_adjudications: u32,
+    pub max_plies: u32,
+    pub tablebase: u32,
+}
+
+/// The statistics at one point of a run, one line of the metrics file.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct Report {
+    pub elapsed_seconds: f64,
+    /// The finished games of the whole run, including those of earlier
+    /// sessions of a resumed run.
+    pub finished_games: u32,
+    pub total_games: u32,
+    pub positions: u64,
+    /// The games finished since the run was started or resumed, which all
+    /// other statistics are about.
+    pub games: u32,
+    pub average_length: f32,
+    pub endings: Endings,
+    /// The playouts per second since the previous report.
+    pub nodes_per_second: f32,
+    /// The average depth of the deepest node of the search trees after each
+    /// move.
+    pub average_depth: f32,
+    /// The games that were played through although a player would have
+    /// resigned, and how many of them the player would have lost wrongly.
+    pub resign_playthroughs: u32,
+    pub resign_false_positives: u32,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let endings = &self.endings;
+        write!(
+            f,
+            "[{:.0}s] games {}/{}, positions {}, length {:.1}, nodes/s {:.0}, depth {:.1}",
+            self.elapsed_seconds,
+            self.finished_games,
+            self.total_games,
+            self.positions,
+            self.average_length,
+            self.nodes_per_second,
+            self.average_depth,
+        )?;
+        write!(
+            f,
+            " | white {}, black {}, stalemate {}, dead {}, repetition {}, fifty moves {}",
+            endings.white_wins,
+            endings.black_wins,
+            endings.stalemates,
+            endings.dead_positions,
+            endings.repetitions,
+            endings.fifty_move_rule,
+        )?;
+        write!(
+            f,
+            " | resigned {}, adjudicated {}, max plies {}, tablebase {}",
+            endings.resignations, endings.draw_adjudications, endings.max_plies, endings.tablebase,
+        )?;
+        if self.resign_playthroughs > 0 {
+            write!(
+                f,
+                ", resign false positives {}/{}",
+                self.resign_false_positives, self.resign_playthroughs
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the statistics of a self-play run as it goes.
+pub struct SelfPlayStats {
+    interval: Duration,
+    start: Instant,
+    games: u32,
+    plies: u64,
+    endings: Endings,
+    searches: u64,
+    depths: u64,
+    resign_playthroughs: u32,
+    resign_false_positives: u32,
+    last_report: Instant,
+    nodes_since_report: f64,
+}
+
+impl SelfPlayStats {
+    /// Creates the statistics for a run that reports every `interval`.
+    pub fn new(interval: Duration) -> SelfPlayStats {
+        let now = Instant::now();
+        SelfPlayStats {
+            interval,
+            start: now,
+            games: 0,
+            plies: 0,
+            endings: Endings::default(),
+            searches: 0,
+            depths: 0,
+            resign_playthroughs: 0,
+            resign_false_positives: 0,
+            last_report: now,
+            nodes_since_report: 0.,
+        }
+    }
+
+    /// Records the search of one move that added `nodes` playouts to a tree
+    /// of the given depth.
+    pub fn record_search(&mut self, nodes: f32, depth: u32) {
+        self.searches += 1;
+        self.depths += depth as u64;
+        self.nodes_since_report += nodes as f64;
+    }
+
+    /// Records a finished game. The result by the rules must be given for
+    /// games that ended by the rules, and only for them.
+    pub fn record_game(
+        &mut self,
+        plies: usize,
+        termination: Termination,
+        result: Option<GameResult>,
+    ) {
+        self.games += 1;
+        self.plies += plies as u64;
+        let endings = &mut self.endings;
+        let count = match (termination, result) {
+            (Termination::Resignation, _) => &mut endings.resignations,
+            (Termination::DrawAdjudication, _) => &mut endings.draw_adjudications,
+            (Termination::MaxPlies, _) => &mut endings.max_plies,
+            (Termination::Tablebase, _) => &mut endings.tablebase,
+            (Termination::Rules, Some(GameResult::White)) => &mut endings.white_wins,
+            (Termination::Rules, Some(GameResult::Black)) => &mut endings.black_wins,
+            (Termination::Rules, Some(GameResult::Stalemate)) => &mut endings.stalemates,
+            (Termination::Rules, Some(GameResult::DeadPosition)) => &mut endings.dead_positions,
+            (Termination::Rules, Some(GameResult::Repitition)) => &mut endings.repetitions,
+            (Termination::Rules, Some(GameResult::FiftyMoveRule)) => &mut endings.fifty_move_rule,
+            (Termination::Rules, None) => {
+                unreachable!("A game that ended by the rules has a result")
+            }
+        };
+        *count += 1;
+    }
+
+    /// Records a game that was played through instead of resigned.
+    pub fn record_playthrough(&mut self, false_positive: bool) {
+        self.resign_playthroughs += 1;
+        if false_positive {
+            self.resign_false_positives += 1;
+        }
+    }
+
+    /// Returns whether the next report is due.
+    pub fn is_due(&self) -> bool {
+        self.last_report.elapsed() >= self.interval
+    }
+
+    /// Returns the statistics so far, and starts the interval of the next
+    /// report.
+    pub fn report(&mut self, manifest: &Manifest) -> Report {
+        let now = Instant::now();
+        let interval = now.duration_since(self.last_report).as_secs_f64();
+        let report = Report {
+            elapsed_seconds: now.duration_since(self.start).as_secs_f64(),
+            finished_games: manifest.finished_games,
+            total_games: manifest.total_games,
+            positions: manifest.positions,
+            games: self.games,
+            average_length: average(self.plies, self.games as u64),
+            endings: self.endings,
+            nodes_per_second: if interval > 0. {
+                (self.nodes_since_report / interval) as f32
+            } else {
+                0.
+            },
+            average_depth: average(self.depths, self.searches),
+            resign_playthroughs: self.resign_playthroughs,
+            resign_false_positives: self.resign_false_positives,
+        };
+        self.last_report = now;
+        self.nodes_since_report = 0.;
+        report
+    }
+}
+
+fn average(sum: u64, count: u64) -> f32 {
+    if count == 0 {
+        0.
+    } else {
+        sum as f32 / count as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_statistics() {
+        let mut stats = SelfPlayStats::new(Duration::from_secs(3600));
+        assert!(!stats.is_due());
+
+        stats.record_search(100., 4);
+        stats.record_search(50., 7);
+        stats.record_game(40, Termination::Rules, Some(GameResult::Black));
+        stats.record_game(61, Termination::Rules, Some(GameResult::Repitition));
+        stats.record_game(30, Termination::Resignation, None);
+        stats.record_playthrough(true);
+        stats.record_playthrough(false);
+
+        let manifest = Manifest {
+            total_games: 10,
+            finished_games: 5,
+            next_game: 7,
+            positions: 400,
+            interrupted: false,
+        };
+        let report = stats.report(&manifest);
+        assert_eq!(report.finished_games, 5);
+        assert_eq!(report.games, 3);
+        assert_eq!(report.average_length, 131. / 3.);
+        assert_eq!(report.average_depth, 5.5);
+        assert_eq!(
+            report.endings,
+            Endings {
+                black_wins: 1,
+                repetitions: 1,
+                resignations: 1,
+                ..Endings::default()
+            }
+        );
+        assert_eq!(
+            (report.resign_playthroughs, report.resign_false_positives),
+            (2, 1)
+        );
+
+        // Nodes per second only cover the time since the last report
+        assert_eq!(stats.report(&manifest).nodes_per_second, 0.);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["endings"]["black_wins"], 1);
+        assert_eq!(json["total_games"], 10);
+    }
+}
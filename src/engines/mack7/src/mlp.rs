@@ -0,0 +1,248 @@
+//! The separate policy and value MLPs that came before the residual network.
+//! Their weights were saved by neuronika as `nn-policy.json` and
+//! `nn-value.json`, and are migrated to the binary weight format with the
+//! magic `MK7M`, whose only architecture field is a u8, 0 for the policy and
+//! 1 for the value network. The residual network has no layers they could
+//! be loaded into, so they are kept as the MLPs they are.
+
+use ndarray::{Array1, Array2, ArrayViewD, ArrayViewMutD};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    nn::Linear,
+    weights::{self, Precision},
+};
+
+const MAGIC: &[u8; 4] = b"MK7M";
+const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MlpKind {
+    /// 837→1121→1405→1689→1972, one output for every `MoveIndex`.
+    Policy,
+    /// 837→628→419→210→1.
+    Value,
+}
+
+impl MlpKind {
+    /// The JSON files of the two networks, in the directory they were run
+    /// from.
+    pub const FILES: [(&'static str, MlpKind); 2] = [
+        ("nn-policy.json", MlpKind::Policy),
+        ("nn-value.json", MlpKind::Value),
+    ];
+
+    fn sizes(self) -> [usize; 5] {
+        match self {
+            MlpKind::Policy => [837, 1121, 1405, 1689, 1972],
+            MlpKind::Value => [837, 628, 419, 210, 1],
+        }
+    }
+}
+
+/// A layer as neuronika saved it, whose weight has one row per output.
+#[derive(serde::Deserialize)]
+struct JsonLinear {
+    weight: Array2<f32>,
+    bias: Array1<f32>,
+}
+
+#[derive(serde::Deserialize)]
+struct JsonMlp {
+    lin1: JsonLinear,
+    lin2: JsonLinear,
+    lin3: JsonLinear,
+    lin4: JsonLinear,
+}
+
+/// Four linear layers, which had ReLUs between them and a sigmoid at the
+/// end.
+pub struct Mlp {
+    kind: MlpKind,
+    layers: Vec<Linear>,
+}
+
+impl Mlp {
+    pub fn new(kind: MlpKind) -> Self {
+        let sizes = kind.sizes();
+        Self {
+            kind,
+            layers: sizes
+                .windows(2)
+                .map(|sizes| Linear::new(sizes[0], sizes[1]))
+                .collect(),
+        }
+    }
+
+    pub fn kind(&self) -> MlpKind {
+        self.kind
+    }
+
+    /// Reads the weights that neuronika saved, which need to have the sizes
+    /// of the given network.
+    pub fn from_json(kind: MlpKind, json: &str) -> std::io::Result<Mlp> {
+        let JsonMlp {
+            lin1,
+            lin2,
+            lin3,
+            lin4,
+        } = serde_json::from_str(json).map_err(|err| {
+            Error::new(ErrorKind::InvalidData, format!("Invalid network: {}", err))
+        })?;
+
+        let mut mlp = Mlp::new(kind);
+        for (layer, json) in mlp.layers.iter_mut().zip([lin1, lin2, lin3, lin4]) {
+            let [mut weight, mut bias] = layer.parameters_mut();
+            if json.weight.t().shape() != weight.shape() || json.bias.shape() != bias.shape() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("The layers do not have the sizes of the {:?} network", kind),
+                ));
+            }
+            weight.assign(&json.weight.t().into_dyn());
+            bias.assign(&json.bias.into_dyn());
+        }
+        Ok(mlp)
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, f32>> {
+        self.layers.iter().flat_map(Linear::parameters).collect()
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, f32>> {
+        self.layers
+            .iter_mut()
+            .flat_map(Linear::parameters_mut)
+            .collect()
+    }
+
+    pub fn to_bytes(&self, precision: Precision) -> Vec<u8> {
+        let mut bytes = weights::header(MAGIC, VERSION, precision);
+        bytes.push(match self.kind {
+            MlpKind::Policy => 0,
+            MlpKind::Value => 1,
+        });
+        weights::push_tensors(&mut bytes, &self.parameters(), precision);
+        weights::push_checksum(&mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Mlp> {
+        let (mut bytes, precision) = weights::open(bytes, MAGIC, VERSION)?;
+        let kind = match bytes.u8()? {
+            0 => MlpKind::Policy,
+            1 => MlpKind::Value,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unknown kind of network",
+                ))
+            }
+        };
+
+        let mut mlp = Mlp::new(kind);
+        weights::read_tensors(&mut bytes, mlp.parameters_mut(), precision)?;
+        bytes.finish()?;
+        Ok(mlp)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Mlp> {
+        Mlp::from_bytes(&weights::read(path)?)
+    }
+}
+
+/// Converts the JSON weights of the network to a `.bin` file next to them,
+/// and removes the JSON file once the binary one reads back the same. Returns
+/// the path of the binary file.
+pub fn convert<P: AsRef<Path>>(
+    json: P,
+    kind: MlpKind,
+    precision: Precision,
+) -> std::io::Result<PathBuf> {
+    let json = json.as_ref();
+    let mlp = Mlp::from_json(kind, &fs::read_to_string(json)?).map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!("{} is invalid: {}", json.display(), err),
+        )
+    })?;
+
+    let path = json.with_extension("bin");
+    weights::write(&path, &mlp.to_bytes(precision))?;
+    let converted = Mlp::load(&path)?;
+    if converted.kind() != kind || converted.to_bytes(precision) != mlp.to_bytes(precision) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} does not read back correctly", path.display()),
+        ));
+    }
+    fs::remove_file(json)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{Array, Ix2};
+    use serde_json::json;
+
+    /// The value of the weight from the input to the output, or of the bias
+    /// of the output if there is no input.
+    fn value(layer: usize, output: usize, input: Option<usize>) -> f32 {
+        (layer * 1_000_000 + output * 1000 + input.map_or(999, |input| input % 999)) as f32
+    }
+
+    /// Writes the layers of the value network as neuronika did.
+    fn value_json() -> String {
+        let sizes = MlpKind::Value.sizes();
+        let mut layers = serde_json::Map::new();
+        for (i, sizes) in sizes.windows(2).enumerate() {
+            layers.insert(
+                format!("lin{}", i + 1),
+                json!({
+                    "weight": Array::from_shape_fn((sizes[1], sizes[0]), |(output, input)| {
+                        value(i, output, Some(input))
+                    }),
+                    "bias": Array::from_shape_fn(sizes[1], |output| value(i, output, None)),
+                }),
+            );
+        }
+        serde_json::Value::Object(layers).to_string()
+    }
+
+    #[test]
+    fn migrates_json_weights() {
+        let directory = std::env::temp_dir().join(format!("mack7-mlp-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let json = directory.join("nn-value.json");
+        fs::write(&json, value_json()).unwrap();
+
+        let path = convert(&json, MlpKind::Value, Precision::F32).unwrap();
+        assert_eq!(path, directory.join("nn-value.bin"));
+        assert!(!json.exists());
+        let mlp = Mlp::load(&path).unwrap();
+        assert_eq!(mlp.kind(), MlpKind::Value);
+
+        // The weights have one row per input instead of per output
+        for (i, layer) in mlp.layers.iter().enumerate() {
+            let [weight, bias] = layer.parameters();
+            let weight = weight.into_dimensionality::<Ix2>().unwrap();
+            assert_eq!(weight.nrows(), MlpKind::Value.sizes()[i]);
+            for ((input, output), &weight) in weight.indexed_iter() {
+                assert_eq!(weight, value(i, output, Some(input)));
+            }
+            for (output, &bias) in bias.iter().enumerate() {
+                assert_eq!(bias, value(i, output, None));
+            }
+        }
+
+        // The layers of one network don't fit the other
+        fs::write(&json, value_json()).unwrap();
+        assert!(convert(&json, MlpKind::Policy, Precision::F32).is_err());
+        assert!(json.exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+use ndarray::{s, Array1, Array2, ArrayViewD, ArrayViewMutD};
+use std::str::FromStr;
+
+use crate::nn::{self, Conv, Linear, Optimizer};
+
+/// The number of 8×8 planes the network input is split into: 12 for the
+/// pieces, one for the en passant square, and one each for the player to move
+/// and the four castling rights.
+const PLANES: usize = 18;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ValueHead {
+    /// A single output between -1 and 1.
+    Tanh,
+    /// Probabilities for a win, a draw and a loss.
+    Wdl,
+}
+
+impl ValueHead {
+    /// Turns the output of the value head into values between -1 and 1.
+    pub fn values(self, output: &Array2<f32>) -> Array1<f32> {
+        match self {
+            ValueHead::Tanh => output.column(0).to_owned(),
+            ValueHead::Wdl => &output.column(0) - &output.column(2),
+        }
+    }
+}
+
+impl FromStr for ValueHead {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tanh" => Ok(ValueHead::Tanh),
+            "wdl" => Ok(ValueHead::Wdl),
+            _ => Err(format!("Unknown value head: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NetworkConfig {
+    pub blocks: usize,
+    pub filters: usize,
+    pub value_head: ValueHead,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            blocks: 4,
+            filters: 32,
+            value_head: ValueHead::Tanh,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ResidualBlock {
+    conv1: Conv,
+    conv2: Conv,
+}
+
+/// The outputs of all layers for a batch, which are needed for training.
+struct Activations {
+    planes: Array2<f32>,
+    input: Array2<f32>,
+    /// The hidden layer and the output of every residual block.
+    blocks: Vec<(Array2<f32>, Array2<f32>)>,
+    policy_conv: Array2<f32>,
+    policy: Array2<f32>,
+    value_conv: Array2<f32>,
+    value_hidden: Array2<f32>,
+    value: Array2<f32>,
+}
+
+impl Activations {
+    fn trunk(&self) -> &Array2<f32> {
+        match self.blocks.last() {
+            Some((_, output)) => output,
+            None => &self.input,
+        }
+    }
+}
+
+/// Evaluates a batch of network inputs, returning the move probabilities for
+/// every `MoveIndex` and the values like `Network::forward`.
+pub trait Inference: Send + Sync {
+    fn forward(&self, input: &Array2<f32>) -> (Array2<f32>, Array1<f32>);
+}
+
+impl Inference for Network {
+    fn forward(&self, input: &Array2<f32>) -> (Array2<f32>, Array1<f32>) {
+        Network::forward(self, input)
+    }
+}
+
+/// A residual network with a policy and a value head that share the same
+/// trunk.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Network {
+    value_head: ValueHead,
+    input: Conv,
+    blocks: Vec<ResidualBlock>,
+    policy_conv: Conv,
+    policy_lin: Linear,
+    value_conv: Conv,
+    value_lin1: Linear,
+    value_lin2: Linear,
+}
+
+/// Splits the network inputs of a batch into one row of planes per square.
+pub fn to_planes(input: &Array2<f32>) -> Array2<f32> {
+    let mut planes = Array2::zeros((input.nrows() * 64, PLANES));
+    for (i, position) in input.rows().into_iter().enumerate() {
+        for square in 0..64 {
+            let mut row = planes.row_mut(i * 64 + square);
+            for piece in 0..12 {
+                row[piece] = position[1 + piece * 64 + square];
+            }
+            row[12] = position[773 + square];
+            row[13] = position[0];
+            row.slice_mut(s![14..18])
+                .assign(&position.slice(s![769..773]));
+        }
+    }
+    planes
+}
+
+/// Turns rows per square into rows per board.
+pub fn flatten(x: Array2<f32>) -> Array2<f32> {
+    let (rows, columns) = x.dim();
+    x.into_shape((rows / 64, 64 * columns)).unwrap()
+}
+
+fn unflatten(x: Array2<f32>, channels: usize) -> Array2<f32> {
+    let (rows, columns) = x.dim();
+    x.into_shape((rows * columns / channels, channels)).unwrap()
+}
+
+impl Network {
+    /// Returns the architecture of the network.
+    pub fn config(&self) -> NetworkConfig {
+        NetworkConfig {
+            blocks: self.blocks.len(),
+            filters: self.input.filters(),
+            value_head: self.value_head,
+        }
+    }
+
+    pub fn new(config: NetworkConfig) -> Self {
+        let filters = config.filters;
+        Self {
+            value_head: config.value_head,
+            input: Conv::new(PLANES, filters, 3),
+            blocks: (0..config.blocks)
+                .map(|_| ResidualBlock {
+                    conv1: Conv::new(filters, filters, 3),
+                    conv2: Conv::new(filters, filters, 3),
+                })
+                .collect(),
+            policy_conv: Conv::new(filters, 2, 1),
+            policy_lin: Linear::new(2 * 64, 1972),
+            value_conv: Conv::new(filters, 1, 1),
+            value_lin1: Linear::new(64, 64),
+            value_lin2: Linear::new(
+                64,
+                match config.value_head {
+                    ValueHead::Tanh => 1,
+                    ValueHead::Wdl => 3,
+                },
+            ),
+        }
+    }
+
+    /// Runs the network and keeps the outputs of all layers. If a mask of legal
+    /// moves is given, the policy only covers those.
+    fn forward_all(&self, input: &Array2<f32>, legal_moves: Option<&Array2<f32>>) -> Activations {
+        let planes = to_planes(input);
+        let input = nn::relu(self.input.forward(&planes));
+
+        let mut blocks: Vec<(Array2<f32>, Array2<f32>)> = vec![];
+        for block in self.blocks.iter() {
+            let x = blocks.last().map_or(&input, |(_, output)| output);
+            let hidden = nn::relu(block.conv1.forward(x));
+            let output = nn::relu(block.conv2.forward(&hidden) + x);
+            blocks.push((hidden, output));
+        }
+        let trunk = blocks.last().map_or(&input, |(_, output)| output);
+
+        let policy_conv = nn::relu(self.policy_conv.forward(trunk));
+        let policy_logits = self.policy_lin.forward(&flatten(policy_conv.clone()));
+        let policy = match legal_moves {
+            Some(legal_moves) => nn::masked_softmax(policy_logits, legal_moves),
+            None => nn::softmax(policy_logits),
+        };
+
+        let value_conv = nn::relu(self.value_conv.forward(trunk));
+        let value_hidden = nn::relu(self.value_lin1.forward(&flatten(value_conv.clone())));
+        let value = match self.value_head {
+            ValueHead::Tanh => nn::tanh(self.value_lin2.forward(&value_hidden)),
+            ValueHead::Wdl => nn::softmax(self.value_lin2.forward(&value_hidden)),
+        };
+
+        Activations {
+            planes,
+            input,
+            blocks,
+            policy_conv,
+            policy,
+            value_conv,
+            value_hidden,
+            value,
+        }
+    }
+
+    /// Returns the move probabilities for every `MoveIndex` and the expected
+    /// result from the perspective of white between -1 and 1 for every
+    /// position in the batch.
+    pub fn forward(&self, input: &Array2<f32>) -> (Array2<f32>, Array1<f32>) {
+        let activations = self.forward_all(input, None);
+        let values = self.value_head.values(&activations.value);
+        (activations.policy, values)
+    }
+
+    /// Takes the gradients of the loss with respect to the inputs of the final
+    /// activation functions of both heads and updates all layers.
+    fn backward(
+        &mut self,
+        activations: &Activations,
+        policy_grad: Array2<f32>,
+        value_grad: Array2<f32>,
+        optimizer: &Optimizer,
+    ) {
+        let trunk = activations.trunk();
+
+        let grad = self.policy_lin.backward(
+            &flatten(activations.policy_conv.clone()),
+            &policy_grad,
+            optimizer,
+        );
+        let grad = nn::relu_backward(&activations.policy_conv, unflatten(grad, 2));
+        let policy_trunk_grad = self.policy_conv.backward(trunk, &grad, optimizer);
+
+        let grad = self
+            .value_lin2
+            .backward(&activations.value_hidden, &value_grad, optimizer);
+        let grad = nn::relu_backward(&activations.value_hidden, grad);
+        let grad =
+            self.value_lin1
+                .backward(&flatten(activations.value_conv.clone()), &grad, optimizer);
+        let grad = nn::relu_backward(&activations.value_conv, unflatten(grad, 1));
+        let value_trunk_grad = self.value_conv.backward(trunk, &grad, optimizer);
+
+        let mut grad = policy_trunk_grad + value_trunk_grad;
+        for (i, block) in self.blocks.iter_mut().enumerate().rev() {
+            let x = match i {
+                0 => &activations.input,
+                _ => &activations.blocks[i - 1].1,
+            };
+            let (hidden, output) = &activations.blocks[i];
+
+            let output_grad = nn::relu_backward(output, grad);
+            let hidden_grad = nn::relu_backward(
+                hidden,
+                block.conv2.backward(hidden, &output_grad, optimizer),
+            );
+            grad = block.conv1.backward(x, &hidden_grad, optimizer) + output_grad;
+        }
+
+        let grad = nn::relu_backward(&activations.input, grad);
+        self.input.backward(&activations.planes, &grad, optimizer);
+    }
+
+    /// Runs one training step on a batch and returns the losses of the policy
+    /// and the value head. The policy is trained with cross-entropy over the
+    /// legal moves given by the mask, and the value with the mean squared error
+    /// or, for a WDL head, cross-entropy. Value targets are the result between
+    /// 0 for a loss and 1 for a win of white. Positions whose policy target is
+    /// all zeros or whose value mask is 0 do not train that head.
+    pub fn train(
+        &mut self,
+        input: &Array2<f32>,
+        legal_moves: &Array2<f32>,
+        policy_target: &Array2<f32>,
+        value_target: &Array2<f32>,
+        value_mask: &Array2<f32>,
+        optimizer: &Optimizer,
+    ) -> (f32, f32) {
+        let activations = self.forward_all(input, Some(legal_moves));
+
+        let (policy_loss, policy_grad) =
+            nn::softmax_cross_entropy_loss(&activations.policy, policy_target);
+        let (value_loss, value_grad) = match self.value_head {
+            ValueHead::Tanh => {
+                let target = value_target.mapv(|t| 2. * t - 1.);
+                let (loss, grad) = nn::mse_loss(&activations.value, &target, value_mask);
+                (loss, nn::tanh_backward(&activations.value, grad))
+            }
+            ValueHead::Wdl => nn::softmax_cross_entropy_loss(
+                &activations.value,
+                &(wdl_target(value_target) * value_mask),
+            ),
+        };
+
+        self.backward(&activations, policy_grad, value_grad, optimizer);
+        (policy_loss, value_loss)
+    }
+
+    /// Returns the sum of all squared weights, which weight decay keeps small.
+    pub fn squared_weights(&self) -> f32 {
+        self.input.squared_weights()
+            + self
+                .blocks
+                .iter()
+                .map(|block| block.conv1.squared_weights() + block.conv2.squared_weights())
+                .sum::<f32>()
+            + self.policy_conv.squared_weights()
+            + self.policy_lin.squared_weights()
+            + self.value_conv.squared_weights()
+            + self.value_lin1.squared_weights()
+            + self.value_lin2.squared_weights()
+    }
+
+    /// Returns all weights and biases in a fixed order.
+    pub fn parameters(&self) -> Vec<ArrayViewD<'_, f32>> {
+        let mut parameters = vec![];
+        parameters.extend(self.input.parameters());
+        for block in self.blocks.iter() {
+            parameters.extend(block.conv1.parameters());
+            parameters.extend(block.conv2.parameters());
+        }
+        parameters.extend(self.policy_conv.parameters());
+        parameters.extend(self.policy_lin.parameters());
+        parameters.extend(self.value_conv.parameters());
+        parameters.extend(self.value_lin1.parameters());
+        parameters.extend(self.value_lin2.parameters());
+        parameters
+    }
+
+    /// Returns all weights and biases in the same order as `parameters`.
+    pub fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, f32>> {
+        let mut parameters = vec![];
+        parameters.extend(self.input.parameters_mut());
+        for block in self.blocks.iter_mut() {
+            parameters.extend(block.conv1.parameters_mut());
+            parameters.extend(block.conv2.parameters_mut());
+        }
+        parameters.extend(self.policy_conv.parameters_mut());
+        parameters.extend(self.policy_lin.parameters_mut());
+        parameters.extend(self.value_conv.parameters_mut());
+        parameters.extend(self.value_lin1.parameters_mut());
+        parameters.extend(self.value_lin2.parameters_mut());
+        parameters
+    }
+}
+
+/// Turns results between 0 and 1 into probabilities for a win, a draw and a
+/// loss.
+fn wdl_target(value_target: &Array2<f32>) -> Array2<f32> {
+    let mut target = Array2::zeros((value_target.nrows(), 3));
+    for (i, &t) in value_target.column(0).iter().enumerate() {
+        let win = (2. * t - 1.).max(0.);
+        let loss = (1. - 2. * t).max(0.);
+        target[[i, 0]] = win;
+        target[[i, 1]] = 1. - win - loss;
+        target[[i, 2]] = loss;
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::Game, nn::Method};
+    use ndarray::Axis;
+
+    fn inputs() -> Array2<f32> {
+        let mut inputs = Array2::<f32>::zeros((0, 837));
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1",
+        ] {
+            inputs
+                .push(Axis(0), Game::from_fen(fen).get_input().view())
+                .unwrap();
+        }
+        inputs
+    }
+
+    #[test]
+    fn planes() {
+        let planes = to_planes(&inputs());
+        assert_eq!(planes.dim(), (128, PLANES));
+        // Kings and pawns of both players in the starting position
+        assert_eq!(planes.column(0).iter().take(64).sum::<f32>(), 1.);
+        assert_eq!(planes.column(11).iter().take(64).sum::<f32>(), 8.);
+        // Castling rights are only left in the first position
+        assert_eq!(planes.column(14).iter().take(64).sum::<f32>(), 64.);
+        assert_eq!(planes.column(14).iter().skip(64).sum::<f32>(), 0.);
+    }
+
+    #[test]
+    fn forward() {
+        for value_head in [ValueHead::Tanh, ValueHead::Wdl] {
+            let network = Network::new(NetworkConfig {
+                blocks: 1,
+                filters: 4,
+                value_head,
+            });
+            let (policy, values) = network.forward(&inputs());
+
+            assert_eq!(policy.dim(), (2, 1972));
+            for row in policy.rows() {
+                assert!((row.sum() - 1.).abs() < 1e-4);
+            }
+            assert_eq!(values.len(), 2);
+            assert!(values.iter().all(|v| (-1. ..=1.).contains(v)));
+        }
+    }
+
+    #[test]
+    fn train_reduces_loss() {
+        for value_head in [ValueHead::Tanh, ValueHead::Wdl] {
+            let mut network = Network::new(NetworkConfig {
+                blocks: 1,
+                filters: 4,
+                value_head,
+            });
+            let input = inputs();
+            let mut policy_target = Array2::zeros((2, 1972));
+            policy_target[[0, 10]] = 1.;
+            policy_target[[1, 20]] = 1.;
+            let value_target = ndarray::array![[1.], [0.]];
+            let value_mask = Array2::ones((2, 1));
+
+            let legal_moves = Array2::ones((2, 1972));
+            let frozen = Optimizer::sgd(0.);
+
+            let (initial_policy_loss, initial_value_loss) = network.train(
+                &input,
+                &legal_moves,
+                &policy_target,
+                &value_target,
+                &value_mask,
+                &frozen,
+            );
+            for step in 1..=50 {
+                let optimizer = Optimizer {
+                    method: Method::Adam {
+                        beta1: 0.9,
+                        beta2: 0.999,
+                        epsilon: 1e-8,
+                    },
+                    learning_rate: 0.001,
+                    weight_decay: 1e-4,
+                    step,
+                };
+                network.train(
+                    &input,
+                    &legal_moves,
+                    &policy_target,
+                    &value_target,
+                    &value_mask,
+                    &optimizer,
+                );
+            }
+            let (policy_loss, value_loss) = network.train(
+                &input,
+                &legal_moves,
+                &policy_target,
+                &value_target,
+                &value_mask,
+                &frozen,
+            );
+
+            assert!(value_loss < initial_value_loss);
+            assert!(policy_loss < initial_policy_loss);
+        }
+    }
+
+    #[test]
+    fn masked_policy_loss() {
+        let mut network = Network::new(NetworkConfig {
+            blocks: 0,
+            filters: 4,
+            value_head: ValueHead::Tanh,
+        });
+        let input = inputs();
+        let mut legal_moves = Array2::zeros((2, 1972));
+        let mut policy_target = Array2::zeros((2, 1972));
+        for i in 0..2 {
+            legal_moves[[i, 10]] = 1.;
+            legal_moves[[i, 20]] = 1.;
+            policy_target[[i, 10]] = 0.5;
+            policy_target[[i, 20]] = 0.5;
+        }
+        let value_target = ndarray::array![[1.], [0.]];
+
+        // The loss is at least ln 2 for two equally likely moves, without the
+        // mask it would be close to ln 1972
+        let (policy_loss, _) = network.train(
+            &input,
+            &legal_moves,
+            &policy_target,
+            &value_target,
+            &Array2::ones((2, 1)),
+            &Optimizer::sgd(0.),
+        );
+        assert!(policy_loss >= 2f32.ln() - 1e-5);
+        assert!(policy_loss < 2f32.ln() + 1.);
+    }
+}
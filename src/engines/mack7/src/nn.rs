@@ -0,0 +1,548 @@
+use ndarray::{s, Array, Array1, Array2, ArrayViewD, ArrayViewMutD, Axis, Dimension, Ix1, Ix2};
+use rand::Rng;
+
+// ##################################################################
+// Optimizers
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Sgd,
+    /// Gradient descent with momentum, where the parameter is the fraction of
+    /// the previous update that is kept.
+    Momentum(f32),
+    Adam {
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+    },
+}
+
+/// The first and second moment estimates for every parameter of an array,
+/// created on the first update.
+type Moments<D> = Option<(Array<f32, D>, Array<f32, D>)>;
+
+/// How parameters are updated from their gradients in one training step.
+#[derive(Clone, Copy, Debug)]
+pub struct Optimizer {
+    pub method: Method,
+    pub learning_rate: f32,
+    /// The factor of the L2 penalty on weights, biases are not penalized.
+    pub weight_decay: f32,
+    /// The number of the training step starting at 1, which Adam uses to
+    /// correct the bias of its moment estimates.
+    pub step: i32,
+}
+
+impl Optimizer {
+    #[cfg(test)]
+    pub fn sgd(learning_rate: f32) -> Self {
+        Self {
+            method: Method::Sgd,
+            learning_rate,
+            weight_decay: 0.,
+            step: 1,
+        }
+    }
+
+    fn update<D: Dimension>(
+        &self,
+        parameter: &mut Array<f32, D>,
+        grad: Array<f32, D>,
+        moments: &mut Moments<D>,
+        weight_decay: f32,
+    ) {
+        let mut grad = grad;
+        if weight_decay > 0. {
+            grad.scaled_add(weight_decay, parameter);
+        }
+
+        let (first, second) = moments.get_or_insert_with(|| {
+            (
+                Array::zeros(parameter.raw_dim()),
+                Array::zeros(parameter.raw_dim()),
+            )
+        });
+        match self.method {
+            Method::Sgd => parameter.scaled_add(-self.learning_rate, &grad),
+            Method::Momentum(momentum) => {
+                first.zip_mut_with(&grad, |v, &g| *v = momentum * *v + g);
+                parameter.scaled_add(-self.learning_rate, first);
+            }
+            Method::Adam {
+                beta1,
+                beta2,
+                epsilon,
+            } => {
+                first.zip_mut_with(&grad, |m, &g| *m = beta1 * *m + (1. - beta1) * g);
+                second.zip_mut_with(&grad, |v, &g| *v = beta2 * *v + (1. - beta2) * g * g);
+                let first_correction = 1. - beta1.powi(self.step);
+                let second_correction = 1. - beta2.powi(self.step);
+                let learning_rate = self.learning_rate;
+                ndarray::Zip::from(parameter)
+                    .and(&*first)
+                    .and(&*second)
+                    .for_each(|p, &m, &v| {
+                        let m = m / first_correction;
+                        let v = v / second_correction;
+                        *p -= learning_rate * m / (v.sqrt() + epsilon);
+                    });
+            }
+        }
+    }
+}
+
+// ##################################################################
+// Layers
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Linear {
+    weight: Array2<f32>,
+    bias: Array1<f32>,
+    /// The moment estimates of the optimizer, which are not saved.
+    #[serde(skip)]
+    weight_moments: Moments<Ix2>,
+    #[serde(skip)]
+    bias_moments: Moments<Ix1>,
+}
+
+impl Linear {
+    pub fn outputs(&self) -> usize {
+        self.bias.len()
+    }
+
+    pub fn new(inputs: usize, outputs: usize) -> Self {
+        // Same initialization as PyTorch uses for linear layers
+        let bound = 1. / (inputs as f32).sqrt();
+        let mut rng = rand::thread_rng();
+
+        Self {
+            weight: Array2::from_shape_simple_fn((inputs, outputs), || {
+                rng.gen_range(-bound..bound)
+            }),
+            bias: Array1::from_shape_simple_fn(outputs, || rng.gen_range(-bound..bound)),
+            weight_moments: None,
+            bias_moments: None,
+        }
+    }
+
+    pub fn forward(&self, input: &Array2<f32>) -> Array2<f32> {
+        input.dot(&self.weight) + &self.bias
+    }
+
+    /// Takes the gradient of the loss with respect to the output of this layer,
+    /// updates weights and biases using the optimizer, and returns the gradient
+    /// of the loss with respect to the input of this layer.
+    pub fn backward(
+        &mut self,
+        input: &Array2<f32>,
+        output_grad: &Array2<f32>,
+        optimizer: &Optimizer,
+    ) -> Array2<f32> {
+        let input_grad = output_grad.dot(&self.weight.t());
+
+        optimizer.update(
+            &mut self.weight,
+            input.t().dot(output_grad),
+            &mut self.weight_moments,
+            optimizer.weight_decay,
+        );
+        optimizer.update(
+            &mut self.bias,
+            output_grad.sum_axis(Axis(0)),
+            &mut self.bias_moments,
+            0.,
+        );
+
+        input_grad
+    }
+
+    /// Returns the sum of all squared weights.
+    pub fn squared_weights(&self) -> f32 {
+        self.weight.mapv(|w| w * w).sum()
+    }
+
+    /// Returns the weight and the bias.
+    pub fn parameters(&self) -> [ArrayViewD<'_, f32>; 2] {
+        [self.weight.view().into_dyn(), self.bias.view().into_dyn()]
+    }
+
+    pub fn parameters_mut(&mut self) -> [ArrayViewMutD<'_, f32>; 2] {
+        [
+            self.weight.view_mut().into_dyn(),
+            self.bias.view_mut().into_dyn(),
+        ]
+    }
+}
+
+/// A convolution over 8×8 boards with zero padding. Activations have one row
+/// for every square of every board in the batch and one column per channel, so
+/// that the convolution becomes a linear layer over the neighbourhood of each
+/// square.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Conv {
+    kernel_size: usize,
+    linear: Linear,
+}
+
+impl Conv {
+    /// The number of filters, which is the number of channels of the output.
+    pub fn filters(&self) -> usize {
+        self.linear.outputs()
+    }
+
+    pub fn new(channels: usize, filters: usize, kernel_size: usize) -> Self {
+        Self {
+            kernel_size,
+            linear: Linear::new(channels * kernel_size * kernel_size, filters),
+        }
+    }
+
+    pub fn forward(&self, input: &Array2<f32>) -> Array2<f32> {
+        self.linear.forward(&im2col(input, self.kernel_size))
+    }
+
+    pub fn squared_weights(&self) -> f32 {
+        self.linear.squared_weights()
+    }
+
+    pub fn parameters(&self) -> [ArrayViewD<'_, f32>; 2] {
+        self.linear.parameters()
+    }
+
+    pub fn parameters_mut(&mut self) -> [ArrayViewMutD<'_, f32>; 2] {
+        self.linear.parameters_mut()
+    }
+
+    /// Works like `Linear::backward`.
+    pub fn backward(
+        &mut self,
+        input: &Array2<f32>,
+        output_grad: &Array2<f32>,
+        optimizer: &Optimizer,
+    ) -> Array2<f32> {
+        let columns_grad =
+            self.linear
+                .backward(&im2col(input, self.kernel_size), output_grad, optimizer);
+        col2im(&columns_grad, self.kernel_size, input.ncols())
+    }
+}
+
+/// Calls the function with the index of every square in the neighbourhood of
+/// the given one, and the position of that neighbour in the kernel.
+fn for_each_neighbour(row: usize, kernel_size: usize, mut visit: impl FnMut(usize, usize)) {
+    let board = row / 64 * 64;
+    let rank = (row % 64 / 8) as isize;
+    let file = (row % 8) as isize;
+    let reach = (kernel_size / 2) as isize;
+
+    let mut k = 0;
+    for rank_offset in -reach..=reach {
+        for file_offset in -reach..=reach {
+            let (r, f) = (rank + rank_offset, file + file_offset);
+            if (0..8).contains(&r) && (0..8).contains(&f) {
+                visit(board + (r * 8 + f) as usize, k);
+            }
+            k += 1;
+        }
+    }
+}
+
+/// Puts the channels of all squares in the neighbourhood of each square into
+/// its row.
+pub fn im2col(input: &Array2<f32>, kernel_size: usize) -> Array2<f32> {
+    if kernel_size == 1 {
+        return input.clone();
+    }
+
+    let channels = input.ncols();
+    let mut columns = Array2::zeros((input.nrows(), channels * kernel_size * kernel_size));
+    for row in 0..input.nrows() {
+        for_each_neighbour(row, kernel_size, |neighbour, k| {
+            columns
+                .slice_mut(s![row, k * channels..(k + 1) * channels])
+                .assign(&input.row(neighbour));
+        });
+    }
+    columns
+}
+
+/// The reverse of `im2col`, which sums up the gradients of every square.
+fn col2im(columns: &Array2<f32>, kernel_size: usize, channels: usize) -> Array2<f32> {
+    if kernel_size == 1 {
+        return columns.clone();
+    }
+
+    let mut output = Array2::zeros((columns.nrows(), channels));
+    for row in 0..columns.nrows() {
+        for_each_neighbour(row, kernel_size, |neighbour, k| {
+            let mut output_row = output.row_mut(neighbour);
+            output_row += &columns.slice(s![row, k * channels..(k + 1) * channels]);
+        });
+    }
+    output
+}
+
+// ##################################################################
+// Activation functions
+
+pub fn relu(x: Array2<f32>) -> Array2<f32> {
+    x.mapv_into(|v| v.max(0.))
+}
+
+pub fn relu_backward(output: &Array2<f32>, output_grad: Array2<f32>) -> Array2<f32> {
+    let mut input_grad = output_grad;
+    input_grad.zip_mut_with(output, |g, &o| {
+        if o <= 0. {
+            *g = 0.
+        }
+    });
+    input_grad
+}
+
+/// Clamps every value between 0 and 1.
+pub fn clipped_relu(x: Array2<f32>) -> Array2<f32> {
+    x.mapv_into(|v| v.clamp(0., 1.))
+}
+
+pub fn clipped_relu_backward(output: &Array2<f32>, output_grad: Array2<f32>) -> Array2<f32> {
+    let mut input_grad = output_grad;
+    input_grad.zip_mut_with(output, |g, &o| {
+        if o <= 0. || o >= 1. {
+            *g = 0.
+        }
+    });
+    input_grad
+}
+
+pub fn tanh(x: Array2<f32>) -> Array2<f32> {
+    x.mapv_into(f32::tanh)
+}
+
+pub fn tanh_backward(output: &Array2<f32>, output_grad: Array2<f32>) -> Array2<f32> {
+    let mut input_grad = output_grad;
+    input_grad.zip_mut_with(output, |g, &o| *g *= 1. - o * o);
+    input_grad
+}
+
+/// Applies the softmax function to every row.
+pub fn softmax(x: Array2<f32>) -> Array2<f32> {
+    let mut output = x;
+    for mut row in output.rows_mut() {
+        let max = row.fold(f32::NEG_INFINITY, |max, &v| max.max(v));
+        row.mapv_inplace(|v| (v - max).exp());
+        let sum = row.sum();
+        row /= sum;
+    }
+    output
+}
+
+/// Like `softmax`, but only over the entries where the mask is not 0. Rows
+/// without any of those are left unmasked.
+pub fn masked_softmax(x: Array2<f32>, mask: &Array2<f32>) -> Array2<f32> {
+    let mut x = x;
+    for (mut row, mask) in x.rows_mut().into_iter().zip(mask.rows()) {
+        if mask.iter().any(|&m| m != 0.) {
+            row.zip_mut_with(&mask, |v, &m| {
+                if m == 0. {
+                    *v = f32::NEG_INFINITY
+                }
+            });
+        }
+    }
+    softmax(x)
+}
+
+// ##################################################################
+// Loss functions
+
+/// Returns the cross-entropy between the rows of the target and the output of
+/// a softmax, averaged over the rows, together with its gradient with respect
+/// to the input of the softmax. The targets of every row have to sum up to 1,
+/// or to 0 for rows without a target, which are left out.
+pub fn softmax_cross_entropy_loss(
+    output: &Array2<f32>,
+    target: &Array2<f32>,
+) -> (f32, Array2<f32>) {
+    let mut rows = 0;
+    let mut loss = 0.;
+    let mut grad = output - target;
+    for ((o, t), mut grad) in output
+        .rows()
+        .into_iter()
+        .zip(target.rows())
+        .zip(grad.rows_mut())
+    {
+        if t.sum() == 0. {
+            grad.fill(0.);
+            continue;
+        }
+        rows += 1;
+        ndarray::Zip::from(o).and(t).for_each(|&o, &t| {
+            if t > 0. {
+                loss -= t * o.max(f32::MIN_POSITIVE).ln();
+            }
+        });
+    }
+    let n = rows.max(1) as f32;
+    (loss / n, grad / n)
+}
+
+/// Returns the mean squared error over the rows whose mask is 1, together
+/// with its gradient with respect to the output. The mask has one column, and
+/// rows whose mask is 0 have no target.
+pub fn mse_loss(
+    output: &Array2<f32>,
+    target: &Array2<f32>,
+    mask: &Array2<f32>,
+) -> (f32, Array2<f32>) {
+    let diff = (output - target) * mask;
+    let n = (mask.sum() * output.ncols() as f32).max(1.);
+    let loss = diff.mapv(|v| v * v).sum() / n;
+    (loss, diff * (2. / n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn backward_reduces_loss() {
+        let mut layer = Linear::new(3, 2);
+        let input = array![[0., 1., 0.], [1., 0., 1.]];
+        let target = array![[0.9, -0.8], [-0.6, 0.8]];
+        let mask = array![[1.], [1.]];
+
+        let (initial_loss, _) = mse_loss(&tanh(layer.forward(&input)), &target, &mask);
+        for _ in 0..100 {
+            let output = tanh(layer.forward(&input));
+            let (_, grad) = mse_loss(&output, &target, &mask);
+            layer.backward(&input, &tanh_backward(&output, grad), &Optimizer::sgd(0.5));
+        }
+        let (loss, _) = mse_loss(&tanh(layer.forward(&input)), &target, &mask);
+
+        assert!(loss < initial_loss / 2.);
+    }
+
+    #[test]
+    fn optimizers_reduce_loss() {
+        let input = array![[0., 1., 0.], [1., 0., 1.]];
+        let target = array![[0.9, -0.8], [-0.6, 0.8]];
+        let mask = array![[1.], [1.]];
+        let methods = [
+            Method::Sgd,
+            Method::Momentum(0.9),
+            Method::Adam {
+                beta1: 0.9,
+                beta2: 0.999,
+                epsilon: 1e-8,
+            },
+        ];
+
+        for method in methods {
+            let mut layer = Linear::new(3, 2);
+            let (initial_loss, _) = mse_loss(&tanh(layer.forward(&input)), &target, &mask);
+            for step in 1..=100 {
+                let optimizer = Optimizer {
+                    method,
+                    learning_rate: 0.05,
+                    weight_decay: 1e-4,
+                    step,
+                };
+                let output = tanh(layer.forward(&input));
+                let (_, grad) = mse_loss(&output, &target, &mask);
+                layer.backward(&input, &tanh_backward(&output, grad), &optimizer);
+            }
+            let (loss, _) = mse_loss(&tanh(layer.forward(&input)), &target, &mask);
+
+            assert!(loss < initial_loss / 2., "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn weight_decay_shrinks_weights() {
+        let mut layer = Linear::new(3, 2);
+        let before = layer.squared_weights();
+        let optimizer = Optimizer {
+            weight_decay: 0.5,
+            ..Optimizer::sgd(0.1)
+        };
+        layer.backward(&array![[0., 0., 0.]], &array![[0., 0.]], &optimizer);
+        assert!((layer.squared_weights() - before * 0.95 * 0.95).abs() < 1e-4);
+    }
+
+    #[test]
+    fn cross_entropy() {
+        let output = softmax(array![[0., 0.], [2., 0.]]);
+        let target = array![[1., 0.], [0.5, 0.5]];
+        let (loss, grad) = softmax_cross_entropy_loss(&output, &target);
+
+        let expected = (2f32.ln() - 0.5 * output[[1, 0]].ln() - 0.5 * output[[1, 1]].ln()) / 2.;
+        assert!((loss - expected).abs() < 1e-5);
+        assert!((grad[[0, 0]] - (0.5 - 1.) / 2.).abs() < 1e-6);
+
+        // A row without a target changes neither the loss nor the gradient
+        let output = softmax(array![[0., 0.], [2., 0.], [1., 3.]]);
+        let target = array![[1., 0.], [0.5, 0.5], [0., 0.]];
+        let (masked_loss, masked_grad) = softmax_cross_entropy_loss(&output, &target);
+        assert!((masked_loss - loss).abs() < 1e-6);
+        assert!((masked_grad[[0, 0]] - grad[[0, 0]]).abs() < 1e-6);
+        assert_eq!(masked_grad.row(2).sum(), 0.);
+    }
+
+    #[test]
+    fn mse_leaves_out_masked_rows() {
+        let output = array![[0.5], [1.], [-1.]];
+        let target = array![[0.], [0.], [1.]];
+        let (loss, grad) = mse_loss(&output, &target, &array![[1.], [0.], [1.]]);
+        assert!((loss - (0.25 + 4.) / 2.).abs() < 1e-6);
+        assert_eq!(grad, array![[0.5], [0.], [-2.]]);
+    }
+
+    #[test]
+    fn masked_softmax_ignores_illegal_moves() {
+        let output = masked_softmax(
+            array![[5., 1., 1.], [1., 2., 3.]],
+            &array![[0., 1., 1.], [0., 0., 0.]],
+        );
+        assert_eq!(output[[0, 0]], 0.);
+        assert!((output[[0, 1]] - 0.5).abs() < 1e-6);
+        assert!((output.row(1).sum() - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn col2im_is_adjoint_of_im2col() {
+        let mut rng = rand::thread_rng();
+        let x = Array2::from_shape_simple_fn((128, 2), || rng.gen_range(-1. ..1.));
+        let y = Array2::from_shape_simple_fn((128, 18), || rng.gen_range(-1. ..1.));
+
+        let lhs = (&im2col(&x, 3) * &y).sum();
+        let rhs = (&x * &col2im(&y, 3, 2)).sum();
+        assert!((lhs - rhs).abs() < 1e-3);
+    }
+
+    #[test]
+    fn conv_sees_neighbours_only() {
+        let mut conv = Conv::new(1, 1, 3);
+        conv.linear.weight.fill(1.);
+        conv.linear.bias.fill(0.);
+
+        // A single piece in the corner of the second board
+        let mut input = Array2::zeros((128, 1));
+        input[[64, 0]] = 1.;
+        let output = conv.forward(&input);
+
+        let touched: Vec<usize> = (0..128).filter(|&i| output[[i, 0]] != 0.).collect();
+        assert_eq!(touched, vec![64, 65, 72, 73]);
+    }
+
+    #[test]
+    fn softmax_rows() {
+        let output = softmax(array![[1., 2., 3.], [1000., 1000., 1000.]]);
+        for row in output.rows() {
+            assert!((row.sum() - 1.).abs() < 1e-6);
+        }
+        assert!(output[[0, 2]] > output[[0, 1]]);
+        assert!((output[[1, 0]] - 1. / 3.).abs() < 1e-6);
+    }
+}
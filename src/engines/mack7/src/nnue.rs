@@ -0,0 +1,446 @@
+//! An efficiently updatable neural network (NNUE) that only predicts the
+//! value of a position. Its first layer sees HalfKP features: for each side,
+//! the square of that side's king combined with the type, color and square of
+//! every other piece. Both sides look at the board from their own end, so
+//! they share the same weights. A move changes only a few features, so the
+//! sums of the first layer are kept in an `Accumulator` and updated from the
+//! previous position instead of being recomputed. A small dense network turns
+//! the sums of both sides into the value.
+//!
+//! The weights are saved in the format of `weights` with the magic `MK7N`,
+//! where the only architecture field is the u32 size of the accumulator.
+
+use ndarray::{s, Array1, Array2, ArrayView1, ArrayViewD, ArrayViewMutD};
+use rand::Rng;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::{
+    bitboard::Bitboard,
+    game::Game,
+    network::Inference,
+    nn::{self, Linear, Optimizer},
+    position::Position,
+    replay::Example,
+    weights::{self, Precision},
+};
+
+const MAGIC: &[u8; 4] = b"MK7N";
+const VERSION: u32 = 1;
+
+/// Own and opposing pawns, knights, bishops, rooks and queens.
+const PIECE_KINDS: usize = 10;
+/// The number of features of each side.
+const FEATURES: usize = 64 * PIECE_KINDS * 64;
+/// The number of outputs of the dense layer after the accumulator.
+const DENSE: usize = 32;
+
+const MAX_SIZE: usize = 4096;
+
+/// Turns the square around for black, so that both sides see their own
+/// pieces at the bottom.
+fn orient(square: usize, side: bool) -> usize {
+    if side {
+        square
+    } else {
+        square ^ 56
+    }
+}
+
+fn king_square(position: &Position, side: bool) -> usize {
+    if side {
+        position.white.king.square()
+    } else {
+        position.black.king.square()
+    }
+}
+
+/// Returns the bitboards of all pieces but the kings, the ones of the given
+/// side first.
+fn pieces(position: &Position, side: bool) -> [Bitboard; PIECE_KINDS] {
+    let (own, other) = if side {
+        (&position.white, &position.black)
+    } else {
+        (&position.black, &position.white)
+    };
+    [
+        own.pawn,
+        own.knight,
+        own.bishop,
+        own.rook,
+        own.queen,
+        other.pawn,
+        other.knight,
+        other.bishop,
+        other.rook,
+        other.queen,
+    ]
+}
+
+fn feature(side: bool, king: usize, kind: usize, square: usize) -> usize {
+    (orient(king, side) * PIECE_KINDS + kind) * 64 + orient(square, side)
+}
+
+/// Returns the active features of the given side.
+fn features(position: &Position, side: bool) -> Vec<usize> {
+    let king = king_square(position, side);
+    let mut features = vec![];
+    for (kind, bitboard) in pieces(position, side).into_iter().enumerate() {
+        for square in bitboard.into_iter() {
+            features.push(feature(side, king, kind, square.square()));
+        }
+    }
+    features
+}
+
+fn side_index(side: bool) -> usize {
+    if side {
+        0
+    } else {
+        1
+    }
+}
+
+/// The sums of the first layer, for white and for black.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Accumulator([Vec<f32>; 2]);
+
+pub struct Nnue {
+    /// One row for every feature, shared by both sides.
+    feature_weights: Array2<f32>,
+    feature_bias: Array1<f32>,
+    hidden: Linear,
+    output: Linear,
+}
+
+impl Nnue {
+    /// Creates a network with random weights and the given accumulator size.
+    pub fn new(size: usize) -> Self {
+        // About 30 features are active at once, so their sums start out in
+        // the same range as the inputs of a `Linear`.
+        let bound = 1. / 32f32.sqrt();
+        let mut rng = rand::thread_rng();
+
+        Self {
+            feature_weights: Array2::from_shape_simple_fn((FEATURES, size), || {
+                rng.gen_range(-bound..bound)
+            }),
+            feature_bias: Array1::zeros(size),
+            hidden: Linear::new(2 * size, DENSE),
+            output: Linear::new(DENSE, 1),
+        }
+    }
+
+    /// The number of sums in the accumulator of each side.
+    pub fn size(&self) -> usize {
+        self.feature_bias.len()
+    }
+
+    fn add(&self, sums: &mut [f32], feature: usize, sign: f32) {
+        let row = self.feature_weights.row(feature);
+        for (sum, weight) in sums.iter_mut().zip(row.as_slice().unwrap()) {
+            *sum += sign * weight;
+        }
+    }
+
+    fn refresh_side(&self, position: &Position, side: bool) -> Vec<f32> {
+        let mut sums = self.feature_bias.to_vec();
+        for feature in features(position, side) {
+            self.add(&mut sums, feature, 1.);
+        }
+        sums
+    }
+
+    /// Computes the accumulator of a position from scratch.
+    pub fn refresh(&self, game: &Game) -> Accumulator {
+        Accumulator([
+            self.refresh_side(&game.position, true),
+            self.refresh_side(&game.position, false),
+        ])
+    }
+
+    /// Returns the accumulator after a move from the accumulator before it.
+    /// Only the pieces that changed their square are added or removed, unless
+    /// the king of a side moved, which changes all features of that side.
+    /// Positions are copied instead of unmade, so the accumulator of the
+    /// position before the move stays valid for taking the move back.
+    pub fn update(&self, accumulator: &Accumulator, before: &Game, after: &Game) -> Accumulator {
+        let mut next = accumulator.clone();
+        for side in [true, false] {
+            let sums = &mut next.0[side_index(side)];
+            let king = king_square(&after.position, side);
+            if king != king_square(&before.position, side) {
+                *sums = self.refresh_side(&after.position, side);
+                continue;
+            }
+
+            let old = pieces(&before.position, side);
+            let new = pieces(&after.position, side);
+            for kind in 0..PIECE_KINDS {
+                let changed = old[kind] ^ new[kind];
+                for square in (changed & old[kind]).into_iter() {
+                    self.add(sums, feature(side, king, kind, square.square()), -1.);
+                }
+                for square in (changed & new[kind]).into_iter() {
+                    self.add(sums, feature(side, king, kind, square.square()), 1.);
+                }
+            }
+        }
+        next
+    }
+
+    /// The input of the dense layers, with the sums of the player to move
+    /// first.
+    fn dense_input(&self, accumulator: &Accumulator, player: bool) -> Array2<f32> {
+        let own = &accumulator.0[side_index(player)];
+        let other = &accumulator.0[side_index(!player)];
+        let input = Array1::from_iter(own.iter().chain(other).copied());
+        nn::clipped_relu(input.insert_axis(ndarray::Axis(0)))
+    }
+
+    /// Returns the value of the position between -1 and 1 from the
+    /// perspective of white.
+    pub fn evaluate(&self, accumulator: &Accumulator, player: bool) -> f32 {
+        let hidden = nn::clipped_relu(self.hidden.forward(&self.dense_input(accumulator, player)));
+        let value = self.output.forward(&hidden)[[0, 0]].tanh();
+        if player {
+            value
+        } else {
+            -value
+        }
+    }
+
+    /// Trains the value of a batch of examples and returns the mean squared
+    /// error before the update. The dense layers use the optimizer, while the
+    /// rows of the active features are updated with plain SGD, because only a
+    /// few of them have a gradient.
+    pub fn train(&mut self, examples: &[Example], optimizer: &Optimizer) -> f32 {
+        let size = self.size();
+        let mut active = vec![];
+        let mut input = Array2::zeros((examples.len(), 2 * size));
+        let mut target = Array2::zeros((examples.len(), 1));
+        let mut mask = Array2::zeros((examples.len(), 1));
+        for (i, example) in examples.iter().enumerate() {
+            let game = &example.game;
+            let sides = [game.player, !game.player];
+            for (j, &side) in sides.iter().enumerate() {
+                let sums = self.refresh_side(&game.position, side);
+                input
+                    .slice_mut(s![i, j * size..(j + 1) * size])
+                    .assign(&ArrayView1::from(&sums[..]));
+            }
+            if let Some(value_target) = example.value_target() {
+                let value = 2. * value_target - 1.;
+                target[[i, 0]] = if game.player { value } else { -value };
+                mask[[i, 0]] = 1.;
+            }
+            active.push(sides.map(|side| features(&game.position, side)));
+        }
+
+        let input = nn::clipped_relu(input);
+        let hidden = nn::clipped_relu(self.hidden.forward(&input));
+        let output = nn::tanh(self.output.forward(&hidden));
+        let (loss, grad) = nn::mse_loss(&output, &target, &mask);
+
+        let grad = self
+            .output
+            .backward(&hidden, &nn::tanh_backward(&output, grad), optimizer);
+        let grad =
+            self.hidden
+                .backward(&input, &nn::clipped_relu_backward(&hidden, grad), optimizer);
+        let grad = nn::clipped_relu_backward(&input, grad);
+
+        let learning_rate = optimizer.learning_rate;
+        for (i, active) in active.iter().enumerate() {
+            for (j, features) in active.iter().enumerate() {
+                let grad = grad.slice(s![i, j * size..(j + 1) * size]);
+                self.feature_bias.scaled_add(-learning_rate, &grad);
+                for &feature in features {
+                    self.feature_weights
+                        .row_mut(feature)
+                        .scaled_add(-learning_rate, &grad);
+                }
+            }
+        }
+
+        loss
+    }
+
+    fn parameters(&self) -> Vec<ArrayViewD<'_, f32>> {
+        let mut parameters = vec![
+            self.feature_weights.view().into_dyn(),
+            self.feature_bias.view().into_dyn(),
+        ];
+        parameters.extend(self.hidden.parameters());
+        parameters.extend(self.output.parameters());
+        parameters
+    }
+
+    fn parameters_mut(&mut self) -> Vec<ArrayViewMutD<'_, f32>> {
+        let mut parameters = vec![
+            self.feature_weights.view_mut().into_dyn(),
+            self.feature_bias.view_mut().into_dyn(),
+        ];
+        parameters.extend(self.hidden.parameters_mut());
+        parameters.extend(self.output.parameters_mut());
+        parameters
+    }
+
+    pub fn to_bytes(&self, precision: Precision) -> Vec<u8> {
+        let mut bytes = weights::header(MAGIC, VERSION, precision);
+        bytes.extend((self.size() as u32).to_le_bytes());
+        weights::push_tensors(&mut bytes, &self.parameters(), precision);
+        weights::push_checksum(&mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Nnue> {
+        let (mut bytes, precision) = weights::open(bytes, MAGIC, VERSION)?;
+        let size = bytes.u32()? as usize;
+        if size == 0 || size > MAX_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported accumulator size {}", size),
+            ));
+        }
+
+        let mut nnue = Nnue::new(size);
+        weights::read_tensors(&mut bytes, nnue.parameters_mut(), precision)?;
+        bytes.finish()?;
+        Ok(nnue)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        weights::write(path, &self.to_bytes(Precision::F32))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Nnue> {
+        Nnue::from_bytes(&weights::read(path)?)
+    }
+}
+
+impl Inference for Nnue {
+    /// The network has no policy, so all moves get the same prior. Positions
+    /// of a batch come from unrelated parts of the tree, so their
+    /// accumulators are computed from scratch. Only the alpha-beta search
+    /// updates accumulators incrementally.
+    fn forward(&self, input: &Array2<f32>) -> (Array2<f32>, Array1<f32>) {
+        let policy = Array2::from_elem((input.nrows(), 1972), 1. / 1972.);
+        let values = input
+            .rows()
+            .into_iter()
+            .map(|row| {
+                let game = Game::from_input(row);
+                self.evaluate(&self.refresh(&game), game.player)
+            })
+            .collect();
+        (policy, values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Sample, Termination};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn assert_close(a: &Accumulator, b: &Accumulator) {
+        for (a, b) in a.0.iter().flatten().zip(b.0.iter().flatten()) {
+            assert!((a - b).abs() < 1e-4, "{} differs from {}", a, b);
+        }
+    }
+
+    #[test]
+    fn incremental_updates_match_refresh() {
+        let nnue = Nnue::new(8);
+        let mut rng = StdRng::seed_from_u64(7);
+        // Positions with castling, en passant, promotions and many captures
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N b - - 0 1",
+        ] {
+            for _ in 0..4 {
+                let mut game = Game::from_fen(fen);
+                let mut accumulator = nnue.refresh(&game);
+                for _ in 0..100 {
+                    let moves = game.legal_moves(game.player);
+                    if moves.is_empty() {
+                        break;
+                    }
+                    let m = &moves[rng.gen_range(0..moves.len())];
+                    let next = game.make_move(m, false);
+                    accumulator = nnue.update(&accumulator, &game, &next);
+                    assert_close(&accumulator, &nnue.refresh(&next));
+                    game = next;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn colors_are_symmetric() {
+        let nnue = Nnue::new(8);
+        let game =
+            Game::from_fen("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+        let recolored = game.recolor();
+
+        let value = nnue.evaluate(&nnue.refresh(&game), game.player);
+        let recolored_value = nnue.evaluate(&nnue.refresh(&recolored), recolored.player);
+        assert!((value + recolored_value).abs() < 1e-5);
+    }
+
+    #[test]
+    fn training_reduces_loss() {
+        let mut nnue = Nnue::new(8);
+        let examples: Vec<Example> = [
+            (
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                0,
+            ),
+            ("4k3/8/8/8/8/8/8/QQQ1K3 w - - 0 1", 1),
+            ("4k3/8/8/8/8/8/8/QQQ1K3 b - - 0 1", 1),
+            ("qqq1k3/8/8/8/8/8/8/4K3 w - - 0 1", -1),
+        ]
+        .iter()
+        .map(|&(fen, result)| {
+            Example::from(Sample {
+                fen: fen.to_string(),
+                start_fen: fen.to_string(),
+                result,
+                termination: Termination::Rules,
+                game: 0,
+                ply: 0,
+                policy: vec![],
+                has_policy_target: false,
+                has_value_target: true,
+            })
+        })
+        .collect();
+
+        let optimizer = Optimizer::sgd(0.1);
+        let initial_loss = nnue.train(&examples, &optimizer);
+        for _ in 0..200 {
+            nnue.train(&examples, &optimizer);
+        }
+        let loss = nnue.train(&examples, &optimizer);
+        assert!(loss < initial_loss / 4., "{} after {}", loss, initial_loss);
+    }
+
+    #[test]
+    fn round_trip() {
+        let nnue = Nnue::new(4);
+        let bytes = nnue.to_bytes(Precision::F32);
+        let loaded = Nnue::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_bytes(Precision::F32), bytes);
+
+        assert!(Nnue::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let network = crate::network::Network::new(crate::network::NetworkConfig {
+            blocks: 1,
+            filters: 4,
+            value_head: crate::network::ValueHead::Tanh,
+        });
+        assert!(Nnue::from_bytes(&weights::to_bytes(&network, Precision::F32)).is_err());
+    }
+}
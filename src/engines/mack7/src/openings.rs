@@ -0,0 +1,299 @@
+//! Starting positions for self-play, read from opening suites in EPD or PGN.
+//! An EPD suite has one position per line, and a PGN suite contributes the
+//! final position of each of its games.
+
+use rand::{rngs::StdRng, Rng};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::{game::Game, pgn};
+
+/// In which order the positions of a suite are handed out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    /// A uniformly random position for every game.
+    Random,
+    /// One position after the other by the id of the game, starting over
+    /// after the last one.
+    RoundRobin,
+}
+
+pub struct Openings {
+    fens: Vec<String>,
+    order: Order,
+}
+
+impl Openings {
+    pub fn new(fens: Vec<String>, order: Order) -> std::io::Result<Openings> {
+        if fens.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The opening suite has no positions",
+            ));
+        }
+        Ok(Openings { fens, order })
+    }
+
+    /// Reads a suite, as PGN if the file ends in `.pgn` and as EPD otherwise.
+    pub fn load<P: AsRef<Path>>(path: P, order: Order) -> std::io::Result<Openings> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|err| {
+            Error::new(
+                err.kind(),
+                format!("Could not read {}: {}", path.display(), err),
+            )
+        })?;
+        let is_pgn = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("pgn"));
+        let fens = if is_pgn {
+            parse_pgn(&text)?
+        } else {
+            parse_epd(&text)?
+        };
+        Openings::new(fens, order)
+    }
+
+    /// Returns the FEN of the starting position for the game with the given
+    /// id.
+    pub fn pick(&self, game: u32, rng: &mut StdRng) -> &str {
+        let index = match self.order {
+            Order::Random => rng.gen_range(0..self.fens.len()),
+            Order::RoundRobin => game as usize % self.fens.len(),
+        };
+        &self.fens[index]
+    }
+}
+
+/// Checks the board and the player to move of a FEN, so that invalid suites
+/// are rejected before any game is played.
+fn check_fen(fen: &str) -> Result<(), &'static str> {
+    let fields: Vec<&str> = fen.split(' ').collect();
+    if fields.len() != 6 {
+        return Err("a FEN needs six fields");
+    }
+
+    let ranks: Vec<&str> = fields[0].split('/').collect();
+    if ranks.len() != 8 {
+        return Err("the board needs eight ranks");
+    }
+    for rank in ranks {
+        let mut squares = 0;
+        for c in rank.chars() {
+            squares += match c {
+                '1'..='8' => c.to_digit(10).unwrap(),
+                'K' | 'Q' | 'R' | 'B' | 'N' | 'P' | 'k' | 'q' | 'r' | 'b' | 'n' | 'p' => 1,
+                _ => return Err("unknown piece"),
+            };
+        }
+        if squares != 8 {
+            return Err("every rank needs eight squares");
+        }
+    }
+    if fields[0].matches('K').count() != 1 || fields[0].matches('k').count() != 1 {
+        return Err("both players need exactly one king");
+    }
+
+    if fields[1] != "w" && fields[1] != "b" {
+        return Err("the player to move must be w or b");
+    }
+    if fields[4].parse::<u32>().is_err() || fields[5].parse::<u32>().is_err() {
+        return Err("the move counters must be numbers");
+    }
+    Ok(())
+}
+
+/// Checks that the game is not over yet, so that self-play has a move to
+/// search from the position.
+fn check_moves(game: &Game) -> Result<(), &'static str> {
+    if game.legal_moves(game.player).is_empty() {
+        return Err("the position is checkmate or stalemate");
+    }
+    Ok(())
+}
+
+/// Returns the positions of an EPD file as FENs. The move counters are taken
+/// from the `hmvc` and `fmvn` operations, or from two numbers after the
+/// position as in a FEN, and are 0 and 1 otherwise.
+pub fn parse_epd(text: &str) -> std::io::Result<Vec<String>> {
+    let mut fens = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Invalid position on line {}, {}: {}",
+                    number + 1,
+                    reason,
+                    line
+                ),
+            )
+        };
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            return Err(invalid("an EPD needs four fields"));
+        }
+        let operations = fields[4..].join(" ");
+        let (mut halfmove, mut fullmove) = ("0", "1");
+        if fields.len() >= 6
+            && fields[4].parse::<u32>().is_ok()
+            && fields[5].trim_end_matches(';').parse::<u32>().is_ok()
+        {
+            halfmove = fields[4];
+            fullmove = fields[5].trim_end_matches(';');
+        } else {
+            for operation in operations.split(';') {
+                let mut parts = operation.split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (Some("hmvc"), Some(value)) => halfmove = value,
+                    (Some("fmvn"), Some(value)) => fullmove = value,
+                    _ => {}
+                }
+            }
+        }
+
+        let fen = format!("{} {} {}", fields[..4].join(" "), halfmove, fullmove);
+        check_fen(&fen).map_err(invalid)?;
+        check_moves(&Game::from_fen(&fen)).map_err(invalid)?;
+        fens.push(fen);
+    }
+    Ok(fens)
+}
+
+/// Returns the final positions of the games of a PGN file as FENs.
+pub fn parse_pgn(text: &str) -> std::io::Result<Vec<String>> {
+    pgn::read_games(text)?
+        .iter()
+        .enumerate()
+        .map(|(i, game)| {
+            check_fen(game.fen()).map_err(|reason| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid FEN in game {}, {}", i + 1, reason),
+                )
+            })?;
+            let game = game.replay(|_, _| {}).map_err(|err| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid game {}: {}", i + 1, err),
+                )
+            })?;
+            check_moves(&game).map_err(|reason| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid final position in game {}, {}", i + 1, reason),
+                )
+            })?;
+            Ok(game.to_fen())
+        })
+        .collect()
+}
+
+/// Plays up to the given number of uniformly random legal moves. Moves that
+/// end the game are avoided, so that there is always something left to play.
+pub fn random_plies(mut game: Game, plies: u32, rng: &mut StdRng) -> Game {
+    for _ in 0..plies {
+        let mut moves: Vec<_> = game
+            .legal_moves(game.player)
+            .into_iter()
+            .filter(|m| {
+                let next = game.make_move(m, false);
+                !next.legal_moves(next.player).is_empty()
+            })
+            .collect();
+        if moves.is_empty() {
+            break;
+        }
+        let m = moves.swap_remove(rng.gen_range(0..moves.len()));
+        game = game.make_move(&m, true);
+    }
+    game
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn parses_epd() {
+        let text = "# A comment\n\
+                    rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 id \"e4\";\n\
+                    \n\
+                    4k3/8/8/8/8/8/8/4K2R w K - hmvc 7; fmvn 40; c0 \"endgame\";\n\
+                    4k3/8/8/8/8/8/8/4K2R b K - 3 12\n";
+        assert_eq!(
+            parse_epd(text).unwrap(),
+            vec![
+                "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1",
+                "4k3/8/8/8/8/8/8/4K2R w K - 7 40",
+                "4k3/8/8/8/8/8/8/4K2R b K - 3 12",
+            ]
+        );
+
+        assert!(parse_epd("4k3/8/8/8/8/8/8/4K2R w K\n").is_err());
+        assert!(parse_epd("4k3/8/8/8/8/8/8/7R w - -\n").is_err());
+        assert!(parse_epd("4k3/8/8/8/8/8/8/4K2X w K -\n").is_err());
+        assert!(parse_epd("4k3/8/8/8/8/8/4K2R w K -\n").is_err());
+        // Games can't start in a checkmate or stalemate
+        assert!(parse_epd("R5k1/5ppp/8/8/8/8/8/6K1 b - -\n").is_err());
+        assert!(parse_epd("7k/5Q2/6K1/8/8/8/8/8 b - -\n").is_err());
+    }
+
+    #[test]
+    fn parses_pgn() {
+        let text = "[Event \"Italian\"]\n\n1. e4 e5 2. Nf3 Nc6 3. Bc4 *\n\n\
+                    [Event \"Endgame\"]\n[FEN \"4k3/8/8/8/8/8/8/4K2R w K - 0 1\"]\n\n1. O-O *\n";
+        assert_eq!(
+            parse_pgn(text).unwrap(),
+            vec![
+                "r1bqkbnr/pppp1ppp/2n5/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R b KQkq - 3 3",
+                "4k3/8/8/8/8/8/8/5RK1 b - - 1 1",
+            ]
+        );
+        assert!(parse_pgn("1. e4 e4 *").is_err());
+        assert!(parse_pgn("1. f3 e5 2. g4 Qh4# 0-1").is_err());
+    }
+
+    #[test]
+    fn hands_out_positions() {
+        let fens: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let openings = Openings::new(fens.clone(), Order::RoundRobin).unwrap();
+        let drawn: Vec<&str> = (0..4).map(|game| openings.pick(game, &mut rng)).collect();
+        assert_eq!(drawn, vec!["a", "b", "c", "a"]);
+
+        let openings = Openings::new(fens, Order::Random).unwrap();
+        let mut seen = [false; 3];
+        for _ in 0..100 {
+            let fen = openings.pick(0, &mut rng);
+            seen[(fen.as_bytes()[0] - b'a') as usize] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+
+        assert!(Openings::new(vec![], Order::Random).is_err());
+    }
+
+    #[test]
+    fn plays_random_plies() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let game = random_plies(Game::from_fen(pgn::START_POSITION), 6, &mut rng);
+        assert!(game.player);
+        assert_eq!(game.to_fen().split(' ').nth(5), Some("4"));
+
+        // Many queen moves stalemate or mate the lone king, which is avoided
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let game = Game::from_fen("7k/5Q2/8/8/8/8/8/6K1 w - - 0 1");
+            let game = random_plies(game, 20, &mut rng);
+            assert!(!game.legal_moves(game.player).is_empty());
+        }
+    }
+}
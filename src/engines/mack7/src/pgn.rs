@@ -0,0 +1,516 @@
+//! Reads and writes games in the Portable Game Notation, with moves in
+//! Standard Algebraic Notation (SAN).
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    chess_move::{Castle, Move},
+    game::{Game, GameResult},
+    piece::{Piece, PromotionPiece},
+};
+
+pub const START_POSITION: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+fn piece_letter(piece: &Piece) -> &'static str {
+    match piece {
+        Piece::King => "K",
+        Piece::Queen => "Q",
+        Piece::Rook => "R",
+        Piece::Bishop => "B",
+        Piece::Knight => "N",
+        Piece::Pawn => "",
+    }
+}
+
+fn promotion_letter(piece: &PromotionPiece) -> &'static str {
+    match piece {
+        PromotionPiece::Queen => "Q",
+        PromotionPiece::Rook => "R",
+        PromotionPiece::Bishop => "B",
+        PromotionPiece::Knight => "N",
+    }
+}
+
+/// Returns the move in SAN without a check mark.
+fn san_without_check(game: &Game, m: &Move) -> String {
+    match m.is_castling {
+        Some(Castle::Kingside) => String::from("O-O"),
+        Some(Castle::Queenside) => String::from("O-O-O"),
+        None => {
+            let from = m.from_square.to_human();
+            let to = m.to_square.to_human();
+            let capture =
+                if m.is_capturing_en_passant || !(game.position.all & m.to_square).is_empty() {
+                    "x"
+                } else {
+                    ""
+                };
+
+            if m.piece == Piece::Pawn {
+                let file = if capture.is_empty() { "" } else { &from[..1] };
+                let promotion = match &m.is_promoting_to {
+                    Some(piece) => format!("={}", promotion_letter(piece)),
+                    None => String::new(),
+                };
+                format!("{}{}{}{}", file, capture, to, promotion)
+            } else {
+                let others: Vec<String> = game
+                    .legal_moves(game.player)
+                    .iter()
+                    .filter(|other| {
+                        other.piece == m.piece
+                            && other.to_square == m.to_square
+                            && other.from_square != m.from_square
+                    })
+                    .map(|other| other.from_square.to_human())
+                    .collect();
+                let disambiguation = if others.is_empty() {
+                    ""
+                } else if others.iter().all(|other| other[..1] != from[..1]) {
+                    &from[..1]
+                } else if others.iter().all(|other| other[1..] != from[1..]) {
+                    &from[1..]
+                } else {
+                    &from
+                };
+                format!(
+                    "{}{}{}{}",
+                    piece_letter(&m.piece),
+                    disambiguation,
+                    capture,
+                    to
+                )
+            }
+        }
+    }
+}
+
+/// Returns the move in SAN, which needs the position before the move to
+/// disambiguate it and the position after it for checks.
+pub fn san(game: &Game, m: &Move) -> String {
+    let mut san = san_without_check(game, m);
+    let next = game.make_move(m, false);
+    if next.position.is_check(next.player) {
+        san.push(if next.legal_moves(next.player).is_empty() {
+            '#'
+        } else {
+            '+'
+        });
+    }
+    san
+}
+
+/// Finds the legal move that is written in SAN. Check marks and annotations
+/// are ignored, and castling with zeros or promotions without `=` are
+/// accepted as well.
+pub fn parse_san(game: &Game, text: &str) -> Option<Move> {
+    let mut text = text
+        .trim_end_matches(|c| "+#!?".contains(c))
+        .replace('0', "O");
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() >= 3
+        && "QRBN".contains(chars[chars.len() - 1])
+        && chars[chars.len() - 2].is_ascii_digit()
+    {
+        text.insert(text.len() - 1, '=');
+    }
+
+    game.legal_moves(game.player)
+        .into_iter()
+        .find(|m| san_without_check(game, m) == text)
+}
+
+/// Returns the result as written in PGN, `*` for unfinished games.
+pub fn result_string(result: Option<&GameResult>) -> &'static str {
+    match result {
+        Some(GameResult::White) => "1-0",
+        Some(GameResult::Black) => "0-1",
+        Some(_) => "1/2-1/2",
+        None => "*",
+    }
+}
+
+/// Returns today's date in the PGN format `YYYY.MM.DD`.
+fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() / 86400) as i64;
+
+    // Converts days since 1970-01-01 to a date of the proleptic Gregorian
+    // calendar, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}.{:02}.{:02}", year, month, day)
+}
+
+/// A game that is written as PGN once it is finished.
+pub struct PgnGame {
+    /// The tags after the seven tag roster.
+    pub tags: Vec<(String, String)>,
+    event: String,
+    round: u32,
+    white: String,
+    black: String,
+    fen: String,
+    moves: Vec<String>,
+    result: &'static str,
+}
+
+impl PgnGame {
+    pub fn new(event: &str, round: u32, white: &str, black: &str, fen: &str) -> PgnGame {
+        PgnGame {
+            tags: vec![],
+            event: String::from(event),
+            round,
+            white: String::from(white),
+            black: String::from(black),
+            fen: String::from(fen),
+            moves: vec![],
+            result: "*",
+        }
+    }
+
+    /// Adds a move played in the given position.
+    pub fn push(&mut self, game: &Game, m: &Move) {
+        self.moves.push(san(game, m));
+    }
+
+    pub fn finish(&mut self, result: Option<&GameResult>) {
+        self.result = result_string(result);
+    }
+
+    /// Ends the game with a result from the perspective of white that does
+    /// not follow from the position, such as a loss on time, and gives the
+    /// reason in the `Termination` tag.
+    pub fn adjudicate(&mut self, result: i8, termination: &str) {
+        self.result = match result {
+            1 => "1-0",
+            -1 => "0-1",
+            _ => "1/2-1/2",
+        };
+        self.tags
+            .push((String::from("Termination"), String::from(termination)));
+    }
+
+    /// The result as written in PGN.
+    pub fn result(&self) -> &'static str {
+        self.result
+    }
+}
+
+impl fmt::Display for PgnGame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tag = |f: &mut fmt::Formatter, name: &str, value: &str| {
+            writeln!(
+                f,
+                "[{} \"{}\"]",
+                name,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        };
+        tag(f, "Event", &self.event)?;
+        tag(f, "Site", "?")?;
+        tag(f, "Date", &today())?;
+        tag(f, "Round", &self.round.to_string())?;
+        tag(f, "White", &self.white)?;
+        tag(f, "Black", &self.black)?;
+        tag(f, "Result", self.result)?;
+        if self.fen != START_POSITION {
+            tag(f, "SetUp", "1")?;
+            tag(f, "FEN", &self.fen)?;
+        }
+        for (name, value) in self.tags.iter() {
+            tag(f, name, value)?;
+        }
+        writeln!(f)?;
+
+        // The move number and the player to move of the starting position
+        let fields: Vec<&str> = self.fen.split(' ').collect();
+        let mut number: u32 = fields.get(5).and_then(|n| n.parse().ok()).unwrap_or(1);
+        let mut white = fields.get(1) != Some(&"b");
+
+        let mut tokens = vec![];
+        for (i, m) in self.moves.iter().enumerate() {
+            if white {
+                tokens.push(format!("{}.", number));
+            } else if i == 0 {
+                tokens.push(format!("{}...", number));
+            }
+            tokens.push(m.clone());
+            if !white {
+                number += 1;
+            }
+            white = !white;
+        }
+        tokens.push(String::from(self.result));
+
+        // Lines are at most 79 characters long
+        let mut line = String::new();
+        for token in tokens {
+            if !line.is_empty() && line.len() + 1 + token.len() > 79 {
+                writeln!(f, "{}", line)?;
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&token);
+        }
+        writeln!(f, "{}", line)
+    }
+}
+
+/// A game read from a PGN file, with its moves still in SAN.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PgnRecord {
+    pub tags: Vec<(String, String)>,
+    pub moves: Vec<String>,
+    /// The result after the moves, `*` if there is none.
+    pub result: String,
+}
+
+impl PgnRecord {
+    fn new() -> PgnRecord {
+        PgnRecord {
+            tags: vec![],
+            moves: vec![],
+            result: String::from("*"),
+        }
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(tag, _)| tag == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The FEN of the position the game starts from.
+    pub fn fen(&self) -> &str {
+        self.tag("FEN").unwrap_or(START_POSITION)
+    }
+
+    /// Plays the moves from the starting position, calling `visit` with the
+    /// position before every move, and returns the final position.
+    pub fn replay<F: FnMut(&Game, &Move)>(&self, mut visit: F) -> std::io::Result<Game> {
+        let mut game = Game::from_fen(self.fen());
+        for (ply, text) in self.moves.iter().enumerate() {
+            let m = parse_san(&game, text).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Illegal move {} at ply {}", text, ply + 1),
+                )
+            })?;
+            visit(&game, &m);
+            game = game.make_move(&m, true);
+        }
+        Ok(game)
+    }
+}
+
+fn read_tag(chars: &mut std::iter::Peekable<std::str::Chars>) -> std::io::Result<(String, String)> {
+    let unterminated = || Error::new(ErrorKind::InvalidData, "Unterminated PGN tag");
+    let mut name = String::new();
+    loop {
+        match chars.next().ok_or_else(unterminated)? {
+            '"' => break,
+            c if !c.is_whitespace() => name.push(c),
+            _ => {}
+        }
+    }
+    let mut value = String::new();
+    loop {
+        match chars.next().ok_or_else(unterminated)? {
+            '\\' => value.push(chars.next().ok_or_else(unterminated)?),
+            '"' => break,
+            c => value.push(c),
+        }
+    }
+    while chars.next().ok_or_else(unterminated)? != ']' {}
+    Ok((name, value))
+}
+
+/// Reads all games of a PGN file. Comments, variations and numeric
+/// annotation glyphs are skipped.
+pub fn read_games(text: &str) -> std::io::Result<Vec<PgnRecord>> {
+    let mut games = vec![];
+    let mut game = PgnRecord::new();
+    let mut in_moves = false;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '[' => {
+                // Tags after moves start a new game, the last one had no result
+                if in_moves {
+                    games.push(std::mem::replace(&mut game, PgnRecord::new()));
+                    in_moves = false;
+                }
+                game.tags.push(read_tag(&mut chars)?);
+            }
+            '{' => while chars.next().is_some_and(|c| c != '}') {},
+            ';' | '%' => while chars.next().is_some_and(|c| c != '\n') {},
+            '(' => {
+                let mut depth = 1;
+                while depth > 0 {
+                    match chars.next() {
+                        Some('(') => depth += 1,
+                        Some(')') => depth -= 1,
+                        Some('{') => while chars.next().is_some_and(|c| c != '}') {},
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut token = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "[]{}();".contains(c) {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+
+                if ["1-0", "0-1", "1/2-1/2", "*"].contains(&token.as_str()) {
+                    game.result = token;
+                    games.push(std::mem::replace(&mut game, PgnRecord::new()));
+                    in_moves = false;
+                } else if !token.starts_with('$') {
+                    // Move numbers may be written right before the move
+                    let san = token
+                        .trim_start_matches(|c: char| c.is_ascii_digit())
+                        .trim_start_matches('.');
+                    if !san.is_empty() {
+                        game.moves.push(String::from(san));
+                    }
+                    in_moves = true;
+                }
+            }
+        }
+    }
+    if in_moves || !game.tags.is_empty() {
+        games.push(game);
+    }
+    Ok(games)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn san_of(fen: &str, uci: &str) -> String {
+        let game = Game::from_fen(fen);
+        let m = game
+            .legal_moves(game.player)
+            .into_iter()
+            .find(|m| m.to_uci() == uci)
+            .unwrap();
+        san(&game, &m)
+    }
+
+    #[test]
+    fn standard_algebraic_notation() {
+        assert_eq!(san_of(START_POSITION, "e2e4"), "e4");
+        assert_eq!(san_of(START_POSITION, "g1f3"), "Nf3");
+        assert_eq!(san_of("4k3/8/8/8/8/8/8/4K2R w K - 0 1", "e1g1"), "O-O");
+        assert_eq!(san_of("r3k3/8/8/8/8/8/8/4K3 b q - 0 1", "e8c8"), "O-O-O");
+        assert_eq!(san_of("7k/8/8/R7/8/8/8/R5K1 w - - 0 1", "a1a3"), "R1a3");
+        assert_eq!(san_of("7k/8/8/8/8/8/8/N1N3K1 w - - 0 1", "a1b3"), "Nab3");
+        assert_eq!(
+            san_of("1r5k/P7/8/8/8/8/8/6K1 w - - 0 1", "a7b8q"),
+            "axb8=Q+"
+        );
+        assert_eq!(san_of("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1", "a1a8"), "Ra8#");
+        assert_eq!(san_of("4k3/8/8/3pP3/8/8/8/4K3 w - d6 0 1", "e5d6"), "exd6");
+    }
+
+    #[test]
+    fn writes_games() {
+        let mut game = Game::from_fen(START_POSITION);
+        let mut pgn = PgnGame::new("Test", 3, "mack7", "\"other\"", START_POSITION);
+        for uci in ["e2e4", "e7e5", "g1f3"] {
+            let m = game
+                .legal_moves(game.player)
+                .into_iter()
+                .find(|m| m.to_uci() == uci)
+                .unwrap();
+            pgn.push(&game, &m);
+            game = game.make_move(&m, true);
+        }
+        let text = pgn.to_string();
+        assert!(text.contains("[Round \"3\"]\n"));
+        assert!(text.contains("[Black \"\\\"other\\\"\"]\n"));
+        assert!(!text.contains("FEN"));
+        assert!(text.ends_with("\n\n1. e4 e5 2. Nf3 *\n"));
+
+        let fen = "4k3/8/8/8/8/8/8/4K2R b K - 3 12";
+        let mut pgn = PgnGame::new("Test", 1, "a", "b", fen);
+        let game = Game::from_fen(fen);
+        let m = game.legal_moves(game.player).into_iter().next().unwrap();
+        pgn.push(&game, &m);
+        pgn.finish(Some(&GameResult::Stalemate));
+        let text = pgn.to_string();
+        assert!(text.contains("[FEN \"4k3/8/8/8/8/8/8/4K2R b K - 3 12\"]\n"));
+        assert!(text.contains("\n12... K"));
+        assert!(text.ends_with(" 1/2-1/2\n"));
+    }
+
+    #[test]
+    fn parses_san() {
+        let parse =
+            |fen: &str, text: &str| parse_san(&Game::from_fen(fen), text).map(|m| m.to_uci());
+        assert_eq!(parse(START_POSITION, "e4").as_deref(), Some("e2e4"));
+        assert_eq!(parse(START_POSITION, "Nf3!?").as_deref(), Some("g1f3"));
+        assert_eq!(parse(START_POSITION, "e5"), None);
+        assert_eq!(
+            parse("4k3/8/8/8/8/8/8/4K2R w K - 0 1", "0-0").as_deref(),
+            Some("e1g1")
+        );
+        assert_eq!(
+            parse("7k/8/8/R7/8/8/8/R5K1 w - - 0 1", "R1a3").as_deref(),
+            Some("a1a3")
+        );
+        assert_eq!(parse("7k/8/8/R7/8/8/8/R5K1 w - - 0 1", "Ra3"), None);
+        assert_eq!(
+            parse("1r5k/P7/8/8/8/8/8/6K1 w - - 0 1", "axb8N").as_deref(),
+            Some("a7b8n")
+        );
+    }
+
+    #[test]
+    fn reads_games() {
+        let text = "[Event \"A \\\"quoted\\\" name\"]\n\
+                    [Result \"1-0\"]\n\n\
+                    1. e4 {best by test} e5 2.Nf3 (2. Bc4 Nc6) $1 Nc6 ; a comment\n\
+                    3. Bb5 1-0\n\n\
+                    [FEN \"4k3/8/8/8/8/8/8/4K2R b K - 0 1\"]\n\
+                    1... Kd7 2. O-O\n";
+        let games = read_games(text).unwrap();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].tag("Event"), Some("A \"quoted\" name"));
+        assert_eq!(games[0].moves, vec!["e4", "e5", "Nf3", "Nc6", "Bb5"]);
+        assert_eq!(games[0].result, "1-0");
+        assert_eq!(games[1].result, "*");
+
+        let mut plies = 0;
+        let game = games[1].replay(|_, _| plies += 1).unwrap();
+        assert_eq!(plies, 2);
+        assert_eq!(game.to_fen(), "8/3k4/8/8/8/8/8/5RK1 b - - 2 2");
+
+        let illegal = read_games("1. e5 *").unwrap();
+        assert!(illegal[0].replay(|_, _| {}).is_err());
+        assert!(read_games("[Event \"unterminated").is_err());
+    }
+}
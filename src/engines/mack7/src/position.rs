@@ -5,7 +5,7 @@ use crate::{
     piece::{CapturedPiece, Piece, PromotionPiece},
 };
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Hash)]
 pub struct Pieces {
     pub all: Bitboard,
     pub king: Bitboard,
@@ -16,7 +16,22 @@ pub struct Pieces {
     pub pawn: Bitboard,
 }
 
-#[derive(Clone, Copy, Debug)]
+impl Pieces {
+    /// Applies the same transformation to all bitboards.
+    pub fn map(&self, f: fn(Bitboard) -> Bitboard) -> Pieces {
+        Pieces {
+            all: f(self.all),
+            king: f(self.king),
+            queen: f(self.queen),
+            rook: f(self.rook),
+            bishop: f(self.bishop),
+            knight: f(self.knight),
+            pawn: f(self.pawn),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash)]
 pub struct Position {
     pub all: Bitboard,
     pub white: Pieces,
@@ -25,7 +40,7 @@ pub struct Position {
 
 impl Position {
     pub fn make_move(self, m: &Move) -> (Position, CapturedPiece) {
-        let mut next = self.clone();
+        let mut next = self;
 
         match m.is_castling {
             Some(Castle::Kingside) => {
@@ -156,25 +171,22 @@ impl Position {
             }
         }
 
-        match &m.is_promoting_to {
-            Some(promotion_piece) => {
-                match (m.player, promotion_piece) {
-                    (true, PromotionPiece::Queen) => next.white.queen |= m.to_square,
-                    (true, PromotionPiece::Rook) => next.white.rook |= m.to_square,
-                    (true, PromotionPiece::Bishop) => next.white.bishop |= m.to_square,
-                    (true, PromotionPiece::Knight) => next.white.knight |= m.to_square,
-                    (false, PromotionPiece::Queen) => next.black.queen |= m.to_square,
-                    (false, PromotionPiece::Rook) => next.black.rook |= m.to_square,
-                    (false, PromotionPiece::Bishop) => next.black.bishop |= m.to_square,
-                    (false, PromotionPiece::Knight) => next.black.knight |= m.to_square,
-                }
-                if m.player {
-                    next.white.pawn ^= m.to_square;
-                } else {
-                    next.black.pawn ^= m.to_square;
-                }
+        if let Some(promotion_piece) = &m.is_promoting_to {
+            match (m.player, promotion_piece) {
+                (true, PromotionPiece::Queen) => next.white.queen |= m.to_square,
+                (true, PromotionPiece::Rook) => next.white.rook |= m.to_square,
+                (true, PromotionPiece::Bishop) => next.white.bishop |= m.to_square,
+                (true, PromotionPiece::Knight) => next.white.knight |= m.to_square,
+                (false, PromotionPiece::Queen) => next.black.queen |= m.to_square,
+                (false, PromotionPiece::Rook) => next.black.rook |= m.to_square,
+                (false, PromotionPiece::Bishop) => next.black.bishop |= m.to_square,
+                (false, PromotionPiece::Knight) => next.black.knight |= m.to_square,
+            }
+            if m.player {
+                next.white.pawn ^= m.to_square;
+            } else {
+                next.black.pawn ^= m.to_square;
             }
-            None => {}
         }
 
         (next, is_capturing)
@@ -215,7 +227,7 @@ impl Position {
         let queen_and_rook = pieces.queen | pieces.rook;
         let queen_and_bishop = pieces.queen | pieces.bishop;
 
-        let attackers = (square.king_moves() & pieces.king)
+        (square.king_moves() & pieces.king)
             | self.attackers_in_direction(square, queen_and_rook, Direction::Top)
             | self.attackers_in_direction(square, queen_and_rook, Direction::Bottom)
             | self.attackers_in_direction(square, queen_and_rook, Direction::Left)
@@ -226,9 +238,7 @@ impl Position {
             | self.attackers_in_direction(square, queen_and_bishop, Direction::BottomRight)
             | (square.knight_moves() & pieces.knight)
             | (forward_square.get_left_square() & pieces.pawn)
-            | (forward_square.get_right_square() & pieces.pawn);
-
-        attackers
+            | (forward_square.get_right_square() & pieces.pawn)
     }
 
     fn attacked_squares_in_direction(
@@ -0,0 +1,275 @@
+//! A network for inference only, with the weights of every layer stored as 8
+//! or 16 bit integers. Inputs of each layer are quantized on the fly, so that
+//! the matrix products run on integers with contiguous rows that the compiler
+//! can vectorize.
+
+use ndarray::{Array1, Array2, ArrayViewD, Ix1, Ix2};
+use std::str::FromStr;
+
+use crate::{
+    network::{self, Inference, Network, ValueHead},
+    nn,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bits {
+    Int8,
+    Int16,
+}
+
+impl FromStr for Bits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "int8" => Ok(Bits::Int8),
+            "int16" => Ok(Bits::Int16),
+            _ => Err(format!("Unknown quantization: {}", s)),
+        }
+    }
+}
+
+impl Bits {
+    fn max(self) -> f32 {
+        match self {
+            Bits::Int8 => i8::MAX as f32,
+            Bits::Int16 => i16::MAX as f32,
+        }
+    }
+}
+
+/// Returns the scale that maps the largest absolute value to the largest
+/// integer, so that `value ≈ integer * scale`.
+fn scale<'a>(values: impl Iterator<Item = &'a f32>, bits: Bits) -> f32 {
+    let max = values.fold(0f32, |max, v| max.max(v.abs()));
+    if max == 0. {
+        1.
+    } else {
+        max / bits.max()
+    }
+}
+
+fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+    a.iter().zip(b).map(|(&x, &y)| x as i32 * y as i32).sum()
+}
+
+fn dot_i16(a: &[i16], b: &[i16]) -> i64 {
+    a.iter()
+        .zip(b)
+        .map(|(&x, &y)| (x as i32 * y as i32) as i64)
+        .sum()
+}
+
+enum Weights {
+    Int8(Vec<i8>),
+    Int16(Vec<i16>),
+}
+
+struct QuantizedLinear {
+    inputs: usize,
+    /// One row of `inputs` weights for every output.
+    weights: Weights,
+    scale: f32,
+    bias: Vec<f32>,
+}
+
+impl QuantizedLinear {
+    fn new(parameters: &[ArrayViewD<f32>], bits: Bits) -> Self {
+        let weight = parameters[0].view().into_dimensionality::<Ix2>().unwrap();
+        let bias = parameters[1].view().into_dimensionality::<Ix1>().unwrap();
+        let scale = scale(weight.iter(), bits);
+        let rows = weight
+            .t()
+            .iter()
+            .map(|w| (w / scale).round())
+            .collect::<Vec<f32>>();
+
+        Self {
+            inputs: weight.nrows(),
+            weights: match bits {
+                Bits::Int8 => Weights::Int8(rows.iter().map(|&w| w as i8).collect()),
+                Bits::Int16 => Weights::Int16(rows.iter().map(|&w| w as i16).collect()),
+            },
+            scale,
+            bias: bias.to_vec(),
+        }
+    }
+
+    fn forward(&self, input: &Array2<f32>) -> Array2<f32> {
+        let mut output = Array2::zeros((input.nrows(), self.bias.len()));
+        for (row, mut output) in input.rows().into_iter().zip(output.rows_mut()) {
+            match &self.weights {
+                Weights::Int8(weights) => {
+                    let input_scale = scale(row.iter(), Bits::Int8);
+                    let x: Vec<i8> = row
+                        .iter()
+                        .map(|v| (v / input_scale).round() as i8)
+                        .collect();
+                    let scale = self.scale * input_scale;
+                    for (o, weights) in output.iter_mut().zip(weights.chunks_exact(self.inputs)) {
+                        *o = dot_i8(weights, &x) as f32 * scale;
+                    }
+                }
+                Weights::Int16(weights) => {
+                    let input_scale = scale(row.iter(), Bits::Int16);
+                    let x: Vec<i16> = row
+                        .iter()
+                        .map(|v| (v / input_scale).round() as i16)
+                        .collect();
+                    let scale = self.scale * input_scale;
+                    for (o, weights) in output.iter_mut().zip(weights.chunks_exact(self.inputs)) {
+                        *o = dot_i16(weights, &x) as f32 * scale;
+                    }
+                }
+            }
+            for (o, b) in output.iter_mut().zip(self.bias.iter()) {
+                *o += b;
+            }
+        }
+        output
+    }
+}
+
+struct QuantizedConv {
+    kernel_size: usize,
+    linear: QuantizedLinear,
+}
+
+impl QuantizedConv {
+    fn forward(&self, input: &Array2<f32>) -> Array2<f32> {
+        self.linear.forward(&nn::im2col(input, self.kernel_size))
+    }
+}
+
+/// The same layers as a `Network`, quantized.
+pub struct QuantizedNetwork {
+    value_head: ValueHead,
+    input: QuantizedConv,
+    blocks: Vec<(QuantizedConv, QuantizedConv)>,
+    policy_conv: QuantizedConv,
+    policy_lin: QuantizedLinear,
+    value_conv: QuantizedConv,
+    value_lin1: QuantizedLinear,
+    value_lin2: QuantizedLinear,
+}
+
+impl QuantizedNetwork {
+    pub fn new(network: &Network, bits: Bits) -> Self {
+        // The weight and the bias of every layer, in the order of the fields
+        let parameters = network.parameters();
+        let mut layers = parameters
+            .chunks_exact(2)
+            .map(|layer| QuantizedLinear::new(layer, bits));
+        let mut next = || layers.next().unwrap();
+        let conv = |linear, kernel_size| QuantizedConv {
+            kernel_size,
+            linear,
+        };
+
+        let config = network.config();
+        Self {
+            value_head: config.value_head,
+            input: conv(next(), 3),
+            blocks: (0..config.blocks)
+                .map(|_| (conv(next(), 3), conv(next(), 3)))
+                .collect(),
+            policy_conv: conv(next(), 1),
+            policy_lin: next(),
+            value_conv: conv(next(), 1),
+            value_lin1: next(),
+            value_lin2: next(),
+        }
+    }
+}
+
+impl Inference for QuantizedNetwork {
+    fn forward(&self, input: &Array2<f32>) -> (Array2<f32>, Array1<f32>) {
+        let mut x = nn::relu(self.input.forward(&network::to_planes(input)));
+        for (conv1, conv2) in self.blocks.iter() {
+            let hidden = nn::relu(conv1.forward(&x));
+            x = nn::relu(conv2.forward(&hidden) + &x);
+        }
+
+        let policy_conv = nn::relu(self.policy_conv.forward(&x));
+        let policy = nn::softmax(self.policy_lin.forward(&network::flatten(policy_conv)));
+
+        let value_conv = nn::relu(self.value_conv.forward(&x));
+        let value_hidden = nn::relu(self.value_lin1.forward(&network::flatten(value_conv)));
+        let value = self.value_lin2.forward(&value_hidden);
+        let value = match self.value_head {
+            ValueHead::Tanh => nn::tanh(value),
+            ValueHead::Wdl => nn::softmax(value),
+        };
+
+        (policy, self.value_head.values(&value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::Game, network::NetworkConfig};
+    use ndarray::Axis;
+
+    fn inputs() -> Array2<f32> {
+        let mut inputs = Array2::zeros((0, 837));
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        ] {
+            inputs
+                .push(Axis(0), Game::from_fen(fen).get_input().view())
+                .unwrap();
+        }
+        inputs
+    }
+
+    /// Returns the largest difference of the logarithms of the policies,
+    /// relative to how far the f32 policy is from uniform, and the largest
+    /// difference of the values. The policy of a random network is close to
+    /// uniform, so a quantized policy that lost its shape differs by about 1.
+    fn difference(network: &Network, bits: Bits) -> (f32, f32) {
+        let inputs = inputs();
+        let (policy, values) = network.forward(&inputs);
+        let (quantized_policy, quantized_values) =
+            QuantizedNetwork::new(network, bits).forward(&inputs);
+
+        let max = |a: Array2<f32>| a.fold(0f32, |max, v| max.max(v.abs()));
+        let logits = policy.mapv(f32::ln);
+        let spread = max(&logits + (policy.ncols() as f32).ln());
+        (
+            max(logits - quantized_policy.mapv(f32::ln)) / spread,
+            max((values - quantized_values).insert_axis(Axis(0))),
+        )
+    }
+
+    #[test]
+    fn stays_close_to_f32() {
+        for value_head in [ValueHead::Tanh, ValueHead::Wdl] {
+            let network = Network::new(NetworkConfig {
+                blocks: 2,
+                filters: 8,
+                value_head,
+            });
+
+            let (policy, value) = difference(&network, Bits::Int8);
+            assert!(policy < 0.05, "int8 policy differs by {}", policy);
+            assert!(value < 0.05, "int8 value differs by {}", value);
+
+            let (policy, value) = difference(&network, Bits::Int16);
+            assert!(policy < 1e-3, "int16 policy differs by {}", policy);
+            assert!(value < 1e-3, "int16 value differs by {}", value);
+        }
+    }
+
+    #[test]
+    fn integer_products() {
+        assert_eq!(
+            dot_i8(&[127, -128, 3], &[127, 127, -2]),
+            127 * 127 - 128 * 127 - 6
+        );
+        let a = vec![i16::MAX; 1024];
+        assert_eq!(dot_i16(&a, &a), 1024 * (i16::MAX as i64).pow(2));
+    }
+}
@@ -0,0 +1,503 @@
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::{
+    chess_move::MoveIndex,
+    chunk::{ChunkReader, Sample},
+    game::Game,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayOptions {
+    /// The number of most recent games that are trained on.
+    pub window: usize,
+    /// The number of positions that are shuffled at once.
+    pub shuffle_buffer: usize,
+    /// The probability to use a position from the oldest game in the window.
+    /// It grows linearly up to 1 for the newest game.
+    pub oldest_weight: f32,
+    /// Whether positions are randomly recolored, and mirrored if no side can
+    /// castle.
+    pub augment: bool,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            window: 10_000,
+            shuffle_buffer: 50_000,
+            oldest_weight: 0.5,
+            augment: false,
+        }
+    }
+}
+
+/// A position with its training targets, possibly merged from several games
+/// that reached the same position.
+#[derive(Clone, Debug)]
+pub struct Example {
+    pub game: Game,
+    /// The summed visits of every searched move, empty if no game recorded a
+    /// policy target for the position.
+    pub policy: Vec<(MoveIndex, f32)>,
+    result_sum: f32,
+    /// The number of games with a value target for the position.
+    count: u32,
+}
+
+impl From<Sample> for Example {
+    fn from(sample: Sample) -> Self {
+        let game = sample.game();
+        let policy = if sample.has_policy_target {
+            sample.policy
+        } else {
+            vec![]
+        };
+        if sample.has_value_target {
+            Example {
+                game,
+                policy,
+                result_sum: sample.result as f32,
+                count: 1,
+            }
+        } else {
+            Example {
+                game,
+                policy,
+                result_sum: 0.,
+                count: 0,
+            }
+        }
+    }
+}
+
+impl Example {
+    /// Adds the targets of the same position from another game.
+    fn merge(&mut self, other: Example) {
+        for (m, visits) in other.policy {
+            match self.policy.iter_mut().find(|(n, _)| *n == m) {
+                Some((_, total)) => *total += visits,
+                None => self.policy.push((m, visits)),
+            }
+        }
+        self.result_sum += other.result_sum;
+        self.count += other.count;
+    }
+
+    /// Returns the example for the position mirrored from left to right.
+    pub fn mirror(&self) -> Example {
+        Example {
+            game: self.game.mirror(),
+            policy: self.policy.iter().map(|&(m, v)| (m.mirror(), v)).collect(),
+            ..*self
+        }
+    }
+
+    /// Returns the example for the position with the colors swapped, which
+    /// also swaps the result.
+    pub fn recolor(&self) -> Example {
+        Example {
+            game: self.game.recolor(),
+            policy: self.policy.iter().map(|&(m, v)| (m.recolor(), v)).collect(),
+            result_sum: -self.result_sum,
+            ..*self
+        }
+    }
+
+    /// Applies a random symmetry of the position.
+    fn augment<R: Rng>(self, rng: &mut R) -> Example {
+        let example = if rng.gen() { self.recolor() } else { self };
+        if !example.game.can_castle() && rng.gen() {
+            example.mirror()
+        } else {
+            example
+        }
+    }
+
+    /// The value target between 0 for a loss and 1 for a win of white,
+    /// averaged over all games that reached the position and recorded it.
+    pub fn value_target(&self) -> Option<f32> {
+        if self.count == 0 {
+            return None;
+        }
+        Some((self.result_sum / self.count as f32 + 1.) / 2.)
+    }
+
+    /// The policy target over all `MoveIndex` slots, normalized to sum up to 1.
+    pub fn policy_target(&self) -> Vec<f32> {
+        let mut target = vec![0.; 1972];
+        let total: f32 = self.policy.iter().map(|(_, visits)| visits).sum();
+        if total > 0. {
+            for &(m, visits) in self.policy.iter() {
+                target[m.0] = visits / total;
+            }
+        }
+        target
+    }
+}
+
+// ##################################################################
+// Replay buffer
+
+/// The training data of the most recent games, spread over several chunks
+/// that are given from oldest to newest.
+pub struct ReplayBuffer {
+    paths: Vec<String>,
+    options: ReplayOptions,
+    /// The games of the window in each chunk, mapped to their position in the
+    /// window, where 0 is the oldest game.
+    window: Vec<HashMap<u32, usize>>,
+    games: usize,
+}
+
+impl ReplayBuffer {
+    /// Reads through all chunks once to find the games in the window.
+    pub fn open(paths: Vec<String>, options: ReplayOptions) -> std::io::Result<Self> {
+        let mut games: Vec<(usize, u32)> = vec![];
+        for (i, path) in paths.iter().enumerate() {
+            let mut seen = HashSet::new();
+            for sample in ChunkReader::open(path)? {
+                let game = sample?.game;
+                if seen.insert(game) {
+                    games.push((i, game));
+                }
+            }
+        }
+
+        let start = games.len().saturating_sub(options.window);
+        let mut window = vec![HashMap::new(); paths.len()];
+        for (age, &(i, game)) in games[start..].iter().enumerate() {
+            window[i].insert(game, age);
+        }
+
+        Ok(Self {
+            paths,
+            options,
+            window,
+            games: games.len() - start,
+        })
+    }
+
+    /// The number of games in the window.
+    pub fn games(&self) -> usize {
+        self.games
+    }
+
+    /// The probability that a position of the game at the given position in
+    /// the window is used.
+    fn weight(&self, age: usize) -> f32 {
+        if self.games <= 1 {
+            return 1.;
+        }
+        let recency = age as f32 / (self.games - 1) as f32;
+        self.options.oldest_weight + (1. - self.options.oldest_weight) * recency
+    }
+
+    /// Streams the positions of the window in random order, reading only as
+    /// much as fits into the shuffle buffer.
+    pub fn examples<'a, R: Rng>(&'a self, rng: &'a mut R) -> Examples<'a, R> {
+        Examples {
+            replay: self,
+            rng,
+            chunk: 0,
+            reader: None,
+            buffer: vec![],
+            positions: HashMap::new(),
+        }
+    }
+}
+
+/// Iterates over the positions of a `ReplayBuffer` in random order. Positions
+/// that are repeated while they wait in the shuffle buffer are merged into one.
+pub struct Examples<'a, R: Rng> {
+    replay: &'a ReplayBuffer,
+    rng: &'a mut R,
+    chunk: usize,
+    reader: Option<ChunkReader<BufReader<File>>>,
+    buffer: Vec<(u64, Example)>,
+    /// Maps the hash of every position in the buffer to its index.
+    positions: HashMap<u64, usize>,
+}
+
+impl<'a, R: Rng> Examples<'a, R> {
+    /// Returns the next sample of the window that passes the recency sampling.
+    fn read(&mut self) -> std::io::Result<Option<Sample>> {
+        loop {
+            if self.reader.is_none() {
+                if self.chunk >= self.replay.paths.len() {
+                    return Ok(None);
+                }
+                if self.replay.window[self.chunk].is_empty() {
+                    self.chunk += 1;
+                    continue;
+                }
+                self.reader = Some(ChunkReader::open(&self.replay.paths[self.chunk])?);
+            }
+
+            match self.reader.as_mut().unwrap().next() {
+                Some(sample) => {
+                    let sample = sample?;
+                    if let Some(&age) = self.replay.window[self.chunk].get(&sample.game) {
+                        if self.rng.gen::<f32>() < self.replay.weight(age) {
+                            return Ok(Some(sample));
+                        }
+                    }
+                }
+                None => {
+                    self.reader = None;
+                    self.chunk += 1;
+                }
+            }
+        }
+    }
+
+    fn fill(&mut self) -> std::io::Result<()> {
+        while self.buffer.len() < self.replay.options.shuffle_buffer.max(1) {
+            let example = match self.read()? {
+                Some(sample) => Example::from(sample),
+                None => break,
+            };
+            let hash = example.game.hash();
+            match self.positions.get(&hash) {
+                Some(&i) => self.buffer[i].1.merge(example),
+                None => {
+                    self.positions.insert(hash, self.buffer.len());
+                    self.buffer.push((hash, example));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, R: Rng> Iterator for Examples<'a, R> {
+    type Item = std::io::Result<Example>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(err) = self.fill() {
+            return Some(Err(err));
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+
+        let i = self.rng.gen_range(0..self.buffer.len());
+        let (hash, example) = self.buffer.swap_remove(i);
+        self.positions.remove(&hash);
+        if let Some((moved, _)) = self.buffer.get(i) {
+            self.positions.insert(*moved, i);
+        }
+        if self.replay.options.augment {
+            Some(Ok(example.augment(self.rng)))
+        } else {
+            Some(Ok(example))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{ChunkWriter, Termination};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const FENS: [&str; 3] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1",
+        "6k1/5ppp/8/8/8/8/5PPP/3R2K1 b - - 0 1",
+    ];
+
+    /// Writes a chunk with the given games, each of which has one position per
+    /// FEN and the result given by its id.
+    fn write_chunk(name: &str, games: &[u32]) -> String {
+        let path = std::env::temp_dir()
+            .join(format!("mack7-replay-{}-{}.bin", std::process::id(), name))
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let mut writer = ChunkWriter::create(&path).unwrap();
+        for &game in games {
+            for (ply, fen) in FENS.iter().enumerate() {
+                writer
+                    .write(&Sample {
+                        fen: fen.to_string(),
+                        start_fen: FENS[0].to_string(),
+                        result: game as i8 % 3 - 1,
+                        termination: Termination::Rules,
+                        game,
+                        ply: ply as u16,
+                        policy: vec![(MoveIndex(game as usize), 1.)],
+                        has_policy_target: true,
+                        has_value_target: true,
+                    })
+                    .unwrap();
+            }
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn collect(replay: &ReplayBuffer, seed: u64) -> Vec<Example> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        replay
+            .examples(&mut rng)
+            .collect::<std::io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn window_keeps_most_recent_games() {
+        let paths = vec![
+            write_chunk("window-0", &[0, 1]),
+            write_chunk("window-1", &[2, 3]),
+        ];
+        let replay = ReplayBuffer::open(
+            paths,
+            ReplayOptions {
+                window: 3,
+                shuffle_buffer: 2,
+                oldest_weight: 1.,
+                augment: false,
+            },
+        )
+        .unwrap();
+        assert_eq!(replay.games(), 3);
+
+        // A small buffer only merges repeated positions that meet in it
+        let examples = collect(&replay, 0);
+        let mut moves: Vec<usize> = examples
+            .iter()
+            .flat_map(|example| example.policy.iter().map(|(m, _)| m.0))
+            .collect();
+        moves.sort_unstable();
+        moves.dedup();
+        assert_eq!(moves, vec![1, 2, 3]);
+        let visits: f32 = examples
+            .iter()
+            .flat_map(|example| example.policy.iter().map(|(_, visits)| visits))
+            .sum();
+        assert_eq!(visits, 9.);
+    }
+
+    #[test]
+    fn merges_repeated_positions() {
+        let replay = ReplayBuffer::open(
+            vec![write_chunk("merge", &[0, 1, 2])],
+            ReplayOptions {
+                window: 10,
+                shuffle_buffer: 100,
+                oldest_weight: 1.,
+                augment: false,
+            },
+        )
+        .unwrap();
+
+        let examples = collect(&replay, 0);
+        assert_eq!(examples.len(), FENS.len());
+        for example in examples {
+            assert_eq!(example.value_target(), Some(0.5));
+            let target = example.policy_target();
+            for probability in target.iter().take(3) {
+                assert!((probability - 1. / 3.).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_out_missing_targets() {
+        let sample = |has_policy_target, has_value_target| Sample {
+            fen: FENS[1].to_string(),
+            start_fen: FENS[0].to_string(),
+            result: 1,
+            termination: Termination::Rules,
+            game: 0,
+            ply: 0,
+            policy: vec![(MoveIndex(3), 1.)],
+            has_policy_target,
+            has_value_target,
+        };
+
+        let mut example = Example::from(sample(false, false));
+        assert!(example.policy_target().iter().all(|&p| p == 0.));
+        assert_eq!(example.value_target(), None);
+
+        example.merge(Example::from(sample(true, false)));
+        assert_eq!(example.policy_target()[3], 1.);
+        assert_eq!(example.value_target(), None);
+
+        example.merge(Example::from(sample(false, true)));
+        assert_eq!(example.value_target(), Some(1.));
+    }
+
+    #[test]
+    fn samples_recent_games_more_often() {
+        let replay = ReplayBuffer::open(
+            vec![write_chunk("recency", &[0, 1, 2])],
+            ReplayOptions {
+                window: 10,
+                shuffle_buffer: 1,
+                oldest_weight: 0.,
+                augment: false,
+            },
+        )
+        .unwrap();
+
+        let mut counts = [0; 3];
+        for seed in 0..100 {
+            for example in collect(&replay, seed) {
+                counts[example.policy[0].0 .0] += 1;
+            }
+        }
+        assert_eq!(counts[0], 0);
+        assert_eq!(counts[2], 300);
+        assert!(counts[1] > 100 && counts[1] < 200);
+    }
+
+    #[test]
+    fn symmetries_keep_policies_legal() {
+        for i in 0..1972 {
+            let m = MoveIndex(i);
+            assert_eq!(m.recolor().recolor(), m);
+            if i >= 4 {
+                assert_eq!(m.mirror().mirror(), m);
+            }
+        }
+
+        for fen in [
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "r3k2r/pPppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPpP/R3K2R b Kq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "6k1/5ppp/8/8/3pP3/8/5PPP/3R2K1 b - e3 0 1",
+            "n1n5/PPPk4/8/8/8/8/4Kppp/5N1N w - - 0 1",
+        ] {
+            let game = Game::from_fen(fen);
+            let example = Example {
+                policy: game
+                    .legal_moves(game.player)
+                    .iter()
+                    .map(|m| (m.index(), 1.))
+                    .collect(),
+                game,
+                result_sum: 1.,
+                count: 1,
+            };
+
+            let mut augmented = vec![example.recolor()];
+            if !example.game.can_castle() {
+                augmented.push(example.mirror());
+                augmented.push(example.recolor().mirror());
+            }
+            for transformed in augmented {
+                let legal = transformed.game.legal_move_mask();
+                let target = transformed.policy_target();
+                assert_eq!(legal.sum() as usize, example.policy.len());
+                for (m, probability) in target.iter().enumerate() {
+                    assert!(*probability == 0. || legal[m] == 1., "{} in {}", m, fen);
+                }
+            }
+            assert_eq!(example.recolor().value_target(), Some(0.));
+        }
+    }
+}
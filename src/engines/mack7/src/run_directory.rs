@@ -0,0 +1,238 @@
+//! The games of a self-play run are saved in their own directory, each game
+//! in a chunk of its own as soon as it ends:
+//!
+//! ```text
+//! games.IDX/
+//!     game-000000.bin
+//!     game-000001.bin
+//!     ...
+//!     manifest.json
+//!     metrics.jsonl
+//! ```
+//!
+//! Games are written to a temporary file that is renamed once it is complete,
+//! so a crash never leaves half a game behind. The manifest keeps track of the
+//! progress, and a resumed run continues after the games that were written.
+//! The statistics of the run are appended to the metrics file, one JSON
+//! object per line.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::chunk::{self, ChunkReader, ChunkWriter, Sample};
+use crate::metrics::Report;
+
+const MANIFEST: &str = "manifest.json";
+const METRICS: &str = "metrics.jsonl";
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    /// The number of games the run plays in total.
+    pub total_games: u32,
+    pub finished_games: u32,
+    /// The id of the next game to start. Games that were in progress when a
+    /// run stopped are dropped, so ids can have gaps.
+    pub next_game: u32,
+    pub positions: u64,
+    /// Whether the run was interrupted before all games were played.
+    pub interrupted: bool,
+}
+
+/// Returns the directory of the run with the given index.
+pub fn path(run_index: &str) -> PathBuf {
+    PathBuf::from(format!("games.{}", run_index))
+}
+
+/// Returns the chunks with the games of a run, which are either in the
+/// directory of the run or, for older runs, in a single chunk.
+pub fn chunks(run_index: &str) -> std::io::Result<Vec<String>> {
+    let directory = path(run_index);
+    if !directory.is_dir() {
+        return Ok(vec![chunk::filename(run_index)]);
+    }
+    Ok(RunDirectory { path: directory }
+        .games()?
+        .into_iter()
+        .map(|(_, path)| path.to_string_lossy().into_owned())
+        .collect())
+}
+
+pub struct RunDirectory {
+    path: PathBuf,
+}
+
+impl RunDirectory {
+    /// Opens the directory of a run. A new run must not have any games yet,
+    /// while a resumed run has to exist and continues after its games.
+    pub fn open<P: AsRef<Path>>(path: P, resume: bool) -> std::io::Result<(Self, Manifest)> {
+        let directory = RunDirectory {
+            path: path.as_ref().to_path_buf(),
+        };
+        let manifest_path = directory.path.join(MANIFEST);
+
+        if !resume {
+            if manifest_path.exists() || !directory.games()?.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!(
+                        "{} already has games, continue the run with --resume",
+                        directory.path.display()
+                    ),
+                ));
+            }
+            fs::create_dir_all(&directory.path)?;
+            return Ok((directory, Manifest::default()));
+        }
+
+        if !directory.path.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("There is no run to resume in {}", directory.path.display()),
+            ));
+        }
+        for entry in fs::read_dir(&directory.path)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let mut manifest: Manifest = if manifest_path.exists() {
+            serde_json::from_reader(File::open(&manifest_path)?)?
+        } else {
+            Manifest::default()
+        };
+        // The games on disk are the truth, the manifest might be from before
+        // the last game was written
+        let games = directory.games()?;
+        manifest.finished_games = games.len() as u32;
+        manifest.next_game = manifest
+            .next_game
+            .max(games.last().map_or(0, |&(id, _)| id + 1));
+        manifest.positions = 0;
+        for (_, path) in games {
+            for sample in ChunkReader::open(path)? {
+                sample?;
+                manifest.positions += 1;
+            }
+        }
+        Ok((directory, manifest))
+    }
+
+    /// Returns the ids and paths of all written games, ordered by id.
+    pub fn games(&self) -> std::io::Result<Vec<(u32, PathBuf)>> {
+        if !self.path.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut games = vec![];
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            let id = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix("game-"))
+                .and_then(|name| name.strip_suffix(".bin"))
+                .and_then(|id| id.parse::<u32>().ok());
+            if let Some(id) = id {
+                games.push((id, path));
+            }
+        }
+        games.sort();
+        Ok(games)
+    }
+
+    /// Writes the positions of a finished game.
+    pub fn write_game(&self, id: u32, samples: &[Sample]) -> std::io::Result<()> {
+        let path = self.path.join(format!("game-{:06}.bin", id));
+        let temporary = path.with_extension("tmp");
+        let mut writer = ChunkWriter::create(&temporary)?;
+        for sample in samples {
+            writer.write(sample)?;
+        }
+        writer.finish()?.into_inner()?.sync_all()?;
+        fs::rename(temporary, path)
+    }
+
+    pub fn write_manifest(&self, manifest: &Manifest) -> std::io::Result<()> {
+        let path = self.path.join(MANIFEST);
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        serde_json::to_writer_pretty(&mut writer, manifest)?;
+        writer.flush()?;
+        fs::rename(temporary, path)
+    }
+
+    pub fn append_metrics(&self, report: &Report) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.join(METRICS))?;
+        let mut line = serde_json::to_vec(report)?;
+        line.push(b'\n');
+        file.write_all(&line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chess_move::MoveIndex;
+    use crate::chunk::Termination;
+
+    fn sample(game: u32, ply: u16) -> Sample {
+        Sample {
+            fen: String::from("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1"),
+            start_fen: String::from("6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1"),
+            result: 0,
+            termination: Termination::Rules,
+            game,
+            ply,
+            policy: vec![(MoveIndex(3), 1.)],
+            has_policy_target: true,
+            has_value_target: true,
+        }
+    }
+
+    #[test]
+    fn resumes_after_written_games() {
+        let path = std::env::temp_dir().join(format!("mack7-run-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        assert!(RunDirectory::open(&path, true).is_err());
+
+        let (directory, mut manifest) = RunDirectory::open(&path, false).unwrap();
+        manifest.total_games = 4;
+        directory
+            .write_game(0, &[sample(0, 0), sample(0, 1)])
+            .unwrap();
+        directory.write_game(2, &[sample(2, 0)]).unwrap();
+        manifest.next_game = 3;
+        manifest.finished_games = 1;
+        directory.write_manifest(&manifest).unwrap();
+        // A game that was being written when the run stopped
+        fs::write(path.join("game-000003.tmp"), b"MK7D").unwrap();
+
+        assert!(RunDirectory::open(&path, false).is_err());
+        let (directory, manifest) = RunDirectory::open(&path, true).unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                total_games: 4,
+                finished_games: 2,
+                next_game: 3,
+                positions: 3,
+                interrupted: false,
+            }
+        );
+        assert!(!path.join("game-000003.tmp").exists());
+        let ids: Vec<u32> = directory
+            .games()
+            .unwrap()
+            .iter()
+            .map(|&(id, _)| id)
+            .collect();
+        assert_eq!(ids, vec![0, 2]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,493 @@
+//! Statistics of match results: Elo differences with a 95% confidence
+//! interval, either from wins, draws and losses or from the results of game
+//! pairs that played the same opening with swapped colors, and a sequential
+//! probability ratio test (SPRT) between two Elo differences.
+//!
+//! The distribution of the score is approximated as normal throughout, as in
+//! the usual tools for engine testing.
+
+/// The quantile of the standard normal distribution for a two-sided 95%
+/// interval.
+const Z_95: f64 = 1.959_964;
+
+/// The count that the SPRT adds to every outcome of a game or pair before
+/// it estimates the score and its variance. Without it, results on only one
+/// side of the average, as in a sweep or only draws, have no variance, and
+/// the first games would decide the test.
+const PSEUDO_COUNT: f64 = 0.5;
+
+/// Returns the expected score of a player that is stronger by the given Elo.
+pub fn expected_score(elo: f64) -> f64 {
+    1. / (1. + 10f64.powf(-elo / 400.))
+}
+
+/// Returns the Elo difference of a player that scores the given fraction of
+/// the points.
+pub fn elo(score: f64) -> f64 {
+    if score <= 0. {
+        f64::NEG_INFINITY
+    } else if score >= 1. {
+        f64::INFINITY
+    } else {
+        400. * (score / (1. - score)).log10()
+    }
+}
+
+/// An Elo difference with the bounds of its 95% confidence interval.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Elo {
+    pub elo: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+impl Elo {
+    /// Returns the Elo difference for the average score per game and the
+    /// variance of that average.
+    fn new(score: f64, variance: f64) -> Elo {
+        let deviation = variance.sqrt();
+        Elo {
+            elo: elo(score),
+            lower: elo(score - Z_95 * deviation),
+            upper: elo(score + Z_95 * deviation),
+        }
+    }
+
+    /// An estimate without any games, which could be anything.
+    fn unknown() -> Elo {
+        Elo {
+            elo: 0.,
+            lower: f64::NEG_INFINITY,
+            upper: f64::INFINITY,
+        }
+    }
+
+    /// The half width of the confidence interval.
+    pub fn error(&self) -> f64 {
+        (self.upper - self.lower) / 2.
+    }
+}
+
+impl std::fmt::Display for Elo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Elo {:.1} +/- {:.1}", self.elo, self.error())
+    }
+}
+
+/// Wins, draws and losses from the perspective of one side.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Score {
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+}
+
+impl Score {
+    /// Adds a result between -1 and 1.
+    pub fn add(&mut self, result: i8) {
+        match result {
+            1 => self.wins += 1,
+            -1 => self.losses += 1,
+            _ => self.draws += 1,
+        }
+    }
+
+    /// The same games from the perspective of the opponent.
+    pub fn reversed(&self) -> Score {
+        Score {
+            wins: self.losses,
+            draws: self.draws,
+            losses: self.wins,
+        }
+    }
+
+    pub fn games(&self) -> u32 {
+        self.wins + self.draws + self.losses
+    }
+
+    /// The fraction of the points scored.
+    pub fn score(&self) -> f64 {
+        (self.wins as f64 + self.draws as f64 / 2.) / self.games().max(1) as f64
+    }
+
+    /// The variance of the average score over all games.
+    fn variance(&self) -> f64 {
+        let games = self.games() as f64;
+        let score = self.score();
+        (self.wins as f64 * (1. - score).powi(2)
+            + self.draws as f64 * (0.5 - score).powi(2)
+            + self.losses as f64 * score.powi(2))
+            / games
+            / games
+    }
+
+    pub fn elo(&self) -> Elo {
+        if self.games() == 0 {
+            return Elo::unknown();
+        }
+        Elo::new(self.score(), self.variance())
+    }
+}
+
+impl std::ops::AddAssign for Score {
+    fn add_assign(&mut self, other: Score) {
+        self.wins += other.wins;
+        self.draws += other.draws;
+        self.losses += other.losses;
+    }
+}
+
+impl std::fmt::Display for Score {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "+{} ={} -{}, score {:.3}, {}",
+            self.wins,
+            self.draws,
+            self.losses,
+            self.score(),
+            self.elo()
+        )
+    }
+}
+
+/// The results of game pairs, where both players had each color once in the
+/// same opening. Counts the pairs that scored 0, 0.5, 1, 1.5 and 2 points.
+/// As the openings favor one side, the two games of a pair are correlated,
+/// and treating them as a pair gives tighter and more honest bounds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pentanomial {
+    pub counts: [u32; 5],
+}
+
+impl Pentanomial {
+    /// Adds a pair of results between -1 and 1.
+    pub fn add(&mut self, first: i8, second: i8) {
+        self.counts[(first + second + 2) as usize] += 1;
+    }
+
+    pub fn pairs(&self) -> u32 {
+        self.counts.iter().sum()
+    }
+
+    /// The fraction of the points scored.
+    pub fn score(&self) -> f64 {
+        let points: f64 = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(points, &count)| points as f64 / 4. * count as f64)
+            .sum();
+        points / self.pairs().max(1) as f64
+    }
+
+    /// The variance of the average score over all pairs.
+    fn variance(&self) -> f64 {
+        let pairs = self.pairs() as f64;
+        let score = self.score();
+        let variance: f64 = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(points, &count)| count as f64 * (points as f64 / 4. - score).powi(2))
+            .sum();
+        variance / pairs / pairs
+    }
+
+    pub fn elo(&self) -> Elo {
+        if self.pairs() == 0 {
+            return Elo::unknown();
+        }
+        Elo::new(self.score(), self.variance())
+    }
+}
+
+impl std::str::FromStr for Pentanomial {
+    type Err = String;
+
+    /// Parses the five counts separated by commas.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let counts: Vec<u32> = s
+            .split(',')
+            .map(|count| count.trim().parse::<u32>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("Expected five counts separated by commas, got {}", s))?;
+        match counts.try_into() {
+            Ok(counts) => Ok(Pentanomial { counts }),
+            Err(_) => Err(format!(
+                "Expected five counts separated by commas, got {}",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Pentanomial {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let counts: Vec<String> = self.counts.iter().map(|count| count.to_string()).collect();
+        write!(
+            f,
+            "pairs [{}], score {:.3}, {}",
+            counts.join(", "),
+            self.score(),
+            self.elo()
+        )
+    }
+}
+
+/// Returns the average score and the variance of that average for outcomes
+/// worth the given points that happened the given number of times, with
+/// the pseudo-count added to every outcome.
+fn regularized(outcomes: &[(f64, u32)]) -> (f64, f64) {
+    let counts: Vec<(f64, f64)> = outcomes
+        .iter()
+        .map(|&(points, count)| (points, count as f64 + PSEUDO_COUNT))
+        .collect();
+    let samples: f64 = counts.iter().map(|(_, count)| count).sum();
+    let score = counts
+        .iter()
+        .map(|(points, count)| points * count)
+        .sum::<f64>()
+        / samples;
+    let variance = counts
+        .iter()
+        .map(|(points, count)| count * (points - score).powi(2))
+        .sum::<f64>()
+        / samples
+        / samples;
+    (score, variance)
+}
+
+/// A sequential probability ratio test of the Elo difference `elo1` against
+/// `elo0`, with the probabilities `alpha` of accepting `elo1` when `elo0` is
+/// true and `beta` of accepting `elo0` when `elo1` is true.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sprt {
+    pub elo0: f64,
+    pub elo1: f64,
+    pub alpha: f64,
+    pub beta: f64,
+}
+
+impl Sprt {
+    /// Returns the bounds of the log-likelihood ratio below which `elo0` and
+    /// above which `elo1` is accepted.
+    pub fn bounds(&self) -> (f64, f64) {
+        (
+            (self.beta / (1. - self.alpha)).ln(),
+            ((1. - self.beta) / self.alpha).ln(),
+        )
+    }
+
+    /// Returns the log-likelihood ratio for an average score with the given
+    /// variance of that average.
+    fn llr(&self, (score, variance): (f64, f64)) -> f64 {
+        let (score0, score1) = (expected_score(self.elo0), expected_score(self.elo1));
+        (score1 - score0) * (2. * score - score0 - score1) / (2. * variance)
+    }
+
+    /// Returns the log-likelihood ratio for wins, draws and losses.
+    pub fn llr_trinomial(&self, score: &Score) -> f64 {
+        if score.games() == 0 {
+            return 0.;
+        }
+        self.llr(regularized(&[
+            (1., score.wins),
+            (0.5, score.draws),
+            (0., score.losses),
+        ]))
+    }
+
+    /// Returns the log-likelihood ratio for the results of game pairs.
+    pub fn llr_pentanomial(&self, pentanomial: &Pentanomial) -> f64 {
+        if pentanomial.pairs() == 0 {
+            return 0.;
+        }
+        let outcomes: Vec<(f64, u32)> = pentanomial
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(points, &count)| (points as f64 / 4., count))
+            .collect();
+        self.llr(regularized(&outcomes))
+    }
+
+    /// Returns whether the test accepted `elo1`, or `None` if it needs more
+    /// games.
+    pub fn decision(&self, llr: f64) -> Option<bool> {
+        let (lower, upper) = self.bounds();
+        if llr >= upper {
+            Some(true)
+        } else if llr <= lower {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Prints the statistics of the given results, and the state of the SPRT if
+/// one is given.
+pub fn report(score: Option<Score>, pentanomial: Option<Pentanomial>, sprt: Option<Sprt>) {
+    if let Some(score) = score {
+        println!("Games: {}", score);
+    }
+    if let Some(pentanomial) = pentanomial {
+        println!("Pairs: {}", pentanomial);
+    }
+
+    let sprt = match sprt {
+        Some(sprt) => sprt,
+        None => return,
+    };
+    // Pairs are preferred, since they account for the openings
+    let llr = match (pentanomial, score) {
+        (Some(pentanomial), _) => sprt.llr_pentanomial(&pentanomial),
+        (None, Some(score)) => sprt.llr_trinomial(&score),
+        (None, None) => 0.,
+    };
+    let (lower, upper) = sprt.bounds();
+    println!(
+        "SPRT of elo0 {} against elo1 {}: LLR {:.2} in [{:.2}, {:.2}], {}",
+        sprt.elo0,
+        sprt.elo1,
+        llr,
+        lower,
+        upper,
+        match sprt.decision(llr) {
+            Some(true) => "elo1 accepted",
+            Some(false) => "elo0 accepted",
+            None => "undecided",
+        }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elo_of_scores() {
+        assert!((elo(0.75) - 190.848_50).abs() < 1e-4);
+        assert!((elo(0.6) - 70.436_50).abs() < 1e-4);
+        assert_eq!(elo(0.5), 0.);
+        assert_eq!(elo(1.), f64::INFINITY);
+        assert!((expected_score(elo(0.6)) - 0.6).abs() < 1e-12);
+    }
+
+    #[test]
+    fn trinomial_reference_values() {
+        let score = Score {
+            wins: 60,
+            draws: 20,
+            losses: 20,
+        };
+        let elo = score.elo();
+        assert!((elo.elo - 147.190_71).abs() < 1e-4);
+        assert!((elo.lower - 86.225_01).abs() < 1e-3);
+        assert!((elo.upper - 218.251_78).abs() < 1e-3);
+        assert!((elo.error() - 66.013_38).abs() < 1e-3);
+        assert!((score.reversed().elo().elo + elo.elo).abs() < 1e-9);
+
+        let elo = Score {
+            wins: 10,
+            draws: 30,
+            losses: 10,
+        }
+        .elo();
+        assert_eq!(elo.elo, 0.);
+        assert!((elo.error() - 61.542_71).abs() < 1e-3);
+
+        assert_eq!(Score::default().elo().error(), f64::INFINITY);
+    }
+
+    #[test]
+    fn pentanomial_reference_values() {
+        let pentanomial = Pentanomial {
+            counts: [5, 10, 20, 15, 10],
+        };
+        assert_eq!(pentanomial.score(), 0.5625);
+        let elo = pentanomial.elo();
+        assert!((elo.elo - 43.657_79).abs() < 1e-4);
+        assert!((elo.lower + 7.722_67).abs() < 1e-3);
+        assert!((elo.upper - 97.026_33).abs() < 1e-3);
+
+        assert_eq!("5, 10,20,15,10".parse(), Ok(pentanomial));
+        assert!("5,10,20,15".parse::<Pentanomial>().is_err());
+        assert!("5,10,20,15,x".parse::<Pentanomial>().is_err());
+
+        let mut pentanomial = Pentanomial::default();
+        pentanomial.add(1, -1);
+        pentanomial.add(1, 0);
+        pentanomial.add(-1, -1);
+        assert_eq!(pentanomial.counts, [1, 0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn sprt_reference_values() {
+        let sprt = Sprt {
+            elo0: 0.,
+            elo1: 10.,
+            alpha: 0.05,
+            beta: 0.05,
+        };
+        let (lower, upper) = sprt.bounds();
+        assert!((lower + 2.944_44).abs() < 1e-4);
+        assert!((upper - 2.944_44).abs() < 1e-4);
+
+        // Score 0.7 with a variance of 0.16 per game, and a pseudo-count of
+        // 0.5 for every outcome
+        let score = Score {
+            wins: 60,
+            draws: 20,
+            losses: 20,
+        };
+        assert!((sprt.llr_trinomial(&score) - 1.725_40).abs() < 1e-4);
+        assert!(sprt.llr_trinomial(&score.reversed()) < 0.);
+        assert_eq!(sprt.llr_trinomial(&Score::default()), 0.);
+        assert_eq!(sprt.decision(1.725_40), None);
+
+        let pentanomial = Pentanomial {
+            counts: [5, 10, 20, 15, 10],
+        };
+        assert!((sprt.llr_pentanomial(&pentanomial) - 0.549_58).abs() < 1e-4);
+
+        let score = Score {
+            wins: 600,
+            draws: 200,
+            losses: 200,
+        };
+        assert_eq!(sprt.decision(sprt.llr_trinomial(&score)), Some(true));
+        assert_eq!(
+            sprt.decision(sprt.llr_trinomial(&score.reversed())),
+            Some(false)
+        );
+
+        // A few results on one side of the average don't decide the test
+        let decision = |wins, draws, losses| {
+            let score = Score {
+                wins,
+                draws,
+                losses,
+            };
+            sprt.decision(sprt.llr_trinomial(&score))
+        };
+        assert_eq!(decision(1, 0, 0), None);
+        assert_eq!(decision(2, 0, 0), None);
+        assert_eq!(decision(0, 3, 0), None);
+        assert_eq!(decision(0, 0, 2), None);
+        for counts in [[0, 0, 0, 0, 1], [0, 0, 0, 0, 2], [0, 0, 3, 0, 0]] {
+            let pentanomial = Pentanomial { counts };
+            assert_eq!(sprt.decision(sprt.llr_pentanomial(&pentanomial)), None);
+        }
+
+        // But long sweeps and long runs of draws do
+        assert_eq!(decision(50, 0, 0), Some(true));
+        assert_eq!(decision(0, 0, 50), Some(false));
+        assert_eq!(decision(0, 1000, 0), Some(false));
+        let sweep = Pentanomial {
+            counts: [0, 0, 0, 0, 30],
+        };
+        assert_eq!(sprt.decision(sprt.llr_pentanomial(&sweep)), Some(true));
+    }
+}
@@ -0,0 +1,1360 @@
+//! Probing of Syzygy endgame tablebases. WDL tables tell whether a position
+//! with few pieces is won, drawn or lost, and DTZ tables tell how many plies
+//! remain until the next capture or pawn move on the way to that result. The
+//! decoder follows the reference prober of the format. Tables are read from a
+//! directory when they are first needed.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::{chess_move::Move, game::Game, game::GameResult, piece::Piece};
+
+/// The most pieces any Syzygy table covers.
+const MAX_PIECES: usize = 7;
+
+/// Beyond the DTZ of any position, to rank moves by their DTZ.
+const MAX_DTZ: i32 = 1 << 18;
+
+const WDL_MAGIC: [u8; 4] = [0x71, 0xE8, 0x23, 0x5D];
+const DTZ_MAGIC: [u8; 4] = [0xD7, 0x66, 0x0C, 0xA5];
+
+// The flags of the compressed data of a table
+const STM: u8 = 1;
+const MAPPED: u8 = 2;
+const WIN_PLIES: u8 = 4;
+const LOSS_PLIES: u8 = 8;
+const WIDE: u8 = 16;
+const SINGLE_VALUE: u8 = 128;
+
+/// The result of a position for the player to move. Cursed wins and blessed
+/// losses are wins and losses that the fifty move rule turns into draws.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Wdl {
+    Loss,
+    BlessedLoss,
+    Draw,
+    CursedWin,
+    Win,
+}
+
+impl Wdl {
+    fn from_i32(value: i32) -> Wdl {
+        match value {
+            -2 => Wdl::Loss,
+            -1 => Wdl::BlessedLoss,
+            0 => Wdl::Draw,
+            1 => Wdl::CursedWin,
+            _ => Wdl::Win,
+        }
+    }
+
+    /// Returns the result for the player to move under the fifty move rule,
+    /// 1 for a win, 0 for a draw and -1 for a loss.
+    pub fn score(self) -> i8 {
+        match self {
+            Wdl::Loss => -1,
+            Wdl::Win => 1,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Wdl,
+    Dtz,
+}
+
+/// The number of pieces of every type for white and black, in the order pawn,
+/// knight, bishop, rook, queen and king.
+type Material = [[u8; 6]; 2];
+
+/// Parses the material of a table name like KRPvKR.
+fn parse_material(name: &str) -> Option<Material> {
+    let (white, black) = name.split_once('v')?;
+    let mut material = [[0; 6]; 2];
+    for (color, side) in [white, black].iter().enumerate() {
+        for c in side.chars() {
+            let piece = "PNBRQK".find(c)?;
+            material[color][piece] += 1;
+        }
+        if material[color][5] != 1 {
+            return None;
+        }
+    }
+    if piece_count(&material) > MAX_PIECES {
+        return None;
+    }
+    Some(material)
+}
+
+fn piece_count(material: &Material) -> usize {
+    material.iter().flatten().map(|&count| count as usize).sum()
+}
+
+fn swap_colors(material: &Material) -> Material {
+    [material[1], material[0]]
+}
+
+fn game_material(game: &Game) -> Material {
+    let count = |pieces: &crate::position::Pieces| {
+        [
+            pieces.pawn.count_ones() as u8,
+            pieces.knight.count_ones() as u8,
+            pieces.bishop.count_ones() as u8,
+            pieces.rook.count_ones() as u8,
+            pieces.queen.count_ones() as u8,
+            pieces.king.count_ones() as u8,
+        ]
+    };
+    [count(&game.position.white), count(&game.position.black)]
+}
+
+/// Returns the pieces on the squares with the codes of the tables, 1 to 6 for
+/// the white pawn to king, the same plus 8 for black pieces and 0 for empty
+/// squares.
+fn board(game: &Game) -> [u8; 64] {
+    let mut board = [0; 64];
+    for (color, pieces) in [(0, &game.position.white), (8, &game.position.black)] {
+        let types = [
+            pieces.pawn,
+            pieces.knight,
+            pieces.bishop,
+            pieces.rook,
+            pieces.queen,
+            pieces.king,
+        ];
+        for (code, bitboard) in types.iter().enumerate() {
+            for square in bitboard.into_iter() {
+                board[square.square()] = color | (code as u8 + 1);
+            }
+        }
+    }
+    board
+}
+
+fn rank(square: usize) -> usize {
+    square >> 3
+}
+
+fn file(square: usize) -> usize {
+    square & 7
+}
+
+/// Returns how far the square is above the a1-h8 diagonal, negative below it.
+fn off_diagonal(square: usize) -> i32 {
+    rank(square) as i32 - file(square) as i32
+}
+
+/// The tables that map squares and groups of pieces to indices.
+struct Indices {
+    /// Maps the squares a2 to h7 so that the leading pawn has the highest value.
+    map_pawns: [usize; 64],
+    /// Maps the squares below the a1-h8 diagonal to 0..28.
+    map_b1h1h7: [usize; 64],
+    /// Maps the squares of the a1-d1-d4 triangle to 0..10.
+    map_a1d1d4: [usize; 64],
+    /// Maps the 462 legal placements of two kings with the first one in the
+    /// a1-d1-d4 triangle.
+    map_kk: [[usize; 64]; 10],
+    /// The binomial coefficients by number of pieces and number of squares.
+    binomial: [[u64; 64]; 6],
+    lead_pawn_idx: [[u64; 64]; 6],
+    lead_pawns_size: [[u64; 4]; 6],
+}
+
+impl Indices {
+    fn new() -> Indices {
+        let mut indices = Indices {
+            map_pawns: [0; 64],
+            map_b1h1h7: [0; 64],
+            map_a1d1d4: [0; 64],
+            map_kk: [[0; 64]; 10],
+            binomial: [[0; 64]; 6],
+            lead_pawn_idx: [[0; 64]; 6],
+            lead_pawns_size: [[0; 4]; 6],
+        };
+
+        let mut code = 0;
+        for square in 0..64 {
+            if off_diagonal(square) < 0 {
+                indices.map_b1h1h7[square] = code;
+                code += 1;
+            }
+        }
+
+        // Squares on the diagonal come last
+        let mut diagonal = vec![];
+        code = 0;
+        for square in 0..=27 {
+            if off_diagonal(square) < 0 && file(square) <= 3 {
+                indices.map_a1d1d4[square] = code;
+                code += 1;
+            } else if off_diagonal(square) == 0 && file(square) <= 3 {
+                diagonal.push(square);
+            }
+        }
+        for square in diagonal {
+            indices.map_a1d1d4[square] = code;
+            code += 1;
+        }
+
+        // With the first king on the diagonal the second one can't be above
+        // it, and placements with both kings on the diagonal come last
+        let mut both_on_diagonal = vec![];
+        code = 0;
+        for idx in 0..10 {
+            for s1 in 0..=27 {
+                if indices.map_a1d1d4[s1] != idx || (idx == 0 && s1 != 1) {
+                    continue;
+                }
+                for s2 in 0..64 {
+                    let distance = file(s1).abs_diff(file(s2)).max(rank(s1).abs_diff(rank(s2)));
+                    if distance <= 1 || (off_diagonal(s1) == 0 && off_diagonal(s2) > 0) {
+                        continue;
+                    }
+                    if off_diagonal(s1) == 0 && off_diagonal(s2) == 0 {
+                        both_on_diagonal.push((idx, s2));
+                    } else {
+                        indices.map_kk[idx][s2] = code;
+                        code += 1;
+                    }
+                }
+            }
+        }
+        for (idx, s2) in both_on_diagonal {
+            indices.map_kk[idx][s2] = code;
+            code += 1;
+        }
+
+        indices.binomial[0][0] = 1;
+        for n in 1..64 {
+            for k in 0..6.min(n + 1) {
+                indices.binomial[k][n] = (if k > 0 {
+                    indices.binomial[k - 1][n - 1]
+                } else {
+                    0
+                }) + (if k < n { indices.binomial[k][n - 1] } else { 0 });
+            }
+        }
+
+        // The leading pawn is the one closest to the edge and, on the same
+        // file, the one on the lowest rank
+        let mut available: i32 = 47;
+        for lead_pawns in 1..=5 {
+            for f in 0..4 {
+                let mut idx = 0;
+                for r in 1..7 {
+                    let square = r * 8 + f;
+                    if lead_pawns == 1 {
+                        indices.map_pawns[square] = available as usize;
+                        indices.map_pawns[square ^ 7] = available as usize - 1;
+                        available -= 2;
+                    }
+                    indices.lead_pawn_idx[lead_pawns][square] = idx;
+                    idx += indices.binomial[lead_pawns - 1][indices.map_pawns[square]];
+                }
+                indices.lead_pawns_size[lead_pawns][f] = idx;
+            }
+        }
+
+        indices
+    }
+}
+
+fn indices() -> &'static Indices {
+    static INDICES: OnceLock<Indices> = OnceLock::new();
+    INDICES.get_or_init(Indices::new)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads big endian bytes for the Huffman decoder, as zeros past the end of
+/// the file.
+fn read_be(data: &[u8], offset: usize, bytes: usize) -> u64 {
+    (0..bytes).fold(0, |value, i| {
+        (value << 8) | *data.get(offset + i).unwrap_or(&0) as u64
+    })
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::InvalidData, "Truncated table")
+}
+
+/// The compressed values of one side to move and one file of the leading pawn.
+#[derive(Clone, Default)]
+struct PairsData {
+    flags: u8,
+    block_size: u64,
+    span: u64,
+    num_blocks: u64,
+    min_sym_len: u8,
+    lowest_sym: usize,
+    btree: usize,
+    block_length: usize,
+    block_length_size: u64,
+    sparse_index: usize,
+    sparse_index_size: u64,
+    data: usize,
+    base64: Vec<u64>,
+    /// The number of values minus one that every symbol expands to.
+    symlen: Vec<u8>,
+    pieces: [u8; MAX_PIECES],
+    group_idx: [u64; MAX_PIECES + 1],
+    group_len: [usize; MAX_PIECES + 1],
+    map_idx: [usize; 4],
+}
+
+struct Table {
+    data: Vec<u8>,
+    kind: Kind,
+    key: Material,
+    symmetric: bool,
+    piece_count: usize,
+    has_pawns: bool,
+    has_unique_pieces: bool,
+    /// The pawns of the leading color and of the other one.
+    pawn_count: [usize; 2],
+    /// The compressed values by file and side to move.
+    pairs: Vec<Vec<PairsData>>,
+    /// The start of the value maps of DTZ tables.
+    map: usize,
+}
+
+impl Table {
+    fn new(kind: Kind, key: Material, data: Vec<u8>) -> Result<Table, Error> {
+        let magic = if kind == Kind::Wdl {
+            WDL_MAGIC
+        } else {
+            DTZ_MAGIC
+        };
+        if data.len() < 5 || data[..4] != magic {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a Syzygy table"));
+        }
+
+        let [white_pawns, black_pawns] = [key[0][0] as usize, key[1][0] as usize];
+        let white_leads = black_pawns == 0 || (white_pawns > 0 && black_pawns >= white_pawns);
+        let mut table = Table {
+            data,
+            kind,
+            key,
+            symmetric: key == swap_colors(&key),
+            piece_count: piece_count(&key),
+            has_pawns: white_pawns + black_pawns > 0,
+            has_unique_pieces: key.iter().any(|side| side[..5].contains(&1)),
+            pawn_count: if white_leads {
+                [white_pawns, black_pawns]
+            } else {
+                [black_pawns, white_pawns]
+            },
+            pairs: vec![],
+            map: 0,
+        };
+        table.init()?;
+        Ok(table)
+    }
+
+    fn init(&mut self) -> Result<(), Error> {
+        let data = &self.data;
+        let flags = data[4];
+        if (flags & 2 != 0) != self.has_pawns || (flags & 1 != 0) == self.symmetric {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Table does not match its name",
+            ));
+        }
+
+        let sides = if self.kind == Kind::Wdl && !self.symmetric {
+            2
+        } else {
+            1
+        };
+        let files = if self.has_pawns { 4 } else { 1 };
+        let pawns_on_both_sides = self.has_pawns && self.pawn_count[1] > 0;
+        let byte = |offset: usize| data.get(offset).copied().ok_or_else(truncated);
+
+        let mut pairs = vec![vec![PairsData::default(); sides]; files];
+        let mut p = 5;
+        for (f, file_pairs) in pairs.iter_mut().enumerate() {
+            let first = byte(p)?;
+            let second = if pawns_on_both_sides {
+                Some(byte(p + 1)?)
+            } else {
+                None
+            };
+            let order = [
+                [first & 0xF, second.map_or(0xF, |byte| byte & 0xF)],
+                [first >> 4, second.map_or(0xF, |byte| byte >> 4)],
+            ];
+            p += 1 + pawns_on_both_sides as usize;
+            for k in 0..self.piece_count {
+                let byte = byte(p)?;
+                for (side, d) in file_pairs.iter_mut().enumerate() {
+                    d.pieces[k] = if side == 1 { byte >> 4 } else { byte & 0xF };
+                }
+                p += 1;
+            }
+            for (side, d) in file_pairs.iter_mut().enumerate() {
+                self.set_groups(d, order[side], f);
+            }
+        }
+        p += p & 1;
+
+        for d in pairs.iter_mut().flatten() {
+            p = set_sizes(data, d, p)?;
+        }
+
+        let map = p;
+        if self.kind == Kind::Dtz {
+            for file_pairs in pairs.iter_mut() {
+                let d = &mut file_pairs[0];
+                if d.flags & MAPPED == 0 {
+                    continue;
+                }
+                if d.flags & WIDE != 0 {
+                    p += p & 1;
+                    for i in 0..4 {
+                        d.map_idx[i] = (p - map) / 2 + 1;
+                        p += 2 * read_u16(data, p).ok_or_else(truncated)? as usize + 2;
+                    }
+                } else {
+                    for i in 0..4 {
+                        d.map_idx[i] = p - map + 1;
+                        p += byte(p)? as usize + 1;
+                    }
+                }
+            }
+            p += p & 1;
+        }
+
+        for d in pairs.iter_mut().flatten() {
+            d.sparse_index = p;
+            p += d.sparse_index_size as usize * 6;
+        }
+        for d in pairs.iter_mut().flatten() {
+            d.block_length = p;
+            p += d.block_length_size as usize * 2;
+        }
+        for d in pairs.iter_mut().flatten() {
+            if d.num_blocks > 0 {
+                p = (p + 0x3F) & !0x3F;
+            }
+            d.data = p;
+            p += (d.num_blocks * d.block_size) as usize;
+        }
+        if p > data.len() {
+            return Err(truncated());
+        }
+
+        self.pairs = pairs;
+        self.map = map;
+        Ok(())
+    }
+
+    /// Splits the pieces into groups of identical pieces, with the leading
+    /// pawns or the two or three leading pieces as the first group, and
+    /// computes the factor of every group in the index.
+    fn set_groups(&self, d: &mut PairsData, order: [u8; 2], f: usize) {
+        let mut n = 0;
+        let mut first_len: i32 = if self.has_pawns {
+            0
+        } else if self.has_unique_pieces {
+            3
+        } else {
+            2
+        };
+        d.group_len[0] = 1;
+        for i in 1..self.piece_count {
+            first_len -= 1;
+            if first_len > 0 || d.pieces[i] == d.pieces[i - 1] {
+                d.group_len[n] += 1;
+            } else {
+                n += 1;
+                d.group_len[n] = 1;
+            }
+        }
+        n += 1;
+        d.group_len[n] = 0;
+
+        // The factors of the groups in the index follow the order in which
+        // the table encodes them
+        let indices = indices();
+        let order = [order[0] as usize, order[1] as usize];
+        let pawns_on_both_sides = self.has_pawns && self.pawn_count[1] > 0;
+        let mut next = if pawns_on_both_sides { 2 } else { 1 };
+        let mut free_squares = 64
+            - d.group_len[0]
+            - if pawns_on_both_sides {
+                d.group_len[1]
+            } else {
+                0
+            };
+        let mut idx: u64 = 1;
+        let mut k = 0;
+        while next < n || k == order[0] || k == order[1] {
+            if k == order[0] {
+                d.group_idx[0] = idx;
+                idx *= if self.has_pawns {
+                    indices.lead_pawns_size[d.group_len[0]][f]
+                } else if self.has_unique_pieces {
+                    31332
+                } else {
+                    462
+                };
+            } else if k == order[1] {
+                d.group_idx[1] = idx;
+                idx *= indices.binomial[d.group_len[1]][48 - d.group_len[0]];
+            } else {
+                d.group_idx[next] = idx;
+                idx *= indices.binomial[d.group_len[next]][free_squares];
+                free_squares -= d.group_len[next];
+                next += 1;
+            }
+            k += 1;
+        }
+        d.group_idx[n] = idx;
+    }
+
+    fn left(&self, d: &PairsData, sym: usize) -> usize {
+        let lr = &self.data[d.btree + 3 * sym..];
+        ((lr[1] as usize & 0xF) << 8) | lr[0] as usize
+    }
+
+    fn right(&self, d: &PairsData, sym: usize) -> usize {
+        let lr = &self.data[d.btree + 3 * sym..];
+        ((lr[2] as usize) << 4) | (lr[1] as usize >> 4)
+    }
+
+    /// Decodes the value at the index from the blocks of canonical Huffman
+    /// codes, whose symbols expand recursively into pairs of symbols.
+    fn decompress(&self, d: &PairsData, idx: u64) -> Option<i32> {
+        if d.flags & SINGLE_VALUE != 0 {
+            return Some(d.min_sym_len as i32);
+        }
+        let data = &self.data;
+
+        // The sparse index points to the middle of every span of indices
+        let entry = d.sparse_index + 6 * (idx / d.span) as usize;
+        let mut block = read_u32(data, entry)? as usize;
+        let mut offset = read_u16(data, entry + 4)? as i64;
+        offset += (idx % d.span) as i64 - (d.span / 2) as i64;
+        let block_length = |block: usize| read_u16(data, d.block_length + 2 * block).map(i64::from);
+        while offset < 0 {
+            block = block.checked_sub(1)?;
+            offset += block_length(block)? + 1;
+        }
+        while offset > block_length(block)? {
+            offset -= block_length(block)? + 1;
+            block += 1;
+        }
+
+        let mut ptr = d.data + block * d.block_size as usize;
+        let mut buffer = read_be(data, ptr, 8);
+        ptr += 8;
+        let mut buffer_size = 64;
+        let min_sym_len = d.min_sym_len as u32;
+        let mut sym;
+        loop {
+            let mut len = 0;
+            while buffer < d.base64[len] {
+                len += 1;
+            }
+            sym = ((buffer - d.base64[len]) >> (64 - len as u32 - min_sym_len)) as usize;
+            sym += read_u16(data, d.lowest_sym + 2 * len)? as usize;
+            if offset < d.symlen[sym] as i64 + 1 {
+                break;
+            }
+            offset -= d.symlen[sym] as i64 + 1;
+            let len = len as u32 + min_sym_len;
+            buffer = buffer.checked_shl(len).unwrap_or(0);
+            buffer_size -= len;
+            if buffer_size <= 32 {
+                buffer_size += 32;
+                buffer |= read_be(data, ptr, 4) << (64 - buffer_size);
+                ptr += 4;
+            }
+        }
+
+        // Symbols of pairs are adjacent, so the value is found by descending
+        // into the half that holds the offset
+        while d.symlen[sym] != 0 {
+            let left = self.left(d, sym);
+            if offset < d.symlen[left] as i64 + 1 {
+                sym = left;
+            } else {
+                offset -= d.symlen[left] as i64 + 1;
+                sym = self.right(d, sym);
+            }
+        }
+        Some(self.left(d, sym) as i32)
+    }
+
+    /// Looks up the position, which has the material of the table in either
+    /// color, and returns the stored value. DTZ tables only store one side to
+    /// move, so for the other one this returns `None`.
+    fn probe(&self, game: &Game, wdl: i32) -> Option<i32> {
+        let indices = indices();
+        let board = board(game);
+
+        // Tables store the stronger side as white, and symmetric ones only
+        // with white to move
+        let flip = (self.symmetric && !game.player) || game_material(game) != self.key;
+        let flip_color = if flip { 8 } else { 0 };
+        let flip_squares = if flip { 56 } else { 0 };
+        let stm = flip as usize ^ !game.player as usize;
+
+        let mut squares = [0usize; MAX_PIECES];
+        let mut pieces = [0u8; MAX_PIECES];
+        let mut size = 0;
+        let mut lead_pawns = 0;
+        let mut tb_file = 0;
+        let lead_pawn = if self.has_pawns {
+            Some(1 | ((self.pairs[0][0].pieces[0] ^ flip_color) & 8))
+        } else {
+            None
+        };
+        if lead_pawn.is_some() {
+            for (square, &piece) in board.iter().enumerate() {
+                if Some(piece) == lead_pawn {
+                    squares[size] = square ^ flip_squares;
+                    size += 1;
+                }
+            }
+            lead_pawns = size;
+            let lead = (0..lead_pawns)
+                .max_by_key(|&i| indices.map_pawns[squares[i]])
+                .unwrap();
+            squares.swap(0, lead);
+            tb_file = file(squares[0]).min(7 - file(squares[0]));
+        }
+
+        if self.kind == Kind::Dtz {
+            let flags = self.pairs[tb_file][0].flags;
+            if (flags & STM) as usize != stm && (self.has_pawns || !self.symmetric) {
+                return None;
+            }
+        }
+
+        for (square, &piece) in board.iter().enumerate() {
+            if piece != 0 && Some(piece) != lead_pawn {
+                squares[size] = square ^ flip_squares;
+                pieces[size] = piece ^ flip_color;
+                size += 1;
+            }
+        }
+
+        let file_pairs = &self.pairs[tb_file];
+        let d = &file_pairs[stm % file_pairs.len()];
+
+        // Put the pieces in the order of the table
+        for i in lead_pawns..size.saturating_sub(1) {
+            for j in i + 1..size {
+                if d.pieces[i] == pieces[j] {
+                    pieces.swap(i, j);
+                    squares.swap(i, j);
+                    break;
+                }
+            }
+        }
+
+        // The leading piece is mapped to the files a to d
+        if file(squares[0]) > 3 {
+            for square in squares[..size].iter_mut() {
+                *square ^= 7;
+            }
+        }
+
+        let mut idx;
+        if self.has_pawns {
+            idx = indices.lead_pawn_idx[lead_pawns][squares[0]];
+            squares[1..lead_pawns].sort_by_key(|&square| indices.map_pawns[square]);
+            for (i, &square) in squares[..lead_pawns].iter().enumerate().skip(1) {
+                idx += indices.binomial[i][indices.map_pawns[square]];
+            }
+        } else {
+            // Without pawns the leading piece is also mapped to the ranks 1
+            // to 4 and below the a1-h8 diagonal
+            if rank(squares[0]) > 3 {
+                for square in squares[..size].iter_mut() {
+                    *square ^= 56;
+                }
+            }
+            for i in 0..d.group_len[0] {
+                let off = off_diagonal(squares[i]);
+                if off == 0 {
+                    continue;
+                }
+                if off > 0 {
+                    for square in squares[i..size].iter_mut() {
+                        *square = ((*square >> 3) | (*square << 3)) & 63;
+                    }
+                }
+                break;
+            }
+
+            if self.has_unique_pieces {
+                let adjust1 = (squares[1] > squares[0]) as usize;
+                let adjust2 =
+                    (squares[2] > squares[0]) as usize + (squares[2] > squares[1]) as usize;
+                idx = if off_diagonal(squares[0]) != 0 {
+                    (indices.map_a1d1d4[squares[0]] * 63 + (squares[1] - adjust1)) * 62 + squares[2]
+                        - adjust2
+                } else if off_diagonal(squares[1]) != 0 {
+                    (6 * 63 + rank(squares[0]) * 28 + indices.map_b1h1h7[squares[1]]) * 62
+                        + squares[2]
+                        - adjust2
+                } else if off_diagonal(squares[2]) != 0 {
+                    6 * 63 * 62
+                        + 4 * 28 * 62
+                        + rank(squares[0]) * 7 * 28
+                        + (rank(squares[1]) - adjust1) * 28
+                        + indices.map_b1h1h7[squares[2]]
+                } else {
+                    6 * 63 * 62
+                        + 4 * 28 * 62
+                        + 4 * 7 * 28
+                        + rank(squares[0]) * 7 * 6
+                        + (rank(squares[1]) - adjust1) * 6
+                        + (rank(squares[2]) - adjust2)
+                } as u64;
+            } else {
+                idx = indices.map_kk[indices.map_a1d1d4[squares[0]]][squares[1]] as u64;
+            }
+        }
+
+        // The remaining groups are encoded as combinations of the squares
+        // that the previous groups leave free
+        idx *= d.group_idx[0];
+        let mut group_start = d.group_len[0];
+        let mut remaining_pawns = self.has_pawns && self.pawn_count[1] > 0;
+        let mut next = 1;
+        while d.group_len[next] != 0 {
+            let len = d.group_len[next];
+            squares[group_start..group_start + len].sort_unstable();
+            let mut n = 0;
+            for i in 0..len {
+                let square = squares[group_start + i];
+                let adjust = squares[..group_start]
+                    .iter()
+                    .filter(|&&s| square > s)
+                    .count();
+                n += indices.binomial[i + 1][square - adjust - 8 * remaining_pawns as usize];
+            }
+            remaining_pawns = false;
+            idx += n * d.group_idx[next];
+            group_start += len;
+            next += 1;
+        }
+
+        let value = self.decompress(d, idx)?;
+        match self.kind {
+            Kind::Wdl => Some(value - 2),
+            Kind::Dtz => self.map_dtz(tb_file, value, wdl),
+        }
+    }
+
+    /// Converts a stored DTZ value to plies, or returns `None` if the map
+    /// points past the end of the table.
+    fn map_dtz(&self, file: usize, value: i32, wdl: i32) -> Option<i32> {
+        const WDL_MAP: [usize; 5] = [1, 3, 0, 2, 0];
+        let d = &self.pairs[file][0];
+        let mut value = value;
+        if d.flags & MAPPED != 0 {
+            let idx = d.map_idx[WDL_MAP[(wdl + 2) as usize]] + value as usize;
+            value = if d.flags & WIDE != 0 {
+                read_u16(&self.data, self.map + 2 * idx)? as i32
+            } else {
+                *self.data.get(self.map + idx)? as i32
+            };
+        }
+        if (wdl == 2 && d.flags & WIN_PLIES == 0)
+            || (wdl == -2 && d.flags & LOSS_PLIES == 0)
+            || wdl == 1
+            || wdl == -1
+        {
+            value *= 2;
+        }
+        Some(value + 1)
+    }
+}
+
+/// Reads the sizes of the compressed data and the canonical Huffman code, and
+/// returns the offset after them.
+fn set_sizes(data: &[u8], d: &mut PairsData, p: usize) -> Result<usize, Error> {
+    let bytes = data.get(p..p + 2).ok_or_else(truncated)?;
+    d.flags = bytes[0];
+    if d.flags & SINGLE_VALUE != 0 {
+        d.min_sym_len = bytes[1];
+        return Ok(p + 2);
+    }
+
+    let bytes = data.get(p..p + 10).ok_or_else(truncated)?;
+    let tb_size = d.group_idx[d.group_len.iter().position(|&len| len == 0).unwrap()];
+    d.block_size = 1 << bytes[1];
+    d.span = 1 << bytes[2];
+    d.sparse_index_size = tb_size.div_ceil(d.span);
+    let padding = bytes[3] as u64;
+    d.num_blocks = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as u64;
+    d.block_length_size = d.num_blocks + padding;
+    let max_sym_len = bytes[8];
+    d.min_sym_len = bytes[9];
+    if d.min_sym_len == 0 || max_sym_len < d.min_sym_len || max_sym_len > 32 {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid symbol lengths"));
+    }
+    let mut p = p + 10;
+
+    // Longer codes have lower values, so that every code, padded to 64 bits,
+    // is at least the base of its length
+    d.lowest_sym = p;
+    let lengths = (max_sym_len - d.min_sym_len + 1) as usize;
+    let read = |offset: usize| read_u16(data, offset).ok_or_else(truncated);
+    d.base64 = vec![0; lengths];
+    for i in (0..lengths - 1).rev() {
+        d.base64[i] = d.base64[i + 1]
+            .wrapping_add(read(p + 2 * i)? as u64)
+            .wrapping_sub(read(p + 2 * i + 2)? as u64)
+            / 2;
+    }
+    for (i, base) in d.base64.iter_mut().enumerate() {
+        *base <<= 64 - i as u32 - d.min_sym_len as u32;
+    }
+    p += 2 * lengths;
+
+    let symbols = read(p)? as usize;
+    p += 2;
+    d.btree = p;
+    if p + 3 * symbols > data.len() {
+        return Err(truncated());
+    }
+    d.symlen = vec![0; symbols];
+    let mut visited = vec![false; symbols];
+    for sym in 0..symbols {
+        if !visited[sym] {
+            d.symlen[sym] = set_symlen(data, d, sym, &mut visited)?;
+        }
+    }
+    Ok(p + 3 * symbols + (symbols & 1))
+}
+
+/// Returns how many values minus one the symbol expands to.
+fn set_symlen(
+    data: &[u8],
+    d: &mut PairsData,
+    sym: usize,
+    visited: &mut [bool],
+) -> Result<u8, Error> {
+    visited[sym] = true;
+    let lr = &data[d.btree + 3 * sym..];
+    let right = ((lr[2] as usize) << 4) | (lr[1] as usize >> 4);
+    if right == 0xFFF {
+        return Ok(0);
+    }
+    let left = ((lr[1] as usize & 0xF) << 8) | lr[0] as usize;
+    if left >= visited.len() || right >= visited.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid symbol"));
+    }
+    for child in [left, right] {
+        if !visited[child] {
+            d.symlen[child] = set_symlen(data, d, child, visited)?;
+        }
+    }
+    Ok(d.symlen[left].wrapping_add(d.symlen[right]).wrapping_add(1))
+}
+
+/// The files of one material and the tables once they are read.
+struct TableFiles {
+    key: Material,
+    wdl_path: Option<PathBuf>,
+    dtz_path: Option<PathBuf>,
+    wdl: OnceLock<Option<Table>>,
+    dtz: OnceLock<Option<Table>>,
+}
+
+fn load(path: &Option<PathBuf>, kind: Kind, key: Material) -> Option<Table> {
+    let path = path.as_ref()?;
+    match fs::read(path).and_then(|data| Table::new(kind, key, data)) {
+        Ok(table) => Some(table),
+        Err(err) => {
+            eprintln!("Could not read {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Returns the DTZ of a position whose result is decided by a capture or a
+/// pawn move, which is the DTZ of the move that zeroes the fifty move counter.
+fn dtz_before_zeroing(wdl: i32) -> i32 {
+    match wdl {
+        2 => 1,
+        1 => 101,
+        -1 => -101,
+        -2 => -1,
+        _ => 0,
+    }
+}
+
+fn is_zeroing(game: &Game, m: &Move) -> bool {
+    m.piece == Piece::Pawn || game.is_capture(m)
+}
+
+pub struct Tablebases {
+    files: Vec<TableFiles>,
+    /// The files by material in both colors.
+    materials: HashMap<Material, usize>,
+    max_pieces: usize,
+}
+
+impl Tablebases {
+    /// Finds the WDL and DTZ files in the directory. The tables themselves
+    /// are read when a position first needs them.
+    pub fn open<P: AsRef<Path>>(directory: P) -> Result<Tablebases, Error> {
+        let mut tablebases = Tablebases {
+            files: vec![],
+            materials: HashMap::new(),
+            max_pieces: 0,
+        };
+        for entry in fs::read_dir(&directory)? {
+            let path = entry?.path();
+            let kind = match path.extension().and_then(|extension| extension.to_str()) {
+                Some("rtbw") => Kind::Wdl,
+                Some("rtbz") => Kind::Dtz,
+                _ => continue,
+            };
+            let material = match path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(parse_material)
+            {
+                Some(material) => material,
+                None => continue,
+            };
+            let index = *tablebases
+                .materials
+                .entry(material)
+                .or_insert(tablebases.files.len());
+            if index == tablebases.files.len() {
+                tablebases.materials.insert(swap_colors(&material), index);
+                tablebases.files.push(TableFiles {
+                    key: material,
+                    wdl_path: None,
+                    dtz_path: None,
+                    wdl: OnceLock::new(),
+                    dtz: OnceLock::new(),
+                });
+            }
+            let files = &mut tablebases.files[index];
+            match kind {
+                Kind::Wdl => {
+                    files.wdl_path = Some(path);
+                    tablebases.max_pieces = tablebases.max_pieces.max(piece_count(&material));
+                }
+                Kind::Dtz => files.dtz_path = Some(path),
+            }
+        }
+        if tablebases.max_pieces == 0 {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("No Syzygy tables in {}", directory.as_ref().display()),
+            ));
+        }
+        Ok(tablebases)
+    }
+
+    /// Returns the most pieces of the WDL tables found.
+    pub fn max_pieces(&self) -> usize {
+        self.max_pieces
+    }
+
+    /// Returns whether the tables may know the position, which must have few
+    /// enough pieces and no castling rights.
+    fn covers(&self, game: &Game) -> bool {
+        (game.position.all.count_ones() as usize) <= self.max_pieces && !game.can_castle()
+    }
+
+    fn probe_table(&self, game: &Game, kind: Kind, wdl: i32) -> Result<Option<i32>, ()> {
+        if game.position.all.count_ones() == 2 {
+            return Ok(Some(0));
+        }
+        let files = &self.files[*self.materials.get(&game_material(game)).ok_or(())?];
+        let table = match kind {
+            Kind::Wdl => files
+                .wdl
+                .get_or_init(|| load(&files.wdl_path, kind, files.key)),
+            Kind::Dtz => files
+                .dtz
+                .get_or_init(|| load(&files.dtz_path, kind, files.key)),
+        };
+        Ok(table.as_ref().ok_or(())?.probe(game, wdl))
+    }
+
+    /// Returns the WDL value of the position by trying the captures, and
+    /// with `zeroing` also the pawn moves, before looking at the table. The
+    /// tables ignore en passant rights, so en passant captures are among the
+    /// captures tried, and the better of them and the table value counts. The
+    /// flag of the result tells whether the best move is one of the moves
+    /// tried.
+    fn search(&self, game: &Game, zeroing: bool) -> Result<(i32, bool), ()> {
+        let moves = game.legal_moves(game.player);
+        let mut best = -2;
+        let mut count = 0;
+        for m in &moves {
+            if !(game.is_capture(m) || zeroing && m.piece == Piece::Pawn) {
+                continue;
+            }
+            count += 1;
+            let value = -self.search(&game.make_move(m, false), false)?.0;
+            if value > best {
+                best = value;
+                if value >= 2 {
+                    return Ok((value, true));
+                }
+            }
+        }
+
+        let no_more_moves = count > 0 && count == moves.len();
+        let value = if no_more_moves {
+            best
+        } else {
+            self.probe_table(game, Kind::Wdl, 0)?.ok_or(())?
+        };
+        if best >= value {
+            Ok((best, best > 0 || no_more_moves))
+        } else {
+            Ok((value, false))
+        }
+    }
+
+    /// Returns the result of the position for the player to move.
+    pub fn probe_wdl(&self, game: &Game) -> Option<Wdl> {
+        if !self.covers(game) {
+            return None;
+        }
+        self.search(game, false)
+            .ok()
+            .map(|(value, _)| Wdl::from_i32(value))
+    }
+
+    fn dtz(&self, game: &Game) -> Result<i32, ()> {
+        let (wdl, zeroing_best_move) = self.search(game, true)?;
+        if wdl == 0 {
+            return Ok(0);
+        }
+        if zeroing_best_move {
+            return Ok(dtz_before_zeroing(wdl));
+        }
+        if let Some(dtz) = self.probe_table(game, Kind::Dtz, wdl)? {
+            let cursed = if wdl.abs() == 1 { 100 } else { 0 };
+            return Ok((dtz + cursed) * wdl.signum());
+        }
+
+        // The table only stores the other side to move, so look one ply
+        // ahead for the move with the best DTZ
+        let mut min_dtz = i32::MAX;
+        for m in game.legal_moves(game.player) {
+            let zeroing = is_zeroing(game, &m);
+            let next = game.make_move(&m, false);
+            let mut dtz = if zeroing {
+                -dtz_before_zeroing(self.search(&next, false)?.0)
+            } else {
+                -self.dtz(&next)?
+            };
+            if dtz == 1
+                && next.position.is_check(next.player)
+                && next.legal_moves(next.player).is_empty()
+            {
+                min_dtz = 1;
+            }
+            if !zeroing {
+                dtz += dtz.signum();
+            }
+            if dtz < min_dtz && dtz.signum() == wdl.signum() {
+                min_dtz = dtz;
+            }
+        }
+        Ok(if min_dtz == i32::MAX { -1 } else { min_dtz })
+    }
+
+    /// Returns the number of plies until the next capture or pawn move with
+    /// the best play, positive when the player to move wins and negative when
+    /// it loses, and 0 for draws.
+    pub fn probe_dtz(&self, game: &Game) -> Option<i32> {
+        if !self.covers(game) {
+            return None;
+        }
+        self.dtz(game).ok()
+    }
+
+    /// Returns the moves that keep the best result with the fewest plies to
+    /// the next zeroing move when winning and the most when losing, so that
+    /// wins make progress and losses are defended as long as possible.
+    pub fn root_moves(&self, game: &Game) -> Option<Vec<Move>> {
+        if !self.covers(game) {
+            return None;
+        }
+        let mut ranked = vec![];
+        for m in game.legal_moves(game.player) {
+            let mut next = game.make_move(&m, false);
+            let dtz = if is_zeroing(game, &m) {
+                dtz_before_zeroing(-self.search(&next, false).ok()?.0)
+            } else {
+                match next.result() {
+                    Some(GameResult::White) | Some(GameResult::Black) => 1,
+                    Some(_) => 0,
+                    None => {
+                        let dtz = -self.dtz(&next).ok()?;
+                        dtz + dtz.signum()
+                    }
+                }
+            };
+            let rank = match dtz {
+                0 => 0,
+                dtz if dtz > 0 => MAX_DTZ - dtz,
+                dtz => -MAX_DTZ - dtz,
+            };
+            ranked.push((rank, m));
+        }
+        let best = ranked.iter().map(|(rank, _)| *rank).max()?;
+        Some(
+            ranked
+                .into_iter()
+                .filter(|(rank, _)| *rank == best)
+                .map(|(_, m)| m)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_index_tables() {
+        let indices = indices();
+        let mut codes: Vec<usize> = indices.map_kk.iter().flatten().copied().collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes, (0..462).collect::<Vec<_>>());
+
+        assert_eq!(indices.map_a1d1d4[1], 0);
+        assert_eq!(indices.map_a1d1d4[27], 9);
+        assert_eq!(indices.binomial[2][48], 1128);
+        assert_eq!(indices.binomial[5][63], 7028847);
+        assert_eq!(indices.map_pawns[8], 47);
+        assert_eq!(indices.map_pawns[15], 46);
+        assert_eq!(indices.lead_pawns_size[1].iter().sum::<u64>(), 24);
+    }
+
+    #[test]
+    fn parses_table_names() {
+        assert_eq!(
+            parse_material("KQvK"),
+            Some([[0, 0, 0, 0, 1, 1], [0, 0, 0, 0, 0, 1]])
+        );
+        assert_eq!(
+            parse_material("KRPvKR"),
+            Some([[1, 0, 0, 1, 0, 1], [0, 0, 0, 1, 0, 1]])
+        );
+        assert_eq!(parse_material("KQQ"), None);
+        assert_eq!(parse_material("KXvK"), None);
+        assert_eq!(parse_material("KQQQQQQvK"), None);
+    }
+
+    /// Writes a KQvK WDL table with the given sizes and data for white and
+    /// black to move.
+    fn write_table(directory: &Path, white: &[u8], black: &[u8], tail: &[u8]) {
+        let mut data = WDL_MAGIC.to_vec();
+        // Split flag, order, then the king, queen and black king for both sides
+        data.extend([1, 0x00, 0x66, 0x55, 0xEE, 0]);
+        data.extend(white);
+        data.extend(black);
+        data.extend(tail);
+        fs::create_dir_all(directory).unwrap();
+        fs::write(directory.join("KQvK.rtbw"), data).unwrap();
+    }
+
+    #[test]
+    fn probes_single_value_tables() {
+        let directory =
+            std::env::temp_dir().join(format!("mack7-syzygy-single-{}", std::process::id()));
+        write_table(&directory, &[SINGLE_VALUE, 4], &[SINGLE_VALUE, 0], &[]);
+        let tablebases = Tablebases::open(&directory).unwrap();
+        assert_eq!(tablebases.max_pieces(), 3);
+
+        let probe = |fen: &str| tablebases.probe_wdl(&Game::from_fen(fen));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4KQ2 w - - 0 1"), Some(Wdl::Win));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4KQ2 b - - 0 1"), Some(Wdl::Loss));
+        // The colors are flipped to look up the stronger side as white
+        assert_eq!(probe("4kq2/8/8/8/8/8/8/4K3 w - - 0 1"), Some(Wdl::Loss));
+        // Black takes the queen, and the kings alone are a draw
+        assert_eq!(probe("8/8/8/8/8/3k4/3Q4/7K b - - 0 1"), Some(Wdl::Draw));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4KR2 w - - 0 1"), None);
+        assert_eq!(probe("4k3/8/8/8/8/8/8/R3K3 w Q - 0 1"), None);
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4K3 w - - 0 1"), Some(Wdl::Draw));
+        assert!(tablebases
+            .root_moves(&Game::from_fen("4k3/8/8/8/8/8/8/4KQ2 w - - 0 1"))
+            .is_none());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn decompresses_pairs() {
+        // Three symbols, the codes 00 and 01 for the values 0 and 4 and the
+        // code 1 for the pair of both
+        let white = [
+            0, 13, 16, 0, 1, 0, 0, 0, 2, 1, 2, 0, 0, 0, 3, 0, 0, 0xF0, 0xFF, 4, 0xF0, 0xFF, 0,
+            0x10, 0x00, 0,
+        ];
+        let black = [SINGLE_VALUE, 0];
+        let mut tail = vec![];
+        // The sparse index and the length of the only block
+        tail.extend([0, 0, 0, 0, 0x00, 0x80]);
+        tail.extend(31331u16.to_le_bytes());
+        let start = 10 + white.len() + black.len() + tail.len();
+        tail.resize(tail.len() + (64 - start % 64) % 64, 0);
+        let mut bits = vec![];
+        for _ in 0..7833 {
+            bits.extend([1, 0, 1, 0, 0]);
+        }
+        let mut block = vec![0u8; 8192];
+        for (i, bit) in bits.iter().enumerate() {
+            block[i / 8] |= bit << (7 - i % 8);
+        }
+        tail.extend(block);
+
+        let directory =
+            std::env::temp_dir().join(format!("mack7-syzygy-pairs-{}", std::process::id()));
+        write_table(&directory, &white, &black, &tail);
+        let data = fs::read(directory.join("KQvK.rtbw")).unwrap();
+        let table = Table::new(Kind::Wdl, parse_material("KQvK").unwrap(), data).unwrap();
+        let d = &table.pairs[0][0];
+        assert_eq!(d.symlen, vec![0, 0, 1]);
+        for idx in (0..31332).step_by(97).chain(31328..31332) {
+            assert_eq!(
+                table.decompress(d, idx),
+                Some([0, 4, 4, 0][idx as usize % 4]),
+                "index {}",
+                idx
+            );
+        }
+        assert_eq!(table.decompress(&table.pairs[0][1], 5), Some(0));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_dtz_maps() {
+        // A single value table with a wide map whose offsets end after the
+        // first of the four lengths
+        let mut data = DTZ_MAGIC.to_vec();
+        data.extend([1, 0x00, 0x66, 0x55, 0xEE, 0]);
+        data.extend([SINGLE_VALUE | MAPPED | WIDE, 1, 0, 0]);
+        let material = parse_material("KQvK").unwrap();
+        assert!(Table::new(Kind::Dtz, material, data.clone()).is_err());
+        data.truncate(12);
+        assert!(Table::new(Kind::Dtz, material, data).is_err());
+    }
+
+    #[test]
+    fn probes_en_passant_captures() {
+        let directory =
+            std::env::temp_dir().join(format!("mack7-syzygy-en-passant-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        // KPvK, won by the side with the pawn in every file, with the pawn,
+        // the king and the black king as pieces
+        let mut data = WDL_MAGIC.to_vec();
+        data.push(3);
+        for _ in 0..4 {
+            data.extend([0x00, 0x11, 0x66, 0xEE]);
+        }
+        data.push(0);
+        for _ in 0..4 {
+            data.extend([SINGLE_VALUE, 4, SINGLE_VALUE, 0]);
+        }
+        fs::write(directory.join("KPvK.rtbw"), data).unwrap();
+        // KPvKP, drawn in every file
+        let mut data = WDL_MAGIC.to_vec();
+        data.push(2);
+        for _ in 0..4 {
+            data.extend([0x00, 0x11, 0x11, 0x99, 0x66, 0xEE]);
+        }
+        data.push(0);
+        for _ in 0..4 {
+            data.extend([SINGLE_VALUE, 2]);
+        }
+        fs::write(directory.join("KPvKP.rtbw"), data).unwrap();
+
+        let tablebases = Tablebases::open(&directory).unwrap();
+        let probe = |fen: &str| tablebases.probe_wdl(&Game::from_fen(fen));
+        assert_eq!(probe("k7/8/8/8/3pP3/8/8/7K b - - 0 1"), Some(Wdl::Draw));
+        // Taking en passant leaves black with the only pawn
+        assert_eq!(probe("k7/8/8/8/3pP3/8/8/7K b - e3 0 1"), Some(Wdl::Win));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    /// Opens the three piece tables KQvK, KRvK and KPvK, with WDL and DTZ
+    /// files, from fixtures/syzygy.
+    fn fixtures() -> Tablebases {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/syzygy");
+        for name in ["KQvK", "KRvK", "KPvK"] {
+            for extension in ["rtbw", "rtbz"] {
+                let path = directory.join(format!("{}.{}", name, extension));
+                assert!(
+                    path.exists(),
+                    "{} is missing, see fixtures/syzygy/README.md",
+                    path.display()
+                );
+            }
+        }
+        Tablebases::open(directory).unwrap()
+    }
+
+    #[test]
+    fn probes_wdl_of_fixtures() {
+        let tablebases = fixtures();
+        let probe = |fen: &str| tablebases.probe_wdl(&Game::from_fen(fen));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4KQ2 w - - 0 1"), Some(Wdl::Win));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/4KQ2 b - - 0 1"), Some(Wdl::Loss));
+        assert_eq!(probe("8/8/8/8/8/3k4/3Q4/7K b - - 0 1"), Some(Wdl::Draw));
+        assert_eq!(probe("4k3/8/8/8/8/8/8/R3K3 w - - 0 1"), Some(Wdl::Win));
+        assert_eq!(probe("k7/8/8/8/8/8/P7/K7 w - - 0 1"), Some(Wdl::Draw));
+        assert_eq!(probe("8/1P6/8/8/8/8/8/K6k w - - 0 1"), Some(Wdl::Win));
+    }
+
+    #[test]
+    fn probes_dtz_of_fixtures() {
+        let tablebases = fixtures();
+        let mate = Game::from_fen("k7/8/1K6/8/8/8/8/7Q w - - 0 1");
+        assert_eq!(tablebases.probe_dtz(&mate), Some(1));
+        assert_eq!(
+            tablebases.probe_dtz(&Game::from_fen("8/1P6/8/8/8/8/8/K6k w - - 0 1")),
+            Some(1)
+        );
+        assert_eq!(
+            tablebases.probe_dtz(&Game::from_fen("k7/8/8/8/8/8/P7/K7 w - - 0 1")),
+            Some(0)
+        );
+
+        let mut moves: Vec<String> = tablebases
+            .root_moves(&mate)
+            .unwrap()
+            .iter()
+            .map(Move::to_uci)
+            .collect();
+        moves.sort();
+        assert_eq!(moves, vec!["h1b7", "h1h8"]);
+    }
+}
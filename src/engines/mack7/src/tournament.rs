@@ -0,0 +1,726 @@
+//! Plays matches between engines that speak UCI, each one running in a
+//! process of its own. The engines meet either in a gauntlet, where the first
+//! engine plays all others, or in a round-robin, where everyone plays
+//! everyone. Every opening is played twice with the colors swapped, the games
+//! are written as PGN and the results are reported as Elo differences.
+
+use rand::{rngs::StdRng, SeedableRng};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::{
+    book::Book,
+    game::{Game, GameResult},
+    openings::Openings,
+    pgn::{self, PgnGame},
+    stats::{Pentanomial, Score},
+    uci,
+};
+
+/// How long an engine may take to start up or to get ready for a game.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An engine given as `NAME=COMMAND`, or just as the command in which case
+/// the name is the file name of the program. The command is split at
+/// whitespace into the program and its arguments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EngineConfig {
+    pub name: String,
+    pub command: String,
+}
+
+impl FromStr for EngineConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, command) = match s.split_once('=') {
+            Some((name, command)) => (name.trim().to_string(), command.trim()),
+            None => {
+                let program = s.split_whitespace().next().unwrap_or("");
+                let name = std::path::Path::new(program)
+                    .file_name()
+                    .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+                (name, s.trim())
+            }
+        };
+        if name.is_empty() || command.is_empty() {
+            return Err(format!("Expected NAME=COMMAND, got {}", s));
+        }
+        Ok(EngineConfig {
+            name,
+            command: String::from(command),
+        })
+    }
+}
+
+/// A time control of `BASE+INCREMENT` in seconds, optionally preceded by
+/// `MOVES/` if the base time is added again every so many moves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeControl {
+    pub moves: Option<u32>,
+    pub base: Duration,
+    pub increment: Duration,
+}
+
+impl FromStr for TimeControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Expected [MOVES/]BASE[+INCREMENT] in seconds, got {}", s);
+        let seconds = |value: &str| -> Result<Duration, String> {
+            match value.parse::<f64>() {
+                Ok(seconds) if seconds >= 0. && seconds.is_finite() => {
+                    Ok(Duration::from_secs_f64(seconds))
+                }
+                _ => Err(invalid()),
+            }
+        };
+
+        let (moves, rest) = match s.split_once('/') {
+            Some((moves, rest)) => match moves.parse::<u32>() {
+                Ok(moves) if moves > 0 => (Some(moves), rest),
+                _ => return Err(invalid()),
+            },
+            None => (None, s),
+        };
+        let (base, increment) = match rest.split_once('+') {
+            Some((base, increment)) => (seconds(base)?, seconds(increment)?),
+            None => (seconds(rest)?, Duration::ZERO),
+        };
+        if base.is_zero() && increment.is_zero() {
+            return Err(invalid());
+        }
+        Ok(TimeControl {
+            moves,
+            base,
+            increment,
+        })
+    }
+}
+
+impl std::fmt::Display for TimeControl {
+    /// Writes the time control in the format of the PGN `TimeControl` tag.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(moves) = self.moves {
+            write!(f, "{}/", moves)?;
+        }
+        write!(f, "{}", self.base.as_secs_f64())?;
+        if !self.increment.is_zero() {
+            write!(f, "+{}", self.increment.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The first engine plays all others.
+    Gauntlet,
+    /// Every engine plays every other one.
+    RoundRobin,
+}
+
+pub struct MatchOptions {
+    pub format: Format,
+    /// The number of games of every pairing, with alternating colors.
+    pub games: u32,
+    pub time_control: TimeControl,
+    /// How long an engine may exceed its clock before it loses on time.
+    pub time_margin: Duration,
+    /// The positions to start from, the standard one if not given. Each is
+    /// played twice, once with either engine as white.
+    pub openings: Option<Openings>,
+    /// A book to play the first moves from after the opening, so that both
+    /// engines play the same random line once with either color.
+    pub book: Option<Book>,
+    pub book_plies: u32,
+    /// Games that are not over after this many plies count as draws.
+    pub max_plies: u32,
+    /// The file the games are written to.
+    pub pgn: PathBuf,
+    pub seed: Option<u64>,
+}
+
+/// The games between two engines, scored from the perspective of the first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pairing {
+    pub first: usize,
+    pub second: usize,
+    pub score: Score,
+    /// The results of the pairs of games that played the same opening.
+    pub pentanomial: Pentanomial,
+}
+
+/// Returns the pairs of engines that play each other.
+fn pairings(engines: usize, format: Format) -> Vec<(usize, usize)> {
+    match format {
+        Format::Gauntlet => (1..engines).map(|opponent| (0, opponent)).collect(),
+        Format::RoundRobin => (0..engines)
+            .flat_map(|first| (first + 1..engines).map(move |second| (first, second)))
+            .collect(),
+    }
+}
+
+/// A running engine, whose output is read by a thread of its own so that
+/// waiting for it can time out.
+struct UciEngine {
+    config: EngineConfig,
+    process: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+}
+
+impl UciEngine {
+    fn start(config: &EngineConfig) -> std::io::Result<UciEngine> {
+        let mut parts = config.command.split_whitespace();
+        let program = parts.next().unwrap_or_default();
+        let mut process = Command::new(program)
+            .args(parts)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                Error::new(
+                    err.kind(),
+                    format!("Could not start {}: {}", config.command, err),
+                )
+            })?;
+
+        let stdin = process.stdin.take().unwrap();
+        let stdout = process.stdout.take().unwrap();
+        let (sender, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut engine = UciEngine {
+            config: config.clone(),
+            process,
+            stdin,
+            lines,
+        };
+        engine.send("uci")?;
+        engine.wait_for("uciok", READY_TIMEOUT)?;
+        Ok(engine)
+    }
+
+    fn is_running(&mut self) -> bool {
+        matches!(self.process.try_wait(), Ok(None))
+    }
+
+    fn send(&mut self, command: &str) -> std::io::Result<()> {
+        writeln!(self.stdin, "{}", command)?;
+        self.stdin.flush()
+    }
+
+    /// Skips the output of the engine up to the first line that starts with
+    /// the given token, and returns that line.
+    fn wait_for(&self, token: &str, timeout: Duration) -> std::io::Result<String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(remaining) {
+                Ok(line) if line.split_whitespace().next() == Some(token) => return Ok(line),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("{} did not send {} in time", self.config.name, token),
+                    ))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("{} exited", self.config.name),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn new_game(&mut self) -> std::io::Result<()> {
+        self.send("ucinewgame")?;
+        self.send("isready")?;
+        self.wait_for("readyok", READY_TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Asks for a move in the position after the moves from the FEN, and
+    /// returns it in UCI notation.
+    fn go(
+        &mut self,
+        fen: &str,
+        moves: &[String],
+        clocks: &Clocks,
+        timeout: Duration,
+    ) -> std::io::Result<String> {
+        let mut position = if fen == pgn::START_POSITION {
+            String::from("position startpos")
+        } else {
+            format!("position fen {}", fen)
+        };
+        if !moves.is_empty() {
+            position.push_str(" moves ");
+            position.push_str(&moves.join(" "));
+        }
+        self.send(&position)?;
+        self.send(&clocks.go_command())?;
+
+        let line = self.wait_for("bestmove", timeout)?;
+        Ok(line.split_whitespace().nth(1).unwrap_or("").to_string())
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if !self.is_running() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// The clocks of both players, white first.
+struct Clocks {
+    time_control: TimeControl,
+    remaining: [Duration; 2],
+    moves: [u32; 2],
+    player: usize,
+}
+
+impl Clocks {
+    fn new(time_control: TimeControl, white_to_move: bool) -> Clocks {
+        Clocks {
+            time_control,
+            remaining: [time_control.base; 2],
+            moves: [0, 0],
+            player: if white_to_move { 0 } else { 1 },
+        }
+    }
+
+    fn go_command(&self) -> String {
+        let increment = self.time_control.increment.as_millis();
+        let mut command = format!(
+            "go wtime {} btime {} winc {} binc {}",
+            self.remaining[0].as_millis(),
+            self.remaining[1].as_millis(),
+            increment,
+            increment
+        );
+        if let Some(moves) = self.time_control.moves {
+            let moves_to_go = moves - self.moves[self.player] % moves;
+            command.push_str(&format!(" movestogo {}", moves_to_go));
+        }
+        command
+    }
+
+    /// Charges the player to move for a move that took the given time, and
+    /// returns whether the player is still within its time.
+    fn punch(&mut self, elapsed: Duration, margin: Duration) -> bool {
+        let player = self.player;
+        self.player = 1 - player;
+        if elapsed > self.remaining[player] + margin {
+            return false;
+        }
+        self.remaining[player] = self.remaining[player].saturating_sub(elapsed);
+        self.remaining[player] += self.time_control.increment;
+        self.moves[player] += 1;
+        if let Some(moves) = self.time_control.moves {
+            if self.moves[player].is_multiple_of(moves) {
+                self.remaining[player] += self.time_control.base;
+            }
+        }
+        true
+    }
+}
+
+/// Plays one game, the first engine as white, and returns the result from
+/// the perspective of white between -1 and 1.
+fn play_game(
+    engines: [&mut UciEngine; 2],
+    fen: &str,
+    pgn: &mut PgnGame,
+    options: &MatchOptions,
+) -> std::io::Result<i8> {
+    pgn.tags.push((
+        String::from("TimeControl"),
+        options.time_control.to_string(),
+    ));
+    let [white, black] = engines;
+    white.new_game()?;
+    black.new_game()?;
+
+    let mut game = Game::from_fen(fen);
+    let mut clocks = Clocks::new(options.time_control, game.player);
+    let mut moves: Vec<String> = vec![];
+    loop {
+        if let Some(result) = game.result() {
+            pgn.finish(Some(&result));
+            return Ok(match result {
+                GameResult::White => 1,
+                GameResult::Black => -1,
+                _ => 0,
+            });
+        }
+        if moves.len() as u32 >= options.max_plies {
+            pgn.adjudicate(0, "adjudication");
+            return Ok(0);
+        }
+
+        let (engine, loss) = if game.player {
+            (&mut *white, -1)
+        } else {
+            (&mut *black, 1)
+        };
+        let timeout = clocks.remaining[clocks.player] + options.time_margin;
+        let start = Instant::now();
+        let reply = engine.go(fen, &moves, &clocks, timeout);
+        let elapsed = start.elapsed();
+
+        let uci = match reply {
+            Ok(uci) => uci,
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                let _ = engine.send("stop");
+                pgn.adjudicate(loss, "time forfeit");
+                return Ok(loss);
+            }
+            Err(err) => {
+                println!("{}", err);
+                pgn.adjudicate(loss, "abandoned");
+                return Ok(loss);
+            }
+        };
+        if !clocks.punch(elapsed, options.time_margin) {
+            pgn.adjudicate(loss, "time forfeit");
+            return Ok(loss);
+        }
+        let m = match uci::find_move(&game, &uci) {
+            Some(m) => m,
+            None => {
+                println!("{} played the illegal move {}", engine.config.name, uci);
+                pgn.adjudicate(loss, "rules infraction");
+                return Ok(loss);
+            }
+        };
+
+        pgn.push(&game, &m);
+        game = game.make_move(&m, true);
+        moves.push(uci);
+    }
+}
+
+/// Plays the tournament and returns the results of all pairings.
+pub fn run(configs: Vec<EngineConfig>, options: MatchOptions) -> std::io::Result<Vec<Pairing>> {
+    if configs.len() < 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "A match needs at least two engines",
+        ));
+    }
+    for (i, config) in configs.iter().enumerate() {
+        if configs[..i].iter().any(|other| other.name == config.name) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "There are several engines named {}, name them with NAME=COMMAND",
+                    config.name
+                ),
+            ));
+        }
+    }
+
+    let mut engines = configs
+        .iter()
+        .map(UciEngine::start)
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut pgns = BufWriter::new(File::create(&options.pgn)?);
+    let mut rng = match options.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    let mut results = vec![];
+    let mut round = 0;
+    for (first, second) in pairings(configs.len(), options.format) {
+        let mut pairing = Pairing {
+            first,
+            second,
+            score: Score::default(),
+            pentanomial: Pentanomial::default(),
+        };
+        let mut fen = String::from(pgn::START_POSITION);
+        let mut first_result = 0;
+        for game_number in 0..options.games {
+            // Both engines play every opening once as white
+            if game_number.is_multiple_of(2) {
+                fen = match options.openings.as_ref() {
+                    Some(openings) => openings.pick(game_number / 2, &mut rng).to_string(),
+                    None => String::from(pgn::START_POSITION),
+                };
+                if let Some(book) = options.book.as_ref() {
+                    let game = Game::from_fen(&fen);
+                    fen = book.play(game, options.book_plies, &mut rng).to_fen();
+                }
+            }
+            let (white, black) = if game_number.is_multiple_of(2) {
+                (first, second)
+            } else {
+                (second, first)
+            };
+            // Engines that crashed in an earlier game get another chance
+            for &i in [white, black].iter() {
+                if !engines[i].is_running() {
+                    engines[i] = UciEngine::start(&configs[i])?;
+                }
+            }
+
+            round += 1;
+            let mut pgn = PgnGame::new(
+                "mack7 match",
+                round,
+                &configs[white].name,
+                &configs[black].name,
+                &fen,
+            );
+            let (white_engine, black_engine) = if white < black {
+                let (left, right) = engines.split_at_mut(black);
+                (&mut left[white], &mut right[0])
+            } else {
+                let (left, right) = engines.split_at_mut(white);
+                (&mut right[0], &mut left[black])
+            };
+            let result = play_game([white_engine, black_engine], &fen, &mut pgn, &options)?;
+            writeln!(pgns, "{}", pgn)?;
+            pgns.flush()?;
+
+            let result = if white == first { result } else { -result };
+            pairing.score.add(result);
+            if game_number.is_multiple_of(2) {
+                first_result = result;
+            } else {
+                pairing.pentanomial.add(first_result, result);
+            }
+            println!(
+                "Game {}: {} - {} {}, {} +{} ={} -{}",
+                round,
+                configs[white].name,
+                configs[black].name,
+                pgn.result(),
+                configs[first].name,
+                pairing.score.wins,
+                pairing.score.draws,
+                pairing.score.losses
+            );
+        }
+        results.push(pairing);
+    }
+
+    println!();
+    for pairing in results.iter() {
+        println!(
+            "{} vs {}: {}",
+            configs[pairing.first].name, configs[pairing.second].name, pairing.score
+        );
+        if pairing.pentanomial.pairs() > 0 {
+            println!("    {}", pairing.pentanomial);
+        }
+    }
+    if options.format == Format::RoundRobin && configs.len() > 2 {
+        println!();
+        for (i, config) in configs.iter().enumerate() {
+            let mut total = Score::default();
+            for pairing in results.iter() {
+                if pairing.first == i {
+                    total += pairing.score;
+                } else if pairing.second == i {
+                    total += pairing.score.reversed();
+                }
+            }
+            println!("{} against the field: {}", config.name, total);
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn parses_engines_and_time_controls() {
+        assert_eq!(
+            "sf=/usr/bin/stockfish".parse(),
+            Ok(EngineConfig {
+                name: String::from("sf"),
+                command: String::from("/usr/bin/stockfish"),
+            })
+        );
+        assert_eq!(
+            "./target/release/mack7 uci --playouts 800"
+                .parse::<EngineConfig>()
+                .unwrap()
+                .name,
+            "mack7"
+        );
+        assert!("=mack7".parse::<EngineConfig>().is_err());
+
+        let time_control: TimeControl = "10+0.1".parse().unwrap();
+        assert_eq!(
+            time_control,
+            TimeControl {
+                moves: None,
+                base: Duration::from_secs(10),
+                increment: Duration::from_millis(100),
+            }
+        );
+        assert_eq!(time_control.to_string(), "10+0.1");
+        let time_control: TimeControl = "40/60".parse().unwrap();
+        assert_eq!(time_control.moves, Some(40));
+        assert_eq!(time_control.to_string(), "40/60");
+        assert!("0/60".parse::<TimeControl>().is_err());
+        assert!("fast".parse::<TimeControl>().is_err());
+        assert!("0+0".parse::<TimeControl>().is_err());
+    }
+
+    #[test]
+    fn keeps_the_clocks() {
+        let time_control = "2/10+1".parse().unwrap();
+        let margin = Duration::ZERO;
+        let mut clocks = Clocks::new(time_control, true);
+        assert_eq!(
+            clocks.go_command(),
+            "go wtime 10000 btime 10000 winc 1000 binc 1000 movestogo 2"
+        );
+        assert!(clocks.punch(Duration::from_secs(4), margin));
+        assert!(clocks.punch(Duration::from_secs(1), margin));
+        assert_eq!(
+            clocks.remaining,
+            [Duration::from_secs(7), Duration::from_secs(10)]
+        );
+        // The base time is added again after the second move
+        assert!(clocks.punch(Duration::from_secs(2), margin));
+        assert_eq!(clocks.remaining[0], Duration::from_secs(16));
+        assert!(!clocks.punch(Duration::from_secs(11), margin));
+    }
+
+    #[test]
+    fn schedules_pairings() {
+        assert_eq!(pairings(3, Format::Gauntlet), vec![(0, 1), (0, 2)]);
+        assert_eq!(
+            pairings(3, Format::RoundRobin),
+            vec![(0, 1), (0, 2), (1, 2)]
+        );
+    }
+
+    /// Writes a shell script that speaks just enough UCI, and always plays
+    /// the moves of the fool's mate or the given illegal move.
+    fn script(directory: &std::path::Path, name: &str, illegal: Option<&str>) -> String {
+        let go = match illegal {
+            Some(m) => format!("echo \"bestmove {}\"", m),
+            None => String::from(
+                "case $plies in 0) echo \"bestmove f2f3\";; 1) echo \"bestmove e7e5\";; \
+                 2) echo \"bestmove g2g4\";; *) echo \"bestmove d8h4\";; esac",
+            ),
+        };
+        let path = directory.join(format!("{}.sh", name));
+        let text = format!(
+            "plies=0\n\
+             while read -r line; do\n\
+             set -- $line\n\
+             case \"$1\" in\n\
+             uci) echo \"id name {}\"; echo uciok ;;\n\
+             isready) echo readyok ;;\n\
+             position) plies=$(($# - 2)); if [ $plies -gt 0 ]; then plies=$((plies - 1)); fi ;;\n\
+             go) {} ;;\n\
+             quit) exit 0 ;;\n\
+             esac\n\
+             done\n",
+            name, go
+        );
+        fs::write(&path, text).unwrap();
+        format!("sh {}", path.display())
+    }
+
+    #[test]
+    fn plays_uci_engines() {
+        let directory = std::env::temp_dir().join(format!("mack7-match-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let engine = |name: &str, illegal: Option<&str>| EngineConfig {
+            name: String::from(name),
+            command: script(&directory, name, illegal),
+        };
+        let options = |format: Format| MatchOptions {
+            format,
+            games: 2,
+            time_control: "10+0.1".parse().unwrap(),
+            time_margin: Duration::from_secs(1),
+            openings: None,
+            book: None,
+            book_plies: 0,
+            max_plies: 100,
+            pgn: directory.join("match.pgn"),
+            seed: Some(0),
+        };
+
+        // Whoever plays black mates, so both engines win once
+        let results = run(
+            vec![
+                engine("a", None),
+                engine("b", None),
+                engine("c", Some("a1a1")),
+            ],
+            options(Format::Gauntlet),
+        )
+        .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[0].score,
+            Score {
+                wins: 1,
+                draws: 0,
+                losses: 1
+            }
+        );
+        assert_eq!(results[0].pentanomial.counts, [0, 0, 1, 0, 0]);
+        // The illegal moves lose both games
+        assert_eq!(
+            (results[1].first, results[1].second, results[1].score.wins),
+            (0, 2, 2)
+        );
+
+        let pgns = fs::read_to_string(directory.join("match.pgn")).unwrap();
+        assert_eq!(pgns.matches("[Event \"mack7 match\"]").count(), 4);
+        assert!(pgns.contains("1. f3 e5 2. g4 Qh4# 0-1"));
+        assert_eq!(
+            pgns.matches("[Termination \"rules infraction\"]").count(),
+            2
+        );
+        assert_eq!(pgns.matches("[TimeControl \"10+0.1\"]").count(), 4);
+
+        assert!(run(vec![engine("a", None)], options(Format::RoundRobin)).is_err());
+        assert!(run(
+            vec![engine("a", None), engine("a", None)],
+            options(Format::RoundRobin)
+        )
+        .is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
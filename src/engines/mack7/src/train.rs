@@ -1,92 +1,298 @@
-use neuronika::{data::DataLoader, nn::loss, optim};
-use std::fs::File;
-use std::io::Read;
+use ndarray::{Array1, Array2};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::str::FromStr;
 
-use crate::{policy_network, value_network};
+use crate::{
+    checkpoint::Checkpoints,
+    nn::{Method, Optimizer},
+    nnue::Nnue,
+    replay::{Example, ReplayBuffer, ReplayOptions},
+    run_directory,
+};
 
-pub fn run(run_indices: Vec<&str>) -> std::io::Result<()> {
-    println!("Loading training data");
-
-    let mut value_csv: String = String::from("");
-    let mut policy_csv: String = String::from("");
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LearningRateSchedule {
+    Constant,
+    /// Multiplies the learning rate by `gamma` every `epochs` epochs.
+    Step {
+        epochs: u32,
+        gamma: f32,
+    },
+    /// Decays the learning rate to 0 along a half cosine over all epochs.
+    Cosine,
+}
 
-    for run_index in run_indices {
-        let mut value_file = File::open(format!("value.{}.csv", run_index))?;
-        let mut value_contents = String::new();
-        value_file.read_to_string(&mut value_contents)?;
-        value_csv.push_str(&value_contents);
-
-        let mut policy_file = File::open(format!("policy.{}.csv", run_index))?;
-        let mut policy_contents = String::new();
-        policy_file.read_to_string(&mut policy_contents)?;
-        policy_csv.push_str(&policy_contents);
+impl LearningRateSchedule {
+    pub fn learning_rate(&self, initial: f32, epoch: u32, epochs: u32) -> f32 {
+        match *self {
+            LearningRateSchedule::Constant => initial,
+            LearningRateSchedule::Step { epochs, gamma } => {
+                initial * gamma.powi((epoch / epochs.max(1)) as i32)
+            }
+            LearningRateSchedule::Cosine => {
+                let progress = epoch as f32 / epochs.max(1) as f32;
+                initial * 0.5 * (1. + (std::f32::consts::PI * progress).cos())
+            }
+        }
     }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptimizerKind {
+    Sgd,
+    Momentum,
+    Adam,
+}
 
-    // Creates data loader.
-    let mut value_dataset = DataLoader::default()
-        .with_labels(&[837])
-        .without_headers()
-        .from_reader(value_csv.as_bytes(), 837, 1);
-    let mut policy_dataset = DataLoader::default()
-        .with_labels(&(837..2809).collect::<Vec<usize>>())
-        .without_headers()
-        .from_reader(policy_csv.as_bytes(), 837, 1972);
-
-    let value_nn = value_network::load()?;
-    value_nn.train();
-
-    // Creates the optimizer.
-    let value_optimizer = optim::SGD::new(value_nn.parameters(), 0.01, optim::L2::new(0.0));
-
-    // Trains the model.
-    for epoch in 0..100 {
-        let batched_data = value_dataset.shuffle().batch(100).drop_last().take(10);
-        let mut total_loss = 0.;
-
-        for (input_array, target_array) in batched_data {
-            let input = neuronika::from_ndarray(input_array.to_owned());
-            let target = neuronika::from_ndarray(target_array.to_owned());
-
-            let result = value_nn.forward(input);
-
-            let loss = loss::mse_loss(result.clone(), target.clone(), loss::Reduction::Mean);
-            loss.forward();
-            total_loss += loss.data()[()];
-            loss.backward(1.0);
-            value_optimizer.step();
+impl FromStr for OptimizerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sgd" => Ok(OptimizerKind::Sgd),
+            "momentum" => Ok(OptimizerKind::Momentum),
+            "adam" => Ok(OptimizerKind::Adam),
+            _ => Err(format!("Unknown optimizer: {}", s)),
         }
+    }
+}
+
+pub struct TrainOptions {
+    pub epochs: u32,
+    /// Ends an epoch early after the given number of batches, otherwise an
+    /// epoch goes through all positions in the window once.
+    pub batches_per_epoch: Option<usize>,
+    pub batch_size: usize,
+    pub learning_rate: f32,
+    pub schedule: LearningRateSchedule,
+    pub optimizer: OptimizerKind,
+    /// The momentum for SGD with momentum, and the decay of the first moment
+    /// for Adam.
+    pub momentum: f32,
+    pub weight_decay: f32,
+    pub replay: ReplayOptions,
+}
+
+/// The arrays for training on a batch of examples.
+struct Batch {
+    inputs: Array2<f32>,
+    legal_moves: Array2<f32>,
+    policy_targets: Array2<f32>,
+    value_targets: Array2<f32>,
+    /// 1 for the examples with a value target and 0 for the others.
+    value_mask: Array2<f32>,
+}
 
-        println!("Value network loss for epoch {} : {} ", epoch, total_loss);
+/// Returns the inputs, legal move masks and targets for a batch of examples.
+fn batch_arrays(examples: &[Example]) -> Batch {
+    let mut inputs = Array2::zeros((examples.len(), 837));
+    let mut legal_moves = Array2::zeros((examples.len(), 1972));
+    let mut policy_targets = Array2::zeros((examples.len(), 1972));
+    let mut value_targets = Array2::zeros((examples.len(), 1));
+    let mut value_mask = Array2::zeros((examples.len(), 1));
+    for (i, example) in examples.iter().enumerate() {
+        inputs.row_mut(i).assign(&example.game.get_input());
+        legal_moves
+            .row_mut(i)
+            .assign(&example.game.legal_move_mask());
+        policy_targets
+            .row_mut(i)
+            .assign(&Array1::from(example.policy_target()));
+        if let Some(value_target) = example.value_target() {
+            value_targets[[i, 0]] = value_target;
+            value_mask[[i, 0]] = 1.;
+        }
+    }
+    Batch {
+        inputs,
+        legal_moves,
+        policy_targets,
+        value_targets,
+        value_mask,
     }
-    value_network::save(&value_nn)?;
+}
 
-    let policy_nn = policy_network::load()?;
-    policy_nn.train();
+fn method(options: &TrainOptions) -> Method {
+    match options.optimizer {
+        OptimizerKind::Sgd => Method::Sgd,
+        OptimizerKind::Momentum => Method::Momentum(options.momentum),
+        OptimizerKind::Adam => Method::Adam {
+            beta1: options.momentum,
+            beta2: 0.999,
+            epsilon: 1e-8,
+        },
+    }
+}
 
-    // Creates the optimizer.
-    let policy_optimizer = optim::SGD::new(policy_nn.parameters(), 0.01, optim::L2::new(0.0));
+/// Loads the replay buffer over the training data of the given runs.
+fn open_replay(run_indices: Vec<&str>, options: ReplayOptions) -> std::io::Result<ReplayBuffer> {
+    println!("Loading training data");
 
-    // Trains the model.
-    for epoch in 0..100 {
-        let batched_data = policy_dataset.shuffle().batch(100).drop_last().take(10);
-        let mut total_loss = 0.;
+    let mut paths = vec![];
+    for run_index in run_indices {
+        paths.extend(run_directory::chunks(run_index)?);
+    }
+    let replay = ReplayBuffer::open(paths, options)?;
+    println!("Training on the last {} games", replay.games());
+    Ok(replay)
+}
 
-        for (input_array, target_array) in batched_data {
-            let input = neuronika::from_ndarray(input_array.to_owned());
-            let target = neuronika::from_ndarray(target_array.to_owned());
+/// Trains the model for all epochs over the replay buffer. Every batch is
+/// passed to `train_batch`, which returns its losses, and `describe` formats
+/// the average losses of an epoch. The last batch of an epoch may be smaller
+/// than the batch size. Returns the number of training steps.
+fn train_epochs<M, const N: usize>(
+    model: &mut M,
+    replay: &ReplayBuffer,
+    options: &TrainOptions,
+    train_batch: impl Fn(&mut M, &[Example], &Optimizer) -> [f32; N],
+    describe: impl Fn(&M, [f32; N]) -> String,
+) -> std::io::Result<i32> {
+    let method = method(options);
+    let mut rng = rand::thread_rng();
+    let mut step = 0;
+    for epoch in 0..options.epochs {
+        let learning_rate =
+            options
+                .schedule
+                .learning_rate(options.learning_rate, epoch, options.epochs);
+        let mut total_losses = [0.; N];
 
-            let result = policy_nn.forward(input);
+        let mut examples = replay.examples(&mut rng);
+        let mut batches = 0;
+        while options.batches_per_epoch.is_none_or(|max| batches < max) {
+            let batch: Vec<Example> = examples
+                .by_ref()
+                .take(options.batch_size)
+                .collect::<std::io::Result<_>>()?;
+            if batch.is_empty() {
+                break;
+            }
+            batches += 1;
+            step += 1;
+            let optimizer = Optimizer {
+                method,
+                learning_rate,
+                weight_decay: options.weight_decay,
+                step,
+            };
 
-            let loss = loss::mse_loss(result.clone(), target.clone(), loss::Reduction::Mean);
-            loss.forward();
-            total_loss += loss.data()[()];
-            loss.backward(1.0);
-            policy_optimizer.step();
+            let losses = train_batch(model, &batch, &optimizer);
+            for (total, loss) in total_losses.iter_mut().zip(losses) {
+                *total += loss;
+            }
+            if batch.len() < options.batch_size {
+                break;
+            }
+        }
+        if batches == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "The training window has no positions",
+            ));
         }
 
-        println!("Policy network loss for epoch {} : {} ", epoch, total_loss);
+        println!(
+            "Loss for epoch {} ({} batches, learning rate {}) : {}",
+            epoch,
+            batches,
+            learning_rate,
+            describe(model, total_losses.map(|total| total / batches as f32))
+        );
     }
-    policy_network::save(&policy_nn)?;
+    Ok(step)
+}
+
+/// Trains the given generation, or the latest one, and saves the result as a
+/// new generation.
+pub fn run(
+    run_indices: Vec<&str>,
+    checkpoints: &Checkpoints,
+    generation: Option<u32>,
+    options: TrainOptions,
+) -> std::io::Result<()> {
+    let replay = open_replay(run_indices, options.replay)?;
+    let (mut network, parent) = checkpoints.load(generation)?;
+
+    let steps = train_epochs(
+        &mut network,
+        &replay,
+        &options,
+        |network, examples, optimizer| {
+            let batch = batch_arrays(examples);
+            let (policy_loss, value_loss) = network.train(
+                &batch.inputs,
+                &batch.legal_moves,
+                &batch.policy_targets,
+                &batch.value_targets,
+                &batch.value_mask,
+                optimizer,
+            );
+            [policy_loss, value_loss]
+        },
+        |network, [policy_loss, value_loss]| {
+            format!(
+                "policy {}, value {}, squared weights {}",
+                policy_loss,
+                value_loss,
+                network.squared_weights()
+            )
+        },
+    )?;
+    checkpoints.save(&network, Some(&parent), steps as u64)?;
 
     Ok(())
 }
+
+/// Trains the value of the NNUE saved at the given path, or of a new one with
+/// the given accumulator size if there is none, and saves it back.
+pub fn run_nnue(
+    run_indices: Vec<&str>,
+    path: &Path,
+    size: usize,
+    options: TrainOptions,
+) -> std::io::Result<()> {
+    let replay = open_replay(run_indices, options.replay)?;
+    let mut nnue = if path.exists() {
+        Nnue::load(path)?
+    } else {
+        println!("Creating a new NNUE at {}", path.display());
+        Nnue::new(size)
+    };
+
+    train_epochs(
+        &mut nnue,
+        &replay,
+        &options,
+        |nnue, examples, optimizer| [nnue.train(examples, optimizer)],
+        |_, [value_loss]| format!("value {}", value_loss),
+    )?;
+    nnue.save(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learning_rate_schedules() {
+        assert_eq!(
+            LearningRateSchedule::Constant.learning_rate(0.1, 50, 100),
+            0.1
+        );
+
+        let step = LearningRateSchedule::Step {
+            epochs: 10,
+            gamma: 0.5,
+        };
+        assert_eq!(step.learning_rate(0.1, 9, 100), 0.1);
+        assert_eq!(step.learning_rate(0.1, 10, 100), 0.05);
+        assert_eq!(step.learning_rate(0.1, 25, 100), 0.025);
+
+        let cosine = LearningRateSchedule::Cosine;
+        assert_eq!(cosine.learning_rate(0.1, 0, 100), 0.1);
+        assert!((cosine.learning_rate(0.1, 50, 100) - 0.05).abs() < 1e-6);
+        assert!(cosine.learning_rate(0.1, 99, 100) < 0.001);
+    }
+}
@@ -0,0 +1,317 @@
+use rand::{rngs::StdRng, SeedableRng};
+use std::io::BufRead;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    alphabeta,
+    bitbase::Bitbases,
+    book::Book,
+    chess_move::Move,
+    evaluator::Evaluator,
+    game::Game,
+    mcts::{self, SearchParams, Tree},
+    nnue::Nnue,
+    syzygy::Tablebases,
+};
+
+const START_POSITION: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+/// Finds the legal move that is written in UCI notation.
+pub fn find_move(game: &Game, uci: &str) -> Option<Move> {
+    game.legal_moves(game.player)
+        .into_iter()
+        .find(|m| m.to_uci() == uci)
+}
+
+/// Plays the moves in UCI notation, or returns the first one that is illegal.
+fn replay(mut game: Game, moves: &[String]) -> Result<(Game, Vec<Move>), String> {
+    let mut played = vec![];
+    for uci in moves {
+        let m = find_move(&game, uci).ok_or_else(|| uci.clone())?;
+        game = game.make_move(&m, true);
+        played.push(m);
+    }
+    Ok((game, played))
+}
+
+/// The position the engine currently searches, given as the starting FEN and
+/// the moves played since then.
+struct Search {
+    fen: String,
+    moves: Vec<String>,
+    tree: Tree,
+}
+
+impl Search {
+    fn new(
+        fen: &str,
+        moves: &[String],
+        params: SearchParams,
+        tablebases: Option<Arc<Tablebases>>,
+        bitbases: Option<Arc<Bitbases>>,
+    ) -> Result<Search, String> {
+        let (game, _) = replay(Game::from_fen(fen), moves)?;
+        Ok(Search {
+            fen: String::from(fen),
+            moves: moves.to_vec(),
+            tree: Tree::new(0, game, params)
+                .with_tablebases(tablebases)
+                .with_bitbases(bitbases),
+        })
+    }
+
+    /// Moves on to the given position. If it follows from the current one,
+    /// the search tree is advanced so that earlier work is not lost. With an
+    /// illegal move, the search stays where it is.
+    fn update(&mut self, fen: &str, moves: &[String]) -> Result<(), String> {
+        if self.fen != fen || !moves.starts_with(&self.moves) {
+            *self = Search::new(
+                fen,
+                moves,
+                self.tree.params(),
+                self.tree.tablebases().cloned(),
+                self.tree.bitbases().cloned(),
+            )?;
+            return Ok(());
+        }
+
+        let (_, played) = replay(self.tree.state().clone(), &moves[self.moves.len()..])?;
+        for m in played {
+            self.play(&m);
+        }
+        Ok(())
+    }
+
+    fn play(&mut self, m: &Move) {
+        self.tree.advance(m.index());
+        self.moves.push(m.to_uci());
+    }
+}
+
+fn parse_position(tokens: &[&str]) -> (String, Vec<String>) {
+    let moves_index = tokens.iter().position(|&token| token == "moves");
+    let fen = match tokens.get(1) {
+        Some(&"fen") => tokens[2..moves_index.unwrap_or(tokens.len())].join(" "),
+        _ => String::from(START_POSITION),
+    };
+    let moves = match moves_index {
+        Some(i) => tokens[i + 1..].iter().map(|m| m.to_string()).collect(),
+        None => vec![],
+    };
+    (fen, moves)
+}
+
+fn parse_go(tokens: &[&str], player: bool) -> (u32, Option<Duration>) {
+    let value = |name: &str| -> Option<u64> {
+        let i = tokens.iter().position(|&token| token == name)?;
+        tokens.get(i + 1)?.parse().ok()
+    };
+
+    let playouts = value("nodes").map_or(u32::MAX, |nodes| nodes as u32);
+    let (time, increment) = if player {
+        (value("wtime"), value("winc"))
+    } else {
+        (value("btime"), value("binc"))
+    };
+    let movetime = match (value("movetime"), time) {
+        (Some(movetime), _) => Some(movetime),
+        // The increment only arrives after the move, so the budget never
+        // uses up the time that is left
+        (None, Some(time)) => Some((time / 30 + increment.unwrap_or(0) / 2).min(time * 9 / 10)),
+        (None, None) => None,
+    };
+
+    if playouts == u32::MAX && movetime.is_none() {
+        (mcts::RUNS, None)
+    } else {
+        (playouts, movetime.map(Duration::from_millis))
+    }
+}
+
+/// How the engine chooses its moves.
+pub enum Engine<'a> {
+    Mcts(&'a Evaluator),
+    /// Searches to a fixed depth, ignoring the time and node limits of `go`.
+    AlphaBeta {
+        nnue: &'a Nnue,
+        depth: u32,
+    },
+}
+
+/// Plays using UCI. Moves from the opening book are played without searching
+/// as long as the position is in the book, and the tablebases decide the
+/// moves in the positions they know. Bitbases end the search in the
+/// positions they know.
+pub fn run(
+    params: SearchParams,
+    engine: Engine,
+    book: Option<&Book>,
+    tablebases: Option<Arc<Tablebases>>,
+    bitbases: Option<Arc<Bitbases>>,
+) -> std::io::Result<()> {
+    let mut search: Option<Search> = None;
+    let mut rng = StdRng::from_entropy();
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+
+        match tokens.first() {
+            Some(&"uci") => {
+                println!("id name mack7");
+                println!("id author Thomas Heyenbrock");
+                if let Some(tablebases) = &tablebases {
+                    println!(
+                        "info string tablebases with up to {} pieces",
+                        tablebases.max_pieces()
+                    );
+                }
+                if let Some(bitbases) = &bitbases {
+                    println!("info string bitbases {}", bitbases);
+                }
+                println!("uciok");
+            }
+            Some(&"isready") => println!("readyok"),
+            Some(&"ucinewgame") => search = None,
+            Some(&"position") => {
+                let (fen, moves) = parse_position(&tokens);
+                let result = match &mut search {
+                    Some(search) => search.update(&fen, &moves),
+                    None => Search::new(&fen, &moves, params, tablebases.clone(), bitbases.clone())
+                        .map(|new| search = Some(new)),
+                };
+                if let Err(uci) = result {
+                    println!("info string illegal move {}, ignoring the position", uci);
+                }
+            }
+            Some(&"go") => {
+                let search = search.get_or_insert_with(|| {
+                    Search::new(
+                        START_POSITION,
+                        &[],
+                        params,
+                        tablebases.clone(),
+                        bitbases.clone(),
+                    )
+                    .expect("The starting position has no moves")
+                });
+                let state = search.tree.state();
+                if state.legal_moves(state.player).is_empty() {
+                    println!("bestmove 0000");
+                    continue;
+                }
+                if let Some(m) = book.and_then(|book| book.pick(state, &mut rng)) {
+                    println!("info string book move");
+                    println!("bestmove {}", m.to_uci());
+                    search.play(&m);
+                    continue;
+                }
+
+                if let Some(dtz) = tablebases
+                    .as_deref()
+                    .and_then(|tablebases| tablebases.probe_dtz(state))
+                {
+                    println!("info string tablebase dtz {}", dtz);
+                }
+
+                let now = Instant::now();
+                let best_move = match &engine {
+                    Engine::Mcts(evaluator) => {
+                        let (playouts, movetime) = parse_go(&tokens, state.player);
+                        let visits_before = search.tree.visits();
+                        let best_move =
+                            mcts::search(&mut search.tree, playouts, movetime, evaluator);
+                        println!(
+                            "info nodes {} time {}",
+                            search.tree.visits() - visits_before,
+                            now.elapsed().as_millis()
+                        );
+                        best_move
+                    }
+                    Engine::AlphaBeta { nnue, depth } => {
+                        let result = alphabeta::search(
+                            state,
+                            nnue,
+                            *depth,
+                            tablebases.as_deref(),
+                            bitbases.as_deref(),
+                        );
+                        println!(
+                            "info depth {} nodes {} time {}",
+                            depth,
+                            result.nodes,
+                            now.elapsed().as_millis()
+                        );
+                        result.best_move.map(|m| m.index())
+                    }
+                };
+                let Some(best_move) = best_move else {
+                    println!("bestmove 0000");
+                    continue;
+                };
+
+                let state = search.tree.state();
+                let m = state
+                    .legal_moves(state.player)
+                    .into_iter()
+                    .find(|m| m.index() == best_move)
+                    .unwrap();
+                println!("bestmove {}", m.to_uci());
+                search.play(&m);
+            }
+            Some(&"quit") => break,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_legal_moves_only() {
+        let moves =
+            |moves: &[&str]| -> Vec<String> { moves.iter().map(|m| m.to_string()).collect() };
+        let (game, played) =
+            replay(Game::from_fen(START_POSITION), &moves(&["e2e4", "e7e5"])).unwrap();
+        assert_eq!(played.len(), 2);
+        assert_eq!(
+            game.to_fen(),
+            "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2"
+        );
+
+        let result = replay(Game::from_fen(START_POSITION), &moves(&["e2e4", "e2e4"]));
+        assert_eq!(result.err(), Some(String::from("e2e4")));
+    }
+
+    #[test]
+    fn budgets_time() {
+        let go = |line: &str, player| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            parse_go(&tokens, player)
+        };
+        assert_eq!(
+            go("go wtime 60000 winc 1000 btime 3000", true),
+            (u32::MAX, Some(Duration::from_millis(2500)))
+        );
+        assert_eq!(
+            go("go wtime 60000 btime 3000", false),
+            (u32::MAX, Some(Duration::from_millis(100)))
+        );
+        // A large increment can't spend more than the time left
+        assert_eq!(
+            go("go wtime 100 winc 1000", true),
+            (u32::MAX, Some(Duration::from_millis(90)))
+        );
+        assert_eq!(
+            go("go movetime 500 wtime 100", true),
+            (u32::MAX, Some(Duration::from_millis(500)))
+        );
+        assert_eq!(go("go nodes 800", true), (800, None));
+        assert_eq!(go("go", true), (mcts::RUNS, None));
+    }
+}
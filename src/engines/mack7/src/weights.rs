@@ -0,0 +1,318 @@
+//! A binary format for the weights of a `Network`. All numbers are little
+//! endian:
+//!
+//! | Field      | Type                                             |
+//! |------------|--------------------------------------------------|
+//! | magic      | `MK7W`                                           |
+//! | version    | u32                                              |
+//! | precision  | u8, 0 for f32 and 1 for f16                      |
+//! | blocks     | u32                                              |
+//! | filters    | u32                                              |
+//! | value head | u8, 0 for tanh and 1 for WDL                     |
+//! | tensors    | u32 count, then per tensor the u8 number of dimensions, the u32 size of each and the values |
+//! | checksum   | u32, CRC-32 of everything before                 |
+//!
+//! Other models use the same layout with their own magic and architecture
+//! fields, see `nnue`.
+
+use half::f16;
+use ndarray::{ArrayViewD, ArrayViewMutD};
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::network::{Network, NetworkConfig, ValueHead};
+
+const MAGIC: &[u8; 4] = b"MK7W";
+const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    F32,
+    /// Half the size, at the cost of about three decimal digits of precision.
+    F16,
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f32" => Ok(Precision::F32),
+            "f16" => Ok(Precision::F16),
+            _ => Err(format!("Unknown precision: {}", s)),
+        }
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// ##################################################################
+// Writing
+
+/// Starts a file with the magic, the format version and the precision.
+pub fn header(magic: &[u8; 4], version: u32, precision: Precision) -> Vec<u8> {
+    let mut bytes = magic.to_vec();
+    bytes.extend(version.to_le_bytes());
+    bytes.push(match precision {
+        Precision::F32 => 0,
+        Precision::F16 => 1,
+    });
+    bytes
+}
+
+/// Appends the number of tensors, followed by the shape and the values of
+/// each.
+pub fn push_tensors(bytes: &mut Vec<u8>, parameters: &[ArrayViewD<f32>], precision: Precision) {
+    bytes.extend((parameters.len() as u32).to_le_bytes());
+    for parameter in parameters {
+        bytes.push(parameter.ndim() as u8);
+        for &size in parameter.shape() {
+            bytes.extend((size as u32).to_le_bytes());
+        }
+        for &value in parameter.iter() {
+            match precision {
+                Precision::F32 => bytes.extend(value.to_le_bytes()),
+                Precision::F16 => bytes.extend(f16::from_f32(value).to_le_bytes()),
+            }
+        }
+    }
+}
+
+/// Appends the checksum of everything written so far.
+pub fn push_checksum(bytes: &mut Vec<u8>) {
+    let checksum = crc32(bytes);
+    bytes.extend(checksum.to_le_bytes());
+}
+
+pub fn to_bytes(network: &Network, precision: Precision) -> Vec<u8> {
+    let config = network.config();
+    let mut bytes = header(MAGIC, VERSION, precision);
+    bytes.extend((config.blocks as u32).to_le_bytes());
+    bytes.extend((config.filters as u32).to_le_bytes());
+    bytes.push(match config.value_head {
+        ValueHead::Tanh => 0,
+        ValueHead::Wdl => 1,
+    });
+    push_tensors(&mut bytes, &network.parameters(), precision);
+    push_checksum(&mut bytes);
+    bytes
+}
+
+/// Writes the bytes to a temporary file first and then renames it, so that
+/// the file is never left half written.
+pub fn write<P: AsRef<Path>>(path: P, bytes: &[u8]) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let temporary = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    writer.write_all(bytes)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    network: &Network,
+    precision: Precision,
+) -> std::io::Result<()> {
+    write(path, &to_bytes(network, precision))
+}
+
+// ##################################################################
+// Reading
+
+/// Reads values from the front of a byte slice.
+pub struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a> {
+    fn take<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        if self.0.len() < N {
+            return Err(invalid("The weights are cut off"));
+        }
+        let (bytes, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    /// Fails if anything is left after the last tensor.
+    pub fn finish(self) -> std::io::Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid("Unexpected data after the weights"))
+        }
+    }
+}
+
+/// Checks the magic, the checksum and the version written by `header` and
+/// `push_checksum`, and returns the precision together with the rest of the
+/// content.
+pub fn open<'a>(
+    bytes: &'a [u8],
+    magic: &[u8; 4],
+    version: u32,
+) -> std::io::Result<(Bytes<'a>, Precision)> {
+    if bytes.len() < 8 || &bytes[..4] != magic {
+        return Err(invalid("Not a weights file"));
+    }
+    let (content, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32(content) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(invalid("The checksum of the weights does not match"));
+    }
+
+    let mut bytes = Bytes(&content[4..]);
+    let file_version = bytes.u32()?;
+    if file_version != version {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported weights version {}", file_version),
+        ));
+    }
+    let precision = match bytes.u8()? {
+        0 => Precision::F32,
+        1 => Precision::F16,
+        _ => return Err(invalid("Unknown precision")),
+    };
+    Ok((bytes, precision))
+}
+
+/// Reads the tensors written by `push_tensors` into the given parameters,
+/// which need to have the same shapes.
+pub fn read_tensors(
+    bytes: &mut Bytes,
+    mut parameters: Vec<ArrayViewMutD<f32>>,
+    precision: Precision,
+) -> std::io::Result<()> {
+    if bytes.u32()? as usize != parameters.len() {
+        return Err(invalid(
+            "The number of tensors does not match the architecture",
+        ));
+    }
+    for parameter in parameters.iter_mut() {
+        let mut shape = vec![];
+        for _ in 0..bytes.u8()? {
+            shape.push(bytes.u32()? as usize);
+        }
+        if shape != parameter.shape() {
+            return Err(invalid(
+                "The shape of a tensor does not match the architecture",
+            ));
+        }
+        for value in parameter.iter_mut() {
+            *value = match precision {
+                Precision::F32 => f32::from_le_bytes(bytes.take()?),
+                Precision::F16 => f16::from_le_bytes(bytes.take()?).to_f32(),
+            };
+        }
+    }
+    Ok(())
+}
+
+pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Network> {
+    let (mut bytes, precision) = open(bytes, MAGIC, VERSION)?;
+    let config = NetworkConfig {
+        blocks: bytes.u32()? as usize,
+        filters: bytes.u32()? as usize,
+        value_head: match bytes.u8()? {
+            0 => ValueHead::Tanh,
+            1 => ValueHead::Wdl,
+            _ => return Err(invalid("Unknown value head")),
+        },
+    };
+
+    let mut network = Network::new(config);
+    read_tensors(&mut bytes, network.parameters_mut(), precision)?;
+    bytes.finish()?;
+    Ok(network)
+}
+
+/// Reads a whole file.
+pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Network> {
+    from_bytes(&read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network() -> Network {
+        Network::new(NetworkConfig {
+            blocks: 1,
+            filters: 4,
+            value_head: ValueHead::Wdl,
+        })
+    }
+
+    fn values(network: &Network) -> Vec<f32> {
+        network
+            .parameters()
+            .iter()
+            .flat_map(|parameter| parameter.iter().copied().collect::<Vec<f32>>())
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let network = network();
+
+        let bytes = to_bytes(&network, Precision::F32);
+        let loaded = from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.config(), network.config());
+        assert_eq!(values(&loaded), values(&network));
+
+        let half = to_bytes(&network, Precision::F16);
+        assert!(half.len() < bytes.len() * 6 / 10);
+        let loaded = from_bytes(&half).unwrap();
+        for (a, b) in values(&loaded).iter().zip(values(&network)) {
+            assert!((a - b).abs() <= b.abs() / 1000. + 1e-7);
+        }
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        let bytes = to_bytes(&network(), Precision::F32);
+
+        let mut corrupted = bytes.clone();
+        corrupted[100] ^= 1;
+        assert!(from_bytes(&corrupted).is_err());
+        assert!(from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(from_bytes(b"{\"value_head\":\"Tanh\"}").is_err());
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}